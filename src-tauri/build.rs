@@ -1,11 +1,6 @@
+#[cfg(feature = "spectrum-logic")]
 use std::path::PathBuf;
 
-macro_rules! p {
-    ($($tokens: tt)*) => {
-        println!("cargo:warning={}", format!($($tokens)*))
-    }
-}
-
 fn main() -> miette::Result<()> {
     /*
     let mut windows = tauri_build::WindowsAttributes::new();
@@ -39,6 +34,14 @@ fn main() -> miette::Result<()> {
     */
     tauri_build::build();
 
+    #[cfg(feature = "spectrum-logic")]
+    build_sdk_wrapper()?;
+
+    Ok(())
+}
+
+#[cfg(feature = "spectrum-logic")]
+fn build_sdk_wrapper() -> miette::Result<()> {
     let path = PathBuf::from("src");
     let include_path = PathBuf::from("C:\\SLDevice\\SDK\\headers");
     let mut b = autocxx_build::Builder::new("src/wrapper.rs", [&path, &include_path]).build()?;
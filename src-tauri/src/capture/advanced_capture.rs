@@ -1,421 +1,2022 @@
 use super::{
-    capture::{CaptureSettingBuilder, SequenceCapture, StreamCapture},
+    backend::DetectorBackend,
+    capture::{
+        CaptureError, CaptureSetting, CaptureSettingBuilder, SequenceCapture, StreamCapture,
+    },
     capture_manager::CorrectionMaps,
-    detector::DetectorController,
-    types::{AdvCapture, CaptureProgress, CaptureStreamItem},
+    correction_pipeline::CorrectionPipeline,
+    corrections::{compute_gain_map, CorrectionError, CorrectionMapKey},
+    defect_map::{
+        generate_defect_map, upper_threshold, DefectMapSettings, StackAccumulator, StackStatistics,
+    },
+    detector::{CapturedFrameStream, DetectorController},
+    lag::{residual_fractions, LagModel},
+    provenance::{MapContent, MapProvenance, NoiseStatistics},
+    stack_reduction::{FrameStack, IncrementalReducer, IncrementalStack, StackReductionSettings},
+    types::{
+        AdvCapture, CaptureResults, CaptureStreamItem, PauseControl, ProgressStep, ProgressTracker,
+    },
 };
 use crate::image::{
-    snr_threaded, CaptureResultData, ImageHandler, ImageMetadata, ImageMetadataBuilder,
-    SignalAccumulationData, SmartCaptureData,
+    snr_threaded, AveragedCaptureData, CaptureResultData, ImageHandler, ImageMetadata,
+    ImageMetadataBuilder, LagMeasurementData, SignalAccumulationData, SmartCaptureData,
 };
+use crate::wrapper::{FullWellModes, FullWellModesRS};
 use async_stream::stream;
+use chrono::Utc;
 
 use futures::stream::{self, StreamExt};
 
 use futures_core::Stream;
-use futures_util::stream::abortable;
+use image::{ImageBuffer, Luma};
 use log::{error, info};
 use serde::{Deserialize, Serialize};
 use specta::Type;
 use std::{
     pin::Pin,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        mpsc::Sender,
-        Arc, Mutex,
-    },
+    time::{Duration, Instant},
 };
 
 #[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
-#[serde(tag = "type", rename = "SmartCapture")]
 pub struct SmartCapture {
     pub exp_times: Vec<u32>,
     pub frames_per_capture: u32,
     pub window_size: u32,
     pub median_filtered: bool,
+    #[serde(default)]
+    pub corrections: CorrectionPipeline,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
-#[serde(tag = "type", rename = "SignalAccumulation")]
+#[serde(rename = "SignalAccumulation")]
 pub struct SignalAccumulationCapture {
     pub exp_times: Vec<u32>,
     pub frames_per_capture: u32,
+    #[serde(default)]
+    pub corrections: CorrectionPipeline,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
-#[serde(tag = "type", rename = "MultiCapture")]
 pub struct MultiCapture {
     pub exp_times: Vec<u32>,
     pub frames_per_capture: u32,
+    #[serde(default)]
+    pub corrections: CorrectionPipeline,
+}
+
+// Frames at each exposure reduced to one less noisy image as they arrive, so only the
+// reduced image is kept for each exposure rather than every frame
+#[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
+pub struct AveragedCapture {
+    pub exp_times: Vec<u32>,
+    pub frames_per_capture: u32,
+    pub reducer: IncrementalReducer,
+    #[serde(default)]
+    pub corrections: CorrectionPipeline,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
 pub struct DarkMapCapture {
     pub exp_times: Vec<u32>,
     pub frames_per_capture: u32,
+    #[serde(default)]
+    pub stack_reduction: StackReductionSettings,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
 pub struct DefectMapCapture {
     pub exp_times: Vec<u32>,
     pub frames_per_capture: u32,
+    #[serde(default)]
+    pub settings: DefectMapSettings,
+}
+
+// Illuminated frames averaged at each exposure to build gain maps, with the source set to
+// the signal level wanted for each one
+#[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
+pub struct FlatFieldCapture {
+    pub exp_times: Vec<u32>,
+    pub frames_per_capture: u32,
+}
+
+// A sequence during which the source is switched off, to measure how much of a bright frame's
+// signal lingers in the frames after it and fit a lag model for the capture settings
+#[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
+pub struct LagMeasurementCapture {
+    pub exp_time: u32,
+    pub num_frames: u32,
+    // Exponentials in the fitted decay
+    pub terms: u32,
+    // Frames at the end assumed to have no lag left, giving the dark level
+    pub settle_frames: u32,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
-#[serde(tag = "type")]
 pub struct LiveCapture {
     pub exp_time: u32,
+    #[serde(default)]
+    pub corrections: CorrectionPipeline,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
+#[serde(tag = "type")]
+pub enum TimeLapseLength {
+    Acquisitions { count: u32 },
+    // Acquisitions keep starting every interval until this long after the first
+    Duration { secs: u32 },
+}
+
+// What's kept from the frames of each acquisition
+#[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
+#[serde(tag = "type")]
+pub enum TimeLapseFrames {
+    All,
+    // Only the frame with the best SNR, picked as in a SmartCapture
+    BestSnr { window_size: u32 },
+}
+
+// Frames taken every `interval_secs` to follow a slowly changing sample, all kept in one image
+// stack with the time each frame was taken
+#[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
+pub struct TimeLapseCapture {
+    pub exp_time: u32,
+    pub frames_per_acquisition: u32,
+    pub interval_secs: u32,
+    pub length: TimeLapseLength,
+    pub frames: TimeLapseFrames,
+    #[serde(default)]
+    pub corrections: CorrectionPipeline,
+}
+
+impl TimeLapseCapture {
+    // Acquisitions are at least a second apart
+    pub fn interval_secs(&self) -> u32 {
+        self.interval_secs.max(1)
+    }
+
+    pub fn acquisitions(&self) -> u32 {
+        let interval_secs = self.interval_secs();
+        match self.length {
+            TimeLapseLength::Acquisitions { count } => count,
+            TimeLapseLength::Duration { secs } => {
+                (secs.saturating_add(interval_secs - 1) / interval_secs).max(1)
+            }
+        }
+    }
+}
+
+// Frames of a sequence that stops after the frame a pause was requested on, and captures the
+// remaining frames in a new sequence once resumed, leaving the detector out of live mode while
+// it's paused. Waits before starting if a pause was requested between exposure steps.
+fn pausable_frames<D: DetectorBackend + Clone + 'static>(
+    detector_controller: &DetectorController<D>,
+    capture_settings: &CaptureSetting,
+    num_frames: u32,
+    correction_maps: &CorrectionMaps,
+    pause: &PauseControl,
+) -> CapturedFrameStream {
+    let mut detector_controller = detector_controller.clone();
+    let capture_settings = capture_settings.clone();
+    let correction_maps = correction_maps.clone();
+    let pause = pause.clone();
+
+    stream! {
+        let mut remaining = num_frames;
+        while remaining > 0 {
+            pause.wait_while_paused().await;
+
+            let mut sequence_settings = capture_settings.clone();
+            sequence_settings.capture_mode = Box::new(SequenceCapture {
+                num_frames: remaining,
+            });
+            let mut frames =
+                capture_frames(&mut detector_controller, &sequence_settings, &correction_maps);
+
+            let mut paused = false;
+            while let Some(frame) = frames.next().await {
+                let failed = frame.is_err();
+                yield frame;
+                if failed {
+                    return;
+                }
+
+                remaining -= 1;
+                if pause.is_requested() {
+                    paused = true;
+                    break;
+                }
+            }
+
+            // A sequence that ended early by itself wouldn't produce the rest either
+            if !paused {
+                break;
+            }
+        }
+    }
+    .boxed()
+}
+
+// Calibration maps are generated for both full well modes at the default binning and DDS
+// settings, as those are what captures currently run with
+fn calibration_full_well_modes() -> [FullWellModesRS; 2] {
+    [
+        FullWellModesRS {
+            remote_ty: FullWellModes::High,
+        },
+        FullWellModesRS {
+            remote_ty: FullWellModes::Low,
+        },
+    ]
+}
+
+// Frames of one capture, or just the error if the detector couldn't start it. The detector is
+// only set up once the stream is first polled, so captures chained one after another don't
+// configure it over each other.
+fn capture_frames<D: DetectorBackend + Clone + 'static>(
+    detector_controller: &mut DetectorController<D>,
+    capture_settings: &CaptureSetting,
+    correction_maps: &CorrectionMaps,
+) -> CapturedFrameStream {
+    let mut detector_controller = detector_controller.clone();
+    let capture_settings = capture_settings.clone();
+    let correction_maps = correction_maps.clone();
+
+    stream::once(async move {
+        match detector_controller.run_capture_stream(capture_settings, correction_maps) {
+            Ok(frames) => frames,
+            Err(e) => stream::once(async move { Err(e) }).boxed(),
+        }
+    })
+    .flatten()
+    .boxed()
+}
+
+// A frame brighter than the dark level by this many robust standard deviations of the dark
+// means the source is on
+const SOURCE_ON_SIGMA: f32 = 20.0;
+const SOURCE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const SOURCE_WAIT_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Takes single frames with the settings `dark` was captured with until one shows the source
+// has been switched on
+async fn wait_for_source<D: DetectorBackend + Clone + 'static>(
+    detector_controller: &mut DetectorController<D>,
+    capture_settings: &CaptureSetting,
+    correction_maps: &CorrectionMaps,
+    dark: &StackStatistics,
+) -> Result<(), CaptureError> {
+    let threshold = upper_threshold(&dark.mean, SOURCE_ON_SIGMA) as f64;
+    let mut poll_settings = capture_settings.clone();
+    poll_settings.capture_mode = Box::new(SequenceCapture { num_frames: 1 });
+
+    let started = Instant::now();
+    while started.elapsed() < SOURCE_WAIT_TIMEOUT {
+        let mut frames = capture_frames(detector_controller, &poll_settings, correction_maps);
+        while let Some(frame) = frames.next().await {
+            let frame = frame?.image.to_image_buffer();
+            let mean =
+                frame.iter().map(|&value| value as f64).sum::<f64>() / frame.len().max(1) as f64;
+            if mean > threshold {
+                return Ok(());
+            }
+        }
+        tokio::time::sleep(SOURCE_POLL_INTERVAL).await;
+    }
+
+    Err(CaptureError::NoSourceSignal(
+        SOURCE_WAIT_TIMEOUT.as_secs() as u32
+    ))
+}
+
+fn calibration_preview(
+    image: ImageBuffer<Luma<u16>, Vec<u16>>,
+    capture_settings: &CaptureSetting,
+) -> ImageHandler {
+    let mut image_handler = ImageHandler::new(
+        image,
+        ImageMetadataBuilder::new()
+            .capture_settings(capture_settings.clone())
+            .build(),
+    );
+    image_handler.apply_histogram_equilization();
+    image_handler
 }
 
 impl AdvCapture for DarkMapCapture {
-    fn start_stream(
+    fn start_stream<D: DetectorBackend + Clone + 'static>(
         &self,
-        detector_controller_mutex: DetectorController,
+        mut detector_controller: DetectorController<D>,
         correction_maps: &CorrectionMaps,
-        mut progress_tx: Sender<CaptureProgress>,
+        pause: PauseControl,
+        _results: CaptureResults,
     ) -> Pin<Box<dyn Stream<Item = CaptureStreamItem> + Send>> {
-        let stream = stream! { yield CaptureStreamItem::Progress(CaptureProgress::new(0, "test".to_string())) };
+        info!("Starting Dark Map Capture");
+
+        let exp_times = self.exp_times.clone();
+        let num_frames = self.frames_per_capture;
+        let stack_reduction = self.stack_reduction.clone();
+        let correction_maps = correction_maps.clone();
+
+        let stream = stream! {
+            let detector = detector_controller.detector_identity();
+            let full_well_modes = calibration_full_well_modes();
+            let mut progress = ProgressTracker::new(
+                full_well_modes
+                    .iter()
+                    .flat_map(|_| exposure_steps(&exp_times, num_frames))
+                    .collect(),
+            );
+
+            let mut dark_maps = Vec::new();
+            for full_well in full_well_modes {
+                for &exp_time in &exp_times {
+                    let capture_settings = CaptureSettingBuilder::new(
+                        exp_time,
+                        Box::new(SequenceCapture { num_frames }),
+                    )
+                    .corrected(false)
+                    .full_well(full_well.clone())
+                    .build();
+                    let key = CorrectionMapKey::from_capture_setting(&capture_settings);
+
+                    yield CaptureStreamItem::Progress(
+                        progress.start_step(format!("Capturing dark frames for {key}")),
+                    );
+
+                    let mut frames = pausable_frames(
+                        &detector_controller,
+                        &capture_settings,
+                        num_frames,
+                        &correction_maps,
+                        &pause,
+                    );
+
+                    let mut frame_stack: Option<FrameStack> = None;
+                    while let Some(captured) = frames.next().await {
+                        let mut captured = match captured {
+                            Ok(captured) => captured,
+                            Err(e) => {
+                                error!("Dark frame capture failed for {key}: {e}");
+                                yield CaptureStreamItem::Failed(e);
+                                return;
+                            }
+                        };
+                        let frame = captured.image.to_image_buffer();
+                        let frame_stack = frame_stack.get_or_insert_with(|| {
+                            FrameStack::new(frame.width(), frame.height(), exp_time)
+                        });
+                        if let Err(e) = frame_stack.add(&frame) {
+                            error!("Skipping frame for dark map generation: {e}");
+                        }
+                        yield CaptureStreamItem::Image(
+                            calibration_preview(frame, &capture_settings),
+                        );
+                        yield CaptureStreamItem::Progress(progress.frame());
+                    }
+
+                    let reduction = match frame_stack.map(|stack| stack.reduce(&stack_reduction)) {
+                        Some(Ok(reduction)) => reduction,
+                        Some(Err(e)) => {
+                            error!("Failed to reduce dark frames for {key}: {e}");
+                            yield CaptureStreamItem::Failed(CaptureError::CalibrationFailed(key, e));
+                            return;
+                        }
+                        None => {
+                            error!("No dark frames captured for {key}");
+                            yield CaptureStreamItem::Failed(CaptureError::CalibrationFailed(
+                                key,
+                                CorrectionError::NoFrames,
+                            ));
+                            return;
+                        }
+                    };
+
+                    let dark_stack = reduction.stack;
+                    let summary = reduction.summary;
+                    yield CaptureStreamItem::Progress(progress.message(format!(
+                        "Rejected {} frames and {} pixels for {key}",
+                        summary.rejected_frames.len(),
+                        summary.rejected_pixels
+                    )));
+
+                    let dark_map = dark_stack.mean_image();
+                    let provenance = MapProvenance::new(
+                        dark_map.content_id(),
+                        detector.clone(),
+                        vec![capture_settings.clone()],
+                        dark_stack.frame_count,
+                        Some(NoiseStatistics::from_stack(&dark_stack)),
+                    )
+                    .with_stack_reduction(summary);
+                    if let Err(e) =
+                        correction_maps.save_dark_map(key.clone(), dark_map.clone(), provenance)
+                    {
+                        error!("Failed to save dark map for {key}: {e}");
+                        yield CaptureStreamItem::Failed(CaptureError::CalibrationFailed(key, e));
+                        return;
+                    }
+                    dark_maps.push(ImageHandler::new(
+                        dark_map,
+                        ImageMetadataBuilder::new()
+                            .capture_settings(capture_settings)
+                            .build(),
+                    ));
+                }
+            }
+
+            yield CaptureStreamItem::CaptureResult(dark_maps);
+        };
+
         Box::pin(stream)
     }
 }
 
 impl AdvCapture for DefectMapCapture {
-    fn start_stream(
+    fn start_stream<D: DetectorBackend + Clone + 'static>(
+        &self,
+        mut detector_controller: DetectorController<D>,
+        correction_maps: &CorrectionMaps,
+        pause: PauseControl,
+        _results: CaptureResults,
+    ) -> Pin<Box<dyn Stream<Item = CaptureStreamItem> + Send>> {
+        info!("Starting Defect Map Capture");
+
+        let exp_times = self.exp_times.clone();
+        let num_frames = self.frames_per_capture;
+        let settings = self.settings.clone();
+        let correction_maps = correction_maps.clone();
+
+        let stream = stream! {
+            let detector = detector_controller.detector_identity();
+            let full_well_modes = calibration_full_well_modes();
+            // A dark and a flat sweep for each full well mode
+            let mut steps: Vec<ProgressStep> = full_well_modes
+                .iter()
+                .chain(full_well_modes.iter())
+                .flat_map(|_| exposure_steps(&exp_times, num_frames))
+                .collect();
+            steps.push(ProgressStep {
+                exp_time: 0,
+                frames: 0,
+            });
+            let mut progress = ProgressTracker::new(steps);
+
+            // One exposure sweep of dark stacks per full well mode, then the same with the source
+            // on for the flat stacks
+            let mut dark_series = Vec::new();
+            let mut flat_series = Vec::new();
+            let mut series_settings = Vec::new();
+            let mut source_reference: Option<(CaptureSetting, StackStatistics)> = None;
+            for illuminated in [false, true] {
+                let kind = if illuminated { "flat" } else { "dark" };
+
+                if illuminated {
+                    let Some((reference_settings, reference_dark)) = source_reference.clone() else {
+                        error!("No dark frames to compare flat frames against");
+                        yield CaptureStreamItem::Failed(CorrectionError::NoFrames.into());
+                        return;
+                    };
+                    yield CaptureStreamItem::Progress(progress.message(
+                        "Switch the X-ray source on to capture the flat frames".to_string(),
+                    ));
+                    if let Err(e) = wait_for_source(
+                        &mut detector_controller,
+                        &reference_settings,
+                        &correction_maps,
+                        &reference_dark,
+                    )
+                    .await
+                    {
+                        error!("Source never came on for the flat frames: {e}");
+                        yield CaptureStreamItem::Failed(e);
+                        return;
+                    }
+                }
+
+                for full_well in full_well_modes.iter() {
+                    let mut series = Vec::new();
+                    for &exp_time in &exp_times {
+                        let capture_settings = CaptureSettingBuilder::new(
+                            exp_time,
+                            Box::new(SequenceCapture { num_frames }),
+                        )
+                        .corrected(false)
+                        .full_well(full_well.clone())
+                        .build();
+                        if !illuminated {
+                            series_settings.push(capture_settings.clone());
+                        }
+
+                        yield CaptureStreamItem::Progress(progress.start_step(format!(
+                            "Capturing {exp_time}ms {kind} frames with {full_well} full well"
+                        )));
+
+                        let mut frames = pausable_frames(
+                            &detector_controller,
+                            &capture_settings,
+                            num_frames,
+                            &correction_maps,
+                            &pause,
+                        );
+
+                        let mut accumulator: Option<StackAccumulator> = None;
+                        while let Some(captured) = frames.next().await {
+                            let mut captured = match captured {
+                                Ok(captured) => captured,
+                                Err(e) => {
+                                    error!("{exp_time}ms {kind} frame capture failed: {e}");
+                                    yield CaptureStreamItem::Failed(e);
+                                    return;
+                                }
+                            };
+                            let frame = captured.image.to_image_buffer();
+                            let accumulator = accumulator.get_or_insert_with(|| {
+                                StackAccumulator::new(frame.width(), frame.height(), exp_time)
+                            });
+                            if let Err(e) = accumulator.add(&frame) {
+                                error!("Skipping frame for defect map generation: {e}");
+                            }
+                            yield CaptureStreamItem::Image(
+                                calibration_preview(frame, &capture_settings),
+                            );
+                            yield CaptureStreamItem::Progress(progress.frame());
+                        }
+
+                        if let Some(stack) = accumulator.map(StackAccumulator::finish) {
+                            if source_reference.is_none() {
+                                source_reference = Some((capture_settings, stack.clone()));
+                            }
+                            series.push(stack);
+                        }
+                    }
+
+                    if illuminated {
+                        flat_series.push(series);
+                    } else {
+                        dark_series.push(series);
+                    }
+                }
+            }
+
+            yield CaptureStreamItem::Progress(
+                progress.start_step("Generating defect map".to_string()),
+            );
+
+            let defect_map = match generate_defect_map(&dark_series, &flat_series, &settings) {
+                Ok(defect_map) => defect_map,
+                Err(e) => {
+                    error!("Failed to generate defect map: {e}");
+                    yield CaptureStreamItem::Failed(e.into());
+                    return;
+                }
+            };
+
+            info!(
+                "Generated defect map with {} defective pixels",
+                defect_map.summary.total_defective_pixels
+            );
+            let map = defect_map.map.clone();
+            let provenance = MapProvenance::new(
+                map.content_id(),
+                detector,
+                series_settings,
+                num_frames,
+                None,
+            );
+            if let Err(e) = correction_maps.save_defect_map(defect_map, provenance) {
+                error!("Failed to save defect map: {e}");
+                yield CaptureStreamItem::Failed(e.into());
+                return;
+            }
+
+            let mut image_handler = ImageHandler::new(map, ImageMetadataBuilder::new().build());
+            image_handler.apply_histogram_equilization();
+            yield CaptureStreamItem::CaptureResult(vec![image_handler]);
+        };
+
+        Box::pin(stream)
+    }
+}
+
+impl AdvCapture for FlatFieldCapture {
+    fn start_stream<D: DetectorBackend + Clone + 'static>(
         &self,
-        detector_controller_mutex: DetectorController,
+        mut detector_controller: DetectorController<D>,
         correction_maps: &CorrectionMaps,
-        mut progress_tx: Sender<CaptureProgress>,
+        pause: PauseControl,
+        _results: CaptureResults,
     ) -> Pin<Box<dyn Stream<Item = CaptureStreamItem> + Send>> {
-        let stream = stream! { yield CaptureStreamItem::Progress(CaptureProgress::new(0, "test".to_string())) };
+        info!("Starting Flat Field Capture");
+
+        let exp_times = self.exp_times.clone();
+        let num_frames = self.frames_per_capture;
+        let correction_maps = correction_maps.clone();
+        let capture_settings = move |exp_time| {
+            CaptureSettingBuilder::new(exp_time, Box::new(SequenceCapture { num_frames }))
+                .corrected(false)
+                .build()
+        };
+
+        let stream = stream! {
+            // Gain maps are normalised after subtracting the dark map for the same exposure
+            for &exp_time in &exp_times {
+                let key = CorrectionMapKey::from_capture_setting(&capture_settings(exp_time));
+                if let Err(e) = correction_maps.get_dark_map(&key) {
+                    error!("Can't generate a gain map for {key}: {e}");
+                    yield CaptureStreamItem::Failed(e.into());
+                    return;
+                }
+            }
+
+            let detector = detector_controller.detector_identity();
+            let mut progress = ProgressTracker::new(exposure_steps(&exp_times, num_frames));
+
+            let mut flats = Vec::new();
+            for &exp_time in &exp_times {
+                let capture_settings = capture_settings(exp_time);
+                let key = CorrectionMapKey::from_capture_setting(&capture_settings);
+
+                yield CaptureStreamItem::Progress(
+                    progress.start_step(format!("Capturing flat fields for {key}")),
+                );
+
+                let mut frames = pausable_frames(
+                    &detector_controller,
+                    &capture_settings,
+                    num_frames,
+                    &correction_maps,
+                    &pause,
+                );
+
+                let mut accumulator: Option<StackAccumulator> = None;
+                while let Some(captured) = frames.next().await {
+                    let mut captured = match captured {
+                        Ok(captured) => captured,
+                        Err(e) => {
+                            error!("Flat field capture failed for {key}: {e}");
+                            yield CaptureStreamItem::Failed(e);
+                            return;
+                        }
+                    };
+                    let frame = captured.image.to_image_buffer();
+                    let accumulator = accumulator.get_or_insert_with(|| {
+                        StackAccumulator::new(frame.width(), frame.height(), exp_time)
+                    });
+                    if let Err(e) = accumulator.add(&frame) {
+                        error!("Skipping frame for gain map generation: {e}");
+                    }
+                    yield CaptureStreamItem::Image(calibration_preview(frame, &capture_settings));
+                    yield CaptureStreamItem::Progress(progress.frame());
+                }
+
+                let Some(flat) = accumulator.map(StackAccumulator::finish) else {
+                    error!("No flat field frames captured for {key}");
+                    yield CaptureStreamItem::Failed(CaptureError::CalibrationFailed(
+                        key,
+                        CorrectionError::NoFrames,
+                    ));
+                    return;
+                };
+
+                let gain_map = match correction_maps
+                    .get_dark_map(&key)
+                    .and_then(|dark_map| compute_gain_map(&flat, &dark_map))
+                {
+                    Ok(gain_map) => gain_map,
+                    Err(e) => {
+                        error!("Failed to generate gain map for {key}: {e}");
+                        yield CaptureStreamItem::Failed(CaptureError::CalibrationFailed(key, e));
+                        return;
+                    }
+                };
+
+                let provenance = MapProvenance::new(
+                    gain_map.content_id(),
+                    detector.clone(),
+                    vec![capture_settings.clone()],
+                    flat.frame_count,
+                    Some(NoiseStatistics::from_stack(&flat)),
+                );
+                if let Err(e) = correction_maps.save_gain_map(key.clone(), gain_map, provenance) {
+                    error!("Failed to save gain map for {key}: {e}");
+                    yield CaptureStreamItem::Failed(CaptureError::CalibrationFailed(key, e));
+                    return;
+                }
+                info!("Generated gain map for {key}");
+                flats.push(ImageHandler::new(
+                    flat.mean_image(),
+                    ImageMetadataBuilder::new()
+                        .capture_settings(capture_settings)
+                        .build(),
+                ));
+            }
+
+            yield CaptureStreamItem::CaptureResult(flats);
+        };
+
+        Box::pin(stream)
+    }
+}
+
+impl AdvCapture for LagMeasurementCapture {
+    fn start_stream<D: DetectorBackend + Clone + 'static>(
+        &self,
+        mut detector_controller: DetectorController<D>,
+        correction_maps: &CorrectionMaps,
+        _pause: PauseControl,
+        _results: CaptureResults,
+    ) -> Pin<Box<dyn Stream<Item = CaptureStreamItem> + Send>> {
+        info!("Starting Lag Measurement Capture");
+
+        let capture_settings = CaptureSettingBuilder::new(
+            self.exp_time,
+            Box::new(SequenceCapture {
+                num_frames: self.num_frames,
+            }),
+        )
+        .corrected(false)
+        .build();
+        let num_frames = self.num_frames;
+        let terms = self.terms;
+        let settle_frames = self.settle_frames;
+        let correction_maps = correction_maps.clone();
+
+        let stream = stream! {
+            let key = CorrectionMapKey::from_capture_setting(&capture_settings);
+            let mut progress = ProgressTracker::new(vec![
+                ProgressStep {
+                    exp_time: capture_settings.exp_time,
+                    frames: num_frames,
+                },
+                ProgressStep {
+                    exp_time: 0,
+                    frames: 0,
+                },
+            ]);
+            yield CaptureStreamItem::Progress(progress.start_step(format!(
+                "Capturing lag frames for {key}, switch the source off partway through"
+            )));
+
+            let mut frames =
+                capture_frames(&mut detector_controller, &capture_settings, &correction_maps);
+
+            let mut frame_means: Vec<f32> = Vec::new();
+            // Returned with the fitted model so the exposure can be checked
+            let mut brightest: Option<ImageBuffer<Luma<u16>, Vec<u16>>> = None;
+            while let Some(captured) = frames.next().await {
+                let mut captured = match captured {
+                    Ok(captured) => captured,
+                    Err(e) => {
+                        error!("Lag capture failed for {key}: {e}");
+                        yield CaptureStreamItem::Failed(e);
+                        return;
+                    }
+                };
+                let frame = captured.image.to_image_buffer();
+                let mean = (frame.iter().map(|&v| v as f64).sum::<f64>()
+                    / frame.as_raw().len().max(1) as f64) as f32;
+                if frame_means.iter().all(|&other| mean > other) {
+                    brightest = Some(frame.clone());
+                }
+                frame_means.push(mean);
+                yield CaptureStreamItem::Image(calibration_preview(frame, &capture_settings));
+                yield CaptureStreamItem::Progress(progress.frame());
+            }
+
+            let Some(brightest) = brightest else {
+                error!("No lag frames captured for {key}");
+                yield CaptureStreamItem::Failed(CorrectionError::NoFrames.into());
+                return;
+            };
+
+            yield CaptureStreamItem::Progress(progress.start_step("Fitting lag model".to_string()));
+
+            let model = match residual_fractions(&frame_means, settle_frames)
+                .and_then(|fractions| LagModel::fit(key.clone(), fractions, terms))
+            {
+                Ok(model) => model,
+                Err(e) => {
+                    error!("Failed to measure lag for {key}: {e}");
+                    yield CaptureStreamItem::Failed(e.into());
+                    return;
+                }
+            };
+            info!(
+                "Measured {:.2}% lag in the first frame for {key}",
+                model.residual_fraction(1) * 100.0
+            );
+            if let Err(e) = correction_maps.save_lag_model(model.clone()) {
+                error!("Failed to save lag model for {key}: {e}");
+                yield CaptureStreamItem::Failed(e.into());
+                return;
+            }
+
+            let mut image_handler = ImageHandler::new(
+                brightest,
+                ImageMetadataBuilder::new()
+                    .capture_settings(capture_settings)
+                    .extra_info(CaptureResultData::LagMeasurementData(LagMeasurementData {
+                        model,
+                    }))
+                    .build(),
+            );
+            image_handler.apply_histogram_equilization();
+            yield CaptureStreamItem::CaptureResult(vec![image_handler]);
+        };
+
         Box::pin(stream)
     }
 }
 
 impl AdvCapture for LiveCapture {
-    fn start_stream(
+    fn start_stream<D: DetectorBackend + Clone + 'static>(
         &self,
-        mut detector_controller: DetectorController,
+        mut detector_controller: DetectorController<D>,
         correction_maps: &CorrectionMaps,
-        mut progress_tx: Sender<CaptureProgress>,
+        _pause: PauseControl,
+        _results: CaptureResults,
     ) -> Pin<Box<dyn Stream<Item = CaptureStreamItem> + Send>> {
         info!("Starting Live Capture");
 
         let capture_settings =
             CaptureSettingBuilder::new(self.exp_time, Box::new(StreamCapture { duration: None }))
+                .corrections(self.corrections.clone())
                 .build();
-        let stream = detector_controller
-            .run_capture_stream(capture_settings.clone(), correction_maps.clone());
-
-        let s = stream
-            .map(move |mut image| {
+        let mut frames =
+            capture_frames(&mut detector_controller, &capture_settings, correction_maps);
+
+        let stream = stream! {
+            let mut progress = ProgressTracker::continuous(capture_settings.exp_time);
+            yield CaptureStreamItem::Progress(
+                progress.start_step(format!("Live capture at {}ms", capture_settings.exp_time)),
+            );
+
+            while let Some(frame) = frames.next().await {
+                let mut frame = match frame {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        yield CaptureStreamItem::Failed(e);
+                        return;
+                    }
+                };
                 let mut image_handler = ImageHandler::new(
-                    image.to_image_buffer(),
+                    frame.image.to_image_buffer(),
                     ImageMetadataBuilder::new()
                         .capture_settings(capture_settings.clone())
+                        .dark_correction(frame.dark_correction)
+                        .correction_map_ids(frame.correction_map_ids)
+                        .correction_stages(frame.correction_stages)
                         .build(),
                 );
                 image_handler.apply_histogram_equilization();
-                CaptureStreamItem::Image(image_handler)
-            })
-            .boxed();
+                yield CaptureStreamItem::Image(image_handler);
+                yield CaptureStreamItem::Progress(progress.frame());
+            }
+        };
 
-        s
+        Box::pin(stream)
     }
 }
 
+// Steps of a capture that takes `frames_per_capture` frames at each exposure
+fn exposure_steps(exp_times: &[u32], frames_per_capture: u32) -> Vec<ProgressStep> {
+    exp_times
+        .iter()
+        .map(|&exp_time| ProgressStep {
+            exp_time,
+            frames: frames_per_capture,
+        })
+        .collect()
+}
+
 impl AdvCapture for SmartCapture {
-    fn start_stream(
+    fn start_stream<D: DetectorBackend + Clone + 'static>(
         &self,
-        mut detector_controller: DetectorController,
+        detector_controller: DetectorController<D>,
         correction_maps: &CorrectionMaps,
-        progress_tx: Sender<CaptureProgress>,
+        pause: PauseControl,
+        results: CaptureResults,
     ) -> Pin<Box<dyn Stream<Item = CaptureStreamItem> + Send>> {
         info!("Starting Smart Capture");
 
-        let best_capture: Arc<Mutex<(Option<ImageHandler>, f64)>> =
-            Arc::new(Mutex::new((None, 0.0)));
-
-        let capture_progress = Arc::new(Mutex::new(CaptureProgress::new(
-            self.exp_times.len() as u32,
-            "Starting Multi Capture".to_string(),
-        )));
+        let capture = self.clone();
+        let correction_maps = correction_maps.clone();
 
-        let streams = self
-            .exp_times
-            .iter()
-            .map(|&exp_time| {
-                let capture_progress = capture_progress.clone();
-
-                match progress_tx.send(CaptureProgress::new(1000, "Test".to_owned())) {
-                    Ok(_) => {}
-                    Err(e) => error!("Failed to send progress update: {}", e),
-                }
+        let stream = stream! {
+            let mut progress = ProgressTracker::new(
+                exposure_steps(&capture.exp_times, capture.frames_per_capture),
+            );
+            let mut best_snr = 0.0;
 
+            for &exp_time in &capture.exp_times {
                 let capture_settings = CaptureSettingBuilder::new(
                     exp_time,
                     Box::new(SequenceCapture {
-                        num_frames: self.frames_per_capture,
+                        num_frames: capture.frames_per_capture,
                     }),
                 )
+                .corrections(capture.corrections.clone())
                 .build();
 
-                let window_size = self.window_size;
-                let best_capture = best_capture.clone();
-
-                detector_controller
-                    .run_capture_stream(capture_settings.clone(), correction_maps.clone())
-                    .map(move |mut image| {
-                        let image_buffer = image.to_image_buffer();
-                        let snr_results = snr_threaded(&image_buffer, window_size).unwrap();
-                        let image_metadata = ImageMetadataBuilder::new()
-                            .capture_settings(capture_settings.clone())
-                            .extra_info(CaptureResultData::SmartCaptureData(SmartCaptureData {
-                                signal_noise_ratio: snr_results.0.clone(),
-                                background_rect: snr_results.1.clone(),
-                                foreground_rect: snr_results.2.clone(),
-                            }))
-                            .build();
-
-                        let mut image_handler = ImageHandler::new(image_buffer, image_metadata);
-
-                        image_handler.apply_histogram_equilization();
+                yield CaptureStreamItem::Progress(
+                    progress.start_step(format!("Capturing images for exposure time {exp_time}ms")),
+                );
 
-                        let mut best = best_capture.lock().unwrap();
-                        if snr_results.0 > best.1 {
-                            *best = (Some(image_handler.clone()), snr_results.0);
+                let mut frames = pausable_frames(
+                    &detector_controller,
+                    &capture_settings,
+                    capture.frames_per_capture,
+                    &correction_maps,
+                    &pause,
+                );
+                while let Some(frame) = frames.next().await {
+                    let mut frame = match frame {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            yield CaptureStreamItem::Failed(e);
+                            return;
                         }
-
-                        CaptureStreamItem::Image(image_handler)
-                    })
-            })
-            .collect::<Vec<_>>();
-
-        let mut stream = stream::iter(streams).flatten().boxed();
-
-        let best_capture = best_capture.clone();
-        let new_stream = stream! {
-            while let Some(stream_item) = stream.next().await {
-                yield stream_item;
+                    };
+                    let image_buffer = frame.image.to_image_buffer();
+                    let Ok(snr_results) = snr_threaded(&image_buffer, capture.window_size) else {
+                        yield CaptureStreamItem::Failed(
+                            CaptureError::SnrWindowTooLarge(capture.window_size),
+                        );
+                        return;
+                    };
+                    let image_metadata = ImageMetadataBuilder::new()
+                        .capture_settings(capture_settings.clone())
+                        .extra_info(CaptureResultData::SmartCaptureData(SmartCaptureData {
+                            signal_noise_ratio: snr_results.0,
+                            background_rect: snr_results.1.clone(),
+                            foreground_rect: snr_results.2.clone(),
+                        }))
+                        .dark_correction(frame.dark_correction)
+                        .correction_map_ids(frame.correction_map_ids)
+                        .correction_stages(frame.correction_stages)
+                        .build();
+
+                    let mut image_handler = ImageHandler::new(image_buffer, image_metadata);
+
+                    image_handler.apply_histogram_equilization();
+
+                    if snr_results.0 > best_snr {
+                        results.replace(vec![image_handler.clone()]);
+                        best_snr = snr_results.0;
+                    }
+
+                    yield CaptureStreamItem::Image(image_handler);
+                    yield CaptureStreamItem::Progress(progress.frame());
+                }
             }
 
-            let final_best_image = {
-                let best_capture_guard = best_capture.lock().unwrap();
-                best_capture_guard.0.clone()
-            };
-
-
-            if let Some(image_handler) = final_best_image {
-                yield CaptureStreamItem::CaptureResult(vec![image_handler]);
+            let best_capture = results.take();
+            if !best_capture.is_empty() {
+                yield CaptureStreamItem::CaptureResult(best_capture);
             }
         };
 
-        Box::pin(new_stream)
+        Box::pin(stream)
     }
 }
 
 impl AdvCapture for SignalAccumulationCapture {
-    fn start_stream(
+    fn start_stream<D: DetectorBackend + Clone + 'static>(
         &self,
-        mut detector_controller: DetectorController,
+        detector_controller: DetectorController<D>,
         correction_maps: &CorrectionMaps,
-        mut progress_tx: Sender<CaptureProgress>,
+        pause: PauseControl,
+        results: CaptureResults,
     ) -> Pin<Box<dyn Stream<Item = CaptureStreamItem> + Send>> {
         const MAX_PIXEL_VALUE: u16 = 16383;
-        info!("Starting Smart Capture");
+        info!("Starting Signal Accumulation Capture");
 
-        let capture_result: Arc<Mutex<Option<Vec<ImageHandler>>>> =
-            Arc::new(Mutex::new(Some(Vec::new())));
-        let capture_progress = Arc::new(Mutex::new(CaptureProgress::new(
-            self.exp_times.len() as u32,
-            "Starting Multi Capture".to_string(),
-        )));
+        let capture = self.clone();
+        let correction_maps = correction_maps.clone();
 
-        let streams = self
-            .exp_times
-            .iter()
-            .map(|&exp_time| {
+        let stream = stream! {
+            let mut progress = ProgressTracker::new(
+                exposure_steps(&capture.exp_times, capture.frames_per_capture),
+            );
+            // Each frame is added onto the accumulated one before it
+            let mut accumulated: Option<ImageBuffer<Luma<u16>, Vec<u16>>> = None;
+
+            for &exp_time in &capture.exp_times {
                 let capture_settings = CaptureSettingBuilder::new(
                     exp_time,
                     Box::new(SequenceCapture {
-                        num_frames: self.frames_per_capture,
+                        num_frames: capture.frames_per_capture,
                     }),
                 )
+                .corrections(capture.corrections.clone())
                 .build();
+                let mut accumulated_exp_time = exp_time;
 
-                let capture_result = capture_result.clone();
-                let accumulated_exp_time = Arc::new(Mutex::new(exp_time));
-                let capture_progress = capture_progress.clone();
-
-                let progress_stream = stream::once(async move {
-                    CaptureStreamItem::Progress(capture_progress.lock().unwrap().update(
-                        format!("Capturing images for exposure time {exp_time}ms").to_string(),
-                    ))
-                });
-
-                let capture_stream = detector_controller
-                    .run_capture_stream(capture_settings.clone(), correction_maps.clone())
-                    .map(move |mut image| {
-                        let mut image_buffer = image.to_image_buffer();
-                        let mut lock = capture_result.lock().unwrap();
-                        if let Some(ref mut vec) = *lock {
-                            if let Some(prev) = vec.last() {
-                                image_buffer.pixels_mut().zip(prev.image.pixels()).for_each(
-                                    |(current_pixel, prev_pixel)| {
-                                        current_pixel[0] = current_pixel[0]
-                                            .saturating_add(prev_pixel[0])
-                                            .min(MAX_PIXEL_VALUE);
-                                    },
-                                );
-                            }
-                        }
+                yield CaptureStreamItem::Progress(
+                    progress.start_step(format!("Capturing images for exposure time {exp_time}ms")),
+                );
 
-                        let mut image_handler = ImageHandler::new(
-                            image_buffer,
-                            ImageMetadataBuilder::new()
-                                .capture_settings(capture_settings.clone())
-                                .extra_info(CaptureResultData::SignalAccumulationData(
-                                    SignalAccumulationData {
-                                        accumulated_exp_time: *accumulated_exp_time.lock().unwrap(),
-                                    },
-                                ))
-                                .build(),
+                let mut frames = pausable_frames(
+                    &detector_controller,
+                    &capture_settings,
+                    capture.frames_per_capture,
+                    &correction_maps,
+                    &pause,
+                );
+                while let Some(frame) = frames.next().await {
+                    let mut frame = match frame {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            yield CaptureStreamItem::Failed(e);
+                            return;
+                        }
+                    };
+                    let mut image_buffer = frame.image.to_image_buffer();
+                    if let Some(prev) = &accumulated {
+                        image_buffer.pixels_mut().zip(prev.pixels()).for_each(
+                            |(current_pixel, prev_pixel)| {
+                                current_pixel[0] = current_pixel[0]
+                                    .saturating_add(prev_pixel[0])
+                                    .min(MAX_PIXEL_VALUE);
+                            },
                         );
+                    }
 
-                        image_handler.apply_histogram_equilization();
+                    accumulated = Some(image_buffer.clone());
 
-                        let mut_vec = lock.as_mut();
-                        mut_vec.unwrap().push(image_handler.clone());
+                    let mut image_handler = ImageHandler::new(
+                        image_buffer,
+                        ImageMetadataBuilder::new()
+                            .capture_settings(capture_settings.clone())
+                            .extra_info(CaptureResultData::SignalAccumulationData(
+                                SignalAccumulationData {
+                                    accumulated_exp_time,
+                                },
+                            ))
+                            .dark_correction(frame.dark_correction)
+                            .correction_map_ids(frame.correction_map_ids)
+                            .correction_stages(frame.correction_stages)
+                            .build(),
+                    );
+
+                    image_handler.apply_histogram_equilization();
+
+                    results.push(image_handler.clone());
+                    accumulated_exp_time += exp_time;
+
+                    yield CaptureStreamItem::Image(image_handler);
+                    yield CaptureStreamItem::Progress(progress.frame());
+                }
+            }
 
-                        *accumulated_exp_time.lock().unwrap() += exp_time;
+            yield CaptureStreamItem::CaptureResult(results.take());
+        };
 
-                        CaptureStreamItem::Image(image_handler)
-                    });
+        Box::pin(stream)
+    }
+}
 
-                progress_stream.chain(capture_stream)
-            })
-            .collect::<Vec<_>>();
+impl AdvCapture for MultiCapture {
+    fn start_stream<D: DetectorBackend + Clone + 'static>(
+        &self,
+        detector_controller: DetectorController<D>,
+        correction_maps: &CorrectionMaps,
+        pause: PauseControl,
+        results: CaptureResults,
+    ) -> Pin<Box<dyn Stream<Item = CaptureStreamItem> + Send>> {
+        info!("Starting Multi Capture");
 
-        let mut stream = stream::iter(streams).flatten().boxed();
+        let capture = self.clone();
+        let correction_maps = correction_maps.clone();
 
-        let capture_result = capture_result.clone();
-        let new_stream = stream! {
-            while let Some(stream_item) = stream.next().await {
-                yield stream_item;
-            }
+        let stream = stream! {
+            let mut progress = ProgressTracker::new(
+                exposure_steps(&capture.exp_times, capture.frames_per_capture),
+            );
 
-            let capture_result_vec = {
-                capture_result.lock().unwrap().take().unwrap()
-            };
+            for &exp_time in &capture.exp_times {
+                let capture_settings = CaptureSettingBuilder::new(
+                    exp_time,
+                    Box::new(SequenceCapture {
+                        num_frames: capture.frames_per_capture,
+                    }),
+                )
+                .corrections(capture.corrections.clone())
+                .build();
 
-            yield CaptureStreamItem::CaptureResult(capture_result_vec);
+                yield CaptureStreamItem::Progress(
+                    progress.start_step(format!("Capturing images for exposure time {exp_time}ms")),
+                );
+
+                let mut frames = pausable_frames(
+                    &detector_controller,
+                    &capture_settings,
+                    capture.frames_per_capture,
+                    &correction_maps,
+                    &pause,
+                );
+                while let Some(frame) = frames.next().await {
+                    let mut frame = match frame {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            yield CaptureStreamItem::Failed(e);
+                            return;
+                        }
+                    };
+                    let mut image_handler = ImageHandler::new(
+                        frame.image.to_image_buffer(),
+                        ImageMetadata {
+                            capture_settings: Some(capture_settings.clone()),
+                            date_created: None,
+                            extra_info: None,
+                            dark_correction: frame.dark_correction,
+                            correction_map_ids: frame.correction_map_ids,
+                            correction_stages: frame.correction_stages,
+                        },
+                    );
+
+                    image_handler.apply_histogram_equilization();
+
+                    results.push(image_handler.clone());
+
+                    yield CaptureStreamItem::Image(image_handler);
+                    yield CaptureStreamItem::Progress(progress.frame());
+                }
+            }
+
+            yield CaptureStreamItem::CaptureResult(results.take());
         };
 
-        Box::pin(new_stream)
+        Box::pin(stream)
     }
 }
 
-impl AdvCapture for MultiCapture {
-    fn start_stream(
+impl AdvCapture for AveragedCapture {
+    fn start_stream<D: DetectorBackend + Clone + 'static>(
         &self,
-        mut detector_controller: DetectorController,
+        detector_controller: DetectorController<D>,
         correction_maps: &CorrectionMaps,
-        mut progress_tx: Sender<CaptureProgress>,
+        pause: PauseControl,
+        results: CaptureResults,
     ) -> Pin<Box<dyn Stream<Item = CaptureStreamItem> + Send>> {
-        info!("Starting Multi Capture");
+        info!("Starting Averaged Capture");
 
-        let capture_result = Arc::new(Mutex::new(Some(Vec::new())));
-        let capture_progress = Arc::new(Mutex::new(CaptureProgress::new(
-            self.exp_times.len() as u32,
-            "Starting Multi Capture".to_string(),
-        )));
+        let capture = self.clone();
+        let correction_maps = correction_maps.clone();
 
-        let streams = self
-            .exp_times
-            .iter()
-            .enumerate()
-            .map(|(index, &exp_time)| {
+        let stream = stream! {
+            let mut progress = ProgressTracker::new(
+                exposure_steps(&capture.exp_times, capture.frames_per_capture),
+            );
+
+            for &exp_time in &capture.exp_times {
                 let capture_settings = CaptureSettingBuilder::new(
                     exp_time,
                     Box::new(SequenceCapture {
-                        num_frames: self.frames_per_capture,
+                        num_frames: capture.frames_per_capture,
                     }),
                 )
+                .corrections(capture.corrections.clone())
                 .build();
 
-                let capture_result = capture_result.clone();
-                let capture_progress = capture_progress.clone();
-
-                let progress_stream = stream::once(async move {
-                    CaptureStreamItem::Progress(capture_progress.lock().unwrap().update(
-                        format!("Capturing images for exposure time {exp_time}ms").to_string(),
-                    ))
-                });
-
-                let capture_stream = detector_controller
-                    .run_capture_stream(capture_settings.clone(), correction_maps.clone())
-                    .map(move |mut image| {
-                        let mut image_handler = ImageHandler::new(
-                            image.to_image_buffer(),
-                            ImageMetadata {
-                                capture_settings: Some(capture_settings.clone()),
-                                date_created: None,
-                                extra_info: None,
-                            },
-                        );
+                yield CaptureStreamItem::Progress(
+                    progress.start_step(format!("Averaging images for exposure time {exp_time}ms")),
+                );
 
-                        image_handler.apply_histogram_equilization();
+                let mut stack: Option<IncrementalStack> = None;
+                // Every frame goes through the same corrections, so the last one's describe them
+                let mut corrections_applied = None;
+                let mut frames = pausable_frames(
+                    &detector_controller,
+                    &capture_settings,
+                    capture.frames_per_capture,
+                    &correction_maps,
+                    &pause,
+                );
+                while let Some(frame) = frames.next().await {
+                    let mut frame = match frame {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            yield CaptureStreamItem::Failed(e);
+                            return;
+                        }
+                    };
+                    let image_buffer = frame.image.to_image_buffer();
+                    let stack = stack.get_or_insert_with(|| {
+                        IncrementalStack::new(
+                            image_buffer.width(),
+                            image_buffer.height(),
+                            capture.reducer.clone(),
+                        )
+                    });
+                    if let Err(e) = stack.add(&image_buffer) {
+                        yield CaptureStreamItem::Failed(e.into());
+                        return;
+                    }
+
+                    // Raw frames are only previews, the averaged image is the result
+                    let mut image_handler = ImageHandler::new(
+                        image_buffer,
+                        ImageMetadata {
+                            capture_settings: Some(capture_settings.clone()),
+                            date_created: None,
+                            extra_info: None,
+                            dark_correction: frame.dark_correction.clone(),
+                            correction_map_ids: frame.correction_map_ids.clone(),
+                            correction_stages: frame.correction_stages.clone(),
+                        },
+                    );
+                    image_handler.apply_histogram_equilization();
+                    corrections_applied = Some((
+                        frame.dark_correction,
+                        frame.correction_map_ids,
+                        frame.correction_stages,
+                    ));
+
+                    yield CaptureStreamItem::Image(image_handler);
+                    yield CaptureStreamItem::Progress(progress.frame());
+                }
 
-                        let mut lock = capture_result.lock().unwrap();
-                        let mut_vec = lock.as_mut();
-                        mut_vec.unwrap().push(image_handler.clone());
+                let (Some(stack), Some((dark_correction, map_ids, stages))) =
+                    (stack, corrections_applied)
+                else {
+                    continue;
+                };
+                let (image_buffer, averaging) = match stack.finish() {
+                    Ok(averaged) => averaged,
+                    Err(e) => {
+                        error!("Failed to average images for exposure time {exp_time}ms: {e}");
+                        continue;
+                    }
+                };
+                info!(
+                    "Averaged {} images at {exp_time}ms, noise reduced {:.2}x",
+                    averaging.frame_count, averaging.noise_reduction
+                );
 
-                        CaptureStreamItem::Image(image_handler)
-                    })
-                    .chain(stream::once(async move {
-                        CaptureStreamItem::Progress(CaptureProgress::new(
-                            0,
-                            format!("Capturing images for exposure time {exp_time}ms").to_string(),
-                        ))
-                    }));
+                let mut metadata = ImageMetadataBuilder::new();
+                metadata
+                    .capture_settings(capture_settings.clone())
+                    .extra_info(CaptureResultData::AveragedCaptureData(AveragedCaptureData {
+                        averaging,
+                    }))
+                    .dark_correction(dark_correction)
+                    .correction_map_ids(map_ids)
+                    .correction_stages(stages);
+                let mut image_handler = ImageHandler::new(image_buffer, metadata.build());
+                image_handler.apply_histogram_equilization();
+                results.push(image_handler);
+            }
 
-                progress_stream.chain(capture_stream)
-            })
-            .collect::<Vec<_>>();
+            yield CaptureStreamItem::CaptureResult(results.take());
+        };
 
-        let mut stream = stream::iter(streams).flatten().boxed();
+        Box::pin(stream)
+    }
+}
 
-        let capture_result = capture_result.clone();
-        let new_stream = stream! {
-            while let Some(stream_item) = stream.next().await {
-                yield stream_item;
-            }
+impl AdvCapture for TimeLapseCapture {
+    fn start_stream<D: DetectorBackend + Clone + 'static>(
+        &self,
+        detector_controller: DetectorController<D>,
+        correction_maps: &CorrectionMaps,
+        pause: PauseControl,
+        results: CaptureResults,
+    ) -> Pin<Box<dyn Stream<Item = CaptureStreamItem> + Send>> {
+        info!("Starting Time Lapse Capture");
+
+        let capture = self.clone();
+        let correction_maps = correction_maps.clone();
+        let acquisitions = self.acquisitions();
+        let interval_ms = self.interval_secs().saturating_mul(1000);
+        let capture_settings = CaptureSettingBuilder::new(
+            self.exp_time,
+            Box::new(SequenceCapture {
+                num_frames: self.frames_per_acquisition,
+            }),
+        )
+        .corrections(self.corrections.clone())
+        .build();
+
+        let stream = stream! {
+            let steps = (0..acquisitions)
+                .map(|_| ProgressStep {
+                    exp_time: capture.exp_time,
+                    frames: capture.frames_per_acquisition,
+                })
+                .collect();
+            let mut progress = ProgressTracker::new(steps).with_interval(interval_ms);
+            let interval = Duration::from_secs(capture.interval_secs() as u64);
+            let started = Instant::now();
+
+            for acquisition in 0..acquisitions {
+                // Scheduled from the first acquisition so slow ones don't push the rest back, while
+                // time spent paused does
+                let starts_at = started + interval * acquisition + pause.paused_for();
+                tokio::time::sleep(starts_at.saturating_duration_since(Instant::now())).await;
+
+                yield CaptureStreamItem::Progress(progress.start_step(format!(
+                    "Acquisition {} of {acquisitions}",
+                    acquisition + 1
+                )));
+
+                let mut best_frame: (Option<ImageHandler>, f64) = (None, 0.0);
+                let mut frames = pausable_frames(
+                    &detector_controller,
+                    &capture_settings,
+                    capture.frames_per_acquisition,
+                    &correction_maps,
+                    &pause,
+                );
+                while let Some(frame) = frames.next().await {
+                    let mut frame = match frame {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            error!("Time lapse acquisition {} failed: {e}", acquisition + 1);
+                            yield CaptureStreamItem::Failed(e);
+                            return;
+                        }
+                    };
+                    let image_buffer = frame.image.to_image_buffer();
+                    let mut metadata = ImageMetadataBuilder::new();
+                    metadata
+                        .capture_settings(capture_settings.clone())
+                        .date_created(Utc::now())
+                        .dark_correction(frame.dark_correction)
+                        .correction_map_ids(frame.correction_map_ids)
+                        .correction_stages(frame.correction_stages);
+
+                    let snr = match capture.frames {
+                        TimeLapseFrames::All => None,
+                        TimeLapseFrames::BestSnr { window_size } => {
+                            let Ok(snr_results) = snr_threaded(&image_buffer, window_size) else {
+                                yield CaptureStreamItem::Failed(
+                                    CaptureError::SnrWindowTooLarge(window_size),
+                                );
+                                return;
+                            };
+                            metadata.extra_info(CaptureResultData::SmartCaptureData(
+                                SmartCaptureData {
+                                    signal_noise_ratio: snr_results.0,
+                                    background_rect: snr_results.1,
+                                    foreground_rect: snr_results.2,
+                                },
+                            ));
+                            Some(snr_results.0)
+                        }
+                    };
 
-            let capture_result_vec = {
-                capture_result.lock().unwrap().take().unwrap()
-            };
+                    let mut image_handler = ImageHandler::new(image_buffer, metadata.build());
+                    image_handler.apply_histogram_equilization();
+
+                    match snr {
+                        None => results.push(image_handler.clone()),
+                        Some(snr) if snr > best_frame.1 => {
+                            best_frame = (Some(image_handler.clone()), snr)
+                        }
+                        Some(_) => {}
+                    }
+
+                    yield CaptureStreamItem::Image(image_handler);
+                    yield CaptureStreamItem::Progress(progress.frame());
+                }
 
-            yield CaptureStreamItem::CaptureResult(capture_result_vec);
+                if let Some(best_frame) = best_frame.0 {
+                    results.push(best_frame);
+                }
+            }
+
+            yield CaptureStreamItem::CaptureResult(results.take());
         };
 
-        Box::pin(new_stream)
+        Box::pin(stream)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::{atomic::AtomicBool, mpsc::channel, Arc, Mutex};
+    use std::{collections::HashMap, time::Duration};
 
-    use futures_util::{pin_mut, StreamExt};
+    use futures_util::{pin_mut, FutureExt, StreamExt};
 
     use crate::{
         capture::{
-            advanced_capture::{SignalAccumulationCapture, SmartCapture},
-            capture_manager::CaptureManager,
-            test_utils::test_utils::{create_app, setup_controller_handle},
-            types::AdvCapture,
+            advanced_capture::{
+                AveragedCapture, DarkMapCapture, DefectMapCapture, FlatFieldCapture,
+                LagMeasurementCapture, MultiCapture, SmartCapture, TimeLapseCapture,
+                TimeLapseFrames, TimeLapseLength,
+            },
+            backend::DetectorBackend,
+            capture::CaptureError,
+            capture_manager::CorrectionMaps,
+            corrections::CorrectionError,
+            detector::DetectorController,
+            simulated::{Phantom, SimulatedDetector, SimulatedDetectorConfig},
+            stack_reduction::IncrementalReducer,
+            test_utils::test_utils::setup_simulated_controller,
+            types::{AdvCapture, CaptureResults, CaptureStreamItem, PauseControl},
         },
-        wrapper::{FullWellModes, FullWellModesRS},
+        image::CaptureResultData,
     };
+
+    fn simulated_config() -> SimulatedDetectorConfig {
+        SimulatedDetectorConfig {
+            width: 128,
+            height: 96,
+            phantom: Some(Phantom::Disc { radius: 20 }),
+            realtime: false,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn simulated_multi_capture() {
+        let controller = setup_simulated_controller(simulated_config());
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+
+        let multi_capture = MultiCapture {
+            exp_times: vec![100, 200],
+            frames_per_capture: 3,
+            corrections: Default::default(),
+        };
+
+        let stream = multi_capture.start_stream(
+            controller,
+            &correction_maps,
+            PauseControl::default(),
+            CaptureResults::default(),
+        );
+        pin_mut!(stream);
+
+        let mut image_count = 0;
+        let mut result_count = None;
+        while let Some(item) = stream.next().await {
+            match item {
+                CaptureStreamItem::Image(_) => image_count += 1,
+                CaptureStreamItem::CaptureResult(images) => result_count = Some(images.len()),
+                CaptureStreamItem::Progress(_) => {}
+                CaptureStreamItem::Failed(e) => panic!("Capture failed: {e}"),
+                CaptureStreamItem::Cancelled => panic!("Capture cancelled"),
+            }
+        }
+
+        assert_eq!(image_count, 6);
+        assert_eq!(result_count, Some(6));
+    }
+
+    #[tokio::test]
+    async fn simulated_averaged_capture() {
+        let controller = setup_simulated_controller(simulated_config());
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+
+        let averaged_capture = AveragedCapture {
+            exp_times: vec![100, 200],
+            frames_per_capture: 3,
+            reducer: IncrementalReducer::SigmaClippedMean { sigma: 3.0 },
+            corrections: Default::default(),
+        };
+
+        let stream = averaged_capture.start_stream(
+            controller,
+            &correction_maps,
+            PauseControl::default(),
+            CaptureResults::default(),
+        );
+        pin_mut!(stream);
+
+        let mut image_count = 0;
+        let mut result = None;
+        while let Some(item) = stream.next().await {
+            match item {
+                CaptureStreamItem::Image(_) => image_count += 1,
+                CaptureStreamItem::CaptureResult(images) => result = Some(images),
+                CaptureStreamItem::Progress(_) => {}
+                CaptureStreamItem::Failed(e) => panic!("Capture failed: {e}"),
+                CaptureStreamItem::Cancelled => panic!("Capture cancelled"),
+            }
+        }
+
+        assert_eq!(image_count, 6);
+        let result = result.unwrap();
+        assert_eq!(result.len(), 2);
+        for image in result {
+            match image.image_metadata.extra_info {
+                Some(CaptureResultData::AveragedCaptureData(data)) => {
+                    assert_eq!(data.averaging.frame_count, 3);
+                    assert!(data.averaging.noise_reduction > 1.0);
+                }
+                other => panic!("Expected averaging data, got {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn stopped_averaged_capture_keeps_only_finished_averages() {
+        let controller = setup_simulated_controller(simulated_config());
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+
+        let averaged_capture = AveragedCapture {
+            exp_times: vec![100, 200],
+            frames_per_capture: 3,
+            reducer: IncrementalReducer::Mean,
+            corrections: Default::default(),
+        };
+
+        let results = CaptureResults::default();
+        let mut stream = averaged_capture.start_stream(
+            controller,
+            &correction_maps,
+            PauseControl::default(),
+            results.clone(),
+        );
+
+        // Stopped part way through the second exposure
+        let mut image_count = 0;
+        while image_count < 4 {
+            if let Some(CaptureStreamItem::Image(_)) = stream.next().await {
+                image_count += 1;
+            }
+        }
+        drop(stream);
+
+        let partial_result = results.take();
+        assert_eq!(partial_result.len(), 1);
+        assert!(matches!(
+            partial_result[0].image_metadata.extra_info,
+            Some(CaptureResultData::AveragedCaptureData(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn paused_multi_capture_waits_and_keeps_frames() {
+        let controller = setup_simulated_controller(simulated_config());
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+
+        let multi_capture = MultiCapture {
+            exp_times: vec![100, 200],
+            frames_per_capture: 3,
+            corrections: Default::default(),
+        };
+
+        let pause = PauseControl::default();
+        let stream = multi_capture.start_stream(
+            controller,
+            &correction_maps,
+            pause.clone(),
+            CaptureResults::default(),
+        );
+        pin_mut!(stream);
+
+        let mut image_count = 0;
+        while image_count == 0 {
+            if let Some(CaptureStreamItem::Image(_)) = stream.next().await {
+                image_count += 1;
+            }
+        }
+
+        // Each frame is followed by its progress update
+        assert!(matches!(
+            stream.next().await,
+            Some(CaptureStreamItem::Progress(_))
+        ));
+
+        pause.pause();
+        assert!(stream.next().now_or_never().is_none());
+
+        pause.resume();
+        let mut result_count = None;
+        while let Some(item) = stream.next().await {
+            match item {
+                CaptureStreamItem::Image(_) => image_count += 1,
+                CaptureStreamItem::CaptureResult(images) => result_count = Some(images.len()),
+                _ => {}
+            }
+        }
+
+        assert_eq!(image_count, 6);
+        assert_eq!(result_count, Some(6));
+    }
+
+    #[tokio::test]
+    async fn simulated_smart_capture() {
+        let controller = setup_simulated_controller(simulated_config());
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+
+        let smart_capture = SmartCapture {
+            exp_times: vec![50, 100],
+            frames_per_capture: 2,
+            window_size: 5,
+            median_filtered: false,
+            corrections: Default::default(),
+        };
+
+        let stream = smart_capture.start_stream(
+            controller,
+            &correction_maps,
+            PauseControl::default(),
+            CaptureResults::default(),
+        );
+        pin_mut!(stream);
+
+        let mut best = None;
+        while let Some(item) = stream.next().await {
+            if let CaptureStreamItem::CaptureResult(images) = item {
+                best = Some(images);
+            }
+        }
+
+        assert_eq!(best.map(|images| images.len()), Some(1));
+    }
+
+    #[tokio::test]
+    async fn smart_capture_fails_when_snr_window_exceeds_frame() {
+        let controller = setup_simulated_controller(simulated_config());
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+
+        let smart_capture = SmartCapture {
+            exp_times: vec![50],
+            frames_per_capture: 1,
+            window_size: 200,
+            median_filtered: false,
+            corrections: Default::default(),
+        };
+
+        let stream = smart_capture.start_stream(
+            controller,
+            &correction_maps,
+            PauseControl::default(),
+            CaptureResults::default(),
+        );
+        pin_mut!(stream);
+
+        let mut failure = None;
+        while let Some(item) = stream.next().await {
+            if let CaptureStreamItem::Failed(e) = item {
+                failure = Some(e);
+            }
+        }
+
+        assert!(matches!(
+            failure,
+            Some(CaptureError::SnrWindowTooLarge(200))
+        ));
+    }
+
+    #[tokio::test]
+    async fn time_lapse_keeps_best_frame_of_each_acquisition_with_its_time() {
+        let controller = setup_simulated_controller(simulated_config());
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+
+        let time_lapse = TimeLapseCapture {
+            exp_time: 50,
+            frames_per_acquisition: 2,
+            interval_secs: 0,
+            length: TimeLapseLength::Acquisitions { count: 2 },
+            frames: TimeLapseFrames::BestSnr { window_size: 5 },
+            corrections: Default::default(),
+        };
+
+        let stream = time_lapse.start_stream(
+            controller,
+            &correction_maps,
+            PauseControl::default(),
+            CaptureResults::default(),
+        );
+        pin_mut!(stream);
+
+        let mut image_count = 0;
+        let mut result = None;
+        while let Some(item) = stream.next().await {
+            match item {
+                CaptureStreamItem::Image(_) => image_count += 1,
+                CaptureStreamItem::CaptureResult(images) => result = Some(images),
+                CaptureStreamItem::Progress(_) => {}
+                CaptureStreamItem::Failed(e) => panic!("Capture failed: {e}"),
+                CaptureStreamItem::Cancelled => panic!("Capture cancelled"),
+            }
+        }
+
+        assert_eq!(image_count, 4);
+        let times: Vec<_> = result
+            .unwrap()
+            .iter()
+            .map(|image| image.image_metadata.date_created.unwrap())
+            .collect();
+        assert_eq!(times.len(), 2);
+        // A zero interval is taken as the shortest one
+        assert!(times[1] - times[0] >= chrono::Duration::milliseconds(900));
+    }
+
+    #[tokio::test]
+    async fn paused_time_lapse_waits_between_frames() {
+        let controller = setup_simulated_controller(simulated_config());
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+
+        let time_lapse = TimeLapseCapture {
+            exp_time: 50,
+            frames_per_acquisition: 3,
+            interval_secs: 1,
+            length: TimeLapseLength::Acquisitions { count: 1 },
+            frames: TimeLapseFrames::All,
+            corrections: Default::default(),
+        };
+
+        let pause = PauseControl::default();
+        let stream = time_lapse.start_stream(
+            controller,
+            &correction_maps,
+            pause.clone(),
+            CaptureResults::default(),
+        );
+        pin_mut!(stream);
+
+        let mut image_count = 0;
+        while image_count == 0 {
+            if let Some(CaptureStreamItem::Image(_)) = stream.next().await {
+                image_count += 1;
+            }
+        }
+        assert!(matches!(
+            stream.next().await,
+            Some(CaptureStreamItem::Progress(_))
+        ));
+
+        pause.pause();
+        assert!(stream.next().now_or_never().is_none());
+
+        pause.resume();
+        let mut result_count = None;
+        while let Some(item) = stream.next().await {
+            match item {
+                CaptureStreamItem::Image(_) => image_count += 1,
+                CaptureStreamItem::CaptureResult(images) => result_count = Some(images.len()),
+                _ => {}
+            }
+        }
+
+        assert_eq!(image_count, 3);
+        assert_eq!(result_count, Some(3));
+        assert!(pause.paused_for() > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn simulated_dark_map_capture() {
+        let controller = setup_simulated_controller(simulated_config());
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+
+        let dark_map_capture = DarkMapCapture {
+            exp_times: vec![100, 200],
+            frames_per_capture: 3,
+            stack_reduction: Default::default(),
+        };
+
+        let stream = dark_map_capture.start_stream(
+            controller,
+            &correction_maps,
+            PauseControl::default(),
+            CaptureResults::default(),
+        );
+        pin_mut!(stream);
+
+        let mut image_count = 0;
+        let mut result_count = None;
+        while let Some(item) = stream.next().await {
+            match item {
+                CaptureStreamItem::Image(_) => image_count += 1,
+                CaptureStreamItem::CaptureResult(images) => result_count = Some(images.len()),
+                CaptureStreamItem::Progress(_) => {}
+                CaptureStreamItem::Failed(e) => panic!("Capture failed: {e}"),
+                CaptureStreamItem::Cancelled => panic!("Capture cancelled"),
+            }
+        }
+
+        // Both full well modes at each exposure
+        assert_eq!(image_count, 12);
+        assert_eq!(result_count, Some(4));
+        assert_eq!(correction_maps.get_dark_map_keys().len(), 4);
+        assert!(!correction_maps.has_defect_map());
+    }
+
+    #[tokio::test]
+    async fn flat_field_capture_saves_gain_maps_against_dark_maps() {
+        let detector = SimulatedDetector::new(simulated_config());
+        detector.set_source(false);
+        let controller = DetectorController::new(detector.clone(), |_| {});
+        std::thread::sleep(Duration::from_millis(500));
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+
+        let dark_map_capture = DarkMapCapture {
+            exp_times: vec![100, 200],
+            frames_per_capture: 3,
+            stack_reduction: Default::default(),
+        };
+        let stream = dark_map_capture.start_stream(
+            controller.clone(),
+            &correction_maps,
+            PauseControl::default(),
+            CaptureResults::default(),
+        );
+        pin_mut!(stream);
+        while stream.next().await.is_some() {}
+
+        detector.set_source(true);
+        let flat_field_capture = FlatFieldCapture {
+            exp_times: vec![100, 200],
+            frames_per_capture: 3,
+        };
+        let stream = flat_field_capture.start_stream(
+            controller,
+            &correction_maps,
+            PauseControl::default(),
+            CaptureResults::default(),
+        );
+        pin_mut!(stream);
+
+        let mut image_count = 0;
+        let mut result_count = None;
+        while let Some(item) = stream.next().await {
+            match item {
+                CaptureStreamItem::Image(_) => image_count += 1,
+                CaptureStreamItem::CaptureResult(images) => result_count = Some(images.len()),
+                CaptureStreamItem::Progress(_) => {}
+                CaptureStreamItem::Failed(e) => panic!("Capture failed: {e}"),
+                CaptureStreamItem::Cancelled => panic!("Capture cancelled"),
+            }
+        }
+
+        assert_eq!(image_count, 6);
+        assert_eq!(result_count, Some(2));
+        assert_eq!(correction_maps.get_gain_map_keys().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn flat_field_capture_fails_without_dark_maps() {
+        let controller = setup_simulated_controller(simulated_config());
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+
+        let flat_field_capture = FlatFieldCapture {
+            exp_times: vec![100],
+            frames_per_capture: 3,
+        };
+        let stream = flat_field_capture.start_stream(
+            controller,
+            &correction_maps,
+            PauseControl::default(),
+            CaptureResults::default(),
+        );
+        pin_mut!(stream);
+
+        assert!(matches!(
+            stream.next().await,
+            Some(CaptureStreamItem::Failed(_))
+        ));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn flat_field_capture_fails_when_gain_map_cant_be_computed() {
+        let detector = SimulatedDetector::new(simulated_config());
+        detector.set_source(false);
+        let controller = DetectorController::new(detector.clone(), |_| {});
+        std::thread::sleep(Duration::from_millis(500));
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+
+        let dark_map_capture = DarkMapCapture {
+            exp_times: vec![100],
+            frames_per_capture: 3,
+            stack_reduction: Default::default(),
+        };
+        let stream = dark_map_capture.start_stream(
+            controller.clone(),
+            &correction_maps,
+            PauseControl::default(),
+            CaptureResults::default(),
+        );
+        pin_mut!(stream);
+        while stream.next().await.is_some() {}
+
+        // With the source still off the flat frames are no brighter than the dark map
+        let flat_field_capture = FlatFieldCapture {
+            exp_times: vec![100],
+            frames_per_capture: 3,
+        };
+        let stream = flat_field_capture.start_stream(
+            controller,
+            &correction_maps,
+            PauseControl::default(),
+            CaptureResults::default(),
+        );
+        pin_mut!(stream);
+
+        let mut failure = None;
+        while let Some(item) = stream.next().await {
+            match item {
+                CaptureStreamItem::Failed(e) => failure = Some(e),
+                CaptureStreamItem::CaptureResult(_) => panic!("Gain map saved without signal"),
+                _ => {}
+            }
+        }
+
+        assert!(matches!(
+            failure,
+            Some(CaptureError::CalibrationFailed(
+                _,
+                CorrectionError::InsufficientSignal(..)
+            ))
+        ));
+        assert!(correction_maps.get_gain_map_keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lag_measurement_fails_without_a_source_transition() {
+        let controller = setup_simulated_controller(simulated_config());
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+
+        // The source is never switched off, so there's no lag to fit
+        let lag_capture = LagMeasurementCapture {
+            exp_time: 50,
+            num_frames: 6,
+            terms: 1,
+            settle_frames: 2,
+        };
+        let stream = lag_capture.start_stream(
+            controller,
+            &correction_maps,
+            PauseControl::default(),
+            CaptureResults::default(),
+        );
+        pin_mut!(stream);
+
+        let mut failure = None;
+        while let Some(item) = stream.next().await {
+            match item {
+                CaptureStreamItem::Failed(e) => failure = Some(e),
+                CaptureStreamItem::CaptureResult(_) => panic!("Fitted lag without a transition"),
+                _ => {}
+            }
+        }
+
+        assert!(failure.is_some());
+    }
+
+    #[tokio::test]
+    async fn defect_map_capture_finds_dead_pixels_in_flats() {
+        // An open beam rather than the disc, so the only low response in the flats is from the
+        // panel's own defects. The simulator only illuminates the panel through a phantom.
+        let config = SimulatedDetectorConfig {
+            dead_pixels: 10,
+            phantom: Some(Phantom::Flat),
+            ..simulated_config()
+        };
+        let mut detector = SimulatedDetector::new(config);
+        detector.set_source(false);
+        let controller = DetectorController::new(detector.clone(), |_| {});
+        std::thread::sleep(Duration::from_millis(500));
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+
+        let defect_map_capture = DefectMapCapture {
+            exp_times: vec![50, 100],
+            frames_per_capture: 3,
+            settings: Default::default(),
+        };
+
+        let stream = defect_map_capture.start_stream(
+            controller,
+            &correction_maps,
+            PauseControl::default(),
+            CaptureResults::default(),
+        );
+        pin_mut!(stream);
+
+        let mut image_count = 0;
+        while let Some(item) = stream.next().await {
+            match item {
+                CaptureStreamItem::Image(_) => image_count += 1,
+                CaptureStreamItem::Progress(progress) => {
+                    if progress.message().contains("Switch the X-ray source on") {
+                        detector.set_source(true);
+                    }
+                }
+                CaptureStreamItem::CaptureResult(_) => {}
+                CaptureStreamItem::Failed(e) => panic!("Capture failed: {e}"),
+                CaptureStreamItem::Cancelled => panic!("Capture cancelled"),
+            }
+        }
+
+        // A dark and a flat sweep for both full well modes
+        assert_eq!(image_count, 24);
+        let bundle = correction_maps.to_bundle(detector.identity().unwrap());
+        let (map, _) = bundle.defect_map.expect("No defect map was saved");
+        let dead_pixels = &detector.defects().dead_pixels;
+        assert_eq!(dead_pixels.len(), 10);
+        for &(x, y) in dead_pixels {
+            assert!(
+                map.get_pixel(x, y)[0] > 0,
+                "Dead pixel ({x}, {y}) not flagged"
+            );
+        }
+    }
+
     /*
     #[tokio::test]
     async fn smart_capture() {
@@ -0,0 +1,300 @@
+#[cfg(feature = "spectrum-logic")]
+use crate::wrapper::SLDeviceRS;
+#[cfg(feature = "spectrum-logic")]
+use crate::wrapper::SLError;
+use crate::wrapper::{BinningModesRS, ExposureModes, FullWellModesRS, InternalSLError, SLImageRs};
+use log::error;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use std::{env, fmt, path::PathBuf};
+
+#[cfg(not(feature = "spectrum-logic"))]
+use super::simulated::{SimulatedDetector, SimulatedDetectorConfig};
+use super::{capture::Roi, replay::ReplayDetector};
+
+// Set to a recorded session to replay it instead of talking to a detector
+const REPLAY_FILE_ENV: &str = "CVIEW_REPLAY_FILE";
+
+// Identifies the detector correction maps were captured on. The SDK bindings don't expose the
+// panel's serial number, so Spectrum Logic panels are only told apart by interface and sensor
+// size, which can't confirm that maps came from the connected panel.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Type)]
+pub struct DetectorIdentity {
+    pub model: String,
+    pub serial: Option<String>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl DetectorIdentity {
+    // Without a serial number, panels of the same model and size look the same
+    pub fn is_verifiable(&self) -> bool {
+        self.serial.is_some()
+    }
+}
+
+impl fmt::Display for DetectorIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.model)?;
+        if let Some(serial) = &self.serial {
+            write!(f, " {serial}")?;
+        }
+        write!(f, " ({}x{})", self.width, self.height)
+    }
+}
+
+// Everything the capture stack needs from a physical (or simulated) detector.
+// Clones of a backend must refer to the same underlying device, as the heartbeat
+// thread and the capture streams each hold their own copy.
+pub trait DetectorBackend: Send + Sync {
+    fn open_camera(&mut self, buffer_depth: u32) -> Result<(), InternalSLError>;
+
+    fn is_connected(&mut self) -> bool;
+
+    fn image_width(&mut self) -> Result<u32, ()>;
+
+    fn image_height(&mut self) -> Result<u32, ()>;
+
+    fn identity(&mut self) -> Result<DetectorIdentity, ()>;
+
+    fn set_exposure_time(&mut self, exp_time: u32) -> Result<(), InternalSLError>;
+
+    fn set_exposure_mode(&mut self, ex_mode: ExposureModes) -> Result<(), InternalSLError>;
+
+    fn set_number_frames(&mut self, frame_count: u32) -> Result<(), InternalSLError>;
+
+    fn set_full_well(&mut self, full_well: FullWellModesRS) -> Result<(), InternalSLError>;
+
+    // Changes the size of the frames read out, which image_width and image_height follow
+    fn set_binning_mode(&mut self, binning_mode: BinningModesRS) -> Result<(), InternalSLError>;
+
+    fn set_dds(&mut self, dds: bool) -> Result<(), InternalSLError>;
+
+    // None reads out the whole binned frame
+    fn set_roi(&mut self, roi: Option<Roi>) -> Result<(), InternalSLError>;
+
+    fn start_stream(&mut self, exp_time: u32) -> Result<(), InternalSLError>;
+
+    fn go_live(&mut self) -> Result<(), InternalSLError>;
+
+    fn go_unlive(&mut self, wipe_stack: bool) -> Result<(), InternalSLError>;
+
+    fn software_trigger(&mut self) -> Result<(), InternalSLError>;
+
+    fn read_buffer(
+        &mut self,
+        buffer: &mut SLImageRs,
+        buf_num: u32,
+        timeout: u32,
+    ) -> Result<(), InternalSLError>;
+
+    fn read_frame(&mut self, buffer: &mut SLImageRs, read_oldest_first: bool) -> bool;
+
+    fn clone_box(&self) -> Box<dyn DetectorBackend>;
+}
+
+// Picks the detector used by the app: a replay file if one is configured, otherwise
+// the Spectrum Logic panel, or a simulated panel when the SDK is not compiled in.
+pub fn create_detector_backend() -> Box<dyn DetectorBackend> {
+    if let Ok(replay_file) = env::var(REPLAY_FILE_ENV) {
+        match ReplayDetector::open(&PathBuf::from(&replay_file), true) {
+            Ok(detector) => return Box::new(detector),
+            Err(e) => error!("Failed to open replay file {replay_file}: {e}"),
+        }
+    }
+
+    #[cfg(feature = "spectrum-logic")]
+    {
+        Box::new(SLDeviceRS::new())
+    }
+
+    #[cfg(not(feature = "spectrum-logic"))]
+    {
+        log::info!("Built without the Spectrum Logic SDK, using a simulated detector");
+        Box::new(SimulatedDetector::new(SimulatedDetectorConfig::default()))
+    }
+}
+
+impl Clone for Box<dyn DetectorBackend> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+impl DetectorBackend for Box<dyn DetectorBackend> {
+    fn open_camera(&mut self, buffer_depth: u32) -> Result<(), InternalSLError> {
+        (**self).open_camera(buffer_depth)
+    }
+
+    fn is_connected(&mut self) -> bool {
+        (**self).is_connected()
+    }
+
+    fn image_width(&mut self) -> Result<u32, ()> {
+        (**self).image_width()
+    }
+
+    fn image_height(&mut self) -> Result<u32, ()> {
+        (**self).image_height()
+    }
+
+    fn identity(&mut self) -> Result<DetectorIdentity, ()> {
+        (**self).identity()
+    }
+
+    fn set_exposure_time(&mut self, exp_time: u32) -> Result<(), InternalSLError> {
+        (**self).set_exposure_time(exp_time)
+    }
+
+    fn set_exposure_mode(&mut self, ex_mode: ExposureModes) -> Result<(), InternalSLError> {
+        (**self).set_exposure_mode(ex_mode)
+    }
+
+    fn set_number_frames(&mut self, frame_count: u32) -> Result<(), InternalSLError> {
+        (**self).set_number_frames(frame_count)
+    }
+
+    fn set_full_well(&mut self, full_well: FullWellModesRS) -> Result<(), InternalSLError> {
+        (**self).set_full_well(full_well)
+    }
+
+    fn set_binning_mode(&mut self, binning_mode: BinningModesRS) -> Result<(), InternalSLError> {
+        (**self).set_binning_mode(binning_mode)
+    }
+
+    fn set_dds(&mut self, dds: bool) -> Result<(), InternalSLError> {
+        (**self).set_dds(dds)
+    }
+
+    fn set_roi(&mut self, roi: Option<Roi>) -> Result<(), InternalSLError> {
+        (**self).set_roi(roi)
+    }
+
+    fn start_stream(&mut self, exp_time: u32) -> Result<(), InternalSLError> {
+        (**self).start_stream(exp_time)
+    }
+
+    fn go_live(&mut self) -> Result<(), InternalSLError> {
+        (**self).go_live()
+    }
+
+    fn go_unlive(&mut self, wipe_stack: bool) -> Result<(), InternalSLError> {
+        (**self).go_unlive(wipe_stack)
+    }
+
+    fn software_trigger(&mut self) -> Result<(), InternalSLError> {
+        (**self).software_trigger()
+    }
+
+    fn read_buffer(
+        &mut self,
+        buffer: &mut SLImageRs,
+        buf_num: u32,
+        timeout: u32,
+    ) -> Result<(), InternalSLError> {
+        (**self).read_buffer(buffer, buf_num, timeout)
+    }
+
+    fn read_frame(&mut self, buffer: &mut SLImageRs, read_oldest_first: bool) -> bool {
+        (**self).read_frame(buffer, read_oldest_first)
+    }
+
+    fn clone_box(&self) -> Box<dyn DetectorBackend> {
+        (**self).clone_box()
+    }
+}
+
+#[cfg(feature = "spectrum-logic")]
+impl DetectorBackend for SLDeviceRS {
+    fn open_camera(&mut self, buffer_depth: u32) -> Result<(), InternalSLError> {
+        SLDeviceRS::open_camera(self, buffer_depth)
+    }
+
+    fn is_connected(&mut self) -> bool {
+        SLDeviceRS::is_connected(self)
+    }
+
+    fn image_width(&mut self) -> Result<u32, ()> {
+        SLDeviceRS::image_width(self)
+    }
+
+    fn image_height(&mut self) -> Result<u32, ()> {
+        SLDeviceRS::image_height(self)
+    }
+
+    fn identity(&mut self) -> Result<DetectorIdentity, ()> {
+        Ok(DetectorIdentity {
+            model: "Spectrum Logic USB".to_string(),
+            serial: None,
+            // Maps are told apart by binning already, so the panel is identified by its
+            // unbinned size
+            width: SLDeviceRS::image_width(self)? * self.binning_factor(),
+            height: SLDeviceRS::image_height(self)? * self.binning_factor(),
+        })
+    }
+
+    fn set_exposure_time(&mut self, exp_time: u32) -> Result<(), InternalSLError> {
+        SLDeviceRS::set_exposure_time(self, exp_time)
+    }
+
+    fn set_exposure_mode(&mut self, ex_mode: ExposureModes) -> Result<(), InternalSLError> {
+        SLDeviceRS::set_exposure_mode(self, ex_mode)
+    }
+
+    fn set_number_frames(&mut self, frame_count: u32) -> Result<(), InternalSLError> {
+        SLDeviceRS::set_number_frames(self, frame_count)
+    }
+
+    fn set_full_well(&mut self, full_well: FullWellModesRS) -> Result<(), InternalSLError> {
+        SLDeviceRS::set_full_well(self, full_well)
+    }
+
+    fn set_binning_mode(&mut self, binning_mode: BinningModesRS) -> Result<(), InternalSLError> {
+        SLDeviceRS::set_binning_mode(self, binning_mode)
+    }
+
+    fn set_dds(&mut self, dds: bool) -> Result<(), InternalSLError> {
+        SLDeviceRS::set_dds(self, dds)
+    }
+
+    // The SDK bindings don't expose a readout region
+    fn set_roi(&mut self, roi: Option<Roi>) -> Result<(), InternalSLError> {
+        match roi {
+            Some(_) => Err(SLError::SL_ERROR_NOT_SUPPORTED.into()),
+            None => Ok(()),
+        }
+    }
+
+    fn start_stream(&mut self, exp_time: u32) -> Result<(), InternalSLError> {
+        SLDeviceRS::start_stream(self, exp_time)
+    }
+
+    fn go_live(&mut self) -> Result<(), InternalSLError> {
+        SLDeviceRS::go_live(self)
+    }
+
+    fn go_unlive(&mut self, wipe_stack: bool) -> Result<(), InternalSLError> {
+        SLDeviceRS::go_unlive(self, wipe_stack)
+    }
+
+    fn software_trigger(&mut self) -> Result<(), InternalSLError> {
+        SLDeviceRS::software_trigger(self)
+    }
+
+    fn read_buffer(
+        &mut self,
+        buffer: &mut SLImageRs,
+        buf_num: u32,
+        timeout: u32,
+    ) -> Result<(), InternalSLError> {
+        SLDeviceRS::read_buffer(self, buffer, buf_num, timeout)
+    }
+
+    fn read_frame(&mut self, buffer: &mut SLImageRs, read_oldest_first: bool) -> bool {
+        SLDeviceRS::read_frame(self, buffer.get_data_pointer(0), read_oldest_first)
+    }
+
+    fn clone_box(&self) -> Box<dyn DetectorBackend> {
+        Box::new(self.clone())
+    }
+}
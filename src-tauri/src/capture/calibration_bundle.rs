@@ -0,0 +1,443 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use chrono::{DateTime, Utc};
+use image::{ImageBuffer, Luma};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::wrapper::BinningModes;
+
+use super::{
+    backend::DetectorIdentity,
+    corrections::{CorrectionError, CorrectionMapKey},
+    provenance::{MapContent, MapProvenance},
+};
+
+const BUNDLE_MAGIC: &[u8; 8] = b"CVCALIB\0";
+const BUNDLE_VERSION: u32 = 1;
+
+type CorrectionMap = ImageBuffer<Luma<u16>, Vec<u16>>;
+type GainMap = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+// Variant names are the tags written to the manifest, so they keep the Map suffix
+#[allow(clippy::enum_variant_names)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+enum BundledMap {
+    DarkMap { key: CorrectionMapKey },
+    GainMap { key: CorrectionMapKey },
+    DefectMap,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct BundleEntry {
+    map: BundledMap,
+    width: u32,
+    height: u32,
+    provenance: MapProvenance,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BundleManifest {
+    detector: DetectorIdentity,
+    exported: DateTime<Utc>,
+    entries: Vec<BundleEntry>,
+}
+
+// How far a bundle's detector could be confirmed to be the connected one
+#[derive(Debug, PartialEq)]
+pub enum DetectorCheck {
+    Matched,
+    // The identities are equal but have no serial number, so could be different panels
+    Unverifiable,
+}
+
+// Every correction map for one detector, so its calibration can move between workstations.
+// Layout: magic, version, a JSON manifest, then the pixels of each map in manifest order.
+pub struct CalibrationBundle {
+    pub detector: DetectorIdentity,
+    pub dark_maps: Vec<(CorrectionMapKey, CorrectionMap, MapProvenance)>,
+    pub gain_maps: Vec<(CorrectionMapKey, GainMap, MapProvenance)>,
+    pub defect_map: Option<(CorrectionMap, MapProvenance)>,
+}
+
+impl CalibrationBundle {
+    pub fn write(&self, path: &Path) -> Result<(), CorrectionError> {
+        self.write_to(path)
+            .map_err(|e| CorrectionError::WriteFailed(format!("{}: {e}", path.display())))?;
+        info!("Exported calibration bundle to {}", path.display());
+        Ok(())
+    }
+
+    // Reads a bundle and checks every map still hashes to the ID in its provenance
+    pub fn read(path: &Path) -> Result<Self, CorrectionError> {
+        let bundle =
+            Self::read_from(path).map_err(|e| CorrectionError::InvalidBundle(e.to_string()))?;
+
+        let mut ids = bundle
+            .dark_maps
+            .iter()
+            .map(|(_, map, provenance)| (map.content_id(), provenance))
+            .chain(
+                bundle
+                    .gain_maps
+                    .iter()
+                    .map(|(_, map, provenance)| (map.content_id(), provenance)),
+            )
+            .chain(
+                bundle
+                    .defect_map
+                    .iter()
+                    .map(|(map, provenance)| (map.content_id(), provenance)),
+            );
+
+        match ids.find(|(id, provenance)| *id != provenance.id) {
+            Some((_, provenance)) => Err(CorrectionError::InvalidBundle(format!(
+                "map {} is corrupt",
+                provenance.id
+            ))),
+            None => Ok(bundle),
+        }
+    }
+
+    // Checks the bundle was made on the given detector and every map fits its sensor
+    pub fn validate(&self, detector: &DetectorIdentity) -> Result<DetectorCheck, CorrectionError> {
+        if &self.detector != detector {
+            return Err(CorrectionError::BundleDetectorMismatch(
+                self.detector.clone(),
+                detector.clone(),
+            ));
+        }
+
+        let unbinned = (detector.width, detector.height);
+        let binned = |key: &CorrectionMapKey| {
+            let factor = match key.binning_mode.0 {
+                BinningModes::x22 => 2,
+                BinningModes::x44 => 4,
+                _ => 1,
+            };
+            (detector.width / factor, detector.height / factor)
+        };
+
+        for (key, map, _) in &self.dark_maps {
+            check_map_dimensions(binned(key), map.dimensions())?;
+        }
+        for (key, map, _) in &self.gain_maps {
+            check_map_dimensions(binned(key), map.dimensions())?;
+        }
+        if let Some((map, _)) = &self.defect_map {
+            check_map_dimensions(unbinned, map.dimensions())?;
+        }
+
+        // An equal identity without a serial number could still be another panel
+        if detector.is_verifiable() {
+            Ok(DetectorCheck::Matched)
+        } else {
+            Ok(DetectorCheck::Unverifiable)
+        }
+    }
+
+    fn write_to(&self, path: &Path) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for (key, map, provenance) in &self.dark_maps {
+            entries.push(entry(
+                BundledMap::DarkMap { key: key.clone() },
+                map.dimensions(),
+                provenance,
+            ));
+        }
+        for (key, map, provenance) in &self.gain_maps {
+            entries.push(entry(
+                BundledMap::GainMap { key: key.clone() },
+                map.dimensions(),
+                provenance,
+            ));
+        }
+        if let Some((map, provenance)) = &self.defect_map {
+            entries.push(entry(BundledMap::DefectMap, map.dimensions(), provenance));
+        }
+
+        let manifest = serde_json::to_vec(&BundleManifest {
+            detector: self.detector.clone(),
+            exported: Utc::now(),
+            entries,
+        })?;
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(BUNDLE_MAGIC)?;
+        writer.write_all(&BUNDLE_VERSION.to_le_bytes())?;
+        writer.write_all(&(manifest.len() as u32).to_le_bytes())?;
+        writer.write_all(&manifest)?;
+
+        for (_, map, _) in &self.dark_maps {
+            for value in map.as_raw() {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+        for (_, map, _) in &self.gain_maps {
+            for value in map.as_raw() {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+        if let Some((map, _)) = &self.defect_map {
+            for value in map.as_raw() {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+        writer.flush()
+    }
+
+    fn read_from(path: &Path) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != BUNDLE_MAGIC {
+            return Err(invalid_data("Not a calibration bundle".to_string()));
+        }
+
+        let version = read_u32(&mut reader)?;
+        if version != BUNDLE_VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported bundle version {version}"
+            )));
+        }
+
+        let len = read_u32(&mut reader)? as usize;
+        let mut manifest = vec![0u8; len];
+        reader.read_exact(&mut manifest)?;
+        let manifest: BundleManifest = serde_json::from_slice(&manifest)?;
+
+        let mut bundle = CalibrationBundle {
+            detector: manifest.detector,
+            dark_maps: Vec::new(),
+            gain_maps: Vec::new(),
+            defect_map: None,
+        };
+
+        for entry in manifest.entries {
+            let (width, height) = (entry.width, entry.height);
+            match entry.map {
+                BundledMap::DarkMap { key } => {
+                    let map = read_u16_map(&mut reader, width, height)?;
+                    bundle.dark_maps.push((key, map, entry.provenance));
+                }
+                BundledMap::GainMap { key } => {
+                    let map = read_f32_map(&mut reader, width, height)?;
+                    bundle.gain_maps.push((key, map, entry.provenance));
+                }
+                BundledMap::DefectMap => {
+                    let map = read_u16_map(&mut reader, width, height)?;
+                    bundle.defect_map = Some((map, entry.provenance));
+                }
+            }
+        }
+
+        if reader.read(&mut [0u8; 1])? != 0 {
+            return Err(invalid_data(
+                "Unexpected data after the last map".to_string(),
+            ));
+        }
+
+        Ok(bundle)
+    }
+}
+
+fn entry(map: BundledMap, dimensions: (u32, u32), provenance: &MapProvenance) -> BundleEntry {
+    BundleEntry {
+        map,
+        width: dimensions.0,
+        height: dimensions.1,
+        provenance: provenance.clone(),
+    }
+}
+
+fn check_map_dimensions(expected: (u32, u32), map: (u32, u32)) -> Result<(), CorrectionError> {
+    if expected != map {
+        return Err(CorrectionError::DimensionMismatch(
+            expected.0, expected.1, map.0, map.1,
+        ));
+    }
+    Ok(())
+}
+
+fn read_u16_map<R: Read>(
+    reader: &mut R,
+    width: u32,
+    height: u32,
+) -> io::Result<ImageBuffer<Luma<u16>, Vec<u16>>> {
+    let mut bytes = vec![0u8; (width * height * 2) as usize];
+    reader.read_exact(&mut bytes)?;
+    let data = bytes
+        .chunks_exact(2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .collect();
+    ImageBuffer::from_vec(width, height, data)
+        .ok_or_else(|| invalid_data("Map smaller than its dimensions".to_string()))
+}
+
+fn read_f32_map<R: Read>(
+    reader: &mut R,
+    width: u32,
+    height: u32,
+) -> io::Result<ImageBuffer<Luma<f32>, Vec<f32>>> {
+    let mut bytes = vec![0u8; (width * height * 4) as usize];
+    reader.read_exact(&mut bytes)?;
+    let data = bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    ImageBuffer::from_vec(width, height, data)
+        .ok_or_else(|| invalid_data("Map smaller than its dimensions".to_string()))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use image::{ImageBuffer, Luma};
+
+    use super::{CalibrationBundle, DetectorCheck};
+    use crate::capture::{
+        backend::DetectorIdentity,
+        corrections::{CorrectionError, CorrectionMapKey},
+        provenance::{MapContent, MapProvenance},
+    };
+    use crate::wrapper::{BinningModes, BinningModesRS, FullWellModes, FullWellModesRS};
+
+    fn detector(width: u32) -> DetectorIdentity {
+        DetectorIdentity {
+            model: "Simulated".to_string(),
+            serial: Some("seed-0".to_string()),
+            width,
+            height: 2,
+        }
+    }
+
+    fn key(exp_time: u32, binning: BinningModes) -> CorrectionMapKey {
+        CorrectionMapKey {
+            exp_time,
+            full_well: FullWellModesRS {
+                remote_ty: FullWellModes::High,
+            },
+            binning_mode: BinningModesRS(binning),
+            dds: false,
+        }
+    }
+
+    fn provenance<M: MapContent>(map: &M) -> MapProvenance {
+        MapProvenance::new(map.content_id(), Some(detector(4)), vec![], 10, None)
+    }
+
+    fn bundle() -> CalibrationBundle {
+        let dark: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_vec(4, 2, vec![100, 101, 102, 103, 104, 105, 106, 107]).unwrap();
+        let binned_dark: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_vec(2, 1, vec![400, 401]).unwrap();
+        let gain: ImageBuffer<Luma<f32>, Vec<f32>> =
+            ImageBuffer::from_vec(4, 2, vec![1.0, 0.9, 1.1, 1.0, 1.0, 0.95, 1.05, 1.0]).unwrap();
+        let defects: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_vec(4, 2, vec![0, 0, 1, 0, 0, 0, 0, 0]).unwrap();
+
+        CalibrationBundle {
+            detector: detector(4),
+            dark_maps: vec![
+                (key(100, BinningModes::x11), dark.clone(), provenance(&dark)),
+                (
+                    key(100, BinningModes::x22),
+                    binned_dark.clone(),
+                    provenance(&binned_dark),
+                ),
+            ],
+            gain_maps: vec![(key(100, BinningModes::x11), gain.clone(), provenance(&gain))],
+            defect_map: Some((defects.clone(), provenance(&defects))),
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cview_{name}_{}.cvcal", std::process::id()))
+    }
+
+    #[test]
+    fn roundtrips_every_map() {
+        let path = temp_path("bundle_roundtrip");
+        let original = bundle();
+        original.write(&path).unwrap();
+
+        let read = CalibrationBundle::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.detector, original.detector);
+        assert_eq!(read.dark_maps.len(), 2);
+        assert_eq!(read.dark_maps[1].0, key(100, BinningModes::x22));
+        assert_eq!(read.dark_maps[1].1, original.dark_maps[1].1);
+        assert_eq!(read.gain_maps[0].1, original.gain_maps[0].1);
+        assert_eq!(
+            read.defect_map.as_ref().unwrap().1.id,
+            original.defect_map.unwrap().1.id
+        );
+        assert_eq!(read.validate(&detector(4)).unwrap(), DetectorCheck::Matched);
+    }
+
+    #[test]
+    fn rejects_corrupt_maps() {
+        let path = temp_path("bundle_corrupt");
+        bundle().write(&path).unwrap();
+
+        // Flip a bit in the last pixel of the defect map
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 2;
+        bytes[last] ^= 1;
+        fs::write(&path, bytes).unwrap();
+
+        let result = CalibrationBundle::read(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(CorrectionError::InvalidBundle(_))));
+    }
+
+    #[test]
+    fn identities_without_serials_are_unverifiable() {
+        let mut unverifiable = bundle();
+        unverifiable.detector.serial = None;
+
+        let mut detector = detector(4);
+        detector.serial = None;
+        assert_eq!(
+            unverifiable.validate(&detector).unwrap(),
+            DetectorCheck::Unverifiable
+        );
+    }
+
+    #[test]
+    fn rejects_other_detectors_and_sizes() {
+        let bundle = bundle();
+
+        let mut other = detector(4);
+        other.serial = Some("seed-1".to_string());
+        assert!(matches!(
+            bundle.validate(&other),
+            Err(CorrectionError::BundleDetectorMismatch(_, _))
+        ));
+
+        let mut resized = bundle;
+        resized.detector = detector(6);
+        assert!(matches!(
+            resized.validate(&detector(6)),
+            Err(CorrectionError::DimensionMismatch(6, 2, 4, 2))
+        ));
+    }
+}
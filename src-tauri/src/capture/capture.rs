@@ -1,39 +1,56 @@
 use std::{
     fmt,
     pin::Pin,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
     thread,
     time::{Duration, Instant},
 };
 
 use async_stream::stream;
 use futures_core::stream::Stream;
-use futures_util::{
-    stream::{AbortHandle, Abortable},
-    StreamExt,
-};
-use log::error;
-use serde::Serialize;
+use futures_util::StreamExt;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
 use specta::Type;
 use thiserror::Error;
 
 use crate::wrapper::{
     BinningModes, BinningModesRS, ExposureModes, FullWellModes, FullWellModesRS, InternalSLError,
-    SLDeviceRS, SLImageRs,
+    SLError, SLImageRs,
+};
+
+use super::{
+    backend::DetectorBackend,
+    correction_pipeline::CorrectionPipeline,
+    corrections::{CorrectionError, CorrectionMapKey},
 };
 
-use super::corrections::CorrectionError;
+// How long to wait between polls of the detector for a sequence frame
+const READ_POLL_MILLIS: u32 = 100;
 
 impl From<InternalSLError> for CaptureError {
     fn from(err: InternalSLError) -> Self {
-        CaptureError::SLError(err)
+        let is_any = |errors: &[SLError]| errors.iter().any(|error| err.is(error));
+
+        if is_any(&[SLError::SL_ERROR_TIMEOUT]) {
+            CaptureError::DetectorTimeout
+        } else if is_any(&[SLError::SL_ERROR_DEVICE_CLOSED, SLError::SL_ERROR_NO_DEVICE]) {
+            CaptureError::DetectorDisconnected
+        } else if is_any(&[SLError::SL_ERROR_BUSY, SLError::SL_ERROR_DEVICE_STREAMING]) {
+            CaptureError::DetectorInUse
+        } else if is_any(&[
+            SLError::SL_ERROR_IO,
+            SLError::SL_ERROR_PIPE,
+            SLError::SL_ERROR_OVERFLOW,
+            SLError::SL_ERROR_INTERRUPTED,
+        ]) {
+            CaptureError::TransferFailed(err)
+        } else {
+            CaptureError::SLError(err)
+        }
     }
 }
 
-#[derive(Debug, Error, Type, Serialize)]
+#[derive(Clone, Debug, Error, Type, Serialize)]
 pub enum CaptureError {
     #[error("Detector is disconnected")]
     DetectorDisconnected,
@@ -44,26 +61,138 @@ pub enum CaptureError {
     #[error("Correction error: {0}")]
     File2Error(#[from] CorrectionError),
 
+    #[error("Failed to generate the correction map for {0}: {1}")]
+    CalibrationFailed(CorrectionMapKey, CorrectionError),
+
     #[error("Got internal SDK Error")]
     SLError(InternalSLError),
 
+    #[error("File error: {0}")]
+    FileError(String),
+
+    #[error("Detector does not support {0}")]
+    UnsupportedSetting(String),
+
+    #[error("Detector rejected {0}: {1:?}")]
+    SettingRejected(String, InternalSLError),
+
+    #[error("Timed out waiting for the detector")]
+    DetectorTimeout,
+
+    #[error("No frame {0} from the detector after {1}ms")]
+    FrameTimeout(u32, u32),
+
+    #[error("Transfer from the detector failed: {0:?}")]
+    TransferFailed(InternalSLError),
+
+    #[error("Could not read the frame size from the detector")]
+    FrameSizeUnavailable,
+
+    #[error("No capture is running")]
+    NotCapturing,
+
+    #[error("Only captures over several exposures can be paused")]
+    PauseUnsupported,
+
+    #[error("No capture job {0} in the queue")]
+    JobNotFound(u32),
+
+    #[error("Live captures run until they're stopped, so can't be queued")]
+    CaptureNeverEnds,
+
+    #[error("No signal from the X-ray source after {0}s")]
+    NoSourceSignal(u32),
+
+    #[error("SNR window of {0}px is larger than the frame")]
+    SnrWindowTooLarge(u32),
+
     #[error("Error")]
     Unknown,
 }
+
+impl CaptureError {
+    // Failures a read can recover from by trying again
+    fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            CaptureError::DetectorInUse | CaptureError::TransferFailed(_)
+        )
+    }
+
+    // Failures no amount of waiting for the frame will fix. Any other SDK error while polling
+    // means the frame isn't ready yet
+    fn is_terminal(&self) -> bool {
+        match self {
+            CaptureError::DetectorDisconnected => true,
+            CaptureError::SLError(err) => [
+                SLError::SL_ERROR_INVALID_PARAM,
+                SLError::SL_ERROR_NOT_SUPPORTED,
+                SLError::SL_ERROR_NOT_ENOUGH_MEMORY,
+                SLError::SL_ERROR_ACCESS,
+                SLError::SL_ERROR_REQUIRES_ADMIN,
+                SLError::SL_ERROR_CRITICAL,
+            ]
+            .iter()
+            .any(|error| err.is(error)),
+            _ => false,
+        }
+    }
+}
 unsafe impl Send for CaptureSetting {}
 unsafe impl Sync for CaptureSetting {}
 
-#[derive(Serialize, Type)]
+#[derive(Serialize, Deserialize, Type)]
 pub struct CaptureSetting {
     pub exp_time: u32,
     #[specta(skip)]
-    #[serde(skip)]
+    #[serde(skip, default = "default_capture_mode")]
     pub capture_mode: Box<dyn Capture + Send + 'static>,
     pub dds: bool,
     pub full_well: FullWellModesRS,
     pub binning_mode: BinningModesRS,
-    pub roi: Option<Vec<u32>>,
-    pub corrected: bool,
+    pub roi: Option<Roi>,
+    #[serde(default)]
+    pub corrections: CorrectionPipeline,
+    #[serde(default)]
+    pub read_policy: ReadPolicy,
+}
+
+// Region of the frame read out, in pixels of the binned frame
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Type, PartialEq)]
+pub struct Roi {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// How long to wait for each frame, and how often a read that failed with a transient error
+// is retried, before the capture fails
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Type, PartialEq)]
+pub struct ReadPolicy {
+    // Allowed on top of the exposure time
+    pub frame_timeout_ms: u32,
+    pub max_retries: u32,
+}
+
+impl Default for ReadPolicy {
+    fn default() -> Self {
+        ReadPolicy {
+            frame_timeout_ms: 2000,
+            max_retries: 3,
+        }
+    }
+}
+
+impl ReadPolicy {
+    fn frame_deadline(&self, exp_time: u32) -> Duration {
+        Duration::from_millis(exp_time as u64 + self.frame_timeout_ms as u64)
+    }
+}
+
+// Capture modes aren't serialized, so settings read back from disk capture a single frame
+fn default_capture_mode() -> Box<dyn Capture + Send + 'static> {
+    Box::new(SequenceCapture { num_frames: 1 })
 }
 
 // Manual implementation of Debug
@@ -76,6 +205,8 @@ impl fmt::Debug for CaptureSetting {
             .field("full_well", &self.full_well)
             .field("binning_mode", &self.binning_mode)
             .field("roi", &self.roi)
+            .field("corrections", &self.corrections)
+            .field("read_policy", &self.read_policy)
             .finish()
     }
 }
@@ -88,26 +219,31 @@ impl Clone for CaptureSetting {
             dds: self.dds,
             full_well: self.full_well.clone(),
             binning_mode: self.binning_mode.clone(),
-            roi: self.roi.clone(),
-            corrected: self.corrected,
+            roi: self.roi,
+            corrections: self.corrections.clone(),
+            read_policy: self.read_policy,
         }
     }
 }
 
 pub struct CaptureSettingBuilder {
-    corrected: bool,
+    corrections: CorrectionPipeline,
+    read_policy: ReadPolicy,
     exp_time: u32,
     capture_mode: Box<dyn Capture + Send + Sync + 'static>,
     dds: bool,
     full_well: FullWellModesRS,
     binning_mode: BinningModesRS,
-    pub roi: Option<Vec<u32>>,
+    roi: Option<Roi>,
 }
 
+// Covers every setting, not all of which have a caller yet
+#[allow(dead_code)]
 impl CaptureSettingBuilder {
     pub fn new(exp_time: u32, capture_mode: Box<dyn Capture + Send + Sync + 'static>) -> Self {
         CaptureSettingBuilder {
-            corrected: true,
+            corrections: CorrectionPipeline::default(),
+            read_policy: ReadPolicy::default(),
             exp_time,
             capture_mode,
             dds: false,
@@ -119,8 +255,18 @@ impl CaptureSettingBuilder {
         }
     }
 
+    // Shorthand for the default pipeline or no corrections at all
     pub fn corrected(mut self, corrected: bool) -> Self {
-        self.corrected = corrected;
+        self.corrections = if corrected {
+            CorrectionPipeline::default()
+        } else {
+            CorrectionPipeline::none()
+        };
+        self
+    }
+
+    pub fn corrections(mut self, corrections: CorrectionPipeline) -> Self {
+        self.corrections = corrections;
         self
     }
 
@@ -139,6 +285,16 @@ impl CaptureSettingBuilder {
         self
     }
 
+    pub fn roi(mut self, roi: Option<Roi>) -> Self {
+        self.roi = roi;
+        self
+    }
+
+    pub fn read_policy(mut self, read_policy: ReadPolicy) -> Self {
+        self.read_policy = read_policy;
+        self
+    }
+
     pub fn build(self) -> CaptureSetting {
         CaptureSetting {
             exp_time: self.exp_time,
@@ -147,17 +303,23 @@ impl CaptureSettingBuilder {
             full_well: self.full_well,
             dds: self.dds,
             roi: self.roi,
-            corrected: self.corrected,
+            corrections: self.corrections,
+            read_policy: self.read_policy,
         }
     }
 }
 
+// Raw frames read from the detector, ending with the error if a read failed
+pub type FrameResults = Pin<Box<dyn Stream<Item = Result<SLImageRs, CaptureError>> + Send>>;
+
 pub trait Capture {
+    // The stream ends after yielding the first error, with the detector taken out of live mode
     fn stream_results(
         &self,
         exp_time: u32,
-        detector: SLDeviceRS,
-    ) -> Result<Pin<Box<dyn Stream<Item = SLImageRs> + Send>>, CaptureError>;
+        read_policy: ReadPolicy,
+        detector: Box<dyn DetectorBackend>,
+    ) -> Result<FrameResults, CaptureError>;
 
     fn clone_box(&self) -> Box<dyn Capture + Send + 'static>;
 }
@@ -172,35 +334,111 @@ pub struct StreamCapture {
     pub duration: Option<Duration>,
 }
 
+// Takes the detector out of live mode once a capture stream is done with it, including when
+// the stream is dropped part way through because the capture was cancelled
+struct LiveGuard(Box<dyn DetectorBackend>);
+
+impl Drop for LiveGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.go_unlive(true) {
+            error!("Failed to take detector out of live mode: {e:?}");
+        }
+    }
+}
+
+// A buffer the size of the frames the detector currently reads out
+fn frame_buffer(detector: &mut dyn DetectorBackend) -> Result<SLImageRs, CaptureError> {
+    match (detector.image_height(), detector.image_width()) {
+        (Ok(height), Ok(width)) => Ok(SLImageRs::new(height, width)),
+        _ => Err(CaptureError::FrameSizeUnavailable),
+    }
+}
+
+// Polls the detector for a frame of a sequence until it arrives or the policy gives up on it
+fn read_sequence_frame(
+    detector: &mut dyn DetectorBackend,
+    image: &mut SLImageRs,
+    frame_num: u32,
+    exp_time: u32,
+    read_policy: &ReadPolicy,
+) -> Result<(), CaptureError> {
+    let deadline = read_policy.frame_deadline(exp_time);
+    let start = Instant::now();
+    let mut retries = 0;
+
+    loop {
+        let err = match detector.read_buffer(image, frame_num, READ_POLL_MILLIS) {
+            Ok(()) => return Ok(()),
+            Err(e) => CaptureError::from(e),
+        };
+
+        match err {
+            err if err.is_transient() && retries < read_policy.max_retries => {
+                retries += 1;
+                warn!(
+                    "Retrying frame {frame_num} after error {err} ({retries}/{})",
+                    read_policy.max_retries
+                );
+            }
+            err if err.is_transient() || err.is_terminal() => return Err(err),
+            _ if start.elapsed() < deadline => {}
+            _ => {
+                return Err(CaptureError::FrameTimeout(
+                    frame_num,
+                    deadline.as_millis() as u32,
+                ))
+            }
+        }
+    }
+}
+
 impl Capture for SequenceCapture {
     fn stream_results(
         &self,
         exp_time: u32,
-        mut detector: SLDeviceRS,
-    ) -> Result<Pin<Box<dyn Stream<Item = SLImageRs> + Send>>, CaptureError> {
-        println!("Setting up a sequence stream");
+        read_policy: ReadPolicy,
+        mut detector: Box<dyn DetectorBackend>,
+    ) -> Result<FrameResults, CaptureError> {
         let capture = self.clone();
 
-        detector.set_exposure_time(exp_time)?;
-        detector.set_exposure_mode(ExposureModes::seq_mode)?;
-        detector.set_number_frames(capture.num_frames as u32)?;
-        detector.go_live()?;
-        detector.software_trigger()?;
-
+        // The detector is only set up once the stream is first polled, so captures can build
+        // the streams for several exposures up front and run them one after another
         Ok(stream! {
-            println!("Running sequence stream");
-
-            for frame_num in 0.. capture.num_frames {
-                let mut image = SLImageRs::new(
-                    detector.image_height().unwrap(),
-                    detector.image_width().unwrap(),
-                );
-
-                while detector.read_buffer(&mut image, frame_num as u32, 100).is_err() {}
-                yield image;
+            let setup = detector
+                .set_exposure_time(exp_time)
+                .and_then(|_| detector.set_exposure_mode(ExposureModes::seq_mode))
+                .and_then(|_| detector.set_number_frames(capture.num_frames))
+                .and_then(|_| detector.go_live());
+            if let Err(e) = setup {
+                yield Err(e.into());
+                return;
+            }
+            let mut live = LiveGuard(detector);
+            if let Err(e) = live.0.software_trigger() {
+                yield Err(e.into());
+                return;
             }
 
-            detector.go_unlive(true);
+            let detector = &mut *live.0;
+
+            for frame_num in 0..capture.num_frames {
+                let frame = frame_buffer(detector).and_then(|mut image| {
+                    read_sequence_frame(
+                        detector,
+                        &mut image,
+                        frame_num,
+                        exp_time,
+                        &read_policy,
+                    )
+                    .map(|()| image)
+                });
+
+                let failed = frame.is_err();
+                yield frame;
+                if failed {
+                    break;
+                }
+            }
         }
         .boxed())
     }
@@ -214,26 +452,43 @@ impl Capture for StreamCapture {
     fn stream_results(
         &self,
         exp_time: u32,
-        mut detector: SLDeviceRS,
-    ) -> Result<Pin<Box<dyn Stream<Item = SLImageRs> + Send>>, CaptureError> {
+        read_policy: ReadPolicy,
+        mut detector: Box<dyn DetectorBackend>,
+    ) -> Result<FrameResults, CaptureError> {
         let capture = self.clone();
+        let deadline = read_policy.frame_deadline(exp_time);
 
-        let (abort_handle, abort_registration) = AbortHandle::new_pair();
         detector.start_stream(exp_time)?;
+        let mut live = LiveGuard(detector);
         Ok(stream! {
+            let detector = &mut *live.0;
             let start_time = Instant::now();
+            let mut last_frame_time = Instant::now();
+            let mut frames_read = 0;
             while capture.duration.is_none() || start_time.elapsed() < capture.duration.unwrap() {
-                let mut image: SLImageRs = SLImageRs::new(
-                    detector.image_height().unwrap(),
-                    detector.image_width().unwrap(),
-                );
+                let mut image = match frame_buffer(detector) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        yield Err(e);
+                        break;
+                    }
+                };
 
                 thread::sleep(Duration::from_millis(1));
-                if detector.read_frame(image.get_data_pointer(0), true) {
-                    yield image;
+                if detector.read_frame(&mut image, true) {
+                    frames_read += 1;
+                    last_frame_time = Instant::now();
+                    yield Ok(image);
+                } else if last_frame_time.elapsed() > deadline {
+                    // read_frame doesn't say why no frame arrived
+                    yield Err(if detector.is_connected() {
+                        CaptureError::FrameTimeout(frames_read, deadline.as_millis() as u32)
+                    } else {
+                        CaptureError::DetectorDisconnected
+                    });
+                    break;
                 }
             }
-            detector.go_unlive(true);
         }
         .boxed())
     }
@@ -242,3 +497,96 @@ impl Capture for StreamCapture {
         Box::new(self.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+
+    use crate::{
+        capture::{
+            backend::DetectorBackend,
+            simulated::{SimulatedDetector, SimulatedDetectorConfig},
+        },
+        wrapper::{InternalSLError, SLError, SLImageRs},
+    };
+
+    use super::{Capture, CaptureError, ReadPolicy, SequenceCapture};
+
+    #[test]
+    fn sdk_errors_map_to_capture_errors() {
+        let map = |error: SLError| CaptureError::from(InternalSLError::from(error));
+
+        assert!(matches!(
+            map(SLError::SL_ERROR_TIMEOUT),
+            CaptureError::DetectorTimeout
+        ));
+        assert!(matches!(
+            map(SLError::SL_ERROR_DEVICE_CLOSED),
+            CaptureError::DetectorDisconnected
+        ));
+        assert!(matches!(
+            map(SLError::SL_ERROR_IO),
+            CaptureError::TransferFailed(_)
+        ));
+        assert!(matches!(
+            map(SLError::SL_ERROR_INVALID_PARAM),
+            CaptureError::SLError(_)
+        ));
+    }
+
+    #[test]
+    fn only_terminal_errors_stop_polling_for_a_frame() {
+        let map = |error: SLError| CaptureError::from(InternalSLError::from(error));
+
+        assert!(map(SLError::SL_ERROR_DEVICE_CLOSED).is_terminal());
+        assert!(map(SLError::SL_ERROR_INVALID_PARAM).is_terminal());
+        assert!(map(SLError::SL_ERROR_CRITICAL).is_terminal());
+        assert!(!map(SLError::SL_ERROR_TIMEOUT).is_terminal());
+        assert!(!map(SLError::SL_ERROR_NOT_FOUND).is_terminal());
+        assert!(!map(SLError::SL_ERROR_OTHER).is_terminal());
+    }
+
+    #[tokio::test]
+    async fn sequence_ends_with_error_when_detector_disconnects() {
+        let mut detector = SimulatedDetector::new(SimulatedDetectorConfig {
+            width: 16,
+            height: 8,
+            realtime: false,
+            ..Default::default()
+        });
+        detector.open_camera(1).unwrap();
+
+        let mut frames = SequenceCapture { num_frames: 3 }
+            .stream_results(100, ReadPolicy::default(), detector.clone_box())
+            .unwrap();
+
+        assert!(frames.next().await.unwrap().is_ok());
+        detector.set_connected(false);
+        assert!(matches!(
+            frames.next().await,
+            Some(Err(CaptureError::DetectorDisconnected))
+        ));
+        assert!(frames.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn dropping_sequence_takes_detector_out_of_live_mode() {
+        let mut detector = SimulatedDetector::new(SimulatedDetectorConfig {
+            width: 16,
+            height: 8,
+            realtime: false,
+            ..Default::default()
+        });
+        detector.open_camera(1).unwrap();
+
+        let mut frames = SequenceCapture { num_frames: 3 }
+            .stream_results(100, ReadPolicy::default(), detector.clone_box())
+            .unwrap();
+        assert!(frames.next().await.unwrap().is_ok());
+        drop(frames);
+
+        // Frames can only be read while live
+        let mut image = SLImageRs::new(8, 16);
+        assert!(detector.read_buffer(&mut image, 1, 100).is_err());
+    }
+}
@@ -1,410 +1,566 @@
 use std::{
+    borrow::Cow,
     collections::HashMap,
     fs,
-    path::PathBuf,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        mpsc, Arc, Mutex,
-    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
 use async_stream::stream;
-use futures::stream::{self, Stream, StreamExt};
+use chrono::{Duration, Utc};
+use futures::stream::{Stream, StreamExt};
 use futures_util::{
     pin_mut,
-    stream::{abortable, AbortHandle},
+    stream::{abortable, AbortHandle, Abortable},
 };
-use log::{error, info};
-use regex::Regex;
+use log::{error, info, warn};
 use tauri::{AppHandle, Manager, Runtime};
 use tauri_specta::Event;
 
-use crate::{capture::corrections::run_defect_map_gen, wrapper::*};
+use image::{imageops, ImageBuffer, Luma, Pixel};
 
 use super::{
-    advanced_capture::{DarkMapCapture, DefectMapCapture},
-    capture::{CaptureError, CaptureSettingBuilder, SequenceCapture},
+    backend::{create_detector_backend, DetectorBackend, DetectorIdentity},
+    calibration_bundle::{CalibrationBundle, DetectorCheck},
+    capture::{CaptureError, Roi},
+    correction_pipeline::{CorrectionStage, CorrectionStageFactory},
+    corrections::{
+        defect_correct, destripe, gain_correct, offset_correct, read_gain_map, write_gain_map,
+        CorrectionError, CorrectionMapKey, CorrectionSettings, DarkCorrectionMethod, DarkModel,
+        LineOffsets,
+    },
+    defect_map::{DefectMap, DEFECT_MAP_FILE_NAME},
     detector::{DetectorController, DetectorStatus},
+    lag::{read_lag_models, write_lag_models, LagModel, LAG_MODEL_FILE_NAME},
+    provenance::{CorrectionMapProvenance, MapContent, MapProvenance, StaleMap},
     types::{
         AdvCapture, AdvancedCapture, CaptureManagerEvent, CaptureManagerEventPayload,
-        CaptureManagerInfo, CaptureManagerStatus, CaptureStreamItem,
+        CaptureManagerInfo, CaptureManagerStatus, CaptureResults, CaptureStreamItem, PauseControl,
     },
 };
 
+type CorrectionMap = ImageBuffer<Luma<u16>, Vec<u16>>;
+type GainMap = ImageBuffer<Luma<f32>, Vec<f32>>;
+// Where generated maps are saved so they're loaded again on the next start
+#[derive(Clone)]
+pub struct CorrectionMapDirs {
+    pub dark_maps: PathBuf,
+    pub defect_map: PathBuf,
+    pub gain_maps: PathBuf,
+    pub lag_models: PathBuf,
+}
+
 #[derive(Clone)]
 pub struct CorrectionMaps {
-    dark_maps: Arc<Mutex<HashMap<u32, SLImageRs>>>,
-    defect_map: Arc<Mutex<Option<SLImageRs>>>,
+    dark_maps: Arc<Mutex<HashMap<CorrectionMapKey, CorrectionMap>>>,
+    defect_map: Arc<Mutex<Option<CorrectionMap>>>,
+    gain_maps: Arc<Mutex<HashMap<CorrectionMapKey, GainMap>>>,
+    lag_models: Arc<Mutex<HashMap<CorrectionMapKey, LagModel>>>,
+    // Dark maps synthesized for keys without a captured map, cleared whenever the dark maps change
+    synthesized_dark_maps:
+        Arc<Mutex<HashMap<CorrectionMapKey, (CorrectionMap, DarkCorrectionMethod)>>>,
+    provenance: Arc<Mutex<CorrectionMapProvenance>>,
+    settings: Arc<Mutex<CorrectionSettings>>,
+    // Generated maps are only kept in memory without these
+    dirs: Option<CorrectionMapDirs>,
+    custom_stages: Arc<Mutex<HashMap<String, CorrectionStageFactory>>>,
 }
 
 impl CorrectionMaps {
-    fn new(dark_maps: HashMap<u32, SLImageRs>, defect_map: Option<SLImageRs>) -> Self {
+    pub fn new(
+        dark_maps: HashMap<CorrectionMapKey, ImageBuffer<Luma<u16>, Vec<u16>>>,
+        defect_map: Option<ImageBuffer<Luma<u16>, Vec<u16>>>,
+        gain_maps: HashMap<CorrectionMapKey, ImageBuffer<Luma<f32>, Vec<f32>>>,
+    ) -> Self {
         CorrectionMaps {
             dark_maps: Arc::new(Mutex::new(dark_maps)),
             defect_map: Arc::new(Mutex::new(defect_map)),
+            gain_maps: Arc::new(Mutex::new(gain_maps)),
+            lag_models: Arc::new(Mutex::new(HashMap::new())),
+            synthesized_dark_maps: Arc::new(Mutex::new(HashMap::new())),
+            provenance: Arc::new(Mutex::new(CorrectionMapProvenance::default())),
+            settings: Arc::new(Mutex::new(CorrectionSettings::default())),
+            dirs: None,
+            custom_stages: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_dirs(mut self, dirs: CorrectionMapDirs) -> Self {
+        self.dirs = Some(dirs);
+        self
+    }
+
+    pub fn with_lag_models(self, lag_models: HashMap<CorrectionMapKey, LagModel>) -> Self {
+        *self.lag_models.lock().unwrap() = lag_models;
+        self
+    }
+
+    // Uses the dark map captured for the key if there is one, otherwise one synthesized from
+    // the maps captured with the same settings at other exposure times
+    pub fn dark_correct_image(
+        &self,
+        image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+        key: &CorrectionMapKey,
+        roi: Option<&Roi>,
+    ) -> Result<DarkCorrectionMethod, CorrectionError> {
+        let pedestal = self.settings.lock().unwrap().pedestal;
+        let dark_maps = self.dark_maps.lock().unwrap();
+        if let Some(dark_map) = dark_maps.get(key) {
+            offset_correct(image, &crop_to_roi(dark_map, roi), pedestal)?;
+            return Ok(DarkCorrectionMethod::Exact { key: key.clone() });
+        }
+
+        let mut synthesized_dark_maps = self.synthesized_dark_maps.lock().unwrap();
+        if !synthesized_dark_maps.contains_key(key) {
+            let synthesized = Self::synthesize_dark_map(&dark_maps, key)?;
+            synthesized_dark_maps.insert(key.clone(), synthesized);
         }
+
+        let (dark_map, method) = &synthesized_dark_maps[key];
+        offset_correct(image, &crop_to_roi(dark_map, roi), pedestal)?;
+        Ok(method.clone())
     }
 
-    pub fn dark_correct_image(&self, image: &mut SLImageRs, exp_time: u32) -> Result<(), ()> {
-        if let Some(ref mut dark_map) = self.dark_maps.lock().unwrap().get_mut(&exp_time) {
-            image.offset_correction(dark_map, 300);
-            return Ok(());
+    pub fn gain_correct_image(
+        &self,
+        image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+        key: &CorrectionMapKey,
+        roi: Option<&Roi>,
+    ) -> Result<(), CorrectionError> {
+        let pedestal = self.settings.lock().unwrap().pedestal;
+        match self.gain_maps.lock().unwrap().get(key) {
+            Some(gain_map) => gain_correct(image, &crop_to_roi(gain_map, roi), pedestal),
+            None => Err(CorrectionError::GainMapNotFound(key.clone())),
         }
-        Err(())
     }
 
     pub fn defect_correct_image(
         &self,
-        image: &mut SLImageRs,
-        exp_time: u32,
-    ) -> Result<(SLImageRs), ()> {
-        if let Some(ref mut defect_map) = *self.defect_map.lock().unwrap() {
-            let mut out_image = SLImageRs::new(image.get_height(), image.get_width());
-            image.defect_correction(&mut out_image, defect_map).unwrap();
-            return Ok(out_image);
-        } else {
-            Err(())
+        image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+        roi: Option<&Roi>,
+    ) -> Result<(), CorrectionError> {
+        let settings = self.settings.lock().unwrap().defect_correction.clone();
+        match self.defect_map.lock().unwrap().as_ref() {
+            Some(defect_map) => defect_correct(image, &crop_to_roi(defect_map, roi), &settings),
+            None => Err(CorrectionError::DefectMapNotFound),
         }
     }
 
+    pub fn destripe_image(
+        &self,
+        image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+    ) -> Result<LineOffsets, CorrectionError> {
+        let settings = self.settings.lock().unwrap().destripe.clone();
+        destripe(image, &settings)
+    }
+
+    pub fn lag_model(&self, key: &CorrectionMapKey) -> Option<LagModel> {
+        self.lag_models.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn pedestal(&self) -> u16 {
+        self.settings.lock().unwrap().pedestal
+    }
+
+    // Makes a stage available to pipelines as CorrectionStageKind::Custom { name }
+    pub fn register_custom_stage(&self, name: &str, factory: CorrectionStageFactory) {
+        self.custom_stages
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), factory);
+    }
+
+    pub fn custom_stage(&self, name: &str) -> Option<Box<dyn CorrectionStage>> {
+        let custom_stages = self.custom_stages.lock().unwrap();
+        custom_stages.get(name).map(|factory| factory())
+    }
+
     pub fn has_defect_map(&self) -> bool {
         self.defect_map.lock().unwrap().is_some()
     }
 
     pub fn get_dark_map_exp_times(&self) -> Vec<u32> {
-        self.dark_maps
+        let mut exp_times: Vec<u32> = self
+            .dark_maps
             .lock()
             .unwrap()
             .keys()
-            .cloned()
-            .collect::<Vec<u32>>()
+            .map(|key| key.exp_time)
+            .collect();
+        exp_times.sort();
+        exp_times.dedup();
+        exp_times
     }
 
-    fn set_dark_maps(&self, new_dark_maps: HashMap<u32, SLImageRs>) {
-        *self.dark_maps.lock().unwrap() = new_dark_maps;
+    pub fn get_dark_map_keys(&self) -> Vec<CorrectionMapKey> {
+        Self::sorted_keys(self.dark_maps.lock().unwrap().keys())
     }
 
-    fn set_defect_map(&mut self, defect_map: SLImageRs) {
-        *self.defect_map.lock().unwrap() = Some(defect_map);
+    pub fn get_gain_map_keys(&self) -> Vec<CorrectionMapKey> {
+        Self::sorted_keys(self.gain_maps.lock().unwrap().keys())
     }
-}
 
-pub struct CaptureManager {
-    detector_controller: DetectorController,
-    capture_abort_handle: Option<AbortHandle>,
-    info: Arc<Mutex<CaptureManagerInfo>>,
-    correction_maps: CorrectionMaps,
-    dark_map_path: PathBuf,
-    defect_map_path: PathBuf,
-}
+    pub fn get_dark_map(
+        &self,
+        key: &CorrectionMapKey,
+    ) -> Result<ImageBuffer<Luma<u16>, Vec<u16>>, CorrectionError> {
+        let dark_maps = self.dark_maps.lock().unwrap();
+        dark_maps
+            .get(key)
+            .cloned()
+            .ok_or_else(|| Self::missing_dark_map(&dark_maps, key))
+    }
 
-impl CaptureManager {
-    pub fn new<T: Runtime>(app: AppHandle<T>) -> Self {
-        let local_data = app.path().app_local_data_dir().unwrap();
+    pub fn set_settings(&self, settings: CorrectionSettings) {
+        *self.settings.lock().unwrap() = settings;
+    }
 
-        let dark_map_path = local_data.join("DarkMaps");
-        let defect_map_path = local_data.join("DefectMap");
+    pub fn set_provenance(&self, provenance: CorrectionMapProvenance) {
+        *self.provenance.lock().unwrap() = provenance;
+    }
 
-        fs::create_dir(&dark_map_path);
-        fs::create_dir(&defect_map_path);
+    // IDs of the captured maps behind a dark correction, one for an exact map or every map
+    // the dark model was fitted to
+    pub fn dark_map_ids(&self, method: &DarkCorrectionMethod) -> Vec<String> {
+        let provenance = self.provenance.lock().unwrap();
+        let keys = match method {
+            DarkCorrectionMethod::Exact { key } => vec![key.clone()],
+            DarkCorrectionMethod::Interpolated { key, exp_times, .. }
+            | DarkCorrectionMethod::Extrapolated { key, exp_times, .. } => exp_times
+                .iter()
+                .map(|&exp_time| CorrectionMapKey {
+                    exp_time,
+                    ..key.clone()
+                })
+                .collect(),
+        };
 
-        let dark_maps = read_dark_maps(&dark_map_path);
-        let defect_map = read_defect_map(&defect_map_path.join("GlobalDefectMap.tif"));
+        keys.iter()
+            .filter_map(|key| provenance.dark_maps.get(key))
+            .map(|map_provenance| map_provenance.id.clone())
+            .collect()
+    }
 
-        let correction_maps = CorrectionMaps::new(dark_maps, defect_map);
+    pub fn gain_map_id(&self, key: &CorrectionMapKey) -> Option<String> {
+        let provenance = self.provenance.lock().unwrap();
+        provenance.gain_maps.get(key).map(|p| p.id.clone())
+    }
 
-        let info = Arc::new(Mutex::new(CaptureManagerInfo {
-            status: CaptureManagerStatus::DetectorDisconnected,
-            detector_info: { None },
-        }));
+    pub fn defect_map_id(&self) -> Option<String> {
+        let provenance = self.provenance.lock().unwrap();
+        provenance.defect_map.as_ref().map(|p| p.id.clone())
+    }
 
-        let detector_controller = DetectorController::new(Self::create_detector_callback(
-            app.clone(),
-            correction_maps.clone(),
-            info.clone(),
-        ));
+    pub fn to_bundle(&self, detector: DetectorIdentity) -> CalibrationBundle {
+        let dark_maps = self.dark_maps.lock().unwrap();
+        let gain_maps = self.gain_maps.lock().unwrap();
+        let defect_map = self.defect_map.lock().unwrap();
+        let provenance = self.provenance.lock().unwrap();
 
-        Self {
-            detector_controller: detector_controller,
-            capture_abort_handle: None,
-            info,
-            correction_maps,
-            dark_map_path,
-            defect_map_path,
+        let recorded = |map_provenance: Option<&MapProvenance>, map: &dyn MapContent| {
+            map_provenance
+                .cloned()
+                .unwrap_or_else(|| MapProvenance::unrecorded(map.content_id()))
+        };
+
+        CalibrationBundle {
+            detector,
+            dark_maps: Self::sorted_keys(dark_maps.keys())
+                .into_iter()
+                .map(|key| {
+                    let map = dark_maps[&key].clone();
+                    let map_provenance = recorded(provenance.dark_maps.get(&key), &map);
+                    (key, map, map_provenance)
+                })
+                .collect(),
+            gain_maps: Self::sorted_keys(gain_maps.keys())
+                .into_iter()
+                .map(|key| {
+                    let map = gain_maps[&key].clone();
+                    let map_provenance = recorded(provenance.gain_maps.get(&key), &map);
+                    (key, map, map_provenance)
+                })
+                .collect(),
+            defect_map: defect_map
+                .as_ref()
+                .map(|map| (map.clone(), recorded(provenance.defect_map.as_ref(), map))),
         }
     }
 
-    /*
-    pub fn generate_corrections(
-        &mut self,
-        exp_times: Vec<u32>,
-        num_frames: u32,
-    ) ->  Pin<Box<dyn Stream<Item = CaptureStreamItem> + Send>>{
-        self.dark_maps.lock().unwrap().clear();
-
-        let dark_maps = self.dark_maps.clone();
-        let dark_map_path = self.dark_map_path.clone();
-        let defect_map = self.defect_map.clone();
-        let stop_signal_clone: Arc<AtomicBool> = self.stop_signal.clone();
-
-
-        let streams = exp_times.iter().map(|&exp_time| {
-            let dark_map_path: PathBuf = dark_map_path.clone();
-            let dark_maps_clone = dark_maps.clone();
-            let dark_map_path = dark_map_path.clone();
-            let defect_map: Arc<Mutex<Option<SLImageRs>>> = defect_map.clone();
-            let stop_signal_clone: Arc<AtomicBool> = stop_signal_clone.clone();
-
-            let capture_settings =
-                CaptureSettingBuilder::new(exp_time, Box::new(SequenceCapture { num_frames }))
-                    .corrected(false)
-                    .build();
-
-            let mut stream = self
-                .detector_controller
-                .run_capture_stream(
-                    capture_settings,
-                    dark_maps_clone,
-                    defect_map,
-                    stop_signal_clone
-                )
-                .unwrap();
-
-            let dark_maps = dark_maps.clone();
-
-            let new_stream = stream! {
-                let mut i = 0;
-                let avg_image = Arc::new(Mutex::new(SLImageRs::new_depth(1536, 1031, num_frames)));
-
-                let avg_image_clone = avg_image.clone();
-                while let Some(mut stream_item) = stream.next().await {
-                    unsafe {
-                        std::ptr::copy_nonoverlapping(
-                        stream_item.get_data_pointer(0),
-                        avg_image_clone.lock().unwrap().get_data_pointer(i),
-                        (stream_item.get_width() * stream_item.get_height() * 2) as usize,
-                    );
-                    i+= 1;
-                }
-                }
+    pub fn stale_maps(&self, detector: Option<&DetectorIdentity>) -> Vec<StaleMap> {
+        let max_age = Duration::days(self.settings.lock().unwrap().max_calibration_age_days as i64);
+        self.provenance
+            .lock()
+            .unwrap()
+            .stale_maps(Utc::now(), max_age, detector)
+    }
 
-                let mut average_image = avg_image.lock().unwrap().get_average_image();
+    #[allow(dead_code)]
+    fn set_dark_maps(
+        &self,
+        new_dark_maps: HashMap<CorrectionMapKey, ImageBuffer<Luma<u16>, Vec<u16>>>,
+    ) {
+        let mut dark_maps = self.dark_maps.lock().unwrap();
+        *dark_maps = new_dark_maps;
+        self.synthesized_dark_maps.lock().unwrap().clear();
+    }
 
-                average_image
-                    .write_tiff_image(&dark_map_path.join(format!("DarkMap_{exp_time}ms.tif")))
-                    .unwrap();
+    // Saves a newly generated dark map and loads it, replacing any with the same key
+    pub fn save_dark_map(
+        &self,
+        key: CorrectionMapKey,
+        dark_map: ImageBuffer<Luma<u16>, Vec<u16>>,
+        map_provenance: MapProvenance,
+    ) -> Result<(), CorrectionError> {
+        if let Some(dirs) = &self.dirs {
+            let path = dirs.dark_maps.join(key.file_name("DarkMap"));
+            dark_map
+                .save(&path)
+                .map_err(|e| CorrectionError::WriteFailed(format!("{}: {e}", path.display())))?;
+            map_provenance.write(&path)?;
+            info!("Saved dark map for {key} to {}", path.display());
+        }
 
-                dark_maps
-                    .lock()
-                    .unwrap()
-                    .insert(exp_time, average_image);
+        let mut dark_maps = self.dark_maps.lock().unwrap();
+        let mut provenance = self.provenance.lock().unwrap();
+        dark_maps.insert(key.clone(), dark_map);
+        provenance.dark_maps.insert(key, map_provenance);
+        self.synthesized_dark_maps.lock().unwrap().clear();
+        Ok(())
+    }
 
-                yield CaptureStreamItem::Progress(CaptureProgress::new(
-                    0,
-                    "Generating Defect Maps".to_string(),
-                ))
-            };
+    // Swaps every map for those from an imported calibration bundle
+    fn replace_all(&self, bundle: CalibrationBundle) {
+        let mut dark_maps = self.dark_maps.lock().unwrap();
+        let mut gain_maps = self.gain_maps.lock().unwrap();
+        let mut defect_map = self.defect_map.lock().unwrap();
+        let mut provenance = self.provenance.lock().unwrap();
+
+        *provenance = CorrectionMapProvenance::default();
+        dark_maps.clear();
+        for (key, map, map_provenance) in bundle.dark_maps {
+            provenance.dark_maps.insert(key.clone(), map_provenance);
+            dark_maps.insert(key, map);
+        }
+        gain_maps.clear();
+        for (key, map, map_provenance) in bundle.gain_maps {
+            provenance.gain_maps.insert(key.clone(), map_provenance);
+            gain_maps.insert(key, map);
+        }
+        *defect_map = bundle.defect_map.map(|(map, map_provenance)| {
+            provenance.defect_map = Some(map_provenance);
+            map
+        });
 
+        self.synthesized_dark_maps.lock().unwrap().clear();
+    }
 
-            return new_stream;
-    }).collect::<Vec<_>>();
+    // Saves a newly generated defect map, along with its summary, and loads it
+    pub fn save_defect_map(
+        &self,
+        defect_map: DefectMap,
+        map_provenance: MapProvenance,
+    ) -> Result<(), CorrectionError> {
+        if let Some(dirs) = &self.dirs {
+            defect_map.save(&dirs.defect_map)?;
+            map_provenance.write(&dirs.defect_map.join(DEFECT_MAP_FILE_NAME))?;
+        }
 
-        let mut stream = stream::iter(streams).flatten().boxed();
+        *self.defect_map.lock().unwrap() = Some(defect_map.map);
+        self.provenance.lock().unwrap().defect_map = Some(map_provenance);
+        Ok(())
+    }
 
-        let new_stream = stream! {
-            while let Some(stream_item) = stream.next().await {
-                yield stream_item;
+    // Saves a newly measured lag model, replacing any with the same key
+    pub fn save_lag_model(&self, model: LagModel) -> Result<(), CorrectionError> {
+        let mut lag_models = self.lag_models.lock().unwrap();
+        let key = model.key.clone();
+        let previous = lag_models.insert(key.clone(), model);
+
+        if let Some(dirs) = &self.dirs {
+            if let Err(e) = write_lag_models(&dirs.lag_models, lag_models.values()) {
+                match previous {
+                    Some(previous) => lag_models.insert(key, previous),
+                    None => lag_models.remove(&key),
+                };
+                return Err(e);
             }
-        };
-
-        return Box::pin(new_stream);
+            info!("Saved lag model for {key} to {}", dirs.lag_models.display());
+        }
+        Ok(())
     }
-    */
 
-    pub fn generate_dark_maps<T: Runtime>(
+    // Saves a newly generated gain map and loads it, replacing any with the same key
+    pub fn save_gain_map(
         &self,
-        app: AppHandle<T>,
-        exp_times: Vec<u32>,
-        num_frames: u32,
-    ) {
-        self.info.lock().unwrap().status =
-            CaptureManagerStatus::Capturing(AdvancedCapture::DarkMapCapture(DarkMapCapture {
-                exp_times: vec![100, 200],
-                frames_per_capture: 10,
-            }));
-        self.emit_event(app.clone());
+        key: CorrectionMapKey,
+        gain_map: ImageBuffer<Luma<f32>, Vec<f32>>,
+        map_provenance: MapProvenance,
+    ) -> Result<(), CorrectionError> {
+        if let Some(dirs) = &self.dirs {
+            let path = dirs.gain_maps.join(key.file_name("GainMap"));
+            write_gain_map(&path, &gain_map)?;
+            map_provenance.write(&path)?;
+            info!("Saved gain map for {key} to {}", path.display());
+        }
 
-        let detector_controller = self.detector_controller.clone();
-        let dark_map_path = self.dark_map_path.clone();
-        let info = self.info.clone();
-        let correction_maps = self.correction_maps.clone();
+        self.gain_maps.lock().unwrap().insert(key.clone(), gain_map);
+        self.provenance
+            .lock()
+            .unwrap()
+            .gain_maps
+            .insert(key, map_provenance);
+        Ok(())
+    }
 
-        tauri::async_runtime::spawn(async move {
-            stream::iter(exp_times)
-                .then(|exp_time| {
-                    let dark_map_path: PathBuf = dark_map_path.clone();
-                    let mut detector_controller = detector_controller.clone();
-                    let dark_map_path = dark_map_path.clone();
-                    let correction_maps = correction_maps.clone();
-
-                    async move {
-                        let capture_settings = CaptureSettingBuilder::new(
-                            exp_time,
-                            Box::new(SequenceCapture { num_frames }),
-                        )
-                        .corrected(false)
-                        .build();
-
-                        let mut average_image = SLImageRs::new_depth(1536, 1031, num_frames);
-
-                        let mut enumerated_stream = detector_controller
-                            .run_capture_stream(capture_settings.clone(), correction_maps)
-                            .enumerate();
-
-                        while let Some((index, mut image)) = enumerated_stream.next().await {
-                            unsafe {
-                                std::ptr::copy_nonoverlapping(
-                                    image.get_data_pointer(0),
-                                    average_image.get_data_pointer(index as u32),
-                                    (image.get_width() * image.get_height() * 2) as usize,
-                                );
-                            }
-                        }
-
-                        average_image = average_image.get_average_image();
-
-                        average_image
-                            .write_tiff_image(
-                                &dark_map_path.join(format!("DarkMap_{exp_time}ms.tif")),
-                            )
-                            .unwrap();
-                    }
-                })
-                .collect::<Vec<_>>()
-                .await;
+    fn synthesize_dark_map(
+        dark_maps: &HashMap<CorrectionMapKey, CorrectionMap>,
+        key: &CorrectionMapKey,
+    ) -> Result<(CorrectionMap, DarkCorrectionMethod), CorrectionError> {
+        let same_settings: Vec<(u32, &CorrectionMap)> = dark_maps
+            .iter()
+            .filter(|(other, _)| other.matches_settings(key))
+            .map(|(other, dark_map)| (other.exp_time, dark_map))
+            .collect();
+
+        let model = match DarkModel::fit(&same_settings) {
+            Ok(model) => model,
+            Err(CorrectionError::InsufficientDarkMaps(_)) => {
+                return Err(Self::missing_dark_map(dark_maps, key))
+            }
+            Err(e) => return Err(e),
+        };
 
-            info.lock().unwrap().status = CaptureManagerStatus::Available;
-        });
+        let dark_map = model.synthesize(key.exp_time)?;
+        let method = model.correction_method(key);
+        info!(
+            "Synthesized a dark map for {key} from maps at {:?}ms: {method:?}",
+            model.exp_times()
+        );
+        Ok((dark_map, method))
     }
 
-    pub fn generate_defect_map<T: Runtime>(
-        &mut self,
-        app: AppHandle<T>,
-        dark_exp_times: Vec<u32>,
-        num_frames: u32,
-    ) {
-        self.info.lock().unwrap().status =
-            CaptureManagerStatus::Capturing(AdvancedCapture::DefectMapCapture(DefectMapCapture {
-                exp_times: vec![100, 200],
-                frames_per_capture: 10,
-            }));
+    // Points out maps at the same exposure captured with other settings, as that usually
+    // means the capture settings changed since the maps were generated
+    fn missing_dark_map(
+        dark_maps: &HashMap<CorrectionMapKey, ImageBuffer<Luma<u16>, Vec<u16>>>,
+        key: &CorrectionMapKey,
+    ) -> CorrectionError {
+        let same_exposure: Vec<CorrectionMapKey> = Self::sorted_keys(
+            dark_maps
+                .keys()
+                .filter(|other| other.exp_time == key.exp_time),
+        );
 
-        self.emit_event(app.clone());
+        if same_exposure.is_empty() {
+            CorrectionError::DarkMapNotFound(key.clone())
+        } else {
+            CorrectionError::DarkMapMismatch(key.clone(), same_exposure)
+        }
+    }
 
-        let defect_map_path = self.defect_map_path.clone();
-        let info = self.info.clone();
-        let correction_maps = self.correction_maps.clone();
-        let detector_controller = self.detector_controller.clone();
+    fn sorted_keys<'a>(keys: impl Iterator<Item = &'a CorrectionMapKey>) -> Vec<CorrectionMapKey> {
+        let mut keys: Vec<CorrectionMapKey> = keys.cloned().collect();
+        keys.sort_by_cached_key(|key| (key.exp_time, key.to_string()));
+        keys
+    }
+}
 
-        tauri::async_runtime::spawn(async move {
-            stream::iter(dark_exp_times)
-                .flat_map(|exp_time| {
-                    let full_well_modes = [
-                        FullWellModesRS {
-                            remote_ty: crate::wrapper::FullWellModes::High,
-                        },
-                        FullWellModesRS {
-                            remote_ty: crate::wrapper::FullWellModes::Low,
-                        },
-                    ];
-
-                    stream::iter(
-                        full_well_modes
-                            .into_iter()
-                            .map(move |full_well_mode| (exp_time, full_well_mode)),
-                    )
-                })
-                .then(|(exp_time, full_well_mode)| {
-                    let correction_maps = correction_maps.clone();
-                    let defect_map_path = defect_map_path.clone();
-                    let mut detector_controller = detector_controller.clone();
-
-                    async move {
-                        let capture_settings = CaptureSettingBuilder::new(
-                            exp_time,
-                            Box::new(SequenceCapture { num_frames }),
-                        )
-                        .corrected(false)
-                        .full_well(full_well_mode.clone())
-                        .build();
-
-                        let mut average_image = SLImageRs::new_depth(1536, 1031, num_frames);
-
-                        let mut enumerated_stream = detector_controller
-                            .run_capture_stream(capture_settings.clone(), correction_maps)
-                            .enumerate();
-
-                        while let Some((index, mut image)) = enumerated_stream.next().await {
-                            unsafe {
-                                std::ptr::copy_nonoverlapping(
-                                    image.get_data_pointer(0),
-                                    average_image.get_data_pointer(index as u32),
-                                    (image.get_width() * image.get_height() * 2) as usize,
-                                );
-                            }
-                        }
-
-                        let dir = defect_map_path.join(format!(
-                            "1510HS_1510_{exp_time}_Dark{full_well_mode}_Mean.tif"
-                        ));
-
-                        info!("Saving defect map gen image {}", dir.display());
-
-                        average_image.write_tiff_image(&dir);
-                    }
-                })
-                .collect::<Vec<_>>()
-                .await;
+pub struct CaptureManager {
+    detector_controller: DetectorController<Box<dyn DetectorBackend>>,
+    capture_abort_handle: Option<AbortHandle>,
+    pause_control: Option<PauseControl>,
+    info: Arc<Mutex<CaptureManagerInfo>>,
+    correction_maps: CorrectionMaps,
+    dark_map_path: PathBuf,
+    defect_map_path: PathBuf,
+    gain_map_path: PathBuf,
+}
 
-            let images_dir = app.path().app_local_data_dir().unwrap().join("DefectMap");
-            let exe_dir = app
-                .path()
-                .resource_dir()
-                .unwrap()
-                .join("resources\\DefectMapGeneration\\DefectMapGen.exe");
+impl CaptureManager {
+    pub fn new<T: Runtime>(app: AppHandle<T>) -> Self {
+        let local_data = app.path().app_local_data_dir().unwrap();
 
-            if let Ok(defect_map_path) = run_defect_map_gen(&images_dir, &exe_dir) {
-                let mut defect_map_image = SLImageRs::new(1, 1);
-                if defect_map_image.read_tiff_image(&defect_map_path).is_ok() {
-                    info!("Set new defect map");
-                }
-            } else {
-                error!("Failed to set new defect map");
+        let dark_map_path = local_data.join("DarkMaps");
+        let defect_map_path = local_data.join("DefectMap");
+        let gain_map_path = local_data.join("GainMaps");
+
+        for dir in [&dark_map_path, &defect_map_path, &gain_map_path] {
+            if let Err(e) = fs::create_dir_all(dir) {
+                error!("Failed to create {}: {e}", dir.display());
             }
+        }
 
-            info.lock().unwrap().status = CaptureManagerStatus::Available;
-        });
+        let dark_maps = read_dark_maps(&dark_map_path);
+        let defect_map_file = defect_map_path.join(DEFECT_MAP_FILE_NAME);
+        let defect_map = read_defect_map(&defect_map_file);
+        let gain_maps = read_gain_maps(&gain_map_path);
+        let lag_model_file = local_data.join(LAG_MODEL_FILE_NAME);
+        let lag_models = read_lag_models(&lag_model_file);
+
+        let provenance = CorrectionMapProvenance {
+            dark_maps: read_keyed_provenance(&dark_map_path, "DarkMap", &dark_maps),
+            gain_maps: read_keyed_provenance(&gain_map_path, "GainMap", &gain_maps),
+            defect_map: defect_map
+                .as_ref()
+                .map(|map| MapProvenance::read(&defect_map_file, map.content_id())),
+        };
+
+        let correction_maps = CorrectionMaps::new(dark_maps, defect_map, gain_maps)
+            .with_dirs(CorrectionMapDirs {
+                dark_maps: dark_map_path.clone(),
+                defect_map: defect_map_path.clone(),
+                gain_maps: gain_map_path.clone(),
+                lag_models: lag_model_file,
+            })
+            .with_lag_models(lag_models);
+        correction_maps.set_provenance(provenance);
+
+        let info = Arc::new(Mutex::new(CaptureManagerInfo {
+            status: CaptureManagerStatus::DetectorDisconnected,
+            detector_info: { None },
+        }));
+
+        let detector = create_detector_backend();
+        let detector_controller = DetectorController::new(
+            detector.clone(),
+            Self::create_detector_callback(
+                app.clone(),
+                correction_maps.clone(),
+                info.clone(),
+                detector,
+            ),
+        );
+
+        Self {
+            detector_controller,
+            capture_abort_handle: None,
+            pause_control: None,
+            info,
+            correction_maps,
+            dark_map_path,
+            defect_map_path,
+            gain_map_path,
+        }
     }
 
     fn create_detector_callback<T: Runtime>(
         app: AppHandle<T>,
         correction_maps: CorrectionMaps,
         info: Arc<Mutex<CaptureManagerInfo>>,
+        mut detector: Box<dyn DetectorBackend>,
     ) -> impl FnMut(DetectorStatus) {
         move |status| {
             let mut info = info.lock().unwrap();
             match status {
-                DetectorStatus::Available => {
-                    if matches!(info.status, CaptureManagerStatus::Capturing(_)) {
-                    } else {
-                        if correction_maps.get_dark_map_exp_times().len() == 0 {
-                            info.status = CaptureManagerStatus::DarkMapsRequired
-                        } else if !correction_maps.has_defect_map() {
-                            info.status = CaptureManagerStatus::DefectMapsRequired
-                        } else {
-                            info.status = CaptureManagerStatus::Available;
-                        }
-                    }
+                DetectorStatus::Available
+                    if !matches!(
+                        info.status,
+                        CaptureManagerStatus::Capturing(_) | CaptureManagerStatus::Paused(_)
+                    ) =>
+                {
+                    let identity = detector.identity().ok();
+                    info.status = Self::correction_status(&correction_maps, identity.as_ref());
                 }
                 DetectorStatus::Disconnected => {
                     info.status = CaptureManagerStatus::DetectorDisconnected;
@@ -412,39 +568,102 @@ impl CaptureManager {
                 _ => {}
             }
 
-            let mut exposure_times = correction_maps.get_dark_map_exp_times();
+            Self::emit_status(&app, &correction_maps, info.status.clone());
+        }
+    }
 
-            exposure_times.sort();
+    // Status of a connected, idle detector given the correction maps currently loaded
+    fn correction_status(
+        correction_maps: &CorrectionMaps,
+        detector: Option<&DetectorIdentity>,
+    ) -> CaptureManagerStatus {
+        if correction_maps.get_dark_map_exp_times().is_empty() {
+            return CaptureManagerStatus::DarkMapsRequired;
+        } else if !correction_maps.has_defect_map() {
+            return CaptureManagerStatus::DefectMapsRequired;
+        }
 
-            match CaptureManagerEvent(CaptureManagerEventPayload {
-                dark_maps: exposure_times,
-                status: info.status.clone(),
-            })
-            .emit_all(&app)
-            {
-                Err(e) => error!("Error when emitting capture manager event {e}"),
-                _ => {}
+        let stale_maps = correction_maps.stale_maps(detector);
+        if !stale_maps.is_empty() {
+            CaptureManagerStatus::CalibrationStale(stale_maps)
+        } else if detector.map_or(false, |detector| !detector.is_verifiable()) {
+            CaptureManagerStatus::CalibrationUnverified
+        } else {
+            CaptureManagerStatus::Available
+        }
+    }
+
+    // Re-evaluates the status once new maps are loaded, unless the detector was disconnected
+    // in the meantime, and lets the frontend know about the new maps
+    fn reevaluate_status<T: Runtime>(
+        app: &AppHandle<T>,
+        correction_maps: &CorrectionMaps,
+        info: &Arc<Mutex<CaptureManagerInfo>>,
+        detector: Option<&DetectorIdentity>,
+    ) {
+        let status = {
+            let mut info = info.lock().unwrap();
+            if info.status != CaptureManagerStatus::DetectorDisconnected {
+                info.status = Self::correction_status(correction_maps, detector);
             }
+            info.status.clone()
+        };
+        Self::emit_status(app, correction_maps, status);
+    }
+
+    fn emit_status<T: Runtime>(
+        app: &AppHandle<T>,
+        correction_maps: &CorrectionMaps,
+        status: CaptureManagerStatus,
+    ) {
+        if let Err(e) = CaptureManagerEvent(CaptureManagerEventPayload {
+            dark_maps: correction_maps.get_dark_map_exp_times(),
+            dark_map_keys: correction_maps.get_dark_map_keys(),
+            gain_maps: correction_maps.get_gain_map_keys(),
+            status,
+        })
+        .emit_all(app)
+        {
+            error!("Error when emitting capture manager event {e}");
         }
     }
 
-    // Tell the capture manager the stream has concluded, and make available again
-    fn wrap_stream<S, T>(
-        input_stream: S,
+    // Ends the stream with the capture's unfinished result and a Cancelled item if the capture
+    // was aborted, and restores the status once the stream is done with, however that happens
+    fn wrap_stream<S, R: Runtime>(
+        input_stream: Abortable<S>,
+        results: CaptureResults,
+        app: AppHandle<R>,
         info: Arc<Mutex<CaptureManagerInfo>>,
-    ) -> impl Stream<Item = T>
+        correction_maps: CorrectionMaps,
+        detector_controller: DetectorController<Box<dyn DetectorBackend>>,
+    ) -> impl Stream<Item = CaptureStreamItem>
     where
-        S: Stream<Item = T> + Unpin,
-        T: Unpin,
+        S: Stream<Item = CaptureStreamItem> + Unpin,
     {
-        let c = stream! {
+        let status_guard = CaptureStatusGuard {
+            app,
+            info,
+            correction_maps,
+            detector_controller,
+        };
+
+        stream! {
+            // Declared first so it's dropped after the capture's streams, which take the
+            // detector out of live mode
+            let _status_guard = status_guard;
             pin_mut!(input_stream);
             while let Some(item) = input_stream.next().await {
                 yield item;
             }
-            info.lock().unwrap().status = CaptureManagerStatus::Available;
-        };
-        return c;
+            if input_stream.is_aborted() {
+                let partial_result = results.take();
+                if !partial_result.is_empty() {
+                    yield CaptureStreamItem::CaptureResult(partial_result);
+                }
+                yield CaptureStreamItem::Cancelled;
+            }
+        }
     }
 
     pub fn start_capture<T: Runtime>(
@@ -452,57 +671,310 @@ impl CaptureManager {
         app: AppHandle<T>,
         capture: AdvancedCapture,
     ) -> Result<impl Stream<Item = CaptureStreamItem>, CaptureError> {
-        if self.info.lock().unwrap().status != CaptureManagerStatus::Available {
-            return Err(CaptureError::DetectorDisconnected);
+        match self.info.lock().unwrap().status {
+            CaptureManagerStatus::Available
+            | CaptureManagerStatus::CalibrationStale(_)
+            | CaptureManagerStatus::CalibrationUnverified => {}
+            CaptureManagerStatus::DarkMapsRequired | CaptureManagerStatus::DefectMapsRequired
+                if capture.builds_calibration() => {}
+            _ => return Err(CaptureError::DetectorDisconnected),
         }
 
         self.info.lock().unwrap().status = CaptureManagerStatus::Capturing(capture.clone());
         self.emit_event(app.clone());
 
-        let (progress_tx, mut progress_rx) = mpsc::channel();
+        let pause_control = self.create_pause_control(app.clone(), capture.clone());
+        let results = CaptureResults::default();
 
         let (abortable_stream, abort_handle) = abortable(capture.start_stream(
             self.detector_controller.clone(),
             &self.correction_maps,
-            progress_tx,
+            pause_control.clone(),
+            results.clone(),
         ));
 
         self.capture_abort_handle = Some(abort_handle);
-
-        Ok(Self::wrap_stream(abortable_stream, self.info.clone()))
+        self.pause_control = Some(pause_control);
+
+        Ok(Self::wrap_stream(
+            abortable_stream,
+            results,
+            app,
+            self.info.clone(),
+            self.correction_maps.clone(),
+            self.detector_controller.clone(),
+        ))
     }
 
     pub fn stop_capture(&mut self) {
-        if let Some(capture_abort_handle) = &self.capture_abort_handle {
+        self.pause_control = None;
+        if let Some(capture_abort_handle) = self.capture_abort_handle.take() {
             capture_abort_handle.abort();
             self.detector_controller.stop_capture();
         }
     }
 
-    fn emit_event<T: Runtime>(&self, app: AppHandle<T>) {
-        CaptureManagerEvent(CaptureManagerEventPayload {
-            dark_maps: self.correction_maps.get_dark_map_exp_times(),
-            status: self.info.lock().unwrap().status.clone(),
+    // The capture carries on to the end of the current frame before pausing
+    pub fn pause_capture(&self) -> Result<(), CaptureError> {
+        match &self.info.lock().unwrap().status {
+            CaptureManagerStatus::Capturing(capture) if capture.can_pause() => {}
+            CaptureManagerStatus::Capturing(_) => return Err(CaptureError::PauseUnsupported),
+            CaptureManagerStatus::Paused(_) => return Ok(()),
+            _ => return Err(CaptureError::NotCapturing),
+        }
+
+        info!("Pausing capture");
+        self.pause_control
+            .as_ref()
+            .ok_or(CaptureError::NotCapturing)?
+            .pause();
+        Ok(())
+    }
+
+    pub fn resume_capture(&self) -> Result<(), CaptureError> {
+        if !matches!(
+            self.info.lock().unwrap().status,
+            CaptureManagerStatus::Capturing(_) | CaptureManagerStatus::Paused(_)
+        ) {
+            return Err(CaptureError::NotCapturing);
+        }
+        let pause_control = self
+            .pause_control
+            .as_ref()
+            .ok_or(CaptureError::NotCapturing)?;
+
+        info!("Resuming capture");
+        pause_control.resume();
+        Ok(())
+    }
+
+    // Moves the status between capturing and paused as the capture reaches a pause and resumes
+    fn create_pause_control<T: Runtime>(
+        &self,
+        app: AppHandle<T>,
+        capture: AdvancedCapture,
+    ) -> PauseControl {
+        let info = self.info.clone();
+        let correction_maps = self.correction_maps.clone();
+
+        PauseControl::new(move |paused| {
+            let status = {
+                let mut info = info.lock().unwrap();
+                if info.status == CaptureManagerStatus::DetectorDisconnected {
+                    return;
+                }
+                info.status = if paused {
+                    CaptureManagerStatus::Paused(capture.clone())
+                } else {
+                    CaptureManagerStatus::Capturing(capture.clone())
+                };
+                info.status.clone()
+            };
+            Self::emit_status(&app, &correction_maps, status);
         })
-        .emit_all(&app)
-        .unwrap();
+    }
+
+    pub fn set_correction_settings(&self, settings: CorrectionSettings) {
+        self.correction_maps.set_settings(settings);
+    }
+
+    pub fn status(&self) -> CaptureManagerStatus {
+        self.info.lock().unwrap().status.clone()
+    }
+
+    pub fn dark_map_exp_times(&self) -> Vec<u32> {
+        self.correction_maps.get_dark_map_exp_times()
+    }
+
+    pub fn export_calibration(&mut self, path: &Path) -> Result<(), CaptureError> {
+        let detector = self
+            .detector_controller
+            .detector_identity()
+            .ok_or(CaptureError::DetectorDisconnected)?;
+        self.correction_maps.to_bundle(detector).write(path)?;
+        Ok(())
+    }
+
+    // Replaces every correction map with those from a bundle exported on the connected detector
+    pub fn import_calibration<T: Runtime>(
+        &mut self,
+        app: AppHandle<T>,
+        path: &Path,
+    ) -> Result<(), CaptureError> {
+        if matches!(
+            self.info.lock().unwrap().status,
+            CaptureManagerStatus::Capturing(_) | CaptureManagerStatus::Paused(_)
+        ) {
+            return Err(CaptureError::DetectorInUse);
+        }
+
+        let detector = self
+            .detector_controller
+            .detector_identity()
+            .ok_or(CaptureError::DetectorDisconnected)?;
+
+        let bundle = CalibrationBundle::read(path)?;
+        if bundle.validate(&detector)? == DetectorCheck::Unverifiable {
+            warn!("{detector} has no serial number, so the bundle can't be confirmed to be for it");
+        }
+        self.write_calibration(&bundle)?;
+
+        info!(
+            "Imported {} dark maps, {} gain maps and {} defect map for {detector}",
+            bundle.dark_maps.len(),
+            bundle.gain_maps.len(),
+            if bundle.defect_map.is_some() {
+                "a"
+            } else {
+                "no"
+            }
+        );
+        self.correction_maps.replace_all(bundle);
+        Self::reevaluate_status(&app, &self.correction_maps, &self.info, Some(&detector));
+        Ok(())
+    }
+
+    // Writes the bundle's maps to staging directories and only swaps them in once every map
+    // is written, so a failed import leaves the existing calibration alone
+    fn write_calibration(&self, bundle: &CalibrationBundle) -> Result<(), CorrectionError> {
+        let write_failed = |path: &Path, e: &dyn std::fmt::Display| {
+            CorrectionError::WriteFailed(format!("{}: {e}", path.display()))
+        };
+
+        let map_dirs = [
+            &self.dark_map_path,
+            &self.gain_map_path,
+            &self.defect_map_path,
+        ];
+        for dir in map_dirs {
+            let staged = staged_dir(dir);
+            let _ = fs::remove_dir_all(&staged);
+            fs::create_dir_all(&staged).map_err(|e| write_failed(&staged, &e))?;
+        }
+
+        for (key, map, provenance) in &bundle.dark_maps {
+            let path = staged_dir(&self.dark_map_path).join(key.file_name("DarkMap"));
+            map.save(&path).map_err(|e| write_failed(&path, &e))?;
+            provenance.write(&path)?;
+        }
+        for (key, map, provenance) in &bundle.gain_maps {
+            let path = staged_dir(&self.gain_map_path).join(key.file_name("GainMap"));
+            write_gain_map(&path, map)?;
+            provenance.write(&path)?;
+        }
+        if let Some((map, provenance)) = &bundle.defect_map {
+            let path = staged_dir(&self.defect_map_path).join(DEFECT_MAP_FILE_NAME);
+            map.save(&path).map_err(|e| write_failed(&path, &e))?;
+            provenance.write(&path)?;
+        }
+
+        swap_in_staged_dirs(&map_dirs)
+    }
+
+    pub fn start_recording(&self, path: &Path) -> Result<(), CaptureError> {
+        self.detector_controller
+            .start_recording(path)
+            .map_err(|e| CaptureError::FileError(e.to_string()))
+    }
+
+    pub fn stop_recording(&self) -> Result<(), CaptureError> {
+        self.detector_controller
+            .stop_recording()
+            .map_err(|e| CaptureError::FileError(e.to_string()))
+    }
+
+    fn emit_event<T: Runtime>(&self, app: AppHandle<T>) {
+        let status = self.info.lock().unwrap().status.clone();
+        Self::emit_status(&app, &self.correction_maps, status);
+    }
+}
+
+// Tells the capture manager a capture has concluded and makes it available again, or asks for
+// whichever maps are still missing if it was a calibration capture. Runs on drop so the status
+// is restored even when the capture stream is aborted or dropped before it finishes.
+struct CaptureStatusGuard<R: Runtime> {
+    app: AppHandle<R>,
+    info: Arc<Mutex<CaptureManagerInfo>>,
+    correction_maps: CorrectionMaps,
+    detector_controller: DetectorController<Box<dyn DetectorBackend>>,
+}
+
+impl<R: Runtime> Drop for CaptureStatusGuard<R> {
+    fn drop(&mut self) {
+        let detector = self.detector_controller.detector_identity();
+        CaptureManager::reevaluate_status(
+            &self.app,
+            &self.correction_maps,
+            &self.info,
+            detector.as_ref(),
+        );
+    }
+}
+
+// Maps cover the whole binned frame, so a capture of a region is corrected with the same
+// region of the map. Maps that don't contain the region are left for the dimension check.
+fn crop_to_roi<'a, P: Pixel + 'static>(
+    map: &'a ImageBuffer<P, Vec<P::Subpixel>>,
+    roi: Option<&Roi>,
+) -> Cow<'a, ImageBuffer<P, Vec<P::Subpixel>>> {
+    match roi {
+        Some(roi)
+            if map.dimensions() != (roi.width, roi.height)
+                && roi.x + roi.width <= map.width()
+                && roi.y + roi.height <= map.height() =>
+        {
+            Cow::Owned(imageops::crop_imm(map, roi.x, roi.y, roi.width, roi.height).to_image())
+        }
+        _ => Cow::Borrowed(map),
     }
 }
 
-pub fn read_dark_maps(path: &PathBuf) -> HashMap<u32, SLImageRs> {
+pub fn read_dark_maps(
+    path: &PathBuf,
+) -> HashMap<CorrectionMapKey, ImageBuffer<Luma<u16>, Vec<u16>>> {
     info!("Looking for dark map resources at {}", path.display());
 
-    let mut dark_maps = HashMap::new();
-    let regex = Regex::new(r"DarkMap_(\d+)ms\.tif").expect("Failed to compile regex");
+    find_keyed_maps(path, "DarkMap")
+        .into_iter()
+        .filter_map(|(key, map_path)| match read_map(&map_path) {
+            Ok(image) => Some((key, image)),
+            Err(err) => {
+                error!("Failed to read dark map: {:?}", err);
+                None
+            }
+        })
+        .collect()
+}
+
+pub fn read_gain_maps(
+    path: &PathBuf,
+) -> HashMap<CorrectionMapKey, ImageBuffer<Luma<f32>, Vec<f32>>> {
+    info!("Looking for gain map resources at {}", path.display());
+
+    find_keyed_maps(path, "GainMap")
+        .into_iter()
+        .filter_map(|(key, map_path)| match read_gain_map(&map_path) {
+            Ok(image) => Some((key, image)),
+            Err(err) => {
+                error!("Failed to read gain map: {err}");
+                None
+            }
+        })
+        .collect()
+}
 
+// Finds maps in a directory named with `CorrectionMapKey::file_name`. Maps with a legacy
+// exposure-only name come first so a properly keyed map for the same settings replaces them.
+fn find_keyed_maps(path: &PathBuf, prefix: &str) -> Vec<(CorrectionMapKey, PathBuf)> {
     let paths = match fs::read_dir(path) {
         Ok(paths) => paths,
         Err(err) => {
             error!("Failed to read directory: {:?}", err);
-            return dark_maps;
+            return Vec::new();
         }
     };
 
+    let mut maps = Vec::new();
     for path in paths.filter_map(Result::ok) {
         let file_name = path.file_name();
         let file_name = match file_name.to_str() {
@@ -510,41 +982,126 @@ pub fn read_dark_maps(path: &PathBuf) -> HashMap<u32, SLImageRs> {
             None => continue,
         };
 
-        if let Some(captures) = regex.captures(file_name) {
-            if let Some(dark_map_exp_str) = captures.get(1) {
-                match dark_map_exp_str.as_str().parse::<u32>() {
-                    Ok(exp_time) => {
-                        info!(
-                            "Found dark map with exp time {} at {}",
-                            exp_time,
-                            path.path().display()
-                        );
-                        let mut image = SLImageRs::new(1536, 1031);
-                        image.read_tiff_image(&path.path());
-                        dark_maps.insert(exp_time, image);
-                    }
-                    Err(err) => error!("Failed to parse exposure time: {:?}", err),
+        if let Some(key) = CorrectionMapKey::parse_file_name(prefix, file_name) {
+            let legacy = file_name != key.file_name(prefix);
+            if legacy {
+                info!("Treating legacy {} as {}", file_name, key);
+            }
+            info!("Found {} for {} at {}", prefix, key, path.path().display());
+            maps.push((legacy, key, path.path()));
+        }
+    }
+
+    maps.sort_by_key(|(legacy, _, _)| !legacy);
+    maps.into_iter().map(|(_, key, path)| (key, path)).collect()
+}
+
+// Where an imported copy of a map directory is written before it's swapped in
+fn staged_dir(dir: &Path) -> PathBuf {
+    dir.with_extension("import")
+}
+
+// Moves each directory's staged copy in place of it. If any move fails, the directories
+// already swapped get their previous contents back, so either every directory is replaced or
+// none are.
+fn swap_in_staged_dirs(dirs: &[&PathBuf]) -> Result<(), CorrectionError> {
+    let write_failed = |path: &Path, e: &dyn std::fmt::Display| {
+        CorrectionError::WriteFailed(format!("{}: {e}", path.display()))
+    };
+
+    // Each directory moved so far, along with where its previous contents went if it existed
+    let mut swapped: Vec<(&PathBuf, Option<PathBuf>)> = Vec::new();
+    let mut result = Ok(());
+    for &dir in dirs {
+        let previous = dir.with_extension("previous");
+        let _ = fs::remove_dir_all(&previous);
+
+        let moved = match dir.exists() {
+            true => match fs::rename(dir, &previous) {
+                Ok(()) => Some(previous),
+                Err(e) => {
+                    result = Err(write_failed(dir, &e));
+                    break;
                 }
+            },
+            false => None,
+        };
+        swapped.push((dir, moved));
+
+        if let Err(e) = fs::rename(staged_dir(dir), dir) {
+            result = Err(write_failed(dir, &e));
+            break;
+        }
+    }
+
+    for (dir, previous) in swapped.into_iter().rev() {
+        if result.is_ok() {
+            if let Some(previous) = previous {
+                let _ = fs::remove_dir_all(previous);
             }
+            continue;
+        }
+
+        let _ = fs::remove_dir_all(dir);
+        if let Some(previous) = previous {
+            if let Err(e) = fs::rename(&previous, dir) {
+                error!(
+                    "Failed to restore {} from {}: {e}",
+                    dir.display(),
+                    previous.display()
+                );
+            }
+        }
+    }
+
+    if result.is_err() {
+        for &dir in dirs {
+            let _ = fs::remove_dir_all(staged_dir(dir));
         }
     }
+    result
+}
 
-    dark_maps
+// Provenance for maps loaded by read_dark_maps or read_gain_maps, found the same way so
+// legacy and keyed maps resolve to the same file
+fn read_keyed_provenance<M: MapContent>(
+    path: &PathBuf,
+    prefix: &str,
+    maps: &HashMap<CorrectionMapKey, M>,
+) -> HashMap<CorrectionMapKey, MapProvenance> {
+    find_keyed_maps(path, prefix)
+        .into_iter()
+        .filter_map(|(key, map_path)| {
+            let map = maps.get(&key)?;
+            Some((key, MapProvenance::read(&map_path, map.content_id())))
+        })
+        .collect()
 }
 
-fn read_defect_map(path: &PathBuf) -> Option<SLImageRs> {
+fn read_defect_map(path: &PathBuf) -> Option<ImageBuffer<Luma<u16>, Vec<u16>>> {
     info!("Looking for defect map resources at {}", path.display());
     if path.exists() {
-        let mut image = SLImageRs::new(1, 1);
-        image.read_tiff_image(&path);
-        info!("Found defect map at {}", path.display());
-        Some(image)
+        match read_map(path) {
+            Ok(image) => {
+                info!("Found defect map at {}", path.display());
+                Some(image)
+            }
+            Err(err) => {
+                error!("Failed to read defect map: {:?}", err);
+                None
+            }
+        }
     } else {
         None
     }
 }
 
-#[cfg(test)]
+fn read_map(path: &PathBuf) -> Result<ImageBuffer<Luma<u16>, Vec<u16>>, image::ImageError> {
+    Ok(image::io::Reader::open(path)?.decode()?.to_luma16())
+}
+
+// These drive the detector CaptureManager opens, so need a calibrated panel connected
+#[cfg(all(test, feature = "spectrum-logic"))]
 mod tests {
     use std::{
         sync::{Arc, Mutex},
@@ -587,6 +1144,7 @@ mod tests {
             frames_per_capture: 10,
             median_filtered: false,
             window_size: 5,
+            corrections: Default::default(),
         });
 
         let stream = capture_manager
@@ -610,6 +1168,7 @@ mod tests {
             0: MultiCapture {
                 exp_times: vec![100, 200],
                 frames_per_capture: 10,
+                corrections: Default::default(),
             },
         };
 
@@ -630,6 +1189,7 @@ mod tests {
                 0: MultiCapture {
                     exp_times: vec![100, 200],
                     frames_per_capture: 10,
+                    corrections: Default::default(),
                 },
             };
             assert!(capture_manager_clone
@@ -642,3 +1202,82 @@ mod tests {
         pin_mut!(stream);
     }
 }
+
+// Tests that don't need a detector
+#[cfg(test)]
+mod calibration_tests {
+    use std::{collections::HashMap, fs};
+
+    use image::ImageBuffer;
+
+    use super::{staged_dir, swap_in_staged_dirs};
+    use crate::capture::{
+        backend::DetectorIdentity,
+        capture::{CaptureSettingBuilder, SequenceCapture},
+        capture_manager::{CaptureManager, CorrectionMaps},
+        corrections::CorrectionMapKey,
+        types::CaptureManagerStatus,
+    };
+
+    #[test]
+    fn detectors_without_serials_leave_calibration_unverified() {
+        let key = CorrectionMapKey::from_capture_setting(
+            &CaptureSettingBuilder::new(100, Box::new(SequenceCapture { num_frames: 1 })).build(),
+        );
+        let correction_maps = CorrectionMaps::new(
+            HashMap::from([(key, ImageBuffer::new(4, 2))]),
+            Some(ImageBuffer::new(4, 2)),
+            HashMap::new(),
+        );
+        let mut detector = DetectorIdentity {
+            model: "Spectrum Logic USB".to_string(),
+            serial: None,
+            width: 4,
+            height: 2,
+        };
+
+        assert_eq!(
+            CaptureManager::correction_status(&correction_maps, Some(&detector)),
+            CaptureManagerStatus::CalibrationUnverified
+        );
+
+        detector.serial = Some("1234".to_string());
+        assert_eq!(
+            CaptureManager::correction_status(&correction_maps, Some(&detector)),
+            CaptureManagerStatus::Available
+        );
+    }
+
+    #[test]
+    fn failed_swap_restores_every_moved_dir() {
+        let base = std::env::temp_dir().join(format!("cview_swap_test_{}", std::process::id()));
+        let dirs: Vec<_> = ["DarkMaps", "GainMaps", "DefectMap"]
+            .iter()
+            .map(|name| base.join(name))
+            .collect();
+        for dir in &dirs {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(dir.join("map"), "previous").unwrap();
+        }
+        // The last directory has nothing staged, so its move fails after the others were made
+        for dir in &dirs[..2] {
+            fs::create_dir_all(staged_dir(dir)).unwrap();
+            fs::write(staged_dir(dir).join("map"), "imported").unwrap();
+        }
+
+        let result = swap_in_staged_dirs(&dirs.iter().collect::<Vec<_>>());
+
+        let contents: Vec<_> = dirs
+            .iter()
+            .map(|dir| fs::read_to_string(dir.join("map")).unwrap())
+            .collect();
+        let leftovers = dirs
+            .iter()
+            .any(|dir| staged_dir(dir).exists() || dir.with_extension("previous").exists());
+        fs::remove_dir_all(&base).unwrap();
+
+        assert!(result.is_err());
+        assert_eq!(contents, vec!["previous"; 3]);
+        assert!(!leftovers);
+    }
+}
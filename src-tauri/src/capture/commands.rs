@@ -1,3 +1,6 @@
+use crate::capture::types::CaptureFailedEvent;
+use crate::capture::types::CaptureFailure;
+use crate::capture::types::CaptureOutcome;
 use crate::capture::types::CaptureProgressEvent;
 use crate::capture::types::CaptureStreamItem;
 use crate::events::StreamCaptureEvent;
@@ -11,6 +14,7 @@ use futures_util::{pin_mut, StreamExt};
 use log::debug;
 use log::error;
 use log::info;
+use std::path::Path;
 use std::sync::Mutex;
 use tauri::ipc::Response;
 use tauri::AppHandle;
@@ -18,8 +22,15 @@ use tauri::Manager;
 use tauri::State;
 use tauri_specta::Event;
 
+use super::advanced_capture::{DarkMapCapture, DefectMapCapture, FlatFieldCapture};
 use super::capture::CaptureError;
 use super::capture_manager::CaptureManager;
+use super::corrections::CorrectionSettings;
+use super::defect_map::DefectMapSettings;
+use super::job_queue::{
+    emit_job, CaptureJob, JobQueue, JobRequest, JobState, RecalibrationSettings,
+};
+use super::stack_reduction::StackReductionSettings;
 use super::types::AdvancedCapture;
 
 #[tauri::command(async)]
@@ -31,8 +42,30 @@ pub async fn run_capture(
     capture_manager_mutex: State<'_, Mutex<CaptureManager>>,
     capture: AdvancedCapture,
     save_capture: bool,
-) -> Result<(), CaptureError> {
+) -> Result<CaptureOutcome, CaptureError> {
+    execute_capture(
+        &app,
+        &image_service_mutex,
+        &stream_buffer_mutex,
+        &capture_manager_mutex,
+        capture,
+        save_capture,
+    )
+    .await
+}
+
+// Runs a capture through to its image stack, for both the frontend and the job scheduler
+pub async fn execute_capture(
+    app: &AppHandle,
+    image_service_mutex: &Mutex<ImageService>,
+    stream_buffer_mutex: &Mutex<StreamBuffer>,
+    capture_manager_mutex: &Mutex<CaptureManager>,
+    capture: AdvancedCapture,
+    save_capture: bool,
+) -> Result<CaptureOutcome, CaptureError> {
     let mut capture_result = None;
+    let mut failure = None;
+    let mut cancelled = false;
 
     let stream = capture_manager_mutex
         .lock()
@@ -45,28 +78,47 @@ pub async fn run_capture(
         match stream_item {
             CaptureStreamItem::Image(image_handler) => {
                 let stream_buffer = stream_buffer_mutex.lock().unwrap();
-                match stream_buffer.q.push(image_handler) {
-                    Err(e) => error!("Failed to push to stream buffer with e {e}"),
-                    _ => {}
+                if let Err(e) = stream_buffer.q.push(image_handler) {
+                    error!("Failed to push to stream buffer with e {e}")
                 }
-                match StreamCaptureEvent().emit_all(&app) {
-                    Err(e) => error!("Failed to stream capture event event with error {e}"),
-                    _ => {}
+                if let Err(e) = StreamCaptureEvent().emit_all(app) {
+                    error!("Failed to stream capture event event with error {e}")
                 }
             }
             CaptureStreamItem::CaptureResult(vec) => {
                 capture_result = Some(vec);
             }
             CaptureStreamItem::Progress(progress) => {
-                info!("got progress event");
-                match CaptureProgressEvent(progress).emit_all(&app) {
-                    Err(e) => error!("Failed to emit capture progress event with error {e}"),
-                    _ => {}
+                if let Err(e) = CaptureProgressEvent(progress).emit_all(app) {
+                    error!("Failed to emit capture progress event with error {e}")
+                }
+            }
+            CaptureStreamItem::Failed(e) => {
+                error!("Capture failed: {e}");
+                if let Err(e) = CaptureFailedEvent(CaptureFailure::new(capture.clone(), e.clone()))
+                    .emit_all(app)
+                {
+                    error!("Failed to emit capture failed event with error {e}")
                 }
+                failure = Some(e);
             }
+            CaptureStreamItem::Cancelled => cancelled = true,
         }
     }
 
+    if let Some(e) = failure {
+        return Err(e);
+    }
+
+    // A cancelled capture's result holds whatever it had finished before it was stopped
+    let outcome = if cancelled {
+        let partial_frames = capture_result.as_ref().map_or(0, |result| result.len()) as u32;
+        info!("Capture cancelled with {partial_frames} result images");
+        CaptureOutcome::Cancelled { partial_frames }
+    } else {
+        CaptureOutcome::Completed
+    };
+
     if let Some(capture_result) = capture_result {
         let timestamp = Utc::now();
 
@@ -78,10 +130,9 @@ pub async fn run_capture(
 
         if save_capture {
             let local_data = app.path().app_local_data_dir().unwrap();
-            let save_dir = local_data.join(format!(
-                "Captures\\{}.tiff",
-                datetime_to_filename(timestamp)
-            ));
+            let save_dir = local_data
+                .join("Captures")
+                .join(format!("{}.tiff", datetime_to_filename(timestamp)));
             image_stack.save(save_dir);
         }
 
@@ -91,11 +142,7 @@ pub async fn run_capture(
             .add_image_stack(image_stack);
     }
 
-    if (save_capture) {
-        if let Ok(local_data_dir) = app.path().local_data_dir() {}
-    }
-
-    Ok(())
+    Ok(outcome)
 }
 
 #[tauri::command(async)]
@@ -111,11 +158,27 @@ pub fn stop_capture(
     stream_buffer_mutex.lock().unwrap().clear();
 }
 
+#[tauri::command(async)]
+#[specta::specta]
+pub fn pause_capture(
+    capture_manager_mutex: State<Mutex<CaptureManager>>,
+) -> Result<(), CaptureError> {
+    capture_manager_mutex.lock().unwrap().pause_capture()
+}
+
+#[tauri::command(async)]
+#[specta::specta]
+pub fn resume_capture(
+    capture_manager_mutex: State<Mutex<CaptureManager>>,
+) -> Result<(), CaptureError> {
+    capture_manager_mutex.lock().unwrap().resume_capture()
+}
+
 #[tauri::command(async)]
 pub fn read_stream_buffer(
     stream_buffer_mutex: State<Mutex<StreamBuffer>>,
     saturated_pixel_threshold: Option<u32>,
-    saturated_pixel_RGB_colour: Option<String>,
+    saturated_pixel_rgb_colour: Option<String>,
 ) -> Response {
     debug!("Reading stream buffer");
     let stream_buffer = stream_buffer_mutex.lock().unwrap();
@@ -124,7 +187,7 @@ pub fn read_stream_buffer(
         return_data.extend_from_slice(&image_handler.image.width().to_le_bytes());
         return_data.extend_from_slice(&image_handler.image.height().to_le_bytes());
 
-        if let Some(rgb) = saturated_pixel_RGB_colour {
+        if let Some(rgb) = saturated_pixel_rgb_colour {
             let rgb = parse_rgb(&rgb);
             return_data.append(&mut image_handler.get_rgba_image(
                 saturated_pixel_threshold,
@@ -147,28 +210,211 @@ pub fn read_stream_buffer(
 #[specta::specta]
 pub async fn generate_defect_map(
     app: AppHandle,
+    image_service_mutex: State<'_, Mutex<ImageService>>,
+    stream_buffer_mutex: State<'_, Mutex<StreamBuffer>>,
     capture_manager_mutex: State<'_, Mutex<CaptureManager>>,
-) -> Result<(), ()> {
+    exp_times: Vec<u32>,
+    frames_per_capture: u32,
+    settings: Option<DefectMapSettings>,
+) -> Result<(), CaptureError> {
     info!("Generating Defect Maps");
-    capture_manager_mutex.lock().unwrap().generate_defect_map(
+    let capture = AdvancedCapture::DefectMapCapture(DefectMapCapture {
+        exp_times,
+        frames_per_capture,
+        settings: settings.unwrap_or_default(),
+    });
+    run_capture(
         app,
-        vec![100, 150, 200, 250, 300],
-        10,
-    );
-    Ok(())
+        image_service_mutex,
+        stream_buffer_mutex,
+        capture_manager_mutex,
+        capture,
+        false,
+    )
+    .await
+    .map(|_| ())
 }
 
 #[tauri::command(async)]
 #[specta::specta]
 pub async fn generate_dark_maps(
     app: AppHandle,
+    image_service_mutex: State<'_, Mutex<ImageService>>,
+    stream_buffer_mutex: State<'_, Mutex<StreamBuffer>>,
     capture_manager_mutex: State<'_, Mutex<CaptureManager>>,
+    exp_times: Vec<u32>,
+    frames_per_capture: u32,
+    stack_reduction: Option<StackReductionSettings>,
 ) -> Result<(), CaptureError> {
     info!("Generating Dark Maps");
-    capture_manager_mutex.lock().unwrap().generate_dark_maps(
+    let capture = AdvancedCapture::DarkMapCapture(DarkMapCapture {
+        exp_times,
+        frames_per_capture,
+        stack_reduction: stack_reduction.unwrap_or_default(),
+    });
+    run_capture(
+        app,
+        image_service_mutex,
+        stream_buffer_mutex,
+        capture_manager_mutex,
+        capture,
+        false,
+    )
+    .await
+    .map(|_| ())
+}
+
+#[tauri::command(async)]
+#[specta::specta]
+pub async fn generate_gain_maps(
+    app: AppHandle,
+    image_service_mutex: State<'_, Mutex<ImageService>>,
+    stream_buffer_mutex: State<'_, Mutex<StreamBuffer>>,
+    capture_manager_mutex: State<'_, Mutex<CaptureManager>>,
+    exp_times: Vec<u32>,
+    frames_per_capture: u32,
+) -> Result<(), CaptureError> {
+    info!("Generating Gain Maps");
+    let capture = AdvancedCapture::FlatFieldCapture(FlatFieldCapture {
+        exp_times,
+        frames_per_capture,
+    });
+    run_capture(
         app,
-        vec![100, 150, 200, 250, 300],
-        10,
-    );
+        image_service_mutex,
+        stream_buffer_mutex,
+        capture_manager_mutex,
+        capture,
+        false,
+    )
+    .await
+    .map(|_| ())
+}
+
+#[tauri::command(async)]
+#[specta::specta]
+pub fn set_correction_settings(
+    capture_manager_mutex: State<'_, Mutex<CaptureManager>>,
+    settings: CorrectionSettings,
+) {
+    info!("Updating correction settings {:?}", settings);
+    capture_manager_mutex
+        .lock()
+        .unwrap()
+        .set_correction_settings(settings);
+}
+
+#[tauri::command(async)]
+#[specta::specta]
+pub fn export_calibration(
+    capture_manager_mutex: State<'_, Mutex<CaptureManager>>,
+    path: String,
+) -> Result<(), CaptureError> {
+    info!("Exporting calibration bundle to {path}");
+    capture_manager_mutex
+        .lock()
+        .unwrap()
+        .export_calibration(Path::new(&path))
+}
+
+#[tauri::command(async)]
+#[specta::specta]
+pub fn import_calibration(
+    app: AppHandle,
+    capture_manager_mutex: State<'_, Mutex<CaptureManager>>,
+    path: String,
+) -> Result<(), CaptureError> {
+    info!("Importing calibration bundle from {path}");
+    capture_manager_mutex
+        .lock()
+        .unwrap()
+        .import_calibration(app, Path::new(&path))
+}
+
+#[tauri::command(async)]
+#[specta::specta]
+pub fn start_session_recording(
+    app: AppHandle,
+    capture_manager_mutex: State<'_, Mutex<CaptureManager>>,
+) -> Result<String, CaptureError> {
+    let recordings_dir = app.path().app_local_data_dir().unwrap().join("Recordings");
+    std::fs::create_dir_all(&recordings_dir).map_err(|e| CaptureError::FileError(e.to_string()))?;
+
+    let path = recordings_dir.join(format!("{}.cvreplay", datetime_to_filename(Utc::now())));
+    info!("Starting session recording at {}", path.display());
+
+    capture_manager_mutex
+        .lock()
+        .unwrap()
+        .start_recording(&path)?;
+    Ok(path.display().to_string())
+}
+
+#[tauri::command(async)]
+#[specta::specta]
+pub fn stop_session_recording(
+    capture_manager_mutex: State<'_, Mutex<CaptureManager>>,
+) -> Result<(), CaptureError> {
+    info!("Stopping session recording");
+    capture_manager_mutex.lock().unwrap().stop_recording()
+}
+
+#[tauri::command(async)]
+#[specta::specta]
+pub fn queue_capture(
+    app: AppHandle,
+    job_queue_mutex: State<'_, Mutex<JobQueue>>,
+    request: JobRequest,
+) -> Result<CaptureJob, CaptureError> {
+    let job = job_queue_mutex.lock().unwrap().push(request)?;
+    info!("Queued capture job {}", job.id);
+    emit_job(&app, job.clone());
+    Ok(job)
+}
+
+#[tauri::command(async)]
+#[specta::specta]
+pub fn get_capture_jobs(job_queue_mutex: State<'_, Mutex<JobQueue>>) -> Vec<CaptureJob> {
+    job_queue_mutex.lock().unwrap().jobs()
+}
+
+#[tauri::command(async)]
+#[specta::specta]
+pub fn move_capture_job(
+    job_queue_mutex: State<'_, Mutex<JobQueue>>,
+    id: u32,
+    position: u32,
+) -> Result<(), CaptureError> {
+    info!("Moving capture job {id} to position {position}");
+    job_queue_mutex.lock().unwrap().move_job(id, position)
+}
+
+// Also stops the capture if the job is running
+#[tauri::command(async)]
+#[specta::specta]
+pub fn cancel_capture_job(
+    app: AppHandle,
+    job_queue_mutex: State<'_, Mutex<JobQueue>>,
+    capture_manager_mutex: State<'_, Mutex<CaptureManager>>,
+    id: u32,
+) -> Result<(), CaptureError> {
+    info!("Cancelling capture job {id}");
+    let mut job = job_queue_mutex.lock().unwrap().cancel(id)?;
+    if job.state == JobState::Running {
+        capture_manager_mutex.lock().unwrap().stop_capture();
+    }
+
+    job.state = JobState::Cancelled;
+    emit_job(&app, job);
     Ok(())
 }
+
+#[tauri::command(async)]
+#[specta::specta]
+pub fn set_recalibration_settings(
+    job_queue_mutex: State<'_, Mutex<JobQueue>>,
+    settings: RecalibrationSettings,
+) {
+    info!("Updating recalibration settings {:?}", settings);
+    job_queue_mutex.lock().unwrap().set_recalibration(settings);
+}
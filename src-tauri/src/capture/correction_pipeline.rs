@@ -0,0 +1,433 @@
+use std::{fmt, sync::Arc};
+
+use image::{ImageBuffer, Luma};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use super::{
+    capture::Roi,
+    capture_manager::CorrectionMaps,
+    corrections::{CorrectionError, CorrectionMapKey, DarkCorrectionMethod},
+    lag::LagCorrector,
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize, Type, PartialEq, Eq, Hash)]
+#[serde(tag = "type")]
+pub enum CorrectionStageKind {
+    // Dark map subtraction
+    Offset,
+    // Residual signal from earlier frames
+    Lag,
+    Gain,
+    Defect,
+    // Row and column correlated noise
+    RowColumnNoise,
+    // A stage registered with CorrectionMaps::register_custom_stage
+    Custom { name: String },
+}
+
+impl fmt::Display for CorrectionStageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CorrectionStageKind::Offset => write!(f, "Offset"),
+            CorrectionStageKind::Lag => write!(f, "Lag"),
+            CorrectionStageKind::Gain => write!(f, "Gain"),
+            CorrectionStageKind::Defect => write!(f, "Defect"),
+            CorrectionStageKind::RowColumnNoise => write!(f, "Row/column noise"),
+            CorrectionStageKind::Custom { name } => write!(f, "{name}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Type, PartialEq)]
+pub struct CorrectionStageSetting {
+    pub stage: CorrectionStageKind,
+    pub enabled: bool,
+}
+
+// The corrections applied to each frame of a capture, run in order on the output of the
+// stage before. Disabled stages stay in the list so they can be switched back on in place.
+#[derive(Clone, Debug, Serialize, Deserialize, Type, PartialEq)]
+pub struct CorrectionPipeline {
+    pub stages: Vec<CorrectionStageSetting>,
+}
+
+impl Default for CorrectionPipeline {
+    fn default() -> Self {
+        CorrectionPipeline {
+            stages: [
+                (CorrectionStageKind::Offset, true),
+                (CorrectionStageKind::Lag, true),
+                (CorrectionStageKind::Gain, true),
+                (CorrectionStageKind::Defect, true),
+                // Only worth the time on detectors with visible banding
+                (CorrectionStageKind::RowColumnNoise, false),
+            ]
+            .into_iter()
+            .map(|(stage, enabled)| CorrectionStageSetting { stage, enabled })
+            .collect(),
+        }
+    }
+}
+
+impl CorrectionPipeline {
+    // For raw frames, e.g. those calibration maps are built from
+    pub fn none() -> Self {
+        CorrectionPipeline { stages: Vec::new() }
+    }
+
+    pub fn enabled_stages(&self) -> impl Iterator<Item = &CorrectionStageKind> {
+        self.stages
+            .iter()
+            .filter(|setting| setting.enabled)
+            .map(|setting| &setting.stage)
+    }
+
+    // Creates fresh stages for one capture, so stages that carry state between frames start
+    // from scratch each time
+    pub fn build(&self, correction_maps: &CorrectionMaps) -> CorrectionRunner {
+        let stages = self
+            .enabled_stages()
+            .filter_map(|kind| -> Option<Box<dyn CorrectionStage>> {
+                match kind {
+                    CorrectionStageKind::Offset => Some(Box::new(OffsetStage)),
+                    CorrectionStageKind::Lag => Some(Box::new(LagStage::default())),
+                    CorrectionStageKind::Gain => Some(Box::new(GainStage)),
+                    CorrectionStageKind::Defect => Some(Box::new(DefectStage)),
+                    CorrectionStageKind::RowColumnNoise => Some(Box::new(DestripeStage)),
+                    CorrectionStageKind::Custom { name } => {
+                        let stage = correction_maps.custom_stage(name);
+                        if stage.is_none() {
+                            warn!("No correction stage registered as {name}, skipping it");
+                        }
+                        stage
+                    }
+                }
+            })
+            .collect();
+
+        CorrectionRunner { stages }
+    }
+}
+
+// What a stage can read and report while correcting a frame
+pub struct CorrectionContext<'a> {
+    pub correction_maps: &'a CorrectionMaps,
+    pub key: &'a CorrectionMapKey,
+    // Region of the full frame the image was read from
+    pub roi: Option<Roi>,
+    pub dark_correction: Option<DarkCorrectionMethod>,
+    // IDs of the correction maps used so far
+    pub correction_map_ids: Vec<String>,
+}
+
+pub enum StageOutcome {
+    Applied,
+    // Nothing to do for this frame, e.g. an optional map that isn't loaded
+    Skipped,
+}
+
+pub trait CorrectionStage: Send {
+    fn kind(&self) -> CorrectionStageKind;
+
+    fn apply(
+        &mut self,
+        image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+        context: &mut CorrectionContext,
+    ) -> Result<StageOutcome, CorrectionError>;
+}
+
+pub type CorrectionStageFactory = Arc<dyn Fn() -> Box<dyn CorrectionStage> + Send + Sync>;
+
+// How a frame was corrected, recorded in its metadata
+pub struct AppliedCorrections {
+    pub dark_correction: Option<DarkCorrectionMethod>,
+    pub correction_map_ids: Vec<String>,
+    // Stages that ran, in order
+    pub stages: Vec<CorrectionStageKind>,
+}
+
+pub struct CorrectionRunner {
+    stages: Vec<Box<dyn CorrectionStage>>,
+}
+
+impl CorrectionRunner {
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    // A failing stage is logged and left out of the record, and the rest still run
+    pub fn run(
+        &mut self,
+        image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+        correction_maps: &CorrectionMaps,
+        key: &CorrectionMapKey,
+        roi: Option<Roi>,
+    ) -> AppliedCorrections {
+        let mut context = CorrectionContext {
+            correction_maps,
+            key,
+            roi,
+            dark_correction: None,
+            correction_map_ids: Vec::new(),
+        };
+
+        let mut stages = Vec::new();
+        for stage in self.stages.iter_mut() {
+            match stage.apply(image, &mut context) {
+                Ok(StageOutcome::Applied) => stages.push(stage.kind()),
+                Ok(StageOutcome::Skipped) => {}
+                Err(e) => error!("{} correction failed: {e}", stage.kind()),
+            }
+        }
+
+        AppliedCorrections {
+            dark_correction: context.dark_correction,
+            correction_map_ids: context.correction_map_ids,
+            stages,
+        }
+    }
+}
+
+pub struct OffsetStage;
+
+impl CorrectionStage for OffsetStage {
+    fn kind(&self) -> CorrectionStageKind {
+        CorrectionStageKind::Offset
+    }
+
+    fn apply(
+        &mut self,
+        image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+        context: &mut CorrectionContext,
+    ) -> Result<StageOutcome, CorrectionError> {
+        let method =
+            context
+                .correction_maps
+                .dark_correct_image(image, context.key, context.roi.as_ref())?;
+        context
+            .correction_map_ids
+            .extend(context.correction_maps.dark_map_ids(&method));
+        context.dark_correction = Some(method);
+        Ok(StageOutcome::Applied)
+    }
+}
+
+// Carries the residual of every frame so far, so needs a fresh instance per capture
+#[derive(Default)]
+pub struct LagStage {
+    // Looked up on the first frame, None afterwards if there's no model for the capture
+    corrector: Option<Option<LagCorrector>>,
+}
+
+impl CorrectionStage for LagStage {
+    fn kind(&self) -> CorrectionStageKind {
+        CorrectionStageKind::Lag
+    }
+
+    // Lag models are optional, so captures without one are left alone
+    fn apply(
+        &mut self,
+        image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+        context: &mut CorrectionContext,
+    ) -> Result<StageOutcome, CorrectionError> {
+        // The model is of the signal above the dark level, which is only known once the
+        // offset stage has run
+        if context.dark_correction.is_none() {
+            return Ok(StageOutcome::Skipped);
+        }
+
+        let corrector = self.corrector.get_or_insert_with(|| {
+            context
+                .correction_maps
+                .lag_model(context.key)
+                .map(|model| LagCorrector::new(model.terms))
+        });
+        let Some(corrector) = corrector else {
+            return Ok(StageOutcome::Skipped);
+        };
+
+        corrector.correct(image, context.correction_maps.pedestal())?;
+        Ok(StageOutcome::Applied)
+    }
+}
+
+pub struct GainStage;
+
+impl CorrectionStage for GainStage {
+    fn kind(&self) -> CorrectionStageKind {
+        CorrectionStageKind::Gain
+    }
+
+    // Gain maps are optional, so frames without one are left alone
+    fn apply(
+        &mut self,
+        image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+        context: &mut CorrectionContext,
+    ) -> Result<StageOutcome, CorrectionError> {
+        match context
+            .correction_maps
+            .gain_correct_image(image, context.key, context.roi.as_ref())
+        {
+            Ok(()) => {
+                context
+                    .correction_map_ids
+                    .extend(context.correction_maps.gain_map_id(context.key));
+                Ok(StageOutcome::Applied)
+            }
+            Err(CorrectionError::GainMapNotFound(_)) => Ok(StageOutcome::Skipped),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub struct DefectStage;
+
+impl CorrectionStage for DefectStage {
+    fn kind(&self) -> CorrectionStageKind {
+        CorrectionStageKind::Defect
+    }
+
+    fn apply(
+        &mut self,
+        image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+        context: &mut CorrectionContext,
+    ) -> Result<StageOutcome, CorrectionError> {
+        context
+            .correction_maps
+            .defect_correct_image(image, context.roi.as_ref())?;
+        context
+            .correction_map_ids
+            .extend(context.correction_maps.defect_map_id());
+        Ok(StageOutcome::Applied)
+    }
+}
+
+pub struct DestripeStage;
+
+impl CorrectionStage for DestripeStage {
+    fn kind(&self) -> CorrectionStageKind {
+        CorrectionStageKind::RowColumnNoise
+    }
+
+    fn apply(
+        &mut self,
+        image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+        context: &mut CorrectionContext,
+    ) -> Result<StageOutcome, CorrectionError> {
+        context.correction_maps.destripe_image(image)?;
+        Ok(StageOutcome::Applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use image::{ImageBuffer, Luma};
+
+    use super::{
+        CorrectionContext, CorrectionPipeline, CorrectionStage, CorrectionStageKind,
+        CorrectionStageSetting, StageOutcome,
+    };
+    use crate::capture::{
+        capture::CaptureSettingBuilder,
+        capture::SequenceCapture,
+        capture_manager::CorrectionMaps,
+        corrections::{CorrectionError, CorrectionMapKey},
+    };
+
+    struct Invert;
+
+    impl CorrectionStage for Invert {
+        fn kind(&self) -> CorrectionStageKind {
+            CorrectionStageKind::Custom {
+                name: "Invert".to_string(),
+            }
+        }
+
+        fn apply(
+            &mut self,
+            image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+            _context: &mut CorrectionContext,
+        ) -> Result<StageOutcome, CorrectionError> {
+            image.iter_mut().for_each(|v| *v = 16383 - *v);
+            Ok(StageOutcome::Applied)
+        }
+    }
+
+    fn key() -> CorrectionMapKey {
+        CorrectionMapKey::from_capture_setting(
+            &CaptureSettingBuilder::new(100, Box::new(SequenceCapture { num_frames: 1 })).build(),
+        )
+    }
+
+    fn setting(stage: CorrectionStageKind, enabled: bool) -> CorrectionStageSetting {
+        CorrectionStageSetting { stage, enabled }
+    }
+
+    #[test]
+    fn runs_enabled_stages_in_order_and_records_them() {
+        let dark_map: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_vec(2, 1, vec![100, 100]).unwrap();
+        let correction_maps =
+            CorrectionMaps::new(HashMap::from([(key(), dark_map)]), None, HashMap::new());
+        correction_maps.register_custom_stage("Invert", Arc::new(|| Box::new(Invert)));
+
+        let pipeline = CorrectionPipeline {
+            stages: vec![
+                setting(CorrectionStageKind::Offset, true),
+                setting(CorrectionStageKind::Gain, true),
+                setting(CorrectionStageKind::Defect, false),
+                setting(
+                    CorrectionStageKind::Custom {
+                        name: "Invert".to_string(),
+                    },
+                    true,
+                ),
+            ],
+        };
+
+        let mut image: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_vec(2, 1, vec![1100, 1200]).unwrap();
+        let applied =
+            pipeline
+                .build(&correction_maps)
+                .run(&mut image, &correction_maps, &key(), None);
+
+        // Gain is skipped without a gain map and defect correction is switched off
+        assert_eq!(
+            applied.stages,
+            vec![
+                CorrectionStageKind::Offset,
+                CorrectionStageKind::Custom {
+                    name: "Invert".to_string()
+                }
+            ]
+        );
+        assert!(applied.dark_correction.is_some());
+        let pedestal = 300;
+        assert_eq!(
+            image.into_raw(),
+            vec![16383 - (1000 + pedestal), 16383 - (1100 + pedestal)]
+        );
+    }
+
+    #[test]
+    fn empty_pipeline_leaves_frames_raw() {
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+        let mut image: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_vec(2, 1, vec![1100, 1200]).unwrap();
+
+        let applied = CorrectionPipeline::none().build(&correction_maps).run(
+            &mut image,
+            &correction_maps,
+            &key(),
+            None,
+        );
+
+        assert!(applied.stages.is_empty());
+        assert_eq!(image.into_raw(), vec![1100, 1200]);
+    }
+}
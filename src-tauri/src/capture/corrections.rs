@@ -1,57 +1,1310 @@
-use log::error;
-use serde::Serialize;
+use crate::wrapper::{
+    BinningModes, BinningModesRS, FullWellModes, FullWellModesRS, InternalSLError,
+};
+use image::{ImageBuffer, Luma};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use specta::Type;
-use std::os::windows::process::CommandExt;
-use std::path::PathBuf;
+use std::{
+    cmp::Ordering,
+    fmt,
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{BufReader, BufWriter},
+    path::Path,
+};
 use thiserror::Error;
-use crate::wrapper::InternalSLError;
-use std::process::Command;
+use tiff::{
+    decoder::{Decoder, DecodingResult},
+    encoder::{colortype, TiffEncoder},
+};
 
-const CREATE_NO_WINDOW: u32 = 0x08000000;
+use super::{backend::DetectorIdentity, capture::CaptureSetting, defect_map::StackStatistics};
 
-#[derive(Error, Debug, Type, Serialize)]
+// Maximum intensity for a 14-bit image
+pub const MAX_PIXEL_VALUE: u16 = 16383;
+// Mean dark corrected signal a flat field needs before its gain map is trusted
+const MIN_FLAT_SIGNAL: f32 = 100.0;
+// Pixels with less relative gain than this are left for defect correction
+const MIN_GAIN: f32 = 0.05;
+// Fraction of the calibrated exposure range a dark model may be extrapolated beyond either end
+const DARK_MODEL_EXTRAPOLATION: f32 = 0.25;
+
+#[derive(Clone, Error, Debug, Type, Serialize)]
 pub enum CorrectionError {
     #[error("Internal SDK Error")]
     SLError(InternalSLError),
 
     #[error("File not found: {0}")]
     FileNotFound(String),
+
+    #[error("No dark map for {0}")]
+    DarkMapNotFound(CorrectionMapKey),
+
+    #[error("No dark map for {0}, only for {}", describe_keys(.1))]
+    DarkMapMismatch(CorrectionMapKey, Vec<CorrectionMapKey>),
+
+    #[error("No defect map loaded")]
+    DefectMapNotFound,
+
+    #[error("Image is {0}x{1} but correction map is {2}x{3}")]
+    DimensionMismatch(u32, u32, u32, u32),
+
+    #[error("Failed to write {0}")]
+    WriteFailed(String),
+
+    #[error("No frames to generate a correction map from")]
+    NoFrames,
+
+    #[error("No gain map for {0}")]
+    GainMapNotFound(CorrectionMapKey),
+
+    #[error("Flat field at {0}ms has a mean signal of {1:.1}, is the source on?")]
+    InsufficientSignal(u32, f32),
+
+    #[error("Modelling the dark signal needs maps at two or more exposure times, found {0}")]
+    InsufficientDarkMaps(u32),
+
+    #[error("{0}ms is too far outside the calibrated dark range of {1}-{2}ms")]
+    DarkModelOutOfRange(u32, u32, u32),
+
+    #[error("Invalid calibration bundle: {0}")]
+    InvalidBundle(String),
+
+    #[error("Calibration bundle is for {0}, not the connected {1}")]
+    BundleDetectorMismatch(DetectorIdentity, DetectorIdentity),
+
+    #[error("Reference lines {0}..{1} are outside the image's {2} lines")]
+    InvalidReferenceLines(u32, u32, u32),
+
+    #[error("No drop from bright to dark frames found, was the source switched off?")]
+    NoLagTransition,
+
+    #[error("Too little residual signal after the bright frames to fit a lag model")]
+    InsufficientLag,
+}
+
+// Everything about a capture that changes the dark signal or gain of a pixel. Dark and gain
+// maps are only ever applied to captures with exactly the same key.
+#[derive(Clone, Debug, Serialize, Deserialize, Type)]
+pub struct CorrectionMapKey {
+    pub exp_time: u32,
+    pub full_well: FullWellModesRS,
+    pub binning_mode: BinningModesRS,
+    pub dds: bool,
+}
+
+impl CorrectionMapKey {
+    pub fn from_capture_setting(capture_setting: &CaptureSetting) -> Self {
+        CorrectionMapKey {
+            exp_time: capture_setting.exp_time,
+            full_well: capture_setting.full_well.clone(),
+            binning_mode: capture_setting.binning_mode.clone(),
+            dds: capture_setting.dds,
+        }
+    }
+
+    // e.g. DarkMap_100ms_HFW_x11_NoDDS.tif
+    pub fn file_name(&self, prefix: &str) -> String {
+        format!(
+            "{prefix}_{}ms_{}_{}_{}.tif",
+            self.exp_time,
+            self.full_well_token(),
+            self.binning_token(),
+            if self.dds { "DDS" } else { "NoDDS" }
+        )
+    }
+
+    // Maps saved before they were keyed on anything but exposure time, named `{prefix}_{exp}ms.tif`,
+    // were always captured with the CaptureSettingBuilder defaults of HFW, x11 binning and no DDS
+    pub fn parse_file_name(prefix: &str, file_name: &str) -> Option<Self> {
+        let regex = Regex::new(&format!(
+            r"^{prefix}_(\d+)ms(?:_(HFW|LFW)_(x11|x22|x44)_(DDS|NoDDS))?\.tif$"
+        ))
+        .expect("Failed to compile regex");
+        let captures = regex.captures(file_name)?;
+
+        let exp_time = captures.get(1)?.as_str().parse().ok()?;
+        let full_well = match captures.get(2).map(|m| m.as_str()) {
+            Some("LFW") => FullWellModes::Low,
+            _ => FullWellModes::High,
+        };
+        let binning_mode = match captures.get(3).map(|m| m.as_str()) {
+            Some("x22") => BinningModes::x22,
+            Some("x44") => BinningModes::x44,
+            _ => BinningModes::x11,
+        };
+
+        Some(CorrectionMapKey {
+            exp_time,
+            full_well: FullWellModesRS {
+                remote_ty: full_well,
+            },
+            binning_mode: BinningModesRS(binning_mode),
+            dds: captures.get(4).map(|m| m.as_str()) == Some("DDS"),
+        })
+    }
+
+    // Whether two keys only differ in exposure time
+    pub fn matches_settings(&self, other: &CorrectionMapKey) -> bool {
+        self.full_well_token() == other.full_well_token()
+            && self.binning_token() == other.binning_token()
+            && self.dds == other.dds
+    }
+
+    fn full_well_token(&self) -> &'static str {
+        match self.full_well.remote_ty {
+            FullWellModes::High => "HFW",
+            FullWellModes::Low => "LFW",
+            FullWellModes::Unknown => "UnknownFW",
+        }
+    }
+
+    fn binning_token(&self) -> &'static str {
+        match self.binning_mode.0 {
+            BinningModes::x11 => "x11",
+            BinningModes::x22 => "x22",
+            BinningModes::x44 => "x44",
+            BinningModes::BinningUnknown => "UnknownBinning",
+        }
+    }
+}
+
+// The SDK enums don't implement Eq or Hash, so compare on their file name tokens
+impl PartialEq for CorrectionMapKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.exp_time == other.exp_time
+            && self.full_well_token() == other.full_well_token()
+            && self.binning_token() == other.binning_token()
+            && self.dds == other.dds
+    }
+}
+
+impl Eq for CorrectionMapKey {}
+
+impl Hash for CorrectionMapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.exp_time.hash(state);
+        self.full_well_token().hash(state);
+        self.binning_token().hash(state);
+        self.dds.hash(state);
+    }
+}
+
+impl fmt::Display for CorrectionMapKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}ms {} {} {}",
+            self.exp_time,
+            self.full_well_token(),
+            self.binning_token(),
+            if self.dds { "DDS" } else { "no DDS" }
+        )
+    }
+}
+
+fn describe_keys(keys: &[CorrectionMapKey]) -> String {
+    keys.iter()
+        .map(|key| key.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Type)]
+pub enum DefectReplacement {
+    Median,
+    Mean,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Type)]
+pub struct DefectCorrectionSettings {
+    // Side length of the square neighbourhood used to replace a defective pixel, must be odd
+    pub kernel_size: u32,
+    pub replacement: DefectReplacement,
+    // Interpolate across row and column defects instead of using the kernel, which would
+    // otherwise be dominated by the other defective pixels in the line
+    pub line_defects: bool,
+    // Number of contiguous defective pixels in a row or column treated as a line defect
+    pub min_line_length: u32,
+}
+
+impl Default for DefectCorrectionSettings {
+    fn default() -> Self {
+        DefectCorrectionSettings {
+            kernel_size: 3,
+            replacement: DefectReplacement::Median,
+            line_defects: true,
+            min_line_length: 10,
+        }
+    }
+}
+
+// A half open range of rows or columns
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Type, PartialEq)]
+pub struct LineRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Type, PartialEq)]
+pub struct DestripeSettings {
+    pub rows: bool,
+    pub columns: bool,
+    // Columns that see no signal, e.g. optically masked ones, to measure row offsets from.
+    // Without them each row is compared with the rows around it instead.
+    pub row_reference: Option<LineRange>,
+    // Rows that see no signal to measure column offsets from
+    pub column_reference: Option<LineRange>,
+    // Lines either side a line is compared with when there is no reference region. Structure
+    // wider than this is treated as signal and kept.
+    pub neighbours: u32,
+}
+
+impl Default for DestripeSettings {
+    fn default() -> Self {
+        DestripeSettings {
+            rows: true,
+            columns: true,
+            row_reference: None,
+            column_reference: None,
+            neighbours: 8,
+        }
+    }
+}
+
+// Offsets subtracted from each row and column by destriping
+#[derive(Clone, Debug, Serialize, Type, PartialEq)]
+pub struct LineOffsets {
+    pub rows: Vec<f32>,
+    pub columns: Vec<f32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Type)]
+pub struct CorrectionSettings {
+    // Added back after subtracting the dark map so noise around zero isn't clipped
+    pub pedestal: u16,
+    pub defect_correction: DefectCorrectionSettings,
+    #[serde(default)]
+    pub destripe: DestripeSettings,
+    // Maps older than this are reported as stale
+    pub max_calibration_age_days: u32,
+}
+
+impl Default for CorrectionSettings {
+    fn default() -> Self {
+        CorrectionSettings {
+            pedestal: 300,
+            defect_correction: DefectCorrectionSettings::default(),
+            destripe: DestripeSettings::default(),
+            max_calibration_age_days: 30,
+        }
+    }
+}
+
+fn check_dimensions(image: (u32, u32), map: (u32, u32)) -> Result<(), CorrectionError> {
+    if image != map {
+        return Err(CorrectionError::DimensionMismatch(
+            image.0, image.1, map.0, map.1,
+        ));
+    }
+    Ok(())
+}
+
+// Subtracts the dark map and adds the pedestal, clamping to the 14-bit range
+pub fn offset_correct(
+    image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+    dark_map: &ImageBuffer<Luma<u16>, Vec<u16>>,
+    pedestal: u16,
+) -> Result<(), CorrectionError> {
+    check_dimensions(image.dimensions(), dark_map.dimensions())?;
+
+    for (pixel, dark) in image.iter_mut().zip(dark_map.iter()) {
+        *pixel = (*pixel as i32 - *dark as i32 + pedestal as i32).clamp(0, MAX_PIXEL_VALUE as i32)
+            as u16;
+    }
+    Ok(())
+}
+
+// Divides a dark corrected image by the relative pixel gain, scaling the signal above the
+// pedestal so the pedestal itself stays put
+pub fn gain_correct(
+    image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+    gain_map: &ImageBuffer<Luma<f32>, Vec<f32>>,
+    pedestal: u16,
+) -> Result<(), CorrectionError> {
+    check_dimensions(image.dimensions(), gain_map.dimensions())?;
+
+    for (pixel, &gain) in image.iter_mut().zip(gain_map.iter()) {
+        if gain < MIN_GAIN {
+            continue;
+        }
+        let signal = (*pixel as f32 - pedestal as f32) / gain;
+        *pixel = (signal + pedestal as f32)
+            .round()
+            .clamp(0.0, MAX_PIXEL_VALUE as f32) as u16;
+    }
+    Ok(())
+}
+
+// Normalises the dark corrected mean of a flat field stack to a mean gain of 1
+pub fn compute_gain_map(
+    flat: &StackStatistics,
+    dark_map: &ImageBuffer<Luma<u16>, Vec<u16>>,
+) -> Result<ImageBuffer<Luma<f32>, Vec<f32>>, CorrectionError> {
+    check_dimensions((flat.width, flat.height), dark_map.dimensions())?;
+
+    let signal: Vec<f32> = flat
+        .mean
+        .iter()
+        .zip(dark_map.iter())
+        .map(|(&flat, &dark)| (flat - dark as f32).max(0.0))
+        .collect();
+
+    let mean_signal = signal.iter().sum::<f32>() / signal.len().max(1) as f32;
+    if mean_signal < MIN_FLAT_SIGNAL {
+        return Err(CorrectionError::InsufficientSignal(
+            flat.exp_time,
+            mean_signal,
+        ));
+    }
+
+    Ok(ImageBuffer::from_vec(
+        flat.width,
+        flat.height,
+        signal
+            .into_iter()
+            .map(|value| value / mean_signal)
+            .collect(),
+    )
+    .unwrap())
+}
+
+// Gain maps are stored as 32-bit float TIFFs, which the image crate can't read as greyscale
+pub fn write_gain_map(
+    path: &Path,
+    gain_map: &ImageBuffer<Luma<f32>, Vec<f32>>,
+) -> Result<(), CorrectionError> {
+    let write_failed = |e: &dyn std::fmt::Display| {
+        CorrectionError::WriteFailed(format!("{}: {e}", path.display()))
+    };
+
+    let file = File::create(path).map_err(|e| write_failed(&e))?;
+    let mut encoder = TiffEncoder::new(BufWriter::new(file)).map_err(|e| write_failed(&e))?;
+    encoder
+        .write_image::<colortype::Gray32Float>(
+            gain_map.width(),
+            gain_map.height(),
+            gain_map.as_raw(),
+        )
+        .map_err(|e| write_failed(&e))
+}
+
+pub fn read_gain_map(path: &Path) -> Result<ImageBuffer<Luma<f32>, Vec<f32>>, CorrectionError> {
+    let not_found = || CorrectionError::FileNotFound(path.display().to_string());
+
+    let file = File::open(path).map_err(|_| not_found())?;
+    let mut decoder = Decoder::new(BufReader::new(file)).map_err(|_| not_found())?;
+    let (width, height) = decoder.dimensions().map_err(|_| not_found())?;
+
+    match decoder.read_image().map_err(|_| not_found())? {
+        DecodingResult::F32(data) => {
+            ImageBuffer::from_vec(width, height, data).ok_or_else(not_found)
+        }
+        _ => Err(not_found()),
+    }
+}
+
+// How a frame was dark corrected, recorded in its metadata
+#[derive(Clone, Debug, Serialize, Type)]
+#[serde(tag = "type")]
+pub enum DarkCorrectionMethod {
+    // A dark map captured with exactly the frame's settings
+    Exact {
+        key: CorrectionMapKey,
+    },
+    // Synthesized by a DarkModel fitted to maps at `exp_times`. The estimated error is in ADU
+    // and is only known when three or more maps were fitted.
+    Interpolated {
+        key: CorrectionMapKey,
+        exp_times: Vec<u32>,
+        estimated_error: Option<f32>,
+    },
+    Extrapolated {
+        key: CorrectionMapKey,
+        exp_times: Vec<u32>,
+        estimated_error: Option<f32>,
+    },
+}
+
+type DarkMap = ImageBuffer<Luma<u16>, Vec<u16>>;
+
+// Per-pixel linear model of the dark signal, offset + slope * exp_time, fitted by least squares
+// across dark maps that only differ in exposure time. Used to synthesize a dark map for
+// exposure times that were never calibrated.
+pub struct DarkModel {
+    width: u32,
+    height: u32,
+    offset: Vec<f32>,
+    slope: Vec<f32>,
+    exp_times: Vec<u32>,
+    map_count: usize,
+    mean_exp_time: f32,
+    exp_time_spread: f32,
+    residual_std_dev: Option<f32>,
+}
+
+impl DarkModel {
+    pub fn fit(dark_maps: &[(u32, &DarkMap)]) -> Result<Self, CorrectionError> {
+        let mut exp_times: Vec<u32> = dark_maps.iter().map(|(exp_time, _)| *exp_time).collect();
+        exp_times.sort_unstable();
+        exp_times.dedup();
+        if exp_times.len() < 2 {
+            return Err(CorrectionError::InsufficientDarkMaps(exp_times.len() as u32));
+        }
+
+        let (width, height) = dark_maps[0].1.dimensions();
+        for (_, dark_map) in dark_maps {
+            check_dimensions((width, height), dark_map.dimensions())?;
+        }
+
+        let map_count = dark_maps.len();
+        let n = map_count as f32;
+        let mean_exp_time = dark_maps.iter().map(|(t, _)| *t as f32).sum::<f32>() / n;
+        let exp_time_spread = dark_maps
+            .iter()
+            .map(|(t, _)| (*t as f32 - mean_exp_time).powi(2))
+            .sum::<f32>();
+
+        let pixel_count = (width * height) as usize;
+        let mut offset = Vec::with_capacity(pixel_count);
+        let mut slope = Vec::with_capacity(pixel_count);
+        let mut squared_residuals = 0.0f64;
+
+        for i in 0..pixel_count {
+            let mean_dark = dark_maps
+                .iter()
+                .map(|(_, map)| map.as_raw()[i] as f32)
+                .sum::<f32>()
+                / n;
+            let covariance = dark_maps
+                .iter()
+                .map(|(t, map)| (*t as f32 - mean_exp_time) * (map.as_raw()[i] as f32 - mean_dark))
+                .sum::<f32>();
+
+            let pixel_slope = covariance / exp_time_spread;
+            let pixel_offset = mean_dark - pixel_slope * mean_exp_time;
+            squared_residuals += dark_maps
+                .iter()
+                .map(|(t, map)| {
+                    let fitted = pixel_offset + pixel_slope * *t as f32;
+                    (map.as_raw()[i] as f64 - fitted as f64).powi(2)
+                })
+                .sum::<f64>();
+
+            offset.push(pixel_offset);
+            slope.push(pixel_slope);
+        }
+
+        // Two maps always fit a line exactly, so there is nothing to judge the error by
+        let residual_std_dev = (map_count > 2).then(|| {
+            (squared_residuals / (pixel_count.max(1) * (map_count - 2)) as f64).sqrt() as f32
+        });
+
+        Ok(DarkModel {
+            width,
+            height,
+            offset,
+            slope,
+            exp_times,
+            map_count,
+            mean_exp_time,
+            exp_time_spread,
+            residual_std_dev,
+        })
+    }
+
+    pub fn exp_times(&self) -> &[u32] {
+        &self.exp_times
+    }
+
+    // The calibrated range widened by DARK_MODEL_EXTRAPOLATION at each end
+    pub fn covers(&self, exp_time: u32) -> bool {
+        let (min, max) = self.calibrated_range();
+        let margin = ((max - min) as f32 * DARK_MODEL_EXTRAPOLATION) as u32;
+        exp_time >= min.saturating_sub(margin) && exp_time <= max + margin
+    }
+
+    // Expected RMS difference in ADU between the synthesized dark and one captured at
+    // exp_time, which grows the further exp_time is from the middle of the calibrated range
+    pub fn estimated_error(&self, exp_time: u32) -> Option<f32> {
+        let deviation = exp_time as f32 - self.mean_exp_time;
+        self.residual_std_dev.map(|std_dev| {
+            std_dev
+                * (1.0 + 1.0 / self.map_count as f32 + deviation.powi(2) / self.exp_time_spread)
+                    .sqrt()
+        })
+    }
+
+    pub fn synthesize(
+        &self,
+        exp_time: u32,
+    ) -> Result<ImageBuffer<Luma<u16>, Vec<u16>>, CorrectionError> {
+        if !self.covers(exp_time) {
+            let (min, max) = self.calibrated_range();
+            return Err(CorrectionError::DarkModelOutOfRange(exp_time, min, max));
+        }
+
+        Ok(ImageBuffer::from_vec(
+            self.width,
+            self.height,
+            self.offset
+                .iter()
+                .zip(self.slope.iter())
+                .map(|(&offset, &slope)| {
+                    (offset + slope * exp_time as f32)
+                        .round()
+                        .clamp(0.0, MAX_PIXEL_VALUE as f32) as u16
+                })
+                .collect(),
+        )
+        .unwrap())
+    }
+
+    pub fn correction_method(&self, key: &CorrectionMapKey) -> DarkCorrectionMethod {
+        let (min, max) = self.calibrated_range();
+        let exp_times = self.exp_times.clone();
+        let estimated_error = self.estimated_error(key.exp_time);
+        if (min..=max).contains(&key.exp_time) {
+            DarkCorrectionMethod::Interpolated {
+                key: key.clone(),
+                exp_times,
+                estimated_error,
+            }
+        } else {
+            DarkCorrectionMethod::Extrapolated {
+                key: key.clone(),
+                exp_times,
+                estimated_error,
+            }
+        }
+    }
+
+    fn calibrated_range(&self) -> (u32, u32) {
+        (self.exp_times[0], self.exp_times[self.exp_times.len() - 1])
+    }
 }
 
-pub fn run_defect_map_gen(images_dir: &PathBuf, exe_dir: &PathBuf) -> Result<PathBuf, ()> {
-    let args = [images_dir.to_str().unwrap(), "1", "0", "-f", "-a", "-p"];
-    let mut cmd = Command::new(exe_dir);
-    let cmd = cmd.args(args).creation_flags(CREATE_NO_WINDOW);
+// Replaces every pixel that is non-zero in the defect map. Pixels in a row or column
+// defect are linearly interpolated from the nearest good pixels either side of the line,
+// everything else takes the median or mean of the good pixels in the kernel. Pixels with
+// no good neighbours are left untouched.
+pub fn defect_correct(
+    image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+    defect_map: &ImageBuffer<Luma<u16>, Vec<u16>>,
+    settings: &DefectCorrectionSettings,
+) -> Result<(), CorrectionError> {
+    check_dimensions(image.dimensions(), defect_map.dimensions())?;
 
-    if (cmd.spawn().unwrap().wait().is_ok()) {
-        return Ok(images_dir.join("GlobalDefectMap.tif"));
+    let (width, height) = image.dimensions();
+    let is_defect = |x: u32, y: u32| defect_map.get_pixel(x, y)[0] != 0;
+    let (row_runs, column_runs) = defect_run_lengths(defect_map);
+    let radius = (settings.kernel_size.max(1) / 2) as i64;
+
+    // Read from the uncorrected image so results don't depend on scan order
+    let source = image.clone();
+    let mut neighbours = Vec::with_capacity((settings.kernel_size * settings.kernel_size) as usize);
+
+    for y in 0..height {
+        for x in 0..width {
+            if !is_defect(x, y) {
+                continue;
+            }
+
+            let idx = (y * width + x) as usize;
+            let mut replacement = None;
+
+            if settings.line_defects {
+                if row_runs[idx] >= settings.min_line_length {
+                    replacement = interpolate_across(&source, defect_map, x, y, false);
+                }
+                if replacement.is_none() && column_runs[idx] >= settings.min_line_length {
+                    replacement = interpolate_across(&source, defect_map, x, y, true);
+                }
+            }
+
+            if replacement.is_none() {
+                neighbours.clear();
+                for dy in -radius..=radius {
+                    for dx in -radius..=radius {
+                        let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                        if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+                            continue;
+                        }
+                        if !is_defect(nx as u32, ny as u32) {
+                            neighbours.push(source.get_pixel(nx as u32, ny as u32)[0]);
+                        }
+                    }
+                }
+                replacement = replace_from(&mut neighbours, settings.replacement);
+            }
+
+            if let Some(value) = replacement {
+                image.put_pixel(x, y, Luma([value]));
+            }
+        }
     }
-    Err(())
+
+    Ok(())
+}
+
+// Length of the horizontal and vertical defect run each pixel belongs to
+fn defect_run_lengths(defect_map: &ImageBuffer<Luma<u16>, Vec<u16>>) -> (Vec<u32>, Vec<u32>) {
+    let (width, height) = defect_map.dimensions();
+    let mut row_runs = vec![0u32; (width * height) as usize];
+    let mut column_runs = vec![0u32; (width * height) as usize];
+
+    for y in 0..height {
+        let mut start = 0;
+        while start < width {
+            let mut end = start;
+            while end < width && defect_map.get_pixel(end, y)[0] != 0 {
+                end += 1;
+            }
+            for x in start..end {
+                row_runs[(y * width + x) as usize] = end - start;
+            }
+            start = end + 1;
+        }
+    }
+
+    for x in 0..width {
+        let mut start = 0;
+        while start < height {
+            let mut end = start;
+            while end < height && defect_map.get_pixel(x, end)[0] != 0 {
+                end += 1;
+            }
+            for y in start..end {
+                column_runs[(y * width + x) as usize] = end - start;
+            }
+            start = end + 1;
+        }
+    }
+
+    (row_runs, column_runs)
+}
+
+// Interpolates between the nearest good pixels either side of (x, y), looking up and down
+// across a row defect or left and right across a column defect
+fn interpolate_across(
+    image: &ImageBuffer<Luma<u16>, Vec<u16>>,
+    defect_map: &ImageBuffer<Luma<u16>, Vec<u16>>,
+    x: u32,
+    y: u32,
+    horizontal: bool,
+) -> Option<u16> {
+    let (position, length) = if horizontal {
+        (x as i64, image.width() as i64)
+    } else {
+        (y as i64, image.height() as i64)
+    };
+    let pixel_at = |p: i64| {
+        if horizontal {
+            (p as u32, y)
+        } else {
+            (x, p as u32)
+        }
+    };
+
+    let find_good = |step: i64| {
+        let mut p = position + step;
+        while p >= 0 && p < length {
+            let (px, py) = pixel_at(p);
+            if defect_map.get_pixel(px, py)[0] == 0 {
+                return Some(((p - position).abs(), image.get_pixel(px, py)[0]));
+            }
+            p += step;
+        }
+        None
+    };
+
+    match (find_good(-1), find_good(1)) {
+        (Some((before_dist, before)), Some((after_dist, after))) => {
+            let total = (before_dist + after_dist) as f64;
+            let value =
+                (before as f64 * after_dist as f64 + after as f64 * before_dist as f64) / total;
+            Some(value.round() as u16)
+        }
+        (Some((_, value)), None) | (None, Some((_, value))) => Some(value),
+        (None, None) => None,
+    }
+}
+
+fn replace_from(values: &mut [u16], replacement: DefectReplacement) -> Option<u16> {
+    if values.is_empty() {
+        return None;
+    }
+
+    match replacement {
+        DefectReplacement::Median => {
+            values.sort_unstable();
+            let mid = values.len() / 2;
+            if values.len() % 2 == 0 {
+                Some(((values[mid - 1] as u32 + values[mid] as u32) / 2) as u16)
+            } else {
+                Some(values[mid])
+            }
+        }
+        DefectReplacement::Mean => {
+            let sum: u32 = values.iter().map(|&v| v as u32).sum();
+            Some((sum as f64 / values.len() as f64).round() as u16)
+        }
+    }
+}
+
+// Removes row and column correlated noise by subtracting an offset from every line. Rows are
+// corrected first so the column offsets aren't skewed by row stripes crossing the reference.
+pub fn destripe(
+    image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+    settings: &DestripeSettings,
+) -> Result<LineOffsets, CorrectionError> {
+    let (width, height) = image.dimensions();
+
+    let rows = if settings.rows {
+        let samples = settings.row_reference.unwrap_or(LineRange {
+            start: 0,
+            end: width,
+        });
+        check_reference(samples, width)?;
+        let medians: Vec<f32> = (0..height)
+            .map(|y| line_median((samples.start..samples.end).map(|x| image.get_pixel(x, y)[0])))
+            .collect();
+        line_offsets(
+            &medians,
+            settings.row_reference.is_some(),
+            settings.neighbours,
+        )
+    } else {
+        vec![0.0; height as usize]
+    };
+    for (y, &offset) in rows.iter().enumerate() {
+        for x in 0..width {
+            subtract_offset(&mut image.get_pixel_mut(x, y as u32)[0], offset);
+        }
+    }
+
+    let columns = if settings.columns {
+        let samples = settings.column_reference.unwrap_or(LineRange {
+            start: 0,
+            end: height,
+        });
+        check_reference(samples, height)?;
+        let medians: Vec<f32> = (0..width)
+            .map(|x| line_median((samples.start..samples.end).map(|y| image.get_pixel(x, y)[0])))
+            .collect();
+        line_offsets(
+            &medians,
+            settings.column_reference.is_some(),
+            settings.neighbours,
+        )
+    } else {
+        vec![0.0; width as usize]
+    };
+    for y in 0..height {
+        for (x, &offset) in columns.iter().enumerate() {
+            subtract_offset(&mut image.get_pixel_mut(x as u32, y)[0], offset);
+        }
+    }
+
+    Ok(LineOffsets { rows, columns })
+}
+
+fn check_reference(reference: LineRange, lines: u32) -> Result<(), CorrectionError> {
+    if reference.start >= reference.end || reference.end > lines {
+        return Err(CorrectionError::InvalidReferenceLines(
+            reference.start,
+            reference.end,
+            lines,
+        ));
+    }
+    Ok(())
+}
+
+fn line_median(values: impl Iterator<Item = u16>) -> f32 {
+    median_of(values.map(|v| v as f32).collect())
+}
+
+fn median_of(mut values: Vec<f32>) -> f32 {
+    values.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let mid = values.len() / 2;
+    match values.len() {
+        0 => 0.0,
+        len if len % 2 == 0 => (values[mid - 1] + values[mid]) / 2.0,
+        _ => values[mid],
+    }
+}
+
+// A reference region sees no signal, so every line should read the same and the offset is
+// the difference from the typical line. Otherwise a line is only compared with its neighbours,
+// which removes stripes but keeps gradients and features wider than the window. The window
+// shrinks towards the edges so it stays centred and doesn't mistake a gradient for a stripe.
+fn line_offsets(medians: &[f32], from_reference: bool, neighbours: u32) -> Vec<f32> {
+    if from_reference {
+        let level = median_of(medians.to_vec());
+        return medians.iter().map(|median| median - level).collect();
+    }
+
+    let len = medians.len();
+    (0..len)
+        .map(|i| {
+            let radius = (neighbours.max(1) as usize).min(i).min(len - 1 - i);
+            medians[i] - median_of(medians[i - radius..=i + radius].to_vec())
+        })
+        .collect()
+}
+
+fn subtract_offset(pixel: &mut u16, offset: f32) {
+    *pixel = (*pixel as f32 - offset)
+        .round()
+        .clamp(0.0, MAX_PIXEL_VALUE as f32) as u16;
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
+    use image::{ImageBuffer, Luma};
+
+    use super::{
+        compute_gain_map, defect_correct, destripe, gain_correct, offset_correct, read_gain_map,
+        write_gain_map, CorrectionError, CorrectionMapKey, DarkCorrectionMethod, DarkModel,
+        DefectCorrectionSettings, DefectReplacement, DestripeSettings, LineRange,
+    };
+    use crate::capture::defect_map::StackStatistics;
+    use crate::wrapper::{BinningModes, BinningModesRS, FullWellModes, FullWellModesRS};
+
+    fn image_from(width: u32, height: u32, data: Vec<u16>) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        ImageBuffer::from_vec(width, height, data).unwrap()
+    }
+
+    fn defect_map_with(
+        width: u32,
+        height: u32,
+        defects: &[(u32, u32)],
+    ) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        let mut map = ImageBuffer::new(width, height);
+        for &(x, y) in defects {
+            map.put_pixel(x, y, Luma([1]));
+        }
+        map
+    }
+
+    #[test]
+    fn offset_correct_subtracts_dark_and_adds_pedestal() {
+        let mut image = image_from(2, 2, vec![1000, 400, 50, 16383]);
+        let dark = image_from(2, 2, vec![200, 500, 400, 100]);
 
-    use tauri::Manager;
+        offset_correct(&mut image, &dark, 300).unwrap();
 
-    use crate::capture::test_utils::test_utils::create_app;
+        // Negative results clamp to zero and overflow clamps to the 14-bit maximum
+        assert_eq!(image.into_raw(), vec![1100, 200, 0, 16383]);
+    }
 
-    use super::run_defect_map_gen;
+    #[test]
+    fn offset_correct_rejects_mismatched_dark_map() {
+        let mut image = image_from(2, 2, vec![0; 4]);
+        let dark = image_from(1, 4, vec![0; 4]);
+
+        assert!(matches!(
+            offset_correct(&mut image, &dark, 300),
+            Err(CorrectionError::DimensionMismatch(2, 2, 1, 4))
+        ));
+    }
+
+    fn key(
+        exp_time: u32,
+        full_well: FullWellModes,
+        binning: BinningModes,
+        dds: bool,
+    ) -> CorrectionMapKey {
+        CorrectionMapKey {
+            exp_time,
+            full_well: FullWellModesRS {
+                remote_ty: full_well,
+            },
+            binning_mode: BinningModesRS(binning),
+            dds,
+        }
+    }
+
+    #[test]
+    fn correction_map_key_roundtrips_file_name() {
+        let lfw = key(150, FullWellModes::Low, BinningModes::x22, true);
+
+        assert_eq!(lfw.file_name("DarkMap"), "DarkMap_150ms_LFW_x22_DDS.tif");
+        assert_eq!(
+            CorrectionMapKey::parse_file_name("DarkMap", &lfw.file_name("DarkMap")),
+            Some(lfw.clone())
+        );
+        assert_eq!(
+            CorrectionMapKey::parse_file_name("GainMap", &lfw.file_name("DarkMap")),
+            None
+        );
+        assert_ne!(lfw, key(150, FullWellModes::High, BinningModes::x22, true));
+    }
 
     #[test]
-    pub fn defect_map_gen() {
-        let app = create_app(tauri::test::mock_builder());
-        let images_dir = app.path().app_local_data_dir().unwrap().join("DefectMap");
-        let exe_dir = PathBuf::from(
-            "C:\\Program Files\\cview\\resources\\DefectMapGeneration\\DefectMapGen.exe",
+    fn correction_map_key_reads_legacy_file_name() {
+        assert_eq!(
+            CorrectionMapKey::parse_file_name("DarkMap", "DarkMap_100ms.tif"),
+            Some(key(100, FullWellModes::High, BinningModes::x11, false))
         );
-        let log_dir = app.path().app_log_dir().unwrap();
-        println!("{}", log_dir.display());
-        if (exe_dir.exists()) {
-            println!("yay");
+        assert_eq!(
+            CorrectionMapKey::parse_file_name("DarkMap", "DarkMap_100ms.tiff"),
+            None
+        );
+    }
+
+    #[test]
+    fn gain_correct_scales_signal_above_pedestal() {
+        let mut image = image_from(4, 1, vec![1300, 1300, 300, 16000]);
+        let gain = ImageBuffer::from_vec(4, 1, vec![2.0, 0.5, 1.5, 0.5]).unwrap();
+
+        gain_correct(&mut image, &gain, 300).unwrap();
+
+        assert_eq!(image.into_raw(), vec![800, 2300, 300, 16383]);
+    }
+
+    #[test]
+    fn gain_correct_skips_pixels_without_gain() {
+        let mut image = image_from(2, 1, vec![1000, 1000]);
+        let gain = ImageBuffer::from_vec(2, 1, vec![0.0, 1.0]).unwrap();
+
+        gain_correct(&mut image, &gain, 300).unwrap();
+
+        assert_eq!(image.into_raw(), vec![1000, 1000]);
+    }
+
+    #[test]
+    fn compute_gain_map_normalises_dark_corrected_flat() {
+        let flat = StackStatistics {
+            width: 2,
+            height: 2,
+            exp_time: 100,
+            frame_count: 10,
+            mean: vec![1300.0, 2300.0, 1800.0, 1800.0],
+            std_dev: vec![0.0; 4],
+        };
+        let dark = image_from(2, 2, vec![300, 300, 300, 300]);
+
+        let gain = compute_gain_map(&flat, &dark).unwrap();
+
+        assert_eq!(gain.into_raw(), vec![2.0 / 3.0, 4.0 / 3.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn compute_gain_map_rejects_dark_flat() {
+        let flat = StackStatistics {
+            width: 2,
+            height: 1,
+            exp_time: 100,
+            frame_count: 10,
+            mean: vec![310.0, 320.0],
+            std_dev: vec![0.0; 2],
+        };
+        let dark = image_from(2, 1, vec![300, 300]);
+
+        assert!(matches!(
+            compute_gain_map(&flat, &dark),
+            Err(CorrectionError::InsufficientSignal(100, _))
+        ));
+    }
+
+    #[test]
+    fn gain_map_roundtrips_through_tiff() {
+        let gain = ImageBuffer::from_vec(3, 2, vec![0.5, 1.0, 1.5, 0.9, 1.1, 1.0]).unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "cview_gain_map_roundtrip_{}.tif",
+            std::process::id()
+        ));
+
+        write_gain_map(&path, &gain).unwrap();
+        let read = read_gain_map(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.unwrap(), gain);
+    }
+
+    #[test]
+    fn dark_model_interpolates_linear_dark_current() {
+        // Offsets of 100 and 200 with dark currents of 1 and 0.5 ADU/ms
+        let dark_100 = image_from(2, 1, vec![200, 250]);
+        let dark_300 = image_from(2, 1, vec![400, 350]);
+        let model = DarkModel::fit(&[(100, &dark_100), (300, &dark_300)]).unwrap();
+
+        assert_eq!(model.synthesize(200).unwrap().into_raw(), vec![300, 300]);
+        // Two maps fit exactly, so there is no residual to estimate the error from
+        assert_eq!(model.estimated_error(200), None);
+    }
+
+    #[test]
+    fn dark_model_estimates_error_from_residuals() {
+        let darks = [
+            image_from(1, 1, vec![110]),
+            image_from(1, 1, vec![130]),
+            image_from(1, 1, vec![130]),
+        ];
+        let model =
+            DarkModel::fit(&[(100, &darks[0]), (200, &darks[1]), (300, &darks[2])]).unwrap();
+
+        // Best fit is 103.33 + 0.1t, leaving residuals of -3.33, 6.67 and -3.33 ADU over one
+        // degree of freedom
+        let residual_std_dev = (2.0f32 * (10.0f32 / 3.0).powi(2) + (20.0f32 / 3.0).powi(2)).sqrt();
+        let centre_error = model.estimated_error(200).unwrap();
+        assert!((centre_error - residual_std_dev * (1.0f32 + 1.0 / 3.0).sqrt()).abs() < 1e-3);
+        assert!(model.estimated_error(350).unwrap() > centre_error);
+    }
+
+    #[test]
+    fn dark_model_limits_extrapolation() {
+        let dark_100 = image_from(1, 1, vec![200]);
+        let dark_300 = image_from(1, 1, vec![400]);
+        let model = DarkModel::fit(&[(100, &dark_100), (300, &dark_300)]).unwrap();
+        let key = key(350, FullWellModes::High, BinningModes::x11, false);
+
+        assert_eq!(model.synthesize(350).unwrap().into_raw(), vec![450]);
+        assert!(matches!(
+            model.correction_method(&key),
+            DarkCorrectionMethod::Extrapolated { .. }
+        ));
+        assert!(matches!(
+            model.synthesize(351),
+            Err(CorrectionError::DarkModelOutOfRange(351, 100, 300))
+        ));
+    }
+
+    #[test]
+    fn dark_model_needs_two_exposure_times() {
+        let dark = image_from(1, 1, vec![200]);
+
+        assert!(matches!(
+            DarkModel::fit(&[(100, &dark), (100, &dark)]),
+            Err(CorrectionError::InsufficientDarkMaps(1))
+        ));
+    }
+
+    #[test]
+    fn defect_correct_replaces_with_neighbour_median() {
+        #[rustfmt::skip]
+        let mut image = image_from(3, 3, vec![
+            10, 20, 30,
+            40, 9999, 50,
+            60, 70, 80,
+        ]);
+        let defects = defect_map_with(3, 3, &[(1, 1)]);
+
+        defect_correct(&mut image, &defects, &DefectCorrectionSettings::default()).unwrap();
+
+        // Median of the eight good neighbours is the mean of 40 and 50
+        assert_eq!(image.get_pixel(1, 1)[0], 45);
+        assert_eq!(image.get_pixel(0, 0)[0], 10);
+    }
+
+    #[test]
+    fn defect_correct_replaces_with_neighbour_mean() {
+        #[rustfmt::skip]
+        let mut image = image_from(3, 3, vec![
+            10, 20, 30,
+            40, 0, 50,
+            60, 70, 81,
+        ]);
+        let defects = defect_map_with(3, 3, &[(1, 1), (0, 0)]);
+        let settings = DefectCorrectionSettings {
+            replacement: DefectReplacement::Mean,
+            ..Default::default()
+        };
+
+        defect_correct(&mut image, &defects, &settings).unwrap();
+
+        // Defective neighbours are excluded and corrections read the original image
+        assert_eq!(image.get_pixel(1, 1)[0], 50);
+        assert_eq!(image.get_pixel(0, 0)[0], 30);
+    }
+
+    #[test]
+    fn defect_correct_uses_kernel_size() {
+        let mut image = image_from(5, 5, (0..25).map(|v| v * 10).collect());
+        image.put_pixel(2, 2, Luma([9999]));
+        let block: Vec<(u32, u32)> = (1..4).flat_map(|x| (1..4).map(move |y| (x, y))).collect();
+        let defects = defect_map_with(5, 5, &block);
+
+        // A 3x3 kernel around the centre only sees defective pixels
+        let mut small_kernel = image.clone();
+        defect_correct(
+            &mut small_kernel,
+            &defects,
+            &DefectCorrectionSettings::default(),
+        )
+        .unwrap();
+        assert_eq!(small_kernel.get_pixel(2, 2)[0], 9999);
+
+        let settings = DefectCorrectionSettings {
+            kernel_size: 5,
+            ..Default::default()
+        };
+        defect_correct(&mut image, &defects, &settings).unwrap();
+        // Median of the 16 border pixels
+        assert_eq!(image.get_pixel(2, 2)[0], 120);
+    }
+
+    #[test]
+    fn defect_correct_interpolates_across_column_defect() {
+        let (width, height) = (5, 12);
+        let mut image = image_from(
+            width,
+            height,
+            (0..height)
+                .flat_map(|_| [100, 200, 9000, 400, 500])
+                .collect(),
+        );
+        let column: Vec<(u32, u32)> = (0..height).map(|y| (2, y)).collect();
+        let defects = defect_map_with(width, height, &column);
+
+        defect_correct(&mut image, &defects, &DefectCorrectionSettings::default()).unwrap();
+
+        for y in 0..height {
+            assert_eq!(image.get_pixel(2, y)[0], 300);
         }
-        println!("{}", exe_dir.display());
-        run_defect_map_gen(&images_dir, &exe_dir);
+    }
+
+    #[test]
+    fn defect_correct_interpolates_across_wide_row_defect() {
+        let (width, height) = (10, 6);
+        let mut image = image_from(
+            width,
+            height,
+            (0..height)
+                .flat_map(|y| std::iter::repeat(y as u16 * 100).take(width as usize))
+                .collect(),
+        );
+        let rows: Vec<(u32, u32)> = (0..width).flat_map(|x| [(x, 2), (x, 3)]).collect();
+        let defects = defect_map_with(width, height, &rows);
+
+        defect_correct(&mut image, &defects, &DefectCorrectionSettings::default()).unwrap();
+
+        // Weighted by distance to the good rows at y = 1 and y = 4
+        for x in 0..width {
+            assert_eq!(image.get_pixel(x, 2)[0], 200);
+            assert_eq!(image.get_pixel(x, 3)[0], 300);
+        }
+    }
+
+    #[test]
+    fn defect_correct_uses_min_line_length() {
+        #[rustfmt::skip]
+        let data = vec![
+            10, 10, 10, 10,
+            0,  0,  90, 90,
+            10, 10, 10, 10,
+        ];
+        let defects = defect_map_with(4, 3, &[(0, 1), (1, 1)]);
+
+        // A run of two is below the line length, so the kernel mean picks up the bright pixel
+        let mut image = image_from(4, 3, data.clone());
+        let settings = DefectCorrectionSettings {
+            replacement: DefectReplacement::Mean,
+            min_line_length: 3,
+            ..Default::default()
+        };
+        defect_correct(&mut image, &defects, &settings).unwrap();
+        assert_eq!(image.get_pixel(1, 1)[0], 21);
+
+        // As a line defect it is interpolated from the rows above and below
+        let mut image = image_from(4, 3, data);
+        let settings = DefectCorrectionSettings {
+            min_line_length: 2,
+            ..settings
+        };
+        defect_correct(&mut image, &defects, &settings).unwrap();
+        assert_eq!(image.get_pixel(0, 1)[0], 10);
+        assert_eq!(image.get_pixel(1, 1)[0], 10);
+    }
+
+    #[test]
+    fn defect_correct_leaves_isolated_pixels_without_good_neighbours() {
+        let mut image = image_from(2, 1, vec![7, 8]);
+        let defects = defect_map_with(2, 1, &[(0, 0), (1, 0)]);
+        let settings = DefectCorrectionSettings {
+            line_defects: false,
+            ..Default::default()
+        };
+
+        defect_correct(&mut image, &defects, &settings).unwrap();
+
+        assert_eq!(image.into_raw(), vec![7, 8]);
+    }
+
+    #[test]
+    fn destripe_from_reference_columns() {
+        // The first two columns are masked, the third row reads 40 high across the whole frame
+        #[rustfmt::skip]
+        let data = vec![
+            100, 100, 500, 900,
+            101, 99,  520, 880,
+            140, 140, 540, 940,
+            100, 100, 510, 910,
+        ];
+        let mut image = image_from(4, 4, data);
+        let settings = DestripeSettings {
+            columns: false,
+            row_reference: Some(LineRange { start: 0, end: 2 }),
+            ..Default::default()
+        };
+
+        let offsets = destripe(&mut image, &settings).unwrap();
+
+        assert_eq!(offsets.rows, vec![0.0, 0.0, 40.0, 0.0]);
+        assert_eq!(offsets.columns, vec![0.0; 4]);
+        assert_eq!(image.get_pixel(2, 2)[0], 500);
+        assert_eq!(image.get_pixel(3, 2)[0], 900);
+        assert_eq!(image.get_pixel(3, 1)[0], 880);
+    }
+
+    #[test]
+    fn destripe_keeps_gradients_without_a_reference() {
+        // A smooth vertical gradient with one bright column
+        let (width, height) = (9, 6);
+        let data = (0..height)
+            .flat_map(|y| {
+                (0..width).map(move |x| (1000 + y * 50 + if x == 4 { 30 } else { 0 }) as u16)
+            })
+            .collect();
+        let mut image = image_from(width, height, data);
+        let settings = DestripeSettings {
+            neighbours: 2,
+            ..Default::default()
+        };
+
+        let offsets = destripe(&mut image, &settings).unwrap();
+
+        assert_eq!(offsets.columns[4], 30.0);
+        for y in 0..height {
+            for x in 0..width {
+                assert_eq!(image.get_pixel(x, y)[0], 1000 + y as u16 * 50);
+            }
+        }
+    }
+
+    #[test]
+    fn destripe_rejects_reference_outside_image() {
+        let mut image = image_from(2, 2, vec![1, 2, 3, 4]);
+        let settings = DestripeSettings {
+            row_reference: Some(LineRange { start: 1, end: 3 }),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            destripe(&mut image, &settings),
+            Err(CorrectionError::InvalidReferenceLines(1, 3, 2))
+        ));
     }
 }
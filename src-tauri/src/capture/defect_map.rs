@@ -0,0 +1,718 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashSet, VecDeque},
+    fs::File,
+    io::BufWriter,
+    path::Path,
+};
+
+use image::{ImageBuffer, Luma};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use super::corrections::CorrectionError;
+
+pub const DEFECT_MAP_FILE_NAME: &str = "GlobalDefectMap.tif";
+pub const DEFECT_MAP_SUMMARY_FILE_NAME: &str = "GlobalDefectMap.json";
+
+// Scales the median absolute deviation to a standard deviation for normally distributed data
+const MAD_TO_SIGMA: f32 = 1.4826;
+// Stops thresholds collapsing onto the median for very clean or quantised data
+const MIN_SIGMA: f32 = 1.0;
+
+#[derive(Clone, Debug, Serialize, Deserialize, Type, PartialEq)]
+pub struct DefectMapSettings {
+    // Dark mean above the median by this many robust standard deviations
+    pub hot_sigma: f32,
+    // Flat response below this fraction of the median response
+    pub dead_fraction: f32,
+    // Temporal noise above the median by this many robust standard deviations
+    pub noisy_sigma: f32,
+    // Residual of a linear fit over exposure time above the median by this many robust standard deviations
+    pub non_linear_sigma: f32,
+    // Fraction of defective pixels at which a whole row or column is marked defective
+    pub line_fraction: f32,
+    // Row or column median offset from its neighbours by this many robust standard deviations
+    pub line_offset_sigma: f32,
+    // Minimum number of connected defective pixels reported as a cluster
+    pub min_cluster_size: u32,
+}
+
+impl Default for DefectMapSettings {
+    fn default() -> Self {
+        DefectMapSettings {
+            hot_sigma: 6.0,
+            dead_fraction: 0.5,
+            noisy_sigma: 6.0,
+            non_linear_sigma: 8.0,
+            line_fraction: 0.25,
+            line_offset_sigma: 8.0,
+            min_cluster_size: 5,
+        }
+    }
+}
+
+// Per-pixel mean and temporal standard deviation of a stack of frames taken at one exposure
+#[derive(Clone, Debug)]
+pub struct StackStatistics {
+    pub width: u32,
+    pub height: u32,
+    pub exp_time: u32,
+    pub frame_count: u32,
+    pub mean: Vec<f32>,
+    pub std_dev: Vec<f32>,
+}
+
+// Accumulates stack statistics a frame at a time so the frames don't need to be kept in memory
+pub struct StackAccumulator {
+    width: u32,
+    height: u32,
+    exp_time: u32,
+    frame_count: u32,
+    mean: Vec<f64>,
+    m2: Vec<f64>,
+}
+
+impl StackAccumulator {
+    pub fn new(width: u32, height: u32, exp_time: u32) -> Self {
+        let len = (width * height) as usize;
+        StackAccumulator {
+            width,
+            height,
+            exp_time,
+            frame_count: 0,
+            mean: vec![0.0; len],
+            m2: vec![0.0; len],
+        }
+    }
+
+    pub fn add(&mut self, frame: &ImageBuffer<Luma<u16>, Vec<u16>>) -> Result<(), CorrectionError> {
+        if frame.dimensions() != (self.width, self.height) {
+            return Err(CorrectionError::DimensionMismatch(
+                frame.width(),
+                frame.height(),
+                self.width,
+                self.height,
+            ));
+        }
+
+        // Welford's online mean and variance
+        self.frame_count += 1;
+        let n = self.frame_count as f64;
+        for ((mean, m2), &value) in self
+            .mean
+            .iter_mut()
+            .zip(self.m2.iter_mut())
+            .zip(frame.iter())
+        {
+            let delta = value as f64 - *mean;
+            *mean += delta / n;
+            *m2 += delta * (value as f64 - *mean);
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> StackStatistics {
+        let n = self.frame_count.max(2) as f64 - 1.0;
+        StackStatistics {
+            width: self.width,
+            height: self.height,
+            exp_time: self.exp_time,
+            frame_count: self.frame_count,
+            mean: self.mean.iter().map(|&mean| mean as f32).collect(),
+            std_dev: self.m2.iter().map(|&m2| (m2 / n).sqrt() as f32).collect(),
+        }
+    }
+}
+
+impl StackStatistics {
+    pub fn mean_image(&self) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        ImageBuffer::from_vec(
+            self.width,
+            self.height,
+            self.mean.iter().map(|&mean| mean.round() as u16).collect(),
+        )
+        .unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Type, PartialEq)]
+pub struct DefectCluster {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub pixel_count: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Type)]
+pub struct DefectMapSummary {
+    pub width: u32,
+    pub height: u32,
+    pub hot_pixels: u32,
+    pub dead_pixels: u32,
+    pub noisy_pixels: u32,
+    pub non_linear_pixels: u32,
+    pub defective_rows: Vec<u32>,
+    pub defective_columns: Vec<u32>,
+    pub clusters: Vec<DefectCluster>,
+    // Every pixel set in the map, including those only marked through a row or column
+    pub total_defective_pixels: u32,
+    pub settings: DefectMapSettings,
+}
+
+pub struct DefectMap {
+    // Non-zero pixels are defective, matching the GlobalDefectMap.tif written by the SDK tools
+    pub map: ImageBuffer<Luma<u16>, Vec<u16>>,
+    pub summary: DefectMapSummary,
+}
+
+impl DefectMap {
+    pub fn save(&self, dir: &Path) -> Result<(), CorrectionError> {
+        let map_path = dir.join(DEFECT_MAP_FILE_NAME);
+        self.map
+            .save(&map_path)
+            .map_err(|e| CorrectionError::WriteFailed(format!("{}: {e}", map_path.display())))?;
+
+        let summary_path = dir.join(DEFECT_MAP_SUMMARY_FILE_NAME);
+        let file = File::create(&summary_path).map_err(|e| {
+            CorrectionError::WriteFailed(format!("{}: {e}", summary_path.display()))
+        })?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &self.summary)
+            .map_err(|e| CorrectionError::WriteFailed(format!("{}: {e}", summary_path.display())))
+    }
+}
+
+// Each inner slice of `dark_series` and `flat_series` is one exposure sweep taken under the
+// same detector settings, e.g. a full well mode. Linearity is only checked for sweeps of at
+// least three exposures, and dead pixels in flats need a dark at the same exposure in the sweep
+// with the same index, otherwise the raw flat is used.
+pub fn generate_defect_map(
+    dark_series: &[Vec<StackStatistics>],
+    flat_series: &[Vec<StackStatistics>],
+    settings: &DefectMapSettings,
+) -> Result<DefectMap, CorrectionError> {
+    let first = dark_series
+        .iter()
+        .chain(flat_series.iter())
+        .flatten()
+        .next()
+        .ok_or(CorrectionError::NoFrames)?;
+    let (width, height) = (first.width, first.height);
+
+    for stack in dark_series.iter().chain(flat_series.iter()).flatten() {
+        if (stack.width, stack.height) != (width, height) {
+            return Err(CorrectionError::DimensionMismatch(
+                stack.width,
+                stack.height,
+                width,
+                height,
+            ));
+        }
+    }
+
+    let len = (width * height) as usize;
+    let mut hot = vec![false; len];
+    let mut dead = vec![false; len];
+    let mut noisy = vec![false; len];
+    let mut non_linear = vec![false; len];
+
+    for stack in dark_series.iter().flatten() {
+        let hot_threshold = upper_threshold(&stack.mean, settings.hot_sigma);
+        let noise_threshold = upper_threshold(&stack.std_dev, settings.noisy_sigma);
+
+        for i in 0..len {
+            hot[i] |= stack.mean[i] > hot_threshold;
+            noisy[i] |= stack.std_dev[i] > noise_threshold;
+            // A pixel that doesn't even show read noise is stuck
+            dead[i] |= stack.frame_count > 1 && stack.std_dev[i] == 0.0;
+        }
+    }
+
+    for (series_idx, series) in flat_series.iter().enumerate() {
+        for stack in series {
+            let dark = dark_series
+                .get(series_idx)
+                .and_then(|darks| darks.iter().find(|dark| dark.exp_time == stack.exp_time));
+            let response: Vec<f32> = match dark {
+                Some(dark) => stack
+                    .mean
+                    .iter()
+                    .zip(dark.mean.iter())
+                    .map(|(flat, dark)| flat - dark)
+                    .collect(),
+                None => stack.mean.clone(),
+            };
+
+            let dead_threshold = median(&response) * settings.dead_fraction;
+            for i in 0..len {
+                dead[i] |= response[i] < dead_threshold;
+            }
+        }
+    }
+
+    for series in dark_series.iter().chain(flat_series.iter()) {
+        if series.len() < 3 {
+            continue;
+        }
+
+        let residuals = linear_fit_residuals(series, len);
+        let threshold = upper_threshold(&residuals, settings.non_linear_sigma);
+        for i in 0..len {
+            non_linear[i] |= residuals[i] > threshold;
+        }
+    }
+
+    let mut defective: Vec<bool> = (0..len)
+        .map(|i| hot[i] || dead[i] || noisy[i] || non_linear[i])
+        .collect();
+
+    // Lines are judged on the longest dark exposure, where offsets are most visible
+    let reference = dark_series
+        .iter()
+        .flatten()
+        .max_by_key(|stack| stack.exp_time)
+        .or(first_of(flat_series));
+
+    let defective_rows = find_defective_lines(&defective, reference, width, height, true, settings);
+    let defective_columns =
+        find_defective_lines(&defective, reference, width, height, false, settings);
+
+    let in_line = |x: u32, y: u32| {
+        defective_rows.binary_search(&y).is_ok() || defective_columns.binary_search(&x).is_ok()
+    };
+    let clusters = find_clusters(
+        &defective,
+        width,
+        height,
+        &in_line,
+        settings.min_cluster_size,
+    );
+
+    for &y in &defective_rows {
+        for x in 0..width {
+            defective[(y * width + x) as usize] = true;
+        }
+    }
+    for &x in &defective_columns {
+        for y in 0..height {
+            defective[(y * width + x) as usize] = true;
+        }
+    }
+
+    let count = |flags: &[bool]| flags.iter().filter(|&&flag| flag).count() as u32;
+    let summary = DefectMapSummary {
+        width,
+        height,
+        hot_pixels: count(&hot),
+        dead_pixels: count(&dead),
+        noisy_pixels: count(&noisy),
+        non_linear_pixels: count(&non_linear),
+        defective_rows,
+        defective_columns,
+        clusters,
+        total_defective_pixels: count(&defective),
+        settings: settings.clone(),
+    };
+
+    let map = ImageBuffer::from_vec(
+        width,
+        height,
+        defective.iter().map(|&defect| defect as u16).collect(),
+    )
+    .unwrap();
+
+    Ok(DefectMap { map, summary })
+}
+
+fn first_of(series: &[Vec<StackStatistics>]) -> Option<&StackStatistics> {
+    series.iter().flatten().next()
+}
+
+fn median(values: &[f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+
+    let mut sorted = values.to_vec();
+    let mid = sorted.len() / 2;
+    let (_, median, _) =
+        sorted.select_nth_unstable_by(mid, |a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    *median
+}
+
+// Median plus `sigma` robust standard deviations, estimated from the median absolute deviation
+pub fn upper_threshold(values: &[f32], sigma: f32) -> f32 {
+    let median = median(values);
+    let deviations: Vec<f32> = values.iter().map(|value| (value - median).abs()).collect();
+    let robust_sigma = (self::median(&deviations) * MAD_TO_SIGMA).max(MIN_SIGMA);
+    median + sigma * robust_sigma
+}
+
+// RMS residual of a per-pixel least squares fit of mean signal against exposure time
+fn linear_fit_residuals(series: &[StackStatistics], len: usize) -> Vec<f32> {
+    let times: Vec<f32> = series.iter().map(|stack| stack.exp_time as f32).collect();
+    let n = times.len() as f32;
+    let mean_t = times.iter().sum::<f32>() / n;
+    let var_t: f32 = times.iter().map(|t| (t - mean_t).powi(2)).sum();
+
+    (0..len)
+        .map(|i| {
+            let mean_v = series.iter().map(|stack| stack.mean[i]).sum::<f32>() / n;
+            let slope = if var_t > 0.0 {
+                series
+                    .iter()
+                    .zip(times.iter())
+                    .map(|(stack, t)| (t - mean_t) * (stack.mean[i] - mean_v))
+                    .sum::<f32>()
+                    / var_t
+            } else {
+                0.0
+            };
+
+            let sum_sq: f32 = series
+                .iter()
+                .zip(times.iter())
+                .map(|(stack, t)| (stack.mean[i] - (mean_v + slope * (t - mean_t))).powi(2))
+                .sum();
+            (sum_sq / n).sqrt()
+        })
+        .collect()
+}
+
+// Rows (or columns) with too many defective pixels, or whose median signal is offset from the
+// lines either side of it
+fn find_defective_lines(
+    defective: &[bool],
+    reference: Option<&StackStatistics>,
+    width: u32,
+    height: u32,
+    rows: bool,
+    settings: &DefectMapSettings,
+) -> Vec<u32> {
+    let (line_count, line_len) = if rows {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let index = |line: u32, pos: u32| {
+        if rows {
+            (line * width + pos) as usize
+        } else {
+            (pos * width + line) as usize
+        }
+    };
+
+    let mut lines: HashSet<u32> = (0..line_count)
+        .filter(|&line| {
+            let count = (0..line_len)
+                .filter(|&pos| defective[index(line, pos)])
+                .count();
+            count as f32 >= settings.line_fraction * line_len as f32
+        })
+        .collect();
+
+    if let Some(reference) = reference {
+        let line_medians: Vec<f32> = (0..line_count)
+            .map(|line| {
+                let values: Vec<f32> = (0..line_len)
+                    .map(|pos| reference.mean[index(line, pos)])
+                    .collect();
+                median(&values)
+            })
+            .collect();
+
+        let offsets: Vec<f32> = (0..line_count as usize)
+            .map(|line| {
+                let neighbours: Vec<f32> = [
+                    line.wrapping_sub(2),
+                    line.wrapping_sub(1),
+                    line + 1,
+                    line + 2,
+                ]
+                .iter()
+                .filter_map(|&n| line_medians.get(n).copied())
+                .collect();
+                if neighbours.is_empty() {
+                    0.0
+                } else {
+                    (line_medians[line] - median(&neighbours)).abs()
+                }
+            })
+            .collect();
+
+        let threshold = upper_threshold(&offsets, settings.line_offset_sigma);
+        lines.extend((0..line_count).filter(|&line| offsets[line as usize] > threshold));
+    }
+
+    let mut lines: Vec<u32> = lines.into_iter().collect();
+    lines.sort();
+    lines
+}
+
+// 8-connected groups of defective pixels outside of line defects
+fn find_clusters(
+    defective: &[bool],
+    width: u32,
+    height: u32,
+    in_line: &dyn Fn(u32, u32) -> bool,
+    min_cluster_size: u32,
+) -> Vec<DefectCluster> {
+    let mut visited = vec![false; defective.len()];
+    let mut clusters = Vec::new();
+    let mut queue = VecDeque::new();
+
+    for start in 0..defective.len() {
+        let (sx, sy) = (start as u32 % width, start as u32 / width);
+        if visited[start] || !defective[start] || in_line(sx, sy) {
+            continue;
+        }
+
+        visited[start] = true;
+        queue.push_back((sx, sy));
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (sx, sy, sx, sy);
+        let mut pixel_count = 0;
+
+        while let Some((x, y)) = queue.pop_front() {
+            pixel_count += 1;
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+
+            for dy in -1i64..=1 {
+                for dx in -1i64..=1 {
+                    let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                    if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+                        continue;
+                    }
+                    let (nx, ny) = (nx as u32, ny as u32);
+                    let idx = (ny * width + nx) as usize;
+                    if !visited[idx] && defective[idx] && !in_line(nx, ny) {
+                        visited[idx] = true;
+                        queue.push_back((nx, ny));
+                    }
+                }
+            }
+        }
+
+        if pixel_count >= min_cluster_size {
+            clusters.push(DefectCluster {
+                x: min_x,
+                y: min_y,
+                width: max_x - min_x + 1,
+                height: max_y - min_y + 1,
+                pixel_count,
+            });
+        }
+    }
+
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Luma};
+
+    use super::{
+        generate_defect_map, DefectCluster, DefectMapSettings, DefectMapSummary, StackAccumulator,
+        StackStatistics, DEFECT_MAP_FILE_NAME, DEFECT_MAP_SUMMARY_FILE_NAME,
+    };
+
+    const WIDTH: u32 = 32;
+    const HEIGHT: u32 = 24;
+
+    // Deterministic pseudo-noise so thresholds have a realistic spread to work with
+    fn noise(i: usize, frame: u32) -> f32 {
+        let v = (i as u32).wrapping_mul(2654435761) ^ frame.wrapping_mul(40503);
+        (v % 7) as f32 - 3.0
+    }
+
+    fn dark_stack(exp_time: u32, edit: impl Fn(usize, f32, u32) -> f32) -> StackStatistics {
+        let mut accumulator = StackAccumulator::new(WIDTH, HEIGHT, exp_time);
+        for frame in 0..8 {
+            let data = (0..(WIDTH * HEIGHT) as usize)
+                .map(|i| {
+                    let value = 300.0 + exp_time as f32 * 0.1 + noise(i, frame);
+                    edit(i, value, frame).round() as u16
+                })
+                .collect();
+            accumulator
+                .add(&ImageBuffer::from_vec(WIDTH, HEIGHT, data).unwrap())
+                .unwrap();
+        }
+        accumulator.finish()
+    }
+
+    fn idx(x: u32, y: u32) -> usize {
+        (y * WIDTH + x) as usize
+    }
+
+    fn is_defect(map: &ImageBuffer<Luma<u16>, Vec<u16>>, x: u32, y: u32) -> bool {
+        map.get_pixel(x, y)[0] != 0
+    }
+
+    #[test]
+    fn accumulator_computes_mean_and_std() {
+        let mut accumulator = StackAccumulator::new(2, 1, 100);
+        for frame in [[10, 5], [20, 5], [30, 5]] {
+            accumulator
+                .add(&ImageBuffer::from_vec(2, 1, frame.to_vec()).unwrap())
+                .unwrap();
+        }
+        let stats = accumulator.finish();
+
+        assert_eq!(stats.frame_count, 3);
+        assert_eq!(stats.mean, vec![20.0, 5.0]);
+        assert_eq!(stats.std_dev, vec![10.0, 0.0]);
+    }
+
+    #[test]
+    fn classifies_hot_noisy_and_stuck_pixels() {
+        let hot = idx(3, 4);
+        let noisy = idx(10, 10);
+        let stuck = idx(20, 5);
+        let darks = vec![[100, 200]
+            .into_iter()
+            .map(|exp_time| {
+                dark_stack(exp_time, |i, value, frame| match i {
+                    i if i == hot => value + 2000.0,
+                    i if i == noisy => value + if frame % 2 == 0 { 300.0 } else { -300.0 },
+                    i if i == stuck => 50.0,
+                    _ => value,
+                })
+            })
+            .collect::<Vec<_>>()];
+
+        let result = generate_defect_map(&darks, &[], &DefectMapSettings::default()).unwrap();
+
+        assert!(is_defect(&result.map, 3, 4));
+        assert!(is_defect(&result.map, 10, 10));
+        assert!(is_defect(&result.map, 20, 5));
+        assert_eq!(result.summary.hot_pixels, 1);
+        assert_eq!(result.summary.noisy_pixels, 1);
+        assert_eq!(result.summary.dead_pixels, 1);
+        assert_eq!(result.summary.total_defective_pixels, 3);
+        assert!(result.summary.defective_rows.is_empty());
+        assert!(result.summary.defective_columns.is_empty());
+    }
+
+    #[test]
+    fn classifies_dead_pixels_from_flats() {
+        let dead = idx(7, 7);
+        let darks = vec![vec![dark_stack(100, |_, value, _| value)]];
+        let flats = vec![vec![dark_stack(100, |i, value, _| {
+            if i == dead {
+                value + 500.0
+            } else {
+                value + 8000.0
+            }
+        })]];
+
+        let result = generate_defect_map(&darks, &flats, &DefectMapSettings::default()).unwrap();
+
+        assert!(is_defect(&result.map, 7, 7));
+        assert_eq!(result.summary.dead_pixels, 1);
+        assert_eq!(result.summary.total_defective_pixels, 1);
+    }
+
+    #[test]
+    fn classifies_non_linear_pixels() {
+        let non_linear = idx(12, 3);
+        let darks = vec![[100, 200, 300, 400]
+            .into_iter()
+            .map(|exp_time| {
+                dark_stack(exp_time, |i, value, _| {
+                    if i == non_linear {
+                        value + (exp_time as f32 - 250.0).powi(2) * 0.01
+                    } else {
+                        value
+                    }
+                })
+            })
+            .collect::<Vec<_>>()];
+
+        let result = generate_defect_map(&darks, &[], &DefectMapSettings::default()).unwrap();
+
+        assert!(is_defect(&result.map, 12, 3));
+        assert_eq!(result.summary.non_linear_pixels, 1);
+    }
+
+    #[test]
+    fn detects_rows_columns_and_clusters() {
+        let darks = vec![vec![dark_stack(100, |i, value, _| {
+            let (x, y) = (i as u32 % WIDTH, i as u32 / WIDTH);
+            if y == 6 {
+                // Offset row, too small for any individual pixel to be hot
+                value + 5.0 * if x % 2 == 0 { 3.0 } else { 2.0 }
+            } else if x == 17 && y % 2 == 0 {
+                // Half of this column is hot
+                value + 2000.0
+            } else if (25..28).contains(&x) && (15..17).contains(&y) {
+                value + 2000.0
+            } else {
+                value
+            }
+        })]];
+
+        let result = generate_defect_map(&darks, &[], &DefectMapSettings::default()).unwrap();
+
+        assert_eq!(result.summary.defective_rows, vec![6]);
+        assert_eq!(result.summary.defective_columns, vec![17]);
+        assert_eq!(
+            result.summary.clusters,
+            vec![DefectCluster {
+                x: 25,
+                y: 15,
+                width: 3,
+                height: 2,
+                pixel_count: 6,
+            }]
+        );
+        assert!((0..WIDTH).all(|x| is_defect(&result.map, x, 6)));
+        assert!((0..HEIGHT).all(|y| is_defect(&result.map, 17, y)));
+        assert_eq!(
+            result.summary.total_defective_pixels,
+            WIDTH + HEIGHT - 1 + 6
+        );
+    }
+
+    #[test]
+    fn save_writes_map_and_summary() {
+        let darks = vec![vec![dark_stack(100, |i, value, _| {
+            if i == idx(1, 1) {
+                value + 2000.0
+            } else {
+                value
+            }
+        })]];
+        let result = generate_defect_map(&darks, &[], &DefectMapSettings::default()).unwrap();
+
+        let dir =
+            std::env::temp_dir().join(format!("cview_defect_map_save_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        result.save(&dir).unwrap();
+
+        let map = image::open(dir.join(DEFECT_MAP_FILE_NAME))
+            .unwrap()
+            .to_luma16();
+        assert_eq!(map, result.map);
+
+        let summary: DefectMapSummary = serde_json::from_reader(
+            std::fs::File::open(dir.join(DEFECT_MAP_SUMMARY_FILE_NAME)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(summary.hot_pixels, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_mismatched_stacks() {
+        let darks = vec![vec![
+            dark_stack(100, |_, value, _| value),
+            StackAccumulator::new(4, 4, 200).finish(),
+        ]];
+
+        assert!(generate_defect_map(&darks, &[], &DefectMapSettings::default()).is_err());
+    }
+}
@@ -4,20 +4,23 @@ use log::{debug, error, info};
 use serde::Serialize;
 use specta::Type;
 use std::{
-    collections::HashMap,
-    future::{self},
+    io,
+    path::Path,
     pin::Pin,
-    sync::{atomic::AtomicBool, Arc, Mutex},
+    sync::{Arc, Mutex},
     thread,
     time::Duration,
 };
-use tokio_util::sync::CancellationToken;
 
-use crate::wrapper::{SLDeviceRS, SLImageRs};
+use crate::wrapper::{BinningModes, FullWellModes, SLImageRs};
 
 use super::{
+    backend::{DetectorBackend, DetectorIdentity},
     capture::{CaptureError, CaptureSetting},
     capture_manager::CorrectionMaps,
+    correction_pipeline::CorrectionStageKind,
+    corrections::{CorrectionMapKey, DarkCorrectionMethod},
+    replay::SessionRecorder,
 };
 
 // Required for opening camera on a detector
@@ -28,23 +31,39 @@ const HEARTBEAT_REFRESH_TIME_MILLIS: u64 = 100;
 pub enum DetectorStatus {
     Available,
     Disconnected,
+    // Part of the status the frontend handles, not reported by the backend yet
+    #[allow(dead_code)]
     Capturing,
 }
 
+// A frame read from the detector along with how it was corrected
+pub struct CapturedFrame {
+    pub image: SLImageRs,
+    pub dark_correction: Option<DarkCorrectionMethod>,
+    pub correction_map_ids: Vec<String>,
+    pub correction_stages: Vec<CorrectionStageKind>,
+}
+
+// Frames of one capture, ending with the error if it couldn't finish
+pub type CapturedFrameStream =
+    Pin<Box<dyn Stream<Item = Result<CapturedFrame, CaptureError>> + Send>>;
+
 #[derive(Clone)]
-pub struct DetectorController {
-    detector: SLDeviceRS,
+pub struct DetectorController<D: DetectorBackend + Clone + 'static> {
+    detector: D,
     detector_status: Arc<Mutex<DetectorStatus>>,
+    recorder: Arc<Mutex<Option<SessionRecorder>>>,
 }
 
-impl DetectorController {
-    pub fn new<F>(heartbeat_callback: F) -> Self
+impl<D: DetectorBackend + Clone + 'static> DetectorController<D> {
+    pub fn new<F>(detector: D, heartbeat_callback: F) -> Self
     where
         F: FnMut(DetectorStatus) + Send + 'static,
     {
         let controller = DetectorController {
-            detector: SLDeviceRS::new(),
+            detector,
             detector_status: Arc::new(Mutex::new(DetectorStatus::Disconnected)),
+            recorder: Arc::new(Mutex::new(None)),
         };
 
         Self::launch_heartbeat_thread::<F>(
@@ -60,82 +79,167 @@ impl DetectorController {
         &mut self,
         capture_settings: CaptureSetting,
         correction_maps: CorrectionMaps,
-    ) -> Pin<Box<dyn Stream<Item = SLImageRs> + Send>> {
-        let stream = capture_settings
-            .capture_mode
-            .stream_results(capture_settings.exp_time, self.detector.clone());
-
-        stream
-            .unwrap()
-            .map(move |mut image| {
-                if capture_settings.corrected {
-                    if correction_maps
-                        .dark_correct_image(&mut image, capture_settings.exp_time)
-                        .is_ok()
-                    {
-                        info!("Dark Correction Successful");
-                    } else {
-                        error!("Couldn't access dark map");
-                    }
+    ) -> Result<CapturedFrameStream, CaptureError> {
+        self.apply_settings(&capture_settings)?;
 
-                    if let Ok(corrected_image) =
-                        correction_maps.defect_correct_image(&mut image, capture_settings.exp_time)
-                    {
-                        return corrected_image;
-                    } else {
-                        return image;
+        let recorder = self.recorder.clone();
+        if let Some(session_recorder) = recorder.lock().unwrap().as_mut() {
+            if let Err(e) = session_recorder.begin_capture(&capture_settings) {
+                error!("Failed to record capture settings: {e}");
+            }
+        }
+
+        let correction_key = CorrectionMapKey::from_capture_setting(&capture_settings);
+        let mut pipeline = capture_settings.corrections.build(&correction_maps);
+        let stream = capture_settings.capture_mode.stream_results(
+            capture_settings.exp_time,
+            capture_settings.read_policy,
+            self.detector.clone_box(),
+        )?;
+
+        Ok(stream
+            .map(move |image| {
+                let mut image = image?;
+                if let Some(session_recorder) = recorder.lock().unwrap().as_mut() {
+                    if let Err(e) = session_recorder.record_frame(&mut image) {
+                        error!("Failed to record frame: {e}");
                     }
+                }
+
+                if !pipeline.is_empty() {
+                    let mut buffer = image.to_image_buffer();
+                    let applied = pipeline.run(
+                        &mut buffer,
+                        &correction_maps,
+                        &correction_key,
+                        capture_settings.roi,
+                    );
+
+                    Ok(CapturedFrame {
+                        image: SLImageRs::from_image_buffer(&buffer),
+                        dark_correction: applied.dark_correction,
+                        correction_map_ids: applied.correction_map_ids,
+                        correction_stages: applied.stages,
+                    })
                 } else {
-                    image
+                    Ok(CapturedFrame {
+                        image,
+                        dark_correction: None,
+                        correction_map_ids: Vec::new(),
+                        correction_stages: Vec::new(),
+                    })
                 }
             })
-            .boxed()
+            .boxed())
+    }
+
+    // Everything in the settings besides the exposure, which the capture mode sets up
+    fn apply_settings(&mut self, capture_settings: &CaptureSetting) -> Result<(), CaptureError> {
+        if matches!(capture_settings.full_well.remote_ty, FullWellModes::Unknown) {
+            return Err(CaptureError::UnsupportedSetting(
+                "an unknown full well mode".to_string(),
+            ));
+        }
+        if matches!(
+            capture_settings.binning_mode.0,
+            BinningModes::BinningUnknown
+        ) {
+            return Err(CaptureError::UnsupportedSetting(
+                "an unknown binning mode".to_string(),
+            ));
+        }
+
+        let full_well = capture_settings.full_well.clone();
+        self.detector
+            .set_full_well(full_well.clone())
+            .map_err(|e| CaptureError::SettingRejected(format!("{full_well} full well"), e))?;
+
+        let binning_mode = capture_settings.binning_mode.clone();
+        self.detector
+            .set_binning_mode(binning_mode.clone())
+            .map_err(|e| CaptureError::SettingRejected(format!("{binning_mode:?} binning"), e))?;
+
+        self.detector.set_dds(capture_settings.dds).map_err(|e| {
+            CaptureError::SettingRejected(format!("DDS {}", capture_settings.dds), e)
+        })?;
+
+        // Set last, as it's relative to the binned frame
+        self.detector.set_roi(capture_settings.roi).map_err(|e| {
+            CaptureError::SettingRejected(format!("ROI {:?}", capture_settings.roi), e)
+        })?;
+
+        debug!(
+            "Applied {:?} binning, {} full well, DDS {} and ROI {:?}",
+            capture_settings.binning_mode,
+            capture_settings.full_well,
+            capture_settings.dds,
+            capture_settings.roi
+        );
+        Ok(())
+    }
+
+    pub fn detector_identity(&mut self) -> Option<DetectorIdentity> {
+        self.detector.identity().ok()
     }
 
     pub fn stop_capture(&mut self) {
-        self.detector.go_unlive(true);
+        if let Err(e) = self.detector.go_unlive(true) {
+            error!("Failed to stop capture: {e:?}");
+        }
+    }
+
+    // Record every raw frame read from the detector, before corrections, to a replay file
+    pub fn start_recording(&self, path: &Path) -> io::Result<()> {
+        let session_recorder = SessionRecorder::create(path)?;
+        if let Some(previous) = self.recorder.lock().unwrap().replace(session_recorder) {
+            previous.finish()?;
+        }
+        Ok(())
+    }
+
+    pub fn stop_recording(&self) -> io::Result<()> {
+        match self.recorder.lock().unwrap().take() {
+            Some(session_recorder) => session_recorder.finish(),
+            None => Ok(()),
+        }
     }
 
     fn launch_heartbeat_thread<F>(
-        mut detector: SLDeviceRS,
+        mut detector: D,
         detector_status_mutex: Arc<Mutex<DetectorStatus>>,
         mut heartbeat_callback: F,
     ) where
         F: FnMut(DetectorStatus) + Send + 'static,
     {
         info!("Launching heartbeat thread");
-        tauri::async_runtime::spawn(async move {
-            loop {
-                thread::sleep(Duration::from_millis(HEARTBEAT_REFRESH_TIME_MILLIS));
-
-                let mut detector_status = detector_status_mutex.lock().unwrap();
-
-                match *detector_status {
-                    DetectorStatus::Disconnected => match detector.open_camera(BUFFER_DEPTH) {
-                        Ok(_) => {
-                            info!("Connected to device");
-                            *detector_status = DetectorStatus::Available;
-                        }
-                        Err(e) => debug!("Error opening camera"),
-                    },
-                    DetectorStatus::Available | DetectorStatus::Capturing => {
-                        if !detector.is_connected() {
-                            info!("Disconnected from device");
-                            *detector_status = DetectorStatus::Disconnected;
-                        }
+        // A plain thread, as the polling sleeps would otherwise block an async runtime worker
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(HEARTBEAT_REFRESH_TIME_MILLIS));
+
+            let mut detector_status = detector_status_mutex.lock().unwrap();
+
+            match *detector_status {
+                DetectorStatus::Disconnected => match detector.open_camera(BUFFER_DEPTH) {
+                    Ok(_) => {
+                        info!("Connected to device");
+                        *detector_status = DetectorStatus::Available;
+                    }
+                    Err(_) => debug!("Error opening camera"),
+                },
+                DetectorStatus::Available | DetectorStatus::Capturing => {
+                    if !detector.is_connected() {
+                        info!("Disconnected from device");
+                        *detector_status = DetectorStatus::Disconnected;
                     }
                 }
-
-                heartbeat_callback(detector_status.clone());
             }
+
+            heartbeat_callback(detector_status.clone());
         });
     }
 }
 
-unsafe impl Send for DetectorController {}
-unsafe impl Sync for DetectorController {}
-
-#[cfg(test)]
+#[cfg(all(test, feature = "spectrum-logic"))]
 mod tests {
     use crate::capture::types::AdvCapture;
     use crate::wrapper::{ExposureModes, SLDeviceRS, SLImageRs};
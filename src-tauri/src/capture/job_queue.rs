@@ -0,0 +1,607 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{AppHandle, Manager};
+use tauri_specta::Event;
+
+use crate::{ImageService, StreamBuffer};
+
+use super::{
+    advanced_capture::DarkMapCapture,
+    capture::CaptureError,
+    capture_manager::CaptureManager,
+    commands::execute_capture,
+    types::{AdvancedCapture, CaptureManagerStatus, CaptureOutcome},
+};
+
+pub const JOB_QUEUE_FILE_NAME: &str = "CaptureQueue.json";
+
+const SCHEDULER_POLL_MILLIS: u64 = 1000;
+// Stale calibration isn't queued again straight after a recalibration job failed
+const RECALIBRATION_RETRY_MINUTES: i64 = 10;
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Type, PartialEq, Eq, PartialOrd, Ord)]
+pub enum JobPriority {
+    Low,
+    Normal,
+    High,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
+#[serde(tag = "type")]
+pub enum JobState {
+    Queued,
+    Running,
+    Completed,
+    Cancelled,
+    Failed { message: String },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Type)]
+pub struct JobRequest {
+    pub capture: AdvancedCapture,
+    pub priority: JobPriority,
+    pub start_at: Option<DateTime<Utc>>,
+    pub repeat_every_secs: Option<u32>,
+    pub save_capture: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
+pub struct CaptureJob {
+    pub id: u32,
+    pub capture: AdvancedCapture,
+    pub priority: JobPriority,
+    // Not started before this, if set
+    pub start_at: Option<DateTime<Utc>>,
+    // Queued again this long after each run started, until it's cancelled
+    pub repeat_every_secs: Option<u32>,
+    pub save_capture: bool,
+    // Queued by the scheduler because the calibration went stale
+    pub recalibration: bool,
+    pub started_at: Option<DateTime<Utc>>,
+    pub state: JobState,
+}
+
+// Dark maps that are missing or stale are captured again ahead of the queued jobs,
+// at `exp_times` or the exposures of the dark maps already loaded when empty
+#[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
+pub struct RecalibrationSettings {
+    pub enabled: bool,
+    pub exp_times: Vec<u32>,
+    pub frames_per_capture: u32,
+}
+
+impl Default for RecalibrationSettings {
+    fn default() -> Self {
+        RecalibrationSettings {
+            enabled: false,
+            exp_times: Vec::new(),
+            frames_per_capture: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Type, Event)]
+pub struct CaptureJobEvent(pub CaptureJob);
+
+// Jobs in the order they run once due, saved on every change so the queue survives a restart.
+// Finished jobs are dropped from the queue, apart from repeating ones which are queued again.
+#[derive(Serialize, Deserialize, Default)]
+pub struct JobQueue {
+    jobs: Vec<CaptureJob>,
+    next_id: u32,
+    #[serde(default)]
+    recalibration: RecalibrationSettings,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl JobQueue {
+    // A job that was running when the app closed is run again from the start
+    pub fn load(path: &Path) -> Self {
+        let mut queue: JobQueue = match File::open(path) {
+            Ok(file) => match serde_json::from_reader(BufReader::new(file)) {
+                Ok(queue) => queue,
+                Err(e) => {
+                    error!("Failed to read capture queue from {}: {e}", path.display());
+                    JobQueue::default()
+                }
+            },
+            Err(_) => JobQueue::default(),
+        };
+
+        for job in queue.jobs.iter_mut() {
+            if job.state == JobState::Running {
+                job.state = JobState::Queued;
+            }
+        }
+        queue.path = Some(path.to_path_buf());
+        queue
+    }
+
+    pub fn jobs(&self) -> Vec<CaptureJob> {
+        self.jobs.clone()
+    }
+
+    pub fn job(&self, id: u32) -> Option<CaptureJob> {
+        self.jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    pub fn recalibration(&self) -> &RecalibrationSettings {
+        &self.recalibration
+    }
+
+    pub fn set_recalibration(&mut self, settings: RecalibrationSettings) {
+        self.recalibration = settings;
+        self.save();
+    }
+
+    // Only captures that end by themselves can be queued, or the rest of the queue would never run
+    pub fn push(&mut self, request: JobRequest) -> Result<CaptureJob, CaptureError> {
+        if !request.capture.terminates() {
+            return Err(CaptureError::CaptureNeverEnds);
+        }
+
+        let job = CaptureJob {
+            id: self.next_id,
+            capture: request.capture,
+            priority: request.priority,
+            start_at: request.start_at,
+            repeat_every_secs: request.repeat_every_secs,
+            save_capture: request.save_capture,
+            recalibration: false,
+            started_at: None,
+            state: JobState::Queued,
+        };
+        self.next_id += 1;
+        self.insert(job.clone());
+        self.save();
+        Ok(job)
+    }
+
+    // Queued ahead of everything but other recalibration jobs, unless the same capture is
+    // already waiting
+    pub fn push_recalibration(&mut self, capture: AdvancedCapture) -> Option<CaptureJob> {
+        if self
+            .jobs
+            .iter()
+            .any(|job| job.recalibration && job.capture == capture)
+        {
+            return None;
+        }
+
+        let job = CaptureJob {
+            id: self.next_id,
+            capture,
+            priority: JobPriority::High,
+            start_at: None,
+            repeat_every_secs: None,
+            save_capture: false,
+            recalibration: true,
+            started_at: None,
+            state: JobState::Queued,
+        };
+        self.next_id += 1;
+        let index = self.jobs.iter().take_while(|job| job.recalibration).count();
+        self.jobs.insert(index, job.clone());
+        self.save();
+        Some(job)
+    }
+
+    // Moves a job to `position` in the run order, regardless of its priority
+    pub fn move_job(&mut self, id: u32, position: u32) -> Result<(), CaptureError> {
+        let index = self.index(id)?;
+        let job = self.jobs.remove(index);
+        let position = (position as usize).min(self.jobs.len());
+        self.jobs.insert(position, job);
+        self.save();
+        Ok(())
+    }
+
+    // Removes the job, returning it as it was so a running capture can be stopped
+    pub fn cancel(&mut self, id: u32) -> Result<CaptureJob, CaptureError> {
+        let index = self.index(id)?;
+        let job = self.jobs.remove(index);
+        self.save();
+        Ok(job)
+    }
+
+    pub fn has_due_job(&self, now: DateTime<Utc>) -> bool {
+        self.jobs.iter().any(|job| Self::is_due(job, now))
+    }
+
+    // The first due job that `can_start` allows, marked as running. Jobs run one at a time.
+    pub fn start_next(
+        &mut self,
+        now: DateTime<Utc>,
+        can_start: impl Fn(&AdvancedCapture) -> bool,
+    ) -> Option<CaptureJob> {
+        if self.jobs.iter().any(|job| job.state == JobState::Running) {
+            return None;
+        }
+
+        let job = self
+            .jobs
+            .iter_mut()
+            .find(|job| Self::is_due(job, now) && can_start(&job.capture))?;
+        job.state = JobState::Running;
+        job.started_at = Some(now);
+        let job = job.clone();
+        self.save();
+        Some(job)
+    }
+
+    // None if the job was cancelled while it ran. A repeating job keeps its id and is queued
+    // again for its next start unless it was cancelled.
+    pub fn finish(&mut self, id: u32, state: JobState) -> Option<CaptureJob> {
+        let index = self.index(id).ok()?;
+        let mut job = self.jobs.remove(index);
+        job.state = state;
+
+        if let (Some(repeat_every_secs), Some(started_at)) = (job.repeat_every_secs, job.started_at)
+        {
+            if job.state != JobState::Cancelled {
+                self.insert(CaptureJob {
+                    start_at: Some(
+                        started_at + chrono::Duration::seconds(repeat_every_secs as i64),
+                    ),
+                    started_at: None,
+                    state: JobState::Queued,
+                    ..job.clone()
+                });
+            }
+        }
+
+        self.save();
+        Some(job)
+    }
+
+    fn is_due(job: &CaptureJob, now: DateTime<Utc>) -> bool {
+        job.state == JobState::Queued && job.start_at.map_or(true, |start_at| start_at <= now)
+    }
+
+    // After every job of the same or a higher priority
+    fn insert(&mut self, job: CaptureJob) {
+        let index = self
+            .jobs
+            .iter()
+            .rposition(|queued| queued.priority >= job.priority)
+            .map_or(0, |index| index + 1);
+        self.jobs.insert(index, job);
+    }
+
+    fn index(&self, id: u32) -> Result<usize, CaptureError> {
+        self.jobs
+            .iter()
+            .position(|job| job.id == id)
+            .ok_or(CaptureError::JobNotFound(id))
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        // Written alongside and moved over the old queue, so a crash part way through a write
+        // can't leave a truncated queue behind
+        let staged_path = staged_file(path);
+        let result = File::create(&staged_path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                serde_json::to_writer_pretty(&mut writer, self).map_err(|e| e.to_string())?;
+                writer.flush().map_err(|e| e.to_string())?;
+                writer.get_ref().sync_all().map_err(|e| e.to_string())
+            })
+            .and_then(|()| fs::rename(&staged_path, path).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&staged_path);
+            error!("Failed to save capture queue to {}: {e}", path.display());
+        }
+    }
+}
+
+fn staged_file(path: &Path) -> PathBuf {
+    path.with_extension("tmp")
+}
+
+// Captures that would bring the calibration up to date, for the maps that can be taken without
+// the source. Gain and defect maps need flat frames so are left to the operator.
+fn recalibration_captures(
+    status: &CaptureManagerStatus,
+    settings: &RecalibrationSettings,
+    loaded_exp_times: Vec<u32>,
+) -> Vec<AdvancedCapture> {
+    let dark_maps = match status {
+        CaptureManagerStatus::DarkMapsRequired => true,
+        CaptureManagerStatus::CalibrationStale(stale_maps) => stale_maps
+            .iter()
+            .any(|stale| stale.map.starts_with("Dark map")),
+        _ => false,
+    };
+    if !dark_maps {
+        return Vec::new();
+    }
+
+    let exp_times = if settings.exp_times.is_empty() {
+        loaded_exp_times
+    } else {
+        settings.exp_times.clone()
+    };
+    if exp_times.is_empty() {
+        return Vec::new();
+    }
+
+    vec![AdvancedCapture::DarkMapCapture(DarkMapCapture {
+        exp_times,
+        frames_per_capture: settings.frames_per_capture,
+        stack_reduction: Default::default(),
+    })]
+}
+
+pub fn emit_job(app: &AppHandle, job: CaptureJob) {
+    if let Err(e) = CaptureJobEvent(job).emit_all(app) {
+        error!("Failed to emit capture job event {e}");
+    }
+}
+
+// Picks the next job once the capture manager is free, queueing recalibration first if it's
+// enabled and due work is waiting on it
+fn next_job(app: &AppHandle, recalibration_failed_at: Option<DateTime<Utc>>) -> Option<CaptureJob> {
+    let (status, loaded_exp_times) = {
+        let capture_manager = app.state::<Mutex<CaptureManager>>();
+        let capture_manager = capture_manager.lock().unwrap();
+        (
+            capture_manager.status(),
+            capture_manager.dark_map_exp_times(),
+        )
+    };
+
+    let job_queue = app.state::<Mutex<JobQueue>>();
+    let mut job_queue = job_queue.lock().unwrap();
+    let now = Utc::now();
+
+    let retry_after = chrono::Duration::minutes(RECALIBRATION_RETRY_MINUTES);
+    if job_queue.recalibration().enabled
+        && recalibration_failed_at.map_or(true, |failed_at| now - failed_at > retry_after)
+        && job_queue.has_due_job(now)
+    {
+        let captures = recalibration_captures(&status, job_queue.recalibration(), loaded_exp_times);
+        for capture in captures {
+            if let Some(job) = job_queue.push_recalibration(capture) {
+                info!("Queued recalibration job {}", job.id);
+                emit_job(app, job);
+            }
+        }
+    }
+
+    let job = job_queue.start_next(now, |capture| match &status {
+        CaptureManagerStatus::Available
+        | CaptureManagerStatus::CalibrationStale(_)
+        | CaptureManagerStatus::CalibrationUnverified => true,
+        CaptureManagerStatus::DarkMapsRequired | CaptureManagerStatus::DefectMapsRequired => {
+            capture.builds_calibration()
+        }
+        _ => false,
+    })?;
+    emit_job(app, job.clone());
+    Some(job)
+}
+
+async fn run_job(app: &AppHandle, job: CaptureJob) -> JobState {
+    info!("Starting capture job {}", job.id);
+
+    let state = match execute_capture(
+        app,
+        &app.state::<Mutex<ImageService>>(),
+        &app.state::<Mutex<StreamBuffer>>(),
+        &app.state::<Mutex<CaptureManager>>(),
+        job.capture.clone(),
+        job.save_capture,
+    )
+    .await
+    {
+        Ok(CaptureOutcome::Completed) => JobState::Completed,
+        Ok(CaptureOutcome::Cancelled { .. }) => JobState::Cancelled,
+        Err(e) => JobState::Failed {
+            message: e.to_string(),
+        },
+    };
+    info!("Capture job {} finished: {state:?}", job.id);
+
+    let (finished, requeued) = {
+        let job_queue = app.state::<Mutex<JobQueue>>();
+        let mut job_queue = job_queue.lock().unwrap();
+        let finished = job_queue.finish(job.id, state.clone());
+        (finished, job_queue.job(job.id))
+    };
+    if let Some(finished) = finished {
+        emit_job(app, finished);
+    }
+    if let Some(requeued) = requeued {
+        emit_job(app, requeued);
+    }
+
+    state
+}
+
+pub fn launch_scheduler(app: AppHandle) {
+    info!("Launching capture job scheduler");
+    tauri::async_runtime::spawn(async move {
+        let mut recalibration_failed_at = None;
+        loop {
+            tokio::time::sleep(Duration::from_millis(SCHEDULER_POLL_MILLIS)).await;
+
+            let Some(job) = next_job(&app, recalibration_failed_at) else {
+                continue;
+            };
+            let recalibration = job.recalibration;
+            if let JobState::Failed { .. } = run_job(&app, job).await {
+                if recalibration {
+                    recalibration_failed_at = Some(Utc::now());
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use super::{JobPriority, JobQueue, JobRequest, JobState};
+    use crate::capture::{
+        advanced_capture::{DarkMapCapture, LiveCapture, MultiCapture},
+        capture::CaptureError,
+        types::AdvancedCapture,
+    };
+
+    fn request(exp_time: u32, priority: JobPriority) -> JobRequest {
+        JobRequest {
+            capture: AdvancedCapture::MultiCapture(MultiCapture {
+                exp_times: vec![exp_time],
+                frames_per_capture: 1,
+                corrections: Default::default(),
+            }),
+            priority,
+            start_at: None,
+            repeat_every_secs: None,
+            save_capture: false,
+        }
+    }
+
+    fn ids(queue: &JobQueue) -> Vec<u32> {
+        queue.jobs().iter().map(|job| job.id).collect()
+    }
+
+    #[test]
+    fn jobs_run_by_priority_then_order_queued() {
+        let mut queue = JobQueue::default();
+        queue.push(request(100, JobPriority::Normal)).unwrap();
+        queue.push(request(100, JobPriority::Low)).unwrap();
+        queue.push(request(100, JobPriority::High)).unwrap();
+        queue.push(request(100, JobPriority::Normal)).unwrap();
+        assert_eq!(ids(&queue), vec![2, 0, 3, 1]);
+
+        queue.move_job(1, 0).unwrap();
+        assert_eq!(ids(&queue), vec![1, 2, 0, 3]);
+        assert!(queue.move_job(7, 0).is_err());
+
+        let recalibration = AdvancedCapture::DarkMapCapture(DarkMapCapture {
+            exp_times: vec![100],
+            frames_per_capture: 10,
+            stack_reduction: Default::default(),
+        });
+        assert!(queue.push_recalibration(recalibration.clone()).is_some());
+        assert!(queue.push_recalibration(recalibration).is_none());
+        assert_eq!(ids(&queue), vec![4, 1, 2, 0, 3]);
+    }
+
+    #[test]
+    fn live_captures_cant_be_queued() {
+        let mut queue = JobQueue::default();
+        let live = JobRequest {
+            capture: AdvancedCapture::LiveCapture(LiveCapture {
+                exp_time: 100,
+                corrections: Default::default(),
+            }),
+            ..request(100, JobPriority::Normal)
+        };
+
+        assert!(matches!(
+            queue.push(live),
+            Err(CaptureError::CaptureNeverEnds)
+        ));
+        assert!(queue.jobs().is_empty());
+    }
+
+    #[test]
+    fn waits_for_start_time_and_one_job_at_a_time() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 22, 0, 0).unwrap();
+        let mut queue = JobQueue::default();
+        queue
+            .push(JobRequest {
+                start_at: Some(now + Duration::hours(1)),
+                ..request(100, JobPriority::High)
+            })
+            .unwrap();
+        queue.push(request(200, JobPriority::Normal)).unwrap();
+
+        assert_eq!(queue.start_next(now, |_| false), None);
+        let job = queue.start_next(now, |_| true).unwrap();
+        assert_eq!((job.id, job.state), (1, JobState::Running));
+        assert_eq!(queue.start_next(now + Duration::hours(2), |_| true), None);
+
+        queue.finish(1, JobState::Completed);
+        assert_eq!(queue.start_next(now, |_| true), None);
+        assert_eq!(
+            queue
+                .start_next(now + Duration::hours(1), |_| true)
+                .map(|job| job.id),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn repeating_jobs_are_queued_again_until_cancelled() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 22, 0, 0).unwrap();
+        let mut queue = JobQueue::default();
+        queue
+            .push(JobRequest {
+                repeat_every_secs: Some(600),
+                ..request(100, JobPriority::Normal)
+            })
+            .unwrap();
+
+        queue.start_next(now, |_| true).unwrap();
+        let finished = queue.finish(
+            0,
+            JobState::Failed {
+                message: "Detector is disconnected".to_string(),
+            },
+        );
+        assert!(matches!(
+            finished.map(|job| job.state),
+            Some(JobState::Failed { .. })
+        ));
+
+        let requeued = queue.job(0).unwrap();
+        assert_eq!(requeued.state, JobState::Queued);
+        assert_eq!(requeued.start_at, Some(now + Duration::minutes(10)));
+
+        queue
+            .start_next(now + Duration::minutes(10), |_| true)
+            .unwrap();
+        assert_eq!(queue.cancel(0).unwrap().state, JobState::Running);
+        assert_eq!(queue.finish(0, JobState::Cancelled), None);
+        assert!(queue.jobs().is_empty());
+    }
+
+    #[test]
+    fn interrupted_jobs_are_queued_again_on_load() {
+        let path = std::env::temp_dir().join(format!(
+            "cview_capture_queue_test_{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let mut queue = JobQueue::load(&path);
+        queue.push(request(100, JobPriority::Normal)).unwrap();
+        queue.start_next(Utc::now(), |_| true).unwrap();
+
+        let queue = JobQueue::load(&path);
+        assert_eq!(queue.jobs()[0].state, JobState::Queued);
+        assert_eq!(ids(&queue), vec![0]);
+        assert!(!path.with_extension("tmp").exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,330 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use image::{ImageBuffer, Luma};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use super::corrections::{CorrectionError, CorrectionMapKey, MAX_PIXEL_VALUE};
+
+pub const LAG_MODEL_FILE_NAME: &str = "LagModels.json";
+
+// Smallest drop from the bright to the dark level that's treated as the source switching off
+const MIN_LAG_SIGNAL: f32 = 100.0;
+// Frames straight after the edge with more residual than this were still partly exposed
+const MAX_LAG_FRACTION: f32 = 0.25;
+// Residual fractions below this are lost in the noise and left out of the fit
+const MIN_FIT_FRACTION: f32 = 1e-5;
+
+// The fraction of a frame's signal left `k` frames later is `amplitude * decay^k`
+#[derive(Clone, Debug, Serialize, Deserialize, Type, PartialEq)]
+pub struct LagTerm {
+    pub amplitude: f32,
+    pub decay: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Type, PartialEq)]
+pub struct LagModel {
+    pub key: CorrectionMapKey,
+    // Summed to give the residual, slowest decay first
+    pub terms: Vec<LagTerm>,
+    // Residual fraction measured in each frame after the bright one, to check the fit against
+    pub measured: Vec<f32>,
+}
+
+impl LagModel {
+    // Fits `num_terms` exponentials by peeling: the slowest term is fitted to the tail of the
+    // decay, subtracted, and the next fitted to the frames before it
+    pub fn fit(
+        key: CorrectionMapKey,
+        measured: Vec<f32>,
+        num_terms: u32,
+    ) -> Result<Self, CorrectionError> {
+        let num_terms = (num_terms.max(1) as usize).min(measured.len() / 2).max(1);
+        let segment_len = measured.len() / num_terms;
+        let mut remaining = measured.clone();
+        let mut terms = Vec::new();
+
+        for segment in (0..num_terms).rev() {
+            let start = segment * segment_len;
+            let end = if segment == num_terms - 1 {
+                remaining.len()
+            } else {
+                start + segment_len
+            };
+
+            let points: Vec<(f32, f32)> = (start..end)
+                .filter(|&i| remaining[i] > MIN_FIT_FRACTION)
+                .map(|i| ((i + 1) as f32, remaining[i].ln()))
+                .collect();
+            let Some((slope, intercept)) = fit_line(&points) else {
+                continue;
+            };
+
+            let term = LagTerm {
+                amplitude: intercept.exp(),
+                decay: slope.exp(),
+            };
+            if !(term.decay > 0.0 && term.decay < 1.0) {
+                continue;
+            }
+            for (i, value) in remaining.iter_mut().enumerate() {
+                *value -= term.amplitude * term.decay.powi(i as i32 + 1);
+            }
+            terms.push(term);
+        }
+
+        if terms.is_empty() {
+            return Err(CorrectionError::InsufficientLag);
+        }
+
+        Ok(LagModel {
+            key,
+            terms,
+            measured,
+        })
+    }
+
+    pub fn residual_fraction(&self, frames_after: u32) -> f32 {
+        self.terms
+            .iter()
+            .map(|term| term.amplitude * term.decay.powi(frames_after as i32))
+            .sum()
+    }
+}
+
+// Least squares line through the points, as (slope, intercept)
+fn fit_line(points: &[(f32, f32)]) -> Option<(f32, f32)> {
+    if points.len() < 2 {
+        return None;
+    }
+
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|&(x, _)| x as f64).sum::<f64>() / n;
+    let mean_y = points.iter().map(|&(_, y)| y as f64).sum::<f64>() / n;
+    let (covariance, variance) = points.iter().fold((0.0, 0.0), |(cov, var), &(x, y)| {
+        let dx = x as f64 - mean_x;
+        (cov + dx * (y as f64 - mean_y), var + dx * dx)
+    });
+    if variance == 0.0 {
+        return None;
+    }
+
+    let slope = covariance / variance;
+    Some((slope as f32, (mean_y - slope * mean_x) as f32))
+}
+
+// Residual fraction in each frame after the source was switched off, from the mean of every
+// frame of the measurement. The last `settle_frames` are assumed to have no lag left and give
+// the dark level.
+pub fn residual_fractions(
+    frame_means: &[f32],
+    settle_frames: u32,
+) -> Result<Vec<f32>, CorrectionError> {
+    let settle_frames = settle_frames.max(1) as usize;
+    if frame_means.len() <= settle_frames + 2 {
+        return Err(CorrectionError::NoLagTransition);
+    }
+
+    let decay_end = frame_means.len() - settle_frames;
+    let dark_level = frame_means[decay_end..].iter().sum::<f32>() / settle_frames as f32;
+    let bright_level = frame_means[..decay_end]
+        .iter()
+        .fold(f32::MIN, |max, &mean| max.max(mean));
+    if bright_level - dark_level < MIN_LAG_SIGNAL {
+        return Err(CorrectionError::NoLagTransition);
+    }
+
+    let half_level = dark_level + (bright_level - dark_level) / 2.0;
+    let edge = frame_means[..decay_end]
+        .iter()
+        .rposition(|&mean| mean > half_level)
+        .ok_or(CorrectionError::NoLagTransition)?;
+    let signal = frame_means[edge] - dark_level;
+
+    let fractions: Vec<f32> = frame_means[edge + 1..decay_end]
+        .iter()
+        .map(|mean| (mean - dark_level) / signal)
+        .collect();
+    let partly_exposed = fractions
+        .iter()
+        .take_while(|&&fraction| fraction > MAX_LAG_FRACTION)
+        .count();
+    if partly_exposed > 0 {
+        info!("Skipping {partly_exposed} partly exposed frames after the source switched off");
+    }
+
+    let fractions = fractions[partly_exposed..].to_vec();
+    if fractions.is_empty() {
+        return Err(CorrectionError::NoLagTransition);
+    }
+    Ok(fractions)
+}
+
+// Subtracts the residual of every earlier frame, keeping one running residual per pixel per
+// term rather than the frames themselves
+pub struct LagCorrector {
+    terms: Vec<LagTerm>,
+    dimensions: (u32, u32),
+    residuals: Vec<Vec<f32>>,
+}
+
+impl LagCorrector {
+    pub fn new(terms: Vec<LagTerm>) -> Self {
+        LagCorrector {
+            terms,
+            dimensions: (0, 0),
+            residuals: Vec::new(),
+        }
+    }
+
+    // `baseline` is the level of a pixel with no signal, e.g. the pedestal of a dark
+    // corrected frame
+    pub fn correct(
+        &mut self,
+        image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+        baseline: u16,
+    ) -> Result<(), CorrectionError> {
+        if self.residuals.is_empty() {
+            self.dimensions = image.dimensions();
+            let len = image.as_raw().len();
+            self.residuals = self.terms.iter().map(|_| vec![0.0; len]).collect();
+        } else if image.dimensions() != self.dimensions {
+            return Err(CorrectionError::DimensionMismatch(
+                image.width(),
+                image.height(),
+                self.dimensions.0,
+                self.dimensions.1,
+            ));
+        }
+
+        for (i, pixel) in image.iter_mut().enumerate() {
+            let residual: f32 = self.residuals.iter().map(|residuals| residuals[i]).sum();
+            let signal = *pixel as f32 - baseline as f32 - residual;
+            *pixel = (signal + baseline as f32)
+                .round()
+                .clamp(0.0, MAX_PIXEL_VALUE as f32) as u16;
+
+            for (term, residuals) in self.terms.iter().zip(self.residuals.iter_mut()) {
+                residuals[i] = term.decay * (residuals[i] + term.amplitude * signal);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn read_lag_models(path: &Path) -> HashMap<CorrectionMapKey, LagModel> {
+    let models: Vec<LagModel> = match File::open(path) {
+        Ok(file) => match serde_json::from_reader(BufReader::new(file)) {
+            Ok(models) => models,
+            Err(e) => {
+                error!("Failed to read lag models from {}: {e}", path.display());
+                Vec::new()
+            }
+        },
+        Err(_) => Vec::new(),
+    };
+
+    models
+        .into_iter()
+        .map(|model| (model.key.clone(), model))
+        .collect()
+}
+
+pub fn write_lag_models<'a>(
+    path: &Path,
+    models: impl Iterator<Item = &'a LagModel>,
+) -> Result<(), CorrectionError> {
+    let models: Vec<&LagModel> = models.collect();
+    let file = File::create(path)
+        .map_err(|e| CorrectionError::WriteFailed(format!("{}: {e}", path.display())))?;
+    serde_json::to_writer_pretty(BufWriter::new(file), &models)
+        .map_err(|e| CorrectionError::WriteFailed(format!("{}: {e}", path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Luma};
+
+    use super::{residual_fractions, LagCorrector, LagModel, LagTerm};
+    use crate::capture::{
+        capture::{CaptureSettingBuilder, SequenceCapture},
+        corrections::CorrectionMapKey,
+    };
+
+    fn key() -> CorrectionMapKey {
+        CorrectionMapKey::from_capture_setting(
+            &CaptureSettingBuilder::new(100, Box::new(SequenceCapture { num_frames: 1 })).build(),
+        )
+    }
+
+    #[test]
+    fn fits_two_term_decay() {
+        let terms = [
+            LagTerm {
+                amplitude: 0.01,
+                decay: 0.95,
+            },
+            LagTerm {
+                amplitude: 0.05,
+                decay: 0.4,
+            },
+        ];
+        let measured: Vec<f32> = (1..=20)
+            .map(|k| terms.iter().map(|t| t.amplitude * t.decay.powi(k)).sum())
+            .collect();
+
+        let model = LagModel::fit(key(), measured.clone(), 2).unwrap();
+
+        assert_eq!(model.terms.len(), 2);
+        for (k, expected) in measured.iter().enumerate() {
+            let fitted = model.residual_fraction(k as u32 + 1);
+            assert!((fitted - expected).abs() < 0.002, "{fitted} vs {expected}");
+        }
+    }
+
+    #[test]
+    fn finds_decay_after_source_switches_off() {
+        let dark = 300.0;
+        let mut means = vec![4300.0; 5];
+        // Partly exposed as the source switched off mid-frame
+        means.push(2000.0);
+        means.extend((1..=6).map(|k| dark + 4000.0 * 0.1 * 0.5f32.powi(k)));
+        means.extend([dark; 4]);
+
+        let fractions = residual_fractions(&means, 4).unwrap();
+
+        assert_eq!(fractions.len(), 6);
+        assert!((fractions[0] - 0.05).abs() < 1e-4);
+        assert!(residual_fractions(&[300.0; 10], 4).is_err());
+    }
+
+    #[test]
+    fn corrector_removes_residual_from_following_frames() {
+        let terms = vec![LagTerm {
+            amplitude: 0.1,
+            decay: 0.5,
+        }];
+        let mut corrector = LagCorrector::new(terms);
+        let baseline = 300;
+
+        let mut bright: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_vec(1, 1, vec![baseline + 10000]).unwrap();
+        corrector.correct(&mut bright, baseline).unwrap();
+        assert_eq!(bright.get_pixel(0, 0)[0], baseline + 10000);
+
+        // The panel reports 10000 * 0.1 * 0.5^k on top of an otherwise dark frame
+        for k in 1..4 {
+            let lag = (10000.0 * 0.1 * 0.5f32.powi(k)) as u16;
+            let mut dark: ImageBuffer<Luma<u16>, Vec<u16>> =
+                ImageBuffer::from_vec(1, 1, vec![baseline + lag]).unwrap();
+            corrector.correct(&mut dark, baseline).unwrap();
+            assert_eq!(dark.get_pixel(0, 0)[0], baseline);
+        }
+    }
+}
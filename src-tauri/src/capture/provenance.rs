@@ -0,0 +1,370 @@
+use chrono::{DateTime, Duration, Utc};
+use image::{ImageBuffer, Luma};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use super::{
+    backend::DetectorIdentity,
+    capture::CaptureSetting,
+    corrections::{CorrectionError, CorrectionMapKey},
+    defect_map::StackStatistics,
+    stack_reduction::StackReductionSummary,
+};
+
+// Sidecars sit next to their map, e.g. DarkMap_100ms_HFW_x11_NoDDS.provenance.json
+const SIDECAR_EXTENSION: &str = "provenance.json";
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+#[derive(Clone, Debug, Serialize, Deserialize, Type, PartialEq)]
+pub struct NoiseStatistics {
+    // Mean of the map in ADU
+    pub mean: f32,
+    // RMS frame to frame noise of a pixel in ADU
+    pub temporal_noise: f32,
+    // Pixel to pixel standard deviation of the map in ADU
+    pub spatial_noise: f32,
+}
+
+impl NoiseStatistics {
+    pub fn from_stack(stack: &StackStatistics) -> Self {
+        let pixel_count = stack.mean.len().max(1) as f64;
+        let mean = stack.mean.iter().map(|&v| v as f64).sum::<f64>() / pixel_count;
+        let spatial_variance = stack
+            .mean
+            .iter()
+            .map(|&v| (v as f64 - mean).powi(2))
+            .sum::<f64>()
+            / pixel_count;
+        let temporal_variance = stack
+            .std_dev
+            .iter()
+            .map(|&v| (v as f64).powi(2))
+            .sum::<f64>()
+            / pixel_count;
+
+        NoiseStatistics {
+            mean: mean as f32,
+            temporal_noise: temporal_variance.sqrt() as f32,
+            spatial_noise: spatial_variance.sqrt() as f32,
+        }
+    }
+}
+
+// Where a correction map came from. Maps from before sidecars were written only have an ID
+// and the file's modification time.
+#[derive(Clone, Debug, Serialize, Deserialize, Type)]
+pub struct MapProvenance {
+    // Hash of the map's contents, which also identifies the map in image metadata
+    pub id: String,
+    pub created: Option<DateTime<Utc>>,
+    pub detector: Option<DetectorIdentity>,
+    pub capture_settings: Vec<CaptureSetting>,
+    pub frame_count: Option<u32>,
+    pub noise: Option<NoiseStatistics>,
+    // Frames and pixel samples left out of the map, missing from sidecars written before
+    // stacks were reduced robustly
+    #[serde(default)]
+    pub stack_reduction: Option<StackReductionSummary>,
+}
+
+impl MapProvenance {
+    pub fn new(
+        id: String,
+        detector: Option<DetectorIdentity>,
+        capture_settings: Vec<CaptureSetting>,
+        frame_count: u32,
+        noise: Option<NoiseStatistics>,
+    ) -> Self {
+        MapProvenance {
+            id,
+            created: Some(Utc::now()),
+            detector,
+            capture_settings,
+            frame_count: Some(frame_count),
+            noise,
+            stack_reduction: None,
+        }
+    }
+
+    pub fn with_stack_reduction(mut self, summary: StackReductionSummary) -> Self {
+        self.stack_reduction = Some(summary);
+        self
+    }
+
+    pub fn write(&self, map_path: &Path) -> Result<(), CorrectionError> {
+        let path = sidecar_path(map_path);
+        let write_failed = |e: &dyn std::fmt::Display| {
+            CorrectionError::WriteFailed(format!("{}: {e}", path.display()))
+        };
+
+        let file = File::create(&path).map_err(|e| write_failed(&e))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self).map_err(|e| write_failed(&e))
+    }
+
+    // Reads the sidecar for a map, falling back to what the file system knows when there is no
+    // sidecar or it describes a different map than the one on disk
+    pub fn read(map_path: &Path, id: String) -> Self {
+        let sidecar: Option<MapProvenance> = File::open(sidecar_path(map_path))
+            .ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok());
+
+        match sidecar {
+            Some(provenance) if provenance.id == id => return provenance,
+            Some(_) => warn!(
+                "{} has changed since its provenance was recorded",
+                map_path.display()
+            ),
+            None => info!("No provenance recorded for {}", map_path.display()),
+        }
+
+        MapProvenance {
+            created: fs::metadata(map_path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .map(DateTime::<Utc>::from),
+            ..MapProvenance::unrecorded(id)
+        }
+    }
+
+    // For maps nothing is known about apart from their contents
+    pub fn unrecorded(id: String) -> Self {
+        MapProvenance {
+            id,
+            created: None,
+            detector: None,
+            capture_settings: Vec::new(),
+            frame_count: None,
+            noise: None,
+            stack_reduction: None,
+        }
+    }
+
+    // Maps with an unknown age or detector are given the benefit of the doubt
+    pub fn stale_reason(
+        &self,
+        now: DateTime<Utc>,
+        max_age: Duration,
+        detector: Option<&DetectorIdentity>,
+    ) -> Option<StaleReason> {
+        if let (Some(map_detector), Some(detector)) = (&self.detector, detector) {
+            if map_detector != detector {
+                return Some(StaleReason::DifferentDetector {
+                    detector: map_detector.clone(),
+                });
+            }
+        }
+
+        match self.created {
+            Some(created) if now - created > max_age => Some(StaleReason::Expired { created }),
+            _ => None,
+        }
+    }
+}
+
+pub fn sidecar_path(map_path: &Path) -> PathBuf {
+    map_path.with_extension(SIDECAR_EXTENSION)
+}
+
+#[derive(Clone, Debug, Serialize, Type, PartialEq)]
+#[serde(tag = "type")]
+pub enum StaleReason {
+    Expired { created: DateTime<Utc> },
+    DifferentDetector { detector: DetectorIdentity },
+}
+
+#[derive(Clone, Debug, Serialize, Type, PartialEq)]
+pub struct StaleMap {
+    pub map: String,
+    pub id: String,
+    pub reason: StaleReason,
+}
+
+// Provenance of the maps loaded into CorrectionMaps, keyed the same way as the maps
+#[derive(Default)]
+pub struct CorrectionMapProvenance {
+    pub dark_maps: HashMap<CorrectionMapKey, MapProvenance>,
+    pub gain_maps: HashMap<CorrectionMapKey, MapProvenance>,
+    pub defect_map: Option<MapProvenance>,
+}
+
+impl CorrectionMapProvenance {
+    pub fn stale_maps(
+        &self,
+        now: DateTime<Utc>,
+        max_age: Duration,
+        detector: Option<&DetectorIdentity>,
+    ) -> Vec<StaleMap> {
+        let mut maps: Vec<(String, &MapProvenance)> = Vec::new();
+        maps.extend(
+            self.dark_maps
+                .iter()
+                .map(|(key, provenance)| (format!("Dark map {key}"), provenance)),
+        );
+        maps.extend(
+            self.gain_maps
+                .iter()
+                .map(|(key, provenance)| (format!("Gain map {key}"), provenance)),
+        );
+        maps.extend(
+            self.defect_map
+                .iter()
+                .map(|provenance| ("Defect map".to_string(), provenance)),
+        );
+        maps.sort_by(|a, b| a.0.cmp(&b.0));
+
+        maps.into_iter()
+            .filter_map(|(map, provenance)| {
+                provenance
+                    .stale_reason(now, max_age, detector)
+                    .map(|reason| StaleMap {
+                        map,
+                        id: provenance.id.clone(),
+                        reason,
+                    })
+            })
+            .collect()
+    }
+}
+
+// Content hashes identify maps across restarts, so they use FNV-1a rather than the
+// std hasher, whose output may change between Rust releases
+pub trait MapContent {
+    fn content_id(&self) -> String;
+}
+
+impl MapContent for ImageBuffer<Luma<u16>, Vec<u16>> {
+    fn content_id(&self) -> String {
+        content_hash(
+            self.dimensions(),
+            self.as_raw().iter().flat_map(|v| v.to_le_bytes()),
+        )
+    }
+}
+
+impl MapContent for ImageBuffer<Luma<f32>, Vec<f32>> {
+    fn content_id(&self) -> String {
+        content_hash(
+            self.dimensions(),
+            self.as_raw().iter().flat_map(|v| v.to_bits().to_le_bytes()),
+        )
+    }
+}
+
+fn content_hash(dimensions: (u32, u32), bytes: impl Iterator<Item = u8>) -> String {
+    let hash = dimensions
+        .0
+        .to_le_bytes()
+        .into_iter()
+        .chain(dimensions.1.to_le_bytes())
+        .chain(bytes)
+        .fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        });
+    format!("{hash:016x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use image::{ImageBuffer, Luma};
+
+    use super::{MapContent, MapProvenance, NoiseStatistics, StaleReason};
+    use crate::capture::{backend::DetectorIdentity, defect_map::StackStatistics};
+
+    fn detector(serial: &str) -> DetectorIdentity {
+        DetectorIdentity {
+            model: "Simulated".to_string(),
+            serial: Some(serial.to_string()),
+            width: 4,
+            height: 2,
+        }
+    }
+
+    #[test]
+    fn content_id_changes_with_pixels_and_dimensions() {
+        let image = |width, height, data| -> ImageBuffer<Luma<u16>, Vec<u16>> {
+            ImageBuffer::from_vec(width, height, data).unwrap()
+        };
+        let map = image(2, 2, vec![1, 2, 3, 4]);
+        let changed = image(2, 2, vec![1, 2, 3, 5]);
+        let reshaped = image(4, 1, vec![1, 2, 3, 4]);
+
+        assert_eq!(map.content_id(), map.clone().content_id());
+        assert_ne!(map.content_id(), changed.content_id());
+        assert_ne!(map.content_id(), reshaped.content_id());
+    }
+
+    #[test]
+    fn noise_statistics_from_stack() {
+        let stack = StackStatistics {
+            width: 2,
+            height: 1,
+            exp_time: 100,
+            frame_count: 10,
+            mean: vec![100.0, 110.0],
+            std_dev: vec![3.0, 4.0],
+        };
+
+        let noise = NoiseStatistics::from_stack(&stack);
+        assert_eq!(noise.mean, 105.0);
+        assert_eq!(noise.spatial_noise, 5.0);
+        assert!((noise.temporal_noise - 12.5f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn roundtrips_sidecar_and_rejects_changed_map() {
+        let dir = std::env::temp_dir().join(format!("cview_provenance_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let map_path = dir.join("DarkMap_100ms_HFW_x11_NoDDS.tif");
+        std::fs::write(&map_path, b"map").unwrap();
+
+        let provenance =
+            MapProvenance::new("abc".to_string(), Some(detector("1")), vec![], 10, None);
+        provenance.write(&map_path).unwrap();
+
+        let read = MapProvenance::read(&map_path, "abc".to_string());
+        assert_eq!(read.frame_count, Some(10));
+        assert_eq!(read.detector, Some(detector("1")));
+
+        let changed = MapProvenance::read(&map_path, "def".to_string());
+        assert_eq!(changed.id, "def");
+        assert_eq!(changed.frame_count, None);
+        assert!(changed.created.is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flags_old_maps_and_other_detectors() {
+        let now = Utc::now();
+        let mut provenance =
+            MapProvenance::new("abc".to_string(), Some(detector("1")), vec![], 10, None);
+
+        assert_eq!(
+            provenance.stale_reason(now, Duration::days(7), Some(&detector("1"))),
+            None
+        );
+        assert_eq!(
+            provenance.stale_reason(now, Duration::days(7), Some(&detector("2"))),
+            Some(StaleReason::DifferentDetector {
+                detector: detector("1")
+            })
+        );
+
+        let created = now - Duration::days(8);
+        provenance.created = Some(created);
+        assert_eq!(
+            provenance.stale_reason(now, Duration::days(7), None),
+            Some(StaleReason::Expired { created })
+        );
+    }
+}
@@ -0,0 +1,494 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::wrapper::{
+    BinningModesRS, ExposureModes, FullWellModesRS, InternalSLError, SLError, SLImageRs,
+};
+
+use super::{
+    backend::{DetectorBackend, DetectorIdentity},
+    capture::{CaptureSetting, Roi},
+    correction_pipeline::CorrectionPipeline,
+};
+
+const REPLAY_MAGIC: &[u8; 8] = b"CVREPLAY";
+const REPLAY_VERSION: u32 = 1;
+
+const RECORD_CAPTURE: u8 = 0;
+const RECORD_FRAME: u8 = 1;
+
+// The serialisable part of a `CaptureSetting`, as stored in a replay file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordedCaptureSetting {
+    pub exp_time: u32,
+    pub dds: bool,
+    pub full_well: FullWellModesRS,
+    pub binning_mode: BinningModesRS,
+    pub roi: Option<Roi>,
+    // Missing from recordings made before corrections were configurable
+    #[serde(default)]
+    pub corrections: CorrectionPipeline,
+}
+
+pub struct RecordedFrame {
+    // Time since the capture was started
+    pub offset: Duration,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u16>,
+}
+
+pub struct RecordedCapture {
+    pub setting: RecordedCaptureSetting,
+    pub frames: Vec<RecordedFrame>,
+}
+
+// Writes every raw frame of a session to a replay file, grouped by the capture
+// that produced it. Layout: magic, version, then a sequence of tagged records.
+pub struct SessionRecorder {
+    writer: BufWriter<File>,
+    capture_start: Instant,
+}
+
+impl SessionRecorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(REPLAY_MAGIC)?;
+        writer.write_all(&REPLAY_VERSION.to_le_bytes())?;
+
+        info!("Recording detector session to {}", path.display());
+
+        Ok(SessionRecorder {
+            writer,
+            capture_start: Instant::now(),
+        })
+    }
+
+    pub fn begin_capture(&mut self, capture_settings: &CaptureSetting) -> io::Result<()> {
+        let setting = serde_json::to_vec(capture_settings)?;
+
+        self.writer.write_all(&[RECORD_CAPTURE])?;
+        self.writer
+            .write_all(&(setting.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&setting)?;
+        self.capture_start = Instant::now();
+        Ok(())
+    }
+
+    pub fn record_frame(&mut self, image: &mut SLImageRs) -> io::Result<()> {
+        let offset = self.capture_start.elapsed().as_micros() as u64;
+        let image_buffer = image.to_image_buffer();
+
+        self.writer.write_all(&[RECORD_FRAME])?;
+        self.writer.write_all(&offset.to_le_bytes())?;
+        self.writer.write_all(&image_buffer.width().to_le_bytes())?;
+        self.writer
+            .write_all(&image_buffer.height().to_le_bytes())?;
+        for value in image_buffer.as_raw() {
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub fn read_replay_file(path: &Path) -> io::Result<Vec<RecordedCapture>> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != REPLAY_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Not a replay file",
+        ));
+    }
+
+    let version = read_u32(&mut reader)?;
+    if version != REPLAY_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported replay file version {version}"),
+        ));
+    }
+
+    let mut captures: Vec<RecordedCapture> = Vec::new();
+    let mut tag = [0u8; 1];
+
+    loop {
+        match reader.read_exact(&mut tag) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+
+        match tag[0] {
+            RECORD_CAPTURE => {
+                let len = read_u32(&mut reader)? as usize;
+                let mut setting = vec![0u8; len];
+                reader.read_exact(&mut setting)?;
+                captures.push(RecordedCapture {
+                    setting: serde_json::from_slice(&setting)?,
+                    frames: Vec::new(),
+                });
+            }
+            RECORD_FRAME => {
+                let offset = Duration::from_micros(read_u64(&mut reader)?);
+                let width = read_u32(&mut reader)?;
+                let height = read_u32(&mut reader)?;
+
+                let mut bytes = vec![0u8; (width * height * 2) as usize];
+                reader.read_exact(&mut bytes)?;
+                let data = bytes
+                    .chunks_exact(2)
+                    .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                    .collect();
+
+                match captures.last_mut() {
+                    Some(capture) => capture.frames.push(RecordedFrame {
+                        offset,
+                        width,
+                        height,
+                        data,
+                    }),
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Frame recorded before any capture",
+                        ))
+                    }
+                }
+            }
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown replay record {other}"),
+                ))
+            }
+        }
+    }
+
+    Ok(captures)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+struct ReplayState {
+    next_capture: usize,
+    current_capture: Option<usize>,
+    capture_start: Instant,
+    stream_frames_read: usize,
+    exp_time: u32,
+}
+
+// Plays back a recorded session. Each go_live/start_stream moves on to the next
+// recorded capture, whose frames are then handed out with their original timing.
+#[derive(Clone)]
+pub struct ReplayDetector {
+    captures: Arc<Vec<RecordedCapture>>,
+    state: Arc<Mutex<ReplayState>>,
+    realtime: bool,
+}
+
+impl ReplayDetector {
+    pub fn open(path: &Path, realtime: bool) -> io::Result<Self> {
+        let captures = read_replay_file(path)?;
+        info!(
+            "Loaded replay file {} with {} captures",
+            path.display(),
+            captures.len()
+        );
+        Ok(Self::from_captures(captures, realtime))
+    }
+
+    pub fn from_captures(captures: Vec<RecordedCapture>, realtime: bool) -> Self {
+        ReplayDetector {
+            captures: Arc::new(captures),
+            state: Arc::new(Mutex::new(ReplayState {
+                next_capture: 0,
+                current_capture: None,
+                capture_start: Instant::now(),
+                stream_frames_read: 0,
+                exp_time: 0,
+            })),
+            realtime,
+        }
+    }
+
+    fn begin_next_capture(&self) -> Result<(), InternalSLError> {
+        let mut state = self.state.lock().unwrap();
+
+        let index = state.next_capture;
+        let capture = match self.captures.get(index) {
+            Some(capture) => capture,
+            None => return Err(SLError::SL_ERROR_NOT_FOUND.into()),
+        };
+
+        if capture.setting.exp_time != state.exp_time {
+            warn!(
+                "Replaying capture recorded at {}ms for a request of {}ms",
+                capture.setting.exp_time, state.exp_time
+            );
+        }
+
+        state.current_capture = Some(index);
+        state.next_capture += 1;
+        state.capture_start = Instant::now();
+        state.stream_frames_read = 0;
+        Ok(())
+    }
+
+    fn frame_dimensions(&self) -> Option<(u32, u32)> {
+        let state = self.state.lock().unwrap();
+        let index = state.current_capture.unwrap_or(state.next_capture);
+        self.captures
+            .get(index)
+            .or(self.captures.first())
+            .and_then(|capture| capture.frames.first())
+            .map(|frame| (frame.width, frame.height))
+    }
+
+    fn copy_frame(buffer: &mut SLImageRs, frame: &RecordedFrame) -> Result<(), InternalSLError> {
+        if buffer.get_width() != frame.width || buffer.get_height() != frame.height {
+            return Err(SLError::SL_ERROR_INVALID_PARAM.into());
+        }
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                frame.data.as_ptr() as *const u8,
+                buffer.get_data_pointer(0),
+                frame.data.len() * 2,
+            );
+        }
+        Ok(())
+    }
+}
+
+impl DetectorBackend for ReplayDetector {
+    fn open_camera(&mut self, _buffer_depth: u32) -> Result<(), InternalSLError> {
+        Ok(())
+    }
+
+    fn is_connected(&mut self) -> bool {
+        true
+    }
+
+    fn image_width(&mut self) -> Result<u32, ()> {
+        self.frame_dimensions().map(|(width, _)| width).ok_or(())
+    }
+
+    fn image_height(&mut self) -> Result<u32, ()> {
+        self.frame_dimensions().map(|(_, height)| height).ok_or(())
+    }
+
+    fn identity(&mut self) -> Result<DetectorIdentity, ()> {
+        let (width, height) = self.frame_dimensions().ok_or(())?;
+        Ok(DetectorIdentity {
+            model: "Replay".to_string(),
+            serial: None,
+            width,
+            height,
+        })
+    }
+
+    fn set_exposure_time(&mut self, exp_time: u32) -> Result<(), InternalSLError> {
+        self.state.lock().unwrap().exp_time = exp_time;
+        Ok(())
+    }
+
+    fn set_exposure_mode(&mut self, _ex_mode: ExposureModes) -> Result<(), InternalSLError> {
+        Ok(())
+    }
+
+    fn set_number_frames(&mut self, _frame_count: u32) -> Result<(), InternalSLError> {
+        Ok(())
+    }
+
+    fn set_full_well(&mut self, _full_well: FullWellModesRS) -> Result<(), InternalSLError> {
+        Ok(())
+    }
+
+    // Recorded frames were already binned and cropped when they were captured
+    fn set_binning_mode(&mut self, _binning_mode: BinningModesRS) -> Result<(), InternalSLError> {
+        Ok(())
+    }
+
+    fn set_dds(&mut self, _dds: bool) -> Result<(), InternalSLError> {
+        Ok(())
+    }
+
+    fn set_roi(&mut self, _roi: Option<Roi>) -> Result<(), InternalSLError> {
+        Ok(())
+    }
+
+    fn start_stream(&mut self, exp_time: u32) -> Result<(), InternalSLError> {
+        self.state.lock().unwrap().exp_time = exp_time;
+        self.begin_next_capture()
+    }
+
+    fn go_live(&mut self) -> Result<(), InternalSLError> {
+        self.begin_next_capture()
+    }
+
+    fn go_unlive(&mut self, _wipe_stack: bool) -> Result<(), InternalSLError> {
+        self.state.lock().unwrap().current_capture = None;
+        Ok(())
+    }
+
+    fn software_trigger(&mut self) -> Result<(), InternalSLError> {
+        Ok(())
+    }
+
+    fn read_buffer(
+        &mut self,
+        buffer: &mut SLImageRs,
+        buf_num: u32,
+        timeout: u32,
+    ) -> Result<(), InternalSLError> {
+        let (capture_index, capture_start) = {
+            let state = self.state.lock().unwrap();
+            match state.current_capture {
+                Some(index) => (index, state.capture_start),
+                None => return Err(SLError::SL_ERROR_OTHER.into()),
+            }
+        };
+
+        let frame = match self.captures[capture_index].frames.get(buf_num as usize) {
+            Some(frame) => frame,
+            None => return Err(SLError::SL_ERROR_NOT_FOUND.into()),
+        };
+
+        if self.realtime {
+            let ready_at = capture_start + frame.offset;
+            let now = Instant::now();
+            if ready_at > now {
+                let wait = ready_at - now;
+                if wait > Duration::from_millis(timeout as u64) {
+                    thread::sleep(Duration::from_millis(timeout as u64));
+                    return Err(SLError::SL_ERROR_TIMEOUT.into());
+                }
+                thread::sleep(wait);
+            }
+        }
+
+        Self::copy_frame(buffer, frame)
+    }
+
+    fn read_frame(&mut self, buffer: &mut SLImageRs, _read_oldest_first: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        let capture_index = match state.current_capture {
+            Some(index) => index,
+            None => return false,
+        };
+
+        let frame = match self.captures[capture_index]
+            .frames
+            .get(state.stream_frames_read)
+        {
+            Some(frame) => frame,
+            None => return false,
+        };
+
+        if self.realtime && state.capture_start.elapsed() < frame.offset {
+            return false;
+        }
+
+        state.stream_frames_read += 1;
+        Self::copy_frame(buffer, frame).is_ok()
+    }
+
+    fn clone_box(&self) -> Box<dyn DetectorBackend> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use futures_util::StreamExt;
+
+    use crate::capture::{
+        capture::{CaptureSettingBuilder, SequenceCapture},
+        capture_manager::CorrectionMaps,
+        detector::DetectorController,
+        simulated::SimulatedDetectorConfig,
+        test_utils::test_utils::setup_simulated_controller,
+    };
+
+    use super::ReplayDetector;
+
+    #[tokio::test]
+    async fn replay_reproduces_recorded_frames() {
+        let path =
+            std::env::temp_dir().join(format!("cview_replay_test_{}.bin", std::process::id()));
+        let correction_maps = CorrectionMaps::new(HashMap::new(), None, HashMap::new());
+
+        let mut controller = setup_simulated_controller(SimulatedDetectorConfig {
+            width: 32,
+            height: 24,
+            realtime: false,
+            ..Default::default()
+        });
+        controller.start_recording(&path).unwrap();
+
+        let mut recorded = Vec::new();
+        for exp_time in [100, 200] {
+            let settings =
+                CaptureSettingBuilder::new(exp_time, Box::new(SequenceCapture { num_frames: 3 }))
+                    .corrected(false)
+                    .build();
+            let mut stream = controller
+                .run_capture_stream(settings, correction_maps.clone())
+                .unwrap();
+            while let Some(frame) = stream.next().await {
+                recorded.push(frame.unwrap().image.to_image_buffer());
+            }
+        }
+        controller.stop_recording().unwrap();
+
+        let mut replay_controller =
+            DetectorController::new(ReplayDetector::open(&path, false).unwrap(), |_| {});
+
+        let mut replayed = Vec::new();
+        for exp_time in [100, 200] {
+            let settings =
+                CaptureSettingBuilder::new(exp_time, Box::new(SequenceCapture { num_frames: 3 }))
+                    .corrected(false)
+                    .build();
+            let mut stream = replay_controller
+                .run_capture_stream(settings, correction_maps.clone())
+                .unwrap();
+            while let Some(frame) = stream.next().await {
+                replayed.push(frame.unwrap().image.to_image_buffer());
+            }
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(recorded.len(), 6);
+        assert_eq!(recorded, replayed);
+    }
+}
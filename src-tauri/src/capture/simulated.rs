@@ -0,0 +1,804 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::wrapper::{
+    BinningModes, BinningModesRS, ExposureModes, FullWellModes, FullWellModesRS, InternalSLError,
+    SLError, SLImageRs,
+};
+
+use super::{
+    backend::{DetectorBackend, DetectorIdentity},
+    capture::Roi,
+};
+
+const MAX_PIXEL_VALUE: f64 = 16383.0;
+
+// Pattern placed between the simulated source and the panel
+#[derive(Clone, Serialize, Deserialize, Debug, Type, PartialEq)]
+#[serde(tag = "type")]
+pub enum Phantom {
+    // Open beam, every pixel sees the full flux
+    Flat,
+    // Horizontal steps of decreasing transmission
+    StepWedge { steps: u32 },
+    // Dense disc in the middle of the field
+    Disc { radius: u32 },
+}
+
+impl Phantom {
+    fn transmission(&self, x: u32, y: u32, width: u32, height: u32) -> f64 {
+        match self {
+            Phantom::Flat => 1.0,
+            Phantom::StepWedge { steps } => {
+                let steps = (*steps).max(1);
+                let step = (x * steps / width.max(1)).min(steps - 1);
+                1.0 - step as f64 / steps as f64
+            }
+            Phantom::Disc { radius } => {
+                let dx = x as f64 - width as f64 / 2.0;
+                let dy = y as f64 - height as f64 / 2.0;
+                if (dx * dx + dy * dy).sqrt() <= *radius as f64 {
+                    0.2
+                } else {
+                    1.0
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SimulatedDetectorConfig {
+    pub width: u32,
+    pub height: u32,
+    // Mean signal of a dark frame in ADU
+    pub dark_offset: f64,
+    // Pixel-to-pixel spread of the dark offset in ADU
+    pub offset_variation: f64,
+    // Dark current in ADU per second of exposure
+    pub dark_current: f64,
+    // Gaussian read noise in ADU
+    pub read_noise: f64,
+    // Relative standard deviation of the per-pixel gain
+    pub gain_variation: f64,
+    // Conversion factor used for shot noise
+    pub electrons_per_adu: f64,
+    // Extra gain applied in low full well mode
+    pub low_full_well_gain: f64,
+    // Illumination in ADU per millisecond of exposure, only used with a phantom
+    pub flux: f64,
+    pub phantom: Option<Phantom>,
+    pub hot_pixels: u32,
+    pub dead_pixels: u32,
+    pub hot_columns: u32,
+    pub dead_columns: u32,
+    // Dark current of hot pixels in ADU per second
+    pub hot_pixel_dark_current: f64,
+    pub seed: u64,
+    // Wait for the exposure time to elapse before frames become readable
+    pub realtime: bool,
+}
+
+impl Default for SimulatedDetectorConfig {
+    fn default() -> Self {
+        SimulatedDetectorConfig {
+            width: 1031,
+            height: 1536,
+            dark_offset: 300.0,
+            offset_variation: 5.0,
+            dark_current: 10.0,
+            read_noise: 3.0,
+            gain_variation: 0.03,
+            electrons_per_adu: 4.0,
+            low_full_well_gain: 4.0,
+            flux: 20.0,
+            phantom: None,
+            hot_pixels: 200,
+            dead_pixels: 100,
+            hot_columns: 2,
+            dead_columns: 1,
+            hot_pixel_dark_current: 3000.0,
+            seed: 0,
+            realtime: true,
+        }
+    }
+}
+
+// Ground truth of the defects injected into the simulated panel
+#[derive(Clone, Debug, Default)]
+pub struct SimulatedDefects {
+    pub hot_pixels: Vec<(u32, u32)>,
+    pub dead_pixels: Vec<(u32, u32)>,
+    pub hot_columns: Vec<u32>,
+    pub dead_columns: Vec<u32>,
+}
+
+struct FixedPattern {
+    gain: Vec<f32>,
+    offset: Vec<f32>,
+    dark_current: Vec<f32>,
+    dead: Vec<bool>,
+    // Only read back by tests checking the defects are found
+    #[cfg_attr(not(test), allow(dead_code))]
+    defects: SimulatedDefects,
+}
+
+impl FixedPattern {
+    fn new(config: &SimulatedDetectorConfig) -> Self {
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let pixel_count = (config.width * config.height) as usize;
+
+        let mut gain = Vec::with_capacity(pixel_count);
+        let mut offset = Vec::with_capacity(pixel_count);
+        let mut dark_current = Vec::with_capacity(pixel_count);
+
+        for _ in 0..pixel_count {
+            gain.push((1.0 + config.gain_variation * gaussian(&mut rng)).max(0.0) as f32);
+            offset.push((config.dark_offset + config.offset_variation * gaussian(&mut rng)) as f32);
+            dark_current
+                .push((config.dark_current * (1.0 + 0.1 * gaussian(&mut rng))).max(0.0) as f32);
+        }
+
+        let mut dead = vec![false; pixel_count];
+        let mut defects = SimulatedDefects::default();
+        let width = config.width;
+
+        for _ in 0..config.hot_columns {
+            let x = rng.gen_range(0..width);
+            for y in 0..config.height {
+                dark_current[(y * width + x) as usize] = config.hot_pixel_dark_current as f32;
+            }
+            defects.hot_columns.push(x);
+        }
+
+        for _ in 0..config.dead_columns {
+            let x = rng.gen_range(0..width);
+            for y in 0..config.height {
+                dead[(y * width + x) as usize] = true;
+            }
+            defects.dead_columns.push(x);
+        }
+
+        for _ in 0..config.hot_pixels {
+            let (x, y) = (rng.gen_range(0..width), rng.gen_range(0..config.height));
+            dark_current[(y * width + x) as usize] = config.hot_pixel_dark_current as f32;
+            defects.hot_pixels.push((x, y));
+        }
+
+        for _ in 0..config.dead_pixels {
+            let (x, y) = (rng.gen_range(0..width), rng.gen_range(0..config.height));
+            dead[(y * width + x) as usize] = true;
+            defects.dead_pixels.push((x, y));
+        }
+
+        FixedPattern {
+            gain,
+            offset,
+            dark_current,
+            dead,
+            defects,
+        }
+    }
+}
+
+// How frames are read off the simulated panel
+#[derive(Clone, Copy)]
+struct Readout {
+    // Pixels averaged along each side
+    binning: u32,
+    // Digital double sampling removes the pixel-to-pixel offset variation
+    dds: bool,
+    roi: Option<Roi>,
+}
+
+impl Readout {
+    fn binned_dimensions(&self, config: &SimulatedDetectorConfig) -> (u32, u32) {
+        (config.width / self.binning, config.height / self.binning)
+    }
+
+    fn dimensions(&self, config: &SimulatedDetectorConfig) -> (u32, u32) {
+        match self.roi {
+            Some(roi) => (roi.width, roi.height),
+            None => self.binned_dimensions(config),
+        }
+    }
+}
+
+struct SimulatedState {
+    exp_time: u32,
+    num_frames: u32,
+    full_well: FullWellModesRS,
+    readout: Readout,
+    connected: bool,
+    open: bool,
+    live: bool,
+    streaming: bool,
+    trigger_time: Option<Instant>,
+    sequence_index: u64,
+    stream_frames_read: u64,
+}
+
+// Software detector producing 14-bit frames with dark offset, dark current,
+// shot noise, read noise, gain non-uniformity and injected defects.
+// Frames are derived from the seed, so seeded runs are fully reproducible.
+#[derive(Clone)]
+pub struct SimulatedDetector {
+    config: Arc<SimulatedDetectorConfig>,
+    pattern: Arc<FixedPattern>,
+    state: Arc<Mutex<SimulatedState>>,
+    source_on: Arc<AtomicBool>,
+}
+
+impl SimulatedDetector {
+    pub fn new(config: SimulatedDetectorConfig) -> Self {
+        let pattern = FixedPattern::new(&config);
+
+        SimulatedDetector {
+            config: Arc::new(config),
+            pattern: Arc::new(pattern),
+            state: Arc::new(Mutex::new(SimulatedState {
+                exp_time: 100,
+                num_frames: 1,
+                full_well: FullWellModesRS {
+                    remote_ty: FullWellModes::High,
+                },
+                readout: Readout {
+                    binning: 1,
+                    dds: false,
+                    roi: None,
+                },
+                connected: true,
+                open: false,
+                live: false,
+                streaming: false,
+                trigger_time: None,
+                sequence_index: 0,
+                stream_frames_read: 0,
+            })),
+            source_on: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn config(&self) -> &SimulatedDetectorConfig {
+        &self.config
+    }
+
+    #[cfg(test)]
+    pub fn defects(&self) -> &SimulatedDefects {
+        &self.pattern.defects
+    }
+
+    // Emulates the panel being unplugged or plugged back in
+    #[cfg(test)]
+    pub fn set_connected(&self, connected: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.connected = connected;
+        if !connected {
+            state.open = false;
+            state.live = false;
+            state.streaming = false;
+        }
+    }
+
+    // Emulates the X-ray source being switched off or on, the phantom is only seen while it's on
+    #[cfg(test)]
+    pub fn set_source(&self, on: bool) {
+        self.source_on.store(on, Ordering::SeqCst);
+    }
+
+    // A full resolution frame, before binning or cropping
+    #[cfg(test)]
+    pub fn generate_frame(
+        &self,
+        exp_time: u32,
+        full_well: &FullWellModesRS,
+        frame_seed: u64,
+    ) -> Vec<u16> {
+        self.generate_pixels(exp_time, full_well, frame_seed, false)
+    }
+
+    fn generate_pixels(
+        &self,
+        exp_time: u32,
+        full_well: &FullWellModesRS,
+        frame_seed: u64,
+        dds: bool,
+    ) -> Vec<u16> {
+        let config = &self.config;
+        let pattern = &self.pattern;
+        let width = config.width;
+        let height = config.height;
+
+        let fw_gain = match full_well.remote_ty {
+            FullWellModes::Low => config.low_full_well_gain,
+            _ => 1.0,
+        };
+        let exp_secs = exp_time as f64 / 1000.0;
+        let phantom = match self.source_on.load(Ordering::SeqCst) {
+            true => config.phantom.as_ref(),
+            false => None,
+        };
+
+        let mut frame = vec![0u16; (width * height) as usize];
+
+        frame
+            .par_chunks_mut(width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let mut rng = StdRng::seed_from_u64(mix_seed(frame_seed, y as u64));
+                let y = y as u32;
+
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    let x = x as u32;
+                    let idx = (y * width + x) as usize;
+
+                    if pattern.dead[idx] {
+                        *pixel = 0;
+                        continue;
+                    }
+
+                    let signal = match phantom {
+                        Some(phantom) => {
+                            config.flux
+                                * exp_time as f64
+                                * phantom.transmission(x, y, width, height)
+                                * pattern.gain[idx] as f64
+                        }
+                        None => 0.0,
+                    };
+                    let dark = pattern.dark_current[idx] as f64 * exp_secs;
+
+                    let offset = match dds {
+                        true => config.dark_offset,
+                        false => pattern.offset[idx] as f64,
+                    };
+
+                    let electrons = poisson(&mut rng, (signal + dark) * config.electrons_per_adu);
+                    let value = electrons / config.electrons_per_adu * fw_gain
+                        + offset
+                        + config.read_noise * gaussian(&mut rng);
+
+                    *pixel = value.round().clamp(0.0, MAX_PIXEL_VALUE) as u16;
+                }
+            });
+
+        frame
+    }
+
+    fn read_out(
+        &self,
+        readout: &Readout,
+        exp_time: u32,
+        full_well: &FullWellModesRS,
+        frame_seed: u64,
+    ) -> Vec<u16> {
+        let frame = self.generate_pixels(exp_time, full_well, frame_seed, readout.dds);
+        let width = self.config.width as usize;
+        let binning = readout.binning as usize;
+        let (binned_width, binned_height) = readout.binned_dimensions(&self.config);
+
+        let binned: Vec<u16> = if binning == 1 {
+            frame
+        } else {
+            (0..binned_height as usize)
+                .flat_map(|y| (0..binned_width as usize).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    let sum: u32 = (0..binning)
+                        .flat_map(|dy| (0..binning).map(move |dx| (dx, dy)))
+                        .map(|(dx, dy)| frame[(y * binning + dy) * width + x * binning + dx] as u32)
+                        .sum();
+                    (sum as f64 / (binning * binning) as f64).round() as u16
+                })
+                .collect()
+        };
+
+        match readout.roi {
+            Some(roi) => (roi.y..roi.y + roi.height)
+                .flat_map(|y| {
+                    let start = (y * binned_width + roi.x) as usize;
+                    binned[start..start + roi.width as usize].iter().copied()
+                })
+                .collect(),
+            None => binned,
+        }
+    }
+
+    fn write_frame(
+        &self,
+        buffer: &mut SLImageRs,
+        frame: &[u16],
+        (width, height): (u32, u32),
+    ) -> Result<(), InternalSLError> {
+        if buffer.get_width() != width || buffer.get_height() != height {
+            return Err(SLError::SL_ERROR_INVALID_PARAM.into());
+        }
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                frame.as_ptr() as *const u8,
+                buffer.get_data_pointer(0),
+                frame.len() * 2,
+            );
+        }
+        Ok(())
+    }
+
+    fn exposure_duration(exp_time: u32) -> Duration {
+        Duration::from_millis(exp_time as u64)
+    }
+}
+
+impl DetectorBackend for SimulatedDetector {
+    fn open_camera(&mut self, _buffer_depth: u32) -> Result<(), InternalSLError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.connected {
+            return Err(SLError::SL_ERROR_NO_DEVICE.into());
+        }
+        state.open = true;
+        Ok(())
+    }
+
+    fn is_connected(&mut self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.connected && state.open
+    }
+
+    fn image_width(&mut self) -> Result<u32, ()> {
+        let readout = self.state.lock().unwrap().readout;
+        Ok(readout.dimensions(&self.config).0)
+    }
+
+    fn image_height(&mut self) -> Result<u32, ()> {
+        let readout = self.state.lock().unwrap().readout;
+        Ok(readout.dimensions(&self.config).1)
+    }
+
+    fn identity(&mut self) -> Result<DetectorIdentity, ()> {
+        Ok(DetectorIdentity {
+            model: "Simulated".to_string(),
+            serial: Some(format!("seed-{}", self.config.seed)),
+            width: self.config.width,
+            height: self.config.height,
+        })
+    }
+
+    fn set_exposure_time(&mut self, exp_time: u32) -> Result<(), InternalSLError> {
+        self.state.lock().unwrap().exp_time = exp_time;
+        Ok(())
+    }
+
+    fn set_exposure_mode(&mut self, _ex_mode: ExposureModes) -> Result<(), InternalSLError> {
+        Ok(())
+    }
+
+    fn set_number_frames(&mut self, frame_count: u32) -> Result<(), InternalSLError> {
+        self.state.lock().unwrap().num_frames = frame_count;
+        Ok(())
+    }
+
+    fn set_full_well(&mut self, full_well: FullWellModesRS) -> Result<(), InternalSLError> {
+        self.state.lock().unwrap().full_well = full_well;
+        Ok(())
+    }
+
+    // Changing the binning resets the readout region, as it no longer fits the frame
+    fn set_binning_mode(&mut self, binning_mode: BinningModesRS) -> Result<(), InternalSLError> {
+        let binning = match binning_mode.0 {
+            BinningModes::x11 => 1,
+            BinningModes::x22 => 2,
+            BinningModes::x44 => 4,
+            BinningModes::BinningUnknown => return Err(SLError::SL_ERROR_NOT_SUPPORTED.into()),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.readout.binning = binning;
+        state.readout.roi = None;
+        Ok(())
+    }
+
+    fn set_dds(&mut self, dds: bool) -> Result<(), InternalSLError> {
+        self.state.lock().unwrap().readout.dds = dds;
+        Ok(())
+    }
+
+    fn set_roi(&mut self, roi: Option<Roi>) -> Result<(), InternalSLError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(roi) = roi {
+            let (width, height) = state.readout.binned_dimensions(&self.config);
+            if roi.width == 0
+                || roi.height == 0
+                || roi.x + roi.width > width
+                || roi.y + roi.height > height
+            {
+                return Err(SLError::SL_ERROR_INVALID_PARAM.into());
+            }
+        }
+        state.readout.roi = roi;
+        Ok(())
+    }
+
+    fn start_stream(&mut self, exp_time: u32) -> Result<(), InternalSLError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.open {
+            return Err(SLError::SL_ERROR_DEVICE_CLOSED.into());
+        }
+        state.exp_time = exp_time;
+        state.live = true;
+        state.streaming = true;
+        state.trigger_time = Some(Instant::now());
+        state.sequence_index += 1;
+        state.stream_frames_read = 0;
+        Ok(())
+    }
+
+    fn go_live(&mut self) -> Result<(), InternalSLError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.open {
+            return Err(SLError::SL_ERROR_DEVICE_CLOSED.into());
+        }
+        state.live = true;
+        Ok(())
+    }
+
+    fn go_unlive(&mut self, _wipe_stack: bool) -> Result<(), InternalSLError> {
+        let mut state = self.state.lock().unwrap();
+        state.live = false;
+        state.streaming = false;
+        state.trigger_time = None;
+        Ok(())
+    }
+
+    fn software_trigger(&mut self) -> Result<(), InternalSLError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.live {
+            return Err(SLError::SL_ERROR_OTHER.into());
+        }
+        state.trigger_time = Some(Instant::now());
+        state.sequence_index += 1;
+        Ok(())
+    }
+
+    fn read_buffer(
+        &mut self,
+        buffer: &mut SLImageRs,
+        buf_num: u32,
+        timeout: u32,
+    ) -> Result<(), InternalSLError> {
+        let (exp_time, full_well, readout, trigger_time, sequence_index) = {
+            let state = self.state.lock().unwrap();
+            if !state.connected {
+                return Err(SLError::SL_ERROR_DEVICE_CLOSED.into());
+            }
+            if buf_num >= state.num_frames {
+                return Err(SLError::SL_ERROR_INVALID_PARAM.into());
+            }
+            match state.trigger_time {
+                Some(trigger_time) if state.live => (
+                    state.exp_time,
+                    state.full_well.clone(),
+                    state.readout,
+                    trigger_time,
+                    state.sequence_index,
+                ),
+                _ => return Err(SLError::SL_ERROR_OTHER.into()),
+            }
+        };
+
+        if self.config.realtime {
+            let ready_at = trigger_time + Self::exposure_duration(exp_time) * (buf_num + 1);
+            let now = Instant::now();
+            if ready_at > now {
+                let wait = ready_at - now;
+                if wait > Duration::from_millis(timeout as u64) {
+                    thread::sleep(Duration::from_millis(timeout as u64));
+                    return Err(SLError::SL_ERROR_TIMEOUT.into());
+                }
+                thread::sleep(wait);
+            }
+        }
+
+        let frame_seed = mix_seed(mix_seed(self.config.seed, sequence_index), buf_num as u64);
+        let frame = self.read_out(&readout, exp_time, &full_well, frame_seed);
+        self.write_frame(buffer, &frame, readout.dimensions(&self.config))
+    }
+
+    fn read_frame(&mut self, buffer: &mut SLImageRs, _read_oldest_first: bool) -> bool {
+        let (exp_time, full_well, readout, frame_index, sequence_index) = {
+            let mut state = self.state.lock().unwrap();
+            if !state.connected || !state.streaming {
+                return false;
+            }
+
+            let trigger_time = match state.trigger_time {
+                Some(trigger_time) => trigger_time,
+                None => return false,
+            };
+
+            if self.config.realtime {
+                let frames_ready =
+                    trigger_time.elapsed().as_millis() as u64 / state.exp_time.max(1) as u64;
+                if frames_ready <= state.stream_frames_read {
+                    return false;
+                }
+            }
+
+            state.stream_frames_read += 1;
+            (
+                state.exp_time,
+                state.full_well.clone(),
+                state.readout,
+                state.stream_frames_read,
+                state.sequence_index,
+            )
+        };
+
+        let frame_seed = mix_seed(mix_seed(self.config.seed, sequence_index), frame_index);
+        let frame = self.read_out(&readout, exp_time, &full_well, frame_seed);
+        self.write_frame(buffer, &frame, readout.dimensions(&self.config))
+            .is_ok()
+    }
+
+    fn clone_box(&self) -> Box<dyn DetectorBackend> {
+        Box::new(self.clone())
+    }
+}
+
+// SplitMix64 finaliser, used to derive independent per-frame and per-row seeds
+fn mix_seed(seed: u64, value: u64) -> u64 {
+    let mut z = seed ^ value.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+fn gaussian<R: Rng>(rng: &mut R) -> f64 {
+    // Box-Muller transform
+    let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+fn poisson<R: Rng>(rng: &mut R, lambda: f64) -> f64 {
+    if lambda <= 0.0 {
+        return 0.0;
+    }
+
+    // Knuth's method for small means, normal approximation otherwise
+    if lambda < 30.0 {
+        let limit = (-lambda).exp();
+        let mut k = 0.0;
+        let mut p = 1.0;
+        loop {
+            p *= rng.gen::<f64>();
+            if p <= limit {
+                return k;
+            }
+            k += 1.0;
+        }
+    }
+
+    (lambda + lambda.sqrt() * gaussian(rng)).round().max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        capture::{backend::DetectorBackend, capture::Roi},
+        wrapper::{BinningModes, BinningModesRS, FullWellModes, FullWellModesRS},
+    };
+
+    use super::{SimulatedDetector, SimulatedDetectorConfig};
+
+    fn small_config() -> SimulatedDetectorConfig {
+        SimulatedDetectorConfig {
+            width: 64,
+            height: 48,
+            hot_pixels: 5,
+            dead_pixels: 5,
+            hot_columns: 1,
+            dead_columns: 1,
+            realtime: false,
+            ..Default::default()
+        }
+    }
+
+    fn mean(frame: &[u16]) -> f64 {
+        frame.iter().map(|&v| v as f64).sum::<f64>() / frame.len() as f64
+    }
+
+    fn high_full_well() -> FullWellModesRS {
+        FullWellModesRS {
+            remote_ty: FullWellModes::High,
+        }
+    }
+
+    #[test]
+    fn seeded_frames_are_deterministic() {
+        let a = SimulatedDetector::new(small_config());
+        let b = SimulatedDetector::new(small_config());
+
+        assert_eq!(
+            a.generate_frame(100, &high_full_well(), 7),
+            b.generate_frame(100, &high_full_well(), 7)
+        );
+        assert_ne!(
+            a.generate_frame(100, &high_full_well(), 7),
+            a.generate_frame(100, &high_full_well(), 8)
+        );
+    }
+
+    #[test]
+    fn dark_frames_sit_on_offset_and_grow_with_exposure() {
+        let detector = SimulatedDetector::new(SimulatedDetectorConfig {
+            hot_pixels: 0,
+            dead_pixels: 0,
+            hot_columns: 0,
+            dead_columns: 0,
+            dark_current: 100.0,
+            ..small_config()
+        });
+
+        let short = mean(&detector.generate_frame(10, &high_full_well(), 1));
+        let long = mean(&detector.generate_frame(2000, &high_full_well(), 1));
+
+        assert!((short - 300.0).abs() < 3.0, "short dark mean {short}");
+        assert!((long - short - 199.0).abs() < 10.0, "long dark mean {long}");
+    }
+
+    #[test]
+    fn injected_defects_are_visible() {
+        let detector = SimulatedDetector::new(small_config());
+        let frame = detector.generate_frame(1000, &high_full_well(), 3);
+        let width = detector.config().width;
+
+        for &(x, y) in detector.defects().dead_pixels.iter() {
+            assert_eq!(frame[(y * width + x) as usize], 0);
+        }
+
+        let defects = detector.defects();
+        for &(x, y) in defects.hot_pixels.iter() {
+            if !defects.dead_columns.contains(&x) && !defects.dead_pixels.contains(&(x, y)) {
+                assert!(frame[(y * width + x) as usize] > 2000);
+            }
+        }
+    }
+
+    #[test]
+    fn readout_follows_binning_and_roi() {
+        let mut detector = SimulatedDetector::new(small_config());
+        detector
+            .set_binning_mode(BinningModesRS(BinningModes::x22))
+            .unwrap();
+        assert_eq!(detector.image_width(), Ok(32));
+        assert_eq!(detector.image_height(), Ok(24));
+
+        let roi = Roi {
+            x: 4,
+            y: 2,
+            width: 10,
+            height: 8,
+        };
+        detector.set_roi(Some(roi)).unwrap();
+        assert_eq!(detector.image_width(), Ok(10));
+        assert_eq!(detector.image_height(), Ok(8));
+        assert!(detector.set_roi(Some(Roi { x: 30, ..roi })).is_err());
+
+        // Binning again clears a region that may no longer fit
+        detector
+            .set_binning_mode(BinningModesRS(BinningModes::x44))
+            .unwrap();
+        assert_eq!(detector.image_width(), Ok(16));
+    }
+}
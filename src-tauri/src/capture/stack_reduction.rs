@@ -0,0 +1,568 @@
+use std::cmp::Ordering;
+
+use image::{ImageBuffer, Luma};
+use log::info;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use super::{
+    corrections::CorrectionError,
+    defect_map::{upper_threshold, StackStatistics},
+};
+
+type Frame = ImageBuffer<Luma<u16>, Vec<u16>>;
+
+// How the frames of a calibration stack are combined into one value per pixel
+#[derive(Clone, Debug, Serialize, Deserialize, Type, PartialEq)]
+#[serde(tag = "type")]
+pub enum StackReducer {
+    Mean,
+    Median,
+    // Mean after repeatedly dropping samples more than `sigma` standard deviations from the median
+    SigmaClippedMean { sigma: f32, iterations: u32 },
+    // Mean after dropping `fraction` of the samples from each end
+    TrimmedMean { fraction: f32 },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Type, PartialEq)]
+pub struct StackReductionSettings {
+    pub reducer: StackReducer,
+    // Whole frames further than this many robust standard deviations from the rest of the
+    // stack are dropped before reducing, None keeps every frame
+    pub frame_rejection_sigma: Option<f32>,
+}
+
+impl Default for StackReductionSettings {
+    fn default() -> Self {
+        StackReductionSettings {
+            reducer: StackReducer::SigmaClippedMean {
+                sigma: 3.0,
+                iterations: 3,
+            },
+            frame_rejection_sigma: Some(6.0),
+        }
+    }
+}
+
+// What was left out when reducing a stack, recorded with the maps built from it
+#[derive(Clone, Debug, Serialize, Deserialize, Type, PartialEq)]
+pub struct StackReductionSummary {
+    pub reducer: StackReducer,
+    // Indices of the frames dropped as a whole
+    pub rejected_frames: Vec<u32>,
+    // Individual pixel samples clipped or trimmed from the frames that were kept
+    pub rejected_pixels: u32,
+}
+
+// Reducers that take a frame at a time, so frames can be averaged as they're captured without
+// keeping the stack
+#[derive(Clone, Debug, Serialize, Deserialize, Type, PartialEq)]
+#[serde(tag = "type")]
+pub enum IncrementalReducer {
+    Mean,
+    // Stochastic approximation of the median, which settles close to the exact median after a
+    // few frames of Gaussian-like noise
+    Median,
+    // Mean of the samples within `sigma` standard deviations of those kept before them
+    SigmaClippedMean { sigma: f32 },
+}
+
+// Samples are only clipped once there are enough to estimate the spread of a pixel
+const MIN_CLIP_SAMPLES: u32 = 3;
+// Samples are whole ADU, so a pixel that read the same every time is still given this spread
+const MIN_STD_DEV: f64 = 1.0;
+// The median of Gaussian noise is sqrt(pi / 2) times noisier than the mean
+const MEDIAN_NOISE_FACTOR: f64 = 1.2533;
+
+// What averaging a capture's frames achieved, kept with the averaged image
+#[derive(Clone, Debug, Serialize, Deserialize, Type, PartialEq)]
+pub struct AveragingSummary {
+    pub reducer: IncrementalReducer,
+    pub frame_count: u32,
+    // Samples left out by sigma clipping
+    pub rejected_pixels: u32,
+    // RMS frame to frame noise of a pixel in a single frame, in ADU
+    pub single_frame_noise: f32,
+    // Estimated noise left in the averaged image, in ADU
+    pub averaged_noise: f32,
+    // How many times quieter the averaged image is than a single frame
+    pub noise_reduction: f32,
+}
+
+pub struct StackReduction {
+    // Reduced value per pixel in `mean`, with the spread of the samples that were kept
+    pub stack: StackStatistics,
+    pub summary: StackReductionSummary,
+}
+
+// Keeps every frame of a stack, as robust reducers need all the samples for a pixel at once
+pub struct FrameStack {
+    width: u32,
+    height: u32,
+    exp_time: u32,
+    frames: Vec<Vec<u16>>,
+}
+
+impl FrameStack {
+    pub fn new(width: u32, height: u32, exp_time: u32) -> Self {
+        FrameStack {
+            width,
+            height,
+            exp_time,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, frame: &ImageBuffer<Luma<u16>, Vec<u16>>) -> Result<(), CorrectionError> {
+        if frame.dimensions() != (self.width, self.height) {
+            return Err(CorrectionError::DimensionMismatch(
+                frame.width(),
+                frame.height(),
+                self.width,
+                self.height,
+            ));
+        }
+        self.frames.push(frame.as_raw().clone());
+        Ok(())
+    }
+
+    pub fn reduce(
+        &self,
+        settings: &StackReductionSettings,
+    ) -> Result<StackReduction, CorrectionError> {
+        if self.frames.is_empty() {
+            return Err(CorrectionError::NoFrames);
+        }
+
+        let rejected_frames = match settings.frame_rejection_sigma {
+            Some(sigma) => self.find_bad_frames(sigma),
+            None => Vec::new(),
+        };
+        let kept: Vec<&Vec<u16>> = self
+            .frames
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| !rejected_frames.contains(&(*idx as u32)))
+            .map(|(_, frame)| frame)
+            .collect();
+
+        let len = (self.width * self.height) as usize;
+        let mut mean = Vec::with_capacity(len);
+        let mut std_dev = Vec::with_capacity(len);
+        let mut rejected_pixels = 0;
+        let mut samples = Vec::with_capacity(kept.len());
+        for i in 0..len {
+            samples.clear();
+            samples.extend(kept.iter().map(|frame| frame[i] as f32));
+
+            let (value, used) = reduce_samples(&mut samples, &settings.reducer);
+            rejected_pixels += (kept.len() - used.len()) as u32;
+            mean.push(value);
+            std_dev.push(sample_std_dev(used));
+        }
+
+        if !rejected_frames.is_empty() || rejected_pixels > 0 {
+            info!(
+                "Rejected {} of {} frames and {rejected_pixels} pixel samples from the {}ms stack",
+                rejected_frames.len(),
+                self.frames.len(),
+                self.exp_time
+            );
+        }
+
+        Ok(StackReduction {
+            stack: StackStatistics {
+                width: self.width,
+                height: self.height,
+                exp_time: self.exp_time,
+                frame_count: kept.len() as u32,
+                mean,
+                std_dev,
+            },
+            summary: StackReductionSummary {
+                reducer: settings.reducer.clone(),
+                rejected_frames,
+                rejected_pixels,
+            },
+        })
+    }
+
+    // Scores each frame by its mean absolute difference from the per-pixel median of the stack,
+    // which picks up readout glitches and level jumps as well as frames full of transients
+    fn find_bad_frames(&self, sigma: f32) -> Vec<u32> {
+        // Too few frames to tell which ones are the odd ones out
+        if self.frames.len() < 3 {
+            return Vec::new();
+        }
+
+        let len = (self.width * self.height) as usize;
+        let mut samples = Vec::with_capacity(self.frames.len());
+        let median_frame: Vec<f32> = (0..len)
+            .map(|i| {
+                samples.clear();
+                samples.extend(self.frames.iter().map(|frame| frame[i] as f32));
+                sample_median(&mut samples)
+            })
+            .collect();
+
+        let scores: Vec<f32> = self
+            .frames
+            .iter()
+            .map(|frame| {
+                let total: f64 = frame
+                    .iter()
+                    .zip(median_frame.iter())
+                    .map(|(&value, &median)| (value as f32 - median).abs() as f64)
+                    .sum();
+                (total / len.max(1) as f64) as f32
+            })
+            .collect();
+
+        let threshold = upper_threshold(&scores, sigma);
+        scores
+            .iter()
+            .enumerate()
+            .filter(|(_, &score)| score > threshold)
+            .map(|(idx, _)| idx as u32)
+            .collect()
+    }
+}
+
+// Reduces a stack one frame at a time, keeping only running statistics per pixel
+pub struct IncrementalStack {
+    width: u32,
+    height: u32,
+    reducer: IncrementalReducer,
+    frame_count: u32,
+    // Welford's online mean and variance of every sample
+    mean: Vec<f64>,
+    m2: Vec<f64>,
+    // Only used by the reducer they're for
+    median: Vec<f64>,
+    kept_count: Vec<u32>,
+    kept_mean: Vec<f64>,
+    kept_m2: Vec<f64>,
+    rejected_pixels: u32,
+}
+
+impl IncrementalStack {
+    pub fn new(width: u32, height: u32, reducer: IncrementalReducer) -> Self {
+        let len = (width * height) as usize;
+        let len_for = |used: bool| if used { len } else { 0 };
+        let median = matches!(reducer, IncrementalReducer::Median);
+        let clipped = matches!(reducer, IncrementalReducer::SigmaClippedMean { .. });
+        IncrementalStack {
+            width,
+            height,
+            reducer,
+            frame_count: 0,
+            mean: vec![0.0; len],
+            m2: vec![0.0; len],
+            median: vec![0.0; len_for(median)],
+            kept_count: vec![0; len_for(clipped)],
+            kept_mean: vec![0.0; len_for(clipped)],
+            kept_m2: vec![0.0; len_for(clipped)],
+            rejected_pixels: 0,
+        }
+    }
+
+    pub fn add(&mut self, frame: &ImageBuffer<Luma<u16>, Vec<u16>>) -> Result<(), CorrectionError> {
+        if frame.dimensions() != (self.width, self.height) {
+            return Err(CorrectionError::DimensionMismatch(
+                frame.width(),
+                frame.height(),
+                self.width,
+                self.height,
+            ));
+        }
+
+        self.frame_count += 1;
+        let n = self.frame_count as f64;
+        for (i, &value) in frame.iter().enumerate() {
+            let value = value as f64;
+            match self.reducer {
+                IncrementalReducer::Mean => {}
+                IncrementalReducer::Median => {
+                    // Robbins-Monro, stepping towards each sample by less every frame, scaled by
+                    // the noise of the samples before it so an outlier can't widen its own step
+                    let median = self.median[i];
+                    let std_dev = (self.m2[i] / (n - 2.0).max(1.0)).sqrt().max(MIN_STD_DEV);
+                    let step = MEDIAN_NOISE_FACTOR * std_dev / n;
+                    self.median[i] = if self.frame_count == 1 {
+                        value
+                    } else if value > median {
+                        (median + step).min(value)
+                    } else {
+                        (median - step).max(value)
+                    };
+                }
+                IncrementalReducer::SigmaClippedMean { sigma } => {
+                    let count = self.kept_count[i];
+                    let std_dev = (self.kept_m2[i] / (count.max(2) - 1) as f64)
+                        .sqrt()
+                        .max(MIN_STD_DEV);
+                    if count >= MIN_CLIP_SAMPLES
+                        && (value - self.kept_mean[i]).abs() > sigma as f64 * std_dev
+                    {
+                        self.rejected_pixels += 1;
+                    } else {
+                        self.kept_count[i] = count + 1;
+                        let delta = value - self.kept_mean[i];
+                        self.kept_mean[i] += delta / (count + 1) as f64;
+                        self.kept_m2[i] += delta * (value - self.kept_mean[i]);
+                    }
+                }
+            }
+
+            let delta = value - self.mean[i];
+            self.mean[i] += delta / n;
+            self.m2[i] += delta * (value - self.mean[i]);
+        }
+        Ok(())
+    }
+
+    pub fn finish(&self) -> Result<(Frame, AveragingSummary), CorrectionError> {
+        if self.frame_count == 0 {
+            return Err(CorrectionError::NoFrames);
+        }
+
+        let values = match self.reducer {
+            IncrementalReducer::Mean => &self.mean,
+            IncrementalReducer::Median => &self.median,
+            IncrementalReducer::SigmaClippedMean { .. } => &self.kept_mean,
+        };
+        let image = ImageBuffer::from_vec(
+            self.width,
+            self.height,
+            values
+                .iter()
+                .map(|&value| value.round().clamp(0.0, u16::MAX as f64) as u16)
+                .collect(),
+        )
+        .unwrap();
+
+        let n = self.frame_count as f64;
+        let pixel_count = self.mean.len().max(1) as f64;
+        let variance = |m2: f64, count: f64| if count > 1.0 { m2 / (count - 1.0) } else { 0.0 };
+        let frame_variance = self.m2.iter().map(|&m2| variance(m2, n)).sum::<f64>() / pixel_count;
+        let averaged_variance = match self.reducer {
+            IncrementalReducer::Mean => frame_variance / n,
+            IncrementalReducer::Median => MEDIAN_NOISE_FACTOR.powi(2) * frame_variance / n,
+            IncrementalReducer::SigmaClippedMean { .. } => {
+                self.kept_m2
+                    .iter()
+                    .zip(self.kept_count.iter())
+                    .map(|(&m2, &count)| variance(m2, count as f64) / count.max(1) as f64)
+                    .sum::<f64>()
+                    / pixel_count
+            }
+        };
+
+        let single_frame_noise = frame_variance.sqrt() as f32;
+        let averaged_noise = averaged_variance.sqrt() as f32;
+        let summary = AveragingSummary {
+            reducer: self.reducer.clone(),
+            frame_count: self.frame_count,
+            rejected_pixels: self.rejected_pixels,
+            single_frame_noise,
+            averaged_noise,
+            noise_reduction: if averaged_noise > 0.0 {
+                single_frame_noise / averaged_noise
+            } else {
+                1.0
+            },
+        };
+        Ok((image, summary))
+    }
+}
+
+// Reduces the samples for one pixel, returning the value and the samples it was taken over
+fn reduce_samples<'a>(samples: &'a mut [f32], reducer: &StackReducer) -> (f32, &'a [f32]) {
+    match reducer {
+        StackReducer::Mean => (sample_mean(samples), samples),
+        StackReducer::Median => (sample_median(samples), samples),
+        StackReducer::TrimmedMean { fraction } => {
+            samples.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            let trim = ((samples.len() as f32 * fraction.clamp(0.0, 0.5)) as usize)
+                .min(samples.len().saturating_sub(1) / 2);
+            let kept = &samples[trim..samples.len() - trim];
+            (sample_mean(kept), kept)
+        }
+        StackReducer::SigmaClippedMean { sigma, iterations } => {
+            samples.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            let (mut start, mut end) = (0, samples.len());
+            for _ in 0..*iterations {
+                let kept = &samples[start..end];
+                let center = sorted_median(kept);
+                let limit = sigma * sample_std_dev(kept);
+                if limit <= 0.0 {
+                    break;
+                }
+
+                // Samples are sorted, so clipping only ever moves the ends inwards
+                let (mut new_start, mut new_end) = (start, end);
+                while new_start < new_end && center - samples[new_start] > limit {
+                    new_start += 1;
+                }
+                while new_end > new_start && samples[new_end - 1] - center > limit {
+                    new_end -= 1;
+                }
+                if (new_start, new_end) == (start, end) || new_start == new_end {
+                    break;
+                }
+                (start, end) = (new_start, new_end);
+            }
+            let kept = &samples[start..end];
+            (sample_mean(kept), kept)
+        }
+    }
+}
+
+fn sample_mean(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    samples.iter().map(|&v| v as f64).sum::<f64>() as f32 / samples.len() as f32
+}
+
+fn sample_std_dev(samples: &[f32]) -> f32 {
+    if samples.len() < 2 {
+        return 0.0;
+    }
+    let mean = sample_mean(samples) as f64;
+    let variance = samples
+        .iter()
+        .map(|&v| (v as f64 - mean).powi(2))
+        .sum::<f64>()
+        / (samples.len() - 1) as f64;
+    variance.sqrt() as f32
+}
+
+fn sample_median(samples: &mut [f32]) -> f32 {
+    samples.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    sorted_median(samples)
+}
+
+fn sorted_median(sorted: &[f32]) -> f32 {
+    let len = sorted.len();
+    match len {
+        0 => 0.0,
+        _ if len % 2 == 1 => sorted[len / 2],
+        _ => (sorted[len / 2 - 1] + sorted[len / 2]) / 2.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Luma};
+
+    use super::{
+        FrameStack, IncrementalReducer, IncrementalStack, StackReducer, StackReductionSettings,
+    };
+
+    // Two pixels, the second of which is hit by a transient in the last frame
+    fn stack_with_transient() -> FrameStack {
+        let mut stack = FrameStack::new(2, 1, 100);
+        for (first, second) in [(100, 200), (102, 202), (98, 198), (101, 201), (99, 4000)] {
+            let frame: ImageBuffer<Luma<u16>, Vec<u16>> =
+                ImageBuffer::from_vec(2, 1, vec![first, second]).unwrap();
+            stack.add(&frame).unwrap();
+        }
+        stack
+    }
+
+    fn settings(reducer: StackReducer) -> StackReductionSettings {
+        StackReductionSettings {
+            reducer,
+            frame_rejection_sigma: None,
+        }
+    }
+
+    #[test]
+    fn robust_reducers_ignore_transients() {
+        let stack = stack_with_transient();
+
+        let mean = stack.reduce(&settings(StackReducer::Mean)).unwrap();
+        assert_eq!(mean.stack.mean, vec![100.0, 960.2]);
+        assert_eq!(mean.summary.rejected_pixels, 0);
+
+        let median = stack.reduce(&settings(StackReducer::Median)).unwrap();
+        assert_eq!(median.stack.mean, vec![100.0, 201.0]);
+
+        let trimmed = stack
+            .reduce(&settings(StackReducer::TrimmedMean { fraction: 0.2 }))
+            .unwrap();
+        assert_eq!(trimmed.stack.mean, vec![100.0, 201.0]);
+        assert_eq!(trimmed.summary.rejected_pixels, 4);
+
+        let clipped = stack
+            .reduce(&settings(StackReducer::SigmaClippedMean {
+                sigma: 1.5,
+                iterations: 3,
+            }))
+            .unwrap();
+        assert_eq!(clipped.stack.mean[1], 200.25);
+        assert_eq!(clipped.summary.rejected_pixels, 1);
+        assert_eq!(clipped.stack.frame_count, 5);
+    }
+
+    #[test]
+    fn incremental_reducers_reduce_frames_as_they_arrive() {
+        let reduce = |reducer: IncrementalReducer| {
+            let mut stack = IncrementalStack::new(2, 1, reducer);
+            for (first, second) in [(100, 200), (102, 202), (98, 198), (101, 201), (99, 4000)] {
+                let frame: ImageBuffer<Luma<u16>, Vec<u16>> =
+                    ImageBuffer::from_vec(2, 1, vec![first, second]).unwrap();
+                stack.add(&frame).unwrap();
+            }
+            stack.finish().unwrap()
+        };
+
+        let (mean, summary) = reduce(IncrementalReducer::Mean);
+        assert_eq!(mean.as_raw(), &vec![100, 960]);
+        assert!((summary.noise_reduction - 5f32.sqrt()).abs() < 1e-3);
+
+        let (median, _) = reduce(IncrementalReducer::Median);
+        assert!(median.as_raw()[0].abs_diff(100) <= 1);
+        assert!(median.as_raw()[1] < 300);
+
+        let (clipped, summary) = reduce(IncrementalReducer::SigmaClippedMean { sigma: 3.0 });
+        assert_eq!(clipped.as_raw(), &vec![100, 200]);
+        assert_eq!(summary.rejected_pixels, 1);
+        assert!(summary.averaged_noise < summary.single_frame_noise / 5f32.sqrt());
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        let mut stack = FrameStack::new(4, 1, 100);
+        for offset in [0, 1, 0, 2, 1, 0] {
+            let frame: ImageBuffer<Luma<u16>, Vec<u16>> =
+                ImageBuffer::from_vec(4, 1, vec![100 + offset; 4]).unwrap();
+            stack.add(&frame).unwrap();
+        }
+        // A frame read out with a level jump
+        let glitched: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_vec(4, 1, vec![900, 900, 900, 900]).unwrap();
+        stack.add(&glitched).unwrap();
+
+        let reduction = stack
+            .reduce(&StackReductionSettings {
+                reducer: StackReducer::Mean,
+                frame_rejection_sigma: Some(6.0),
+            })
+            .unwrap();
+
+        assert_eq!(reduction.summary.rejected_frames, vec![6]);
+        assert_eq!(reduction.stack.frame_count, 6);
+        assert!(reduction.stack.mean.iter().all(|&v| v < 102.0));
+    }
+
+    #[test]
+    fn rejects_mismatched_frames() {
+        let mut stack = FrameStack::new(2, 1, 100);
+        let frame: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_vec(1, 2, vec![1, 2]).unwrap();
+        assert!(stack.add(&frame).is_err());
+        assert!(stack.reduce(&StackReductionSettings::default()).is_err());
+    }
+}
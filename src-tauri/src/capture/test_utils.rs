@@ -1,11 +1,19 @@
 pub mod test_utils {
     use std::time::Duration;
 
-    use tauri::{test::MockRuntime, AppHandle};
+    #[cfg(feature = "spectrum-logic")]
     use tauri_plugin_log::{fern::colors::ColoredLevelConfig, Target, TargetKind};
 
-    use crate::capture::{detector::DetectorController, types::CaptureManagerEvent};
+    #[cfg(feature = "spectrum-logic")]
+    use crate::capture::types::CaptureManagerEvent;
+    use crate::capture::{
+        detector::DetectorController,
+        simulated::{SimulatedDetector, SimulatedDetectorConfig},
+    };
+    #[cfg(feature = "spectrum-logic")]
+    use crate::wrapper::SLDeviceRS;
 
+    #[cfg(feature = "spectrum-logic")]
     pub fn create_app<R: tauri::Runtime>(builder: tauri::Builder<R>) -> tauri::App<R> {
         let specta_builder =
             tauri_specta::ts::builder().events(tauri_specta::collect_events!(CaptureManagerEvent));
@@ -23,16 +31,28 @@ pub mod test_utils {
             .expect("failed to build app")
     }
 
-    pub fn setup_controller_handle(app_handle: AppHandle<MockRuntime>) -> DetectorController {
-        let controller = DetectorController::new(|status| {});
+    #[cfg(feature = "spectrum-logic")]
+    pub fn setup_controller_handle(
+        app_handle: AppHandle<MockRuntime>,
+    ) -> DetectorController<SLDeviceRS> {
+        let controller = DetectorController::new(SLDeviceRS::new(), |status| {});
         std::thread::sleep(Duration::from_secs(2));
         controller
     }
 
-    pub fn setup_controller() -> DetectorController {
+    #[cfg(feature = "spectrum-logic")]
+    pub fn setup_controller() -> DetectorController<SLDeviceRS> {
         let app = create_app(tauri::test::mock_builder());
-        let controller = DetectorController::new(|_| {});
+        let controller = DetectorController::new(SLDeviceRS::new(), |_| {});
         std::thread::sleep(Duration::from_secs(2));
         controller
     }
+
+    pub fn setup_simulated_controller(
+        config: SimulatedDetectorConfig,
+    ) -> DetectorController<SimulatedDetector> {
+        let controller = DetectorController::new(SimulatedDetector::new(config), |_| {});
+        std::thread::sleep(Duration::from_millis(500));
+        controller
+    }
 }
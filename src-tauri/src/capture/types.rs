@@ -1,24 +1,31 @@
 use std::{
+    cmp,
     pin::Pin,
     sync::{
         atomic::{AtomicBool, Ordering},
-        mpsc::{self, Sender},
-        Arc,
+        Arc, Mutex,
     },
+    task::{Poll, Waker},
+    time::{Duration, Instant},
 };
 
 use crate::image::ImageHandler;
 
 use super::{
     advanced_capture::{
-        DarkMapCapture, DefectMapCapture, LiveCapture, MultiCapture, SignalAccumulationCapture,
-        SmartCapture,
+        AveragedCapture, DarkMapCapture, DefectMapCapture, FlatFieldCapture, LagMeasurementCapture,
+        LiveCapture, MultiCapture, SignalAccumulationCapture, SmartCapture, TimeLapseCapture,
     },
+    backend::DetectorBackend,
+    capture::CaptureError,
     capture_manager::CorrectionMaps,
+    corrections::CorrectionMapKey,
     detector::DetectorController,
+    provenance::StaleMap,
 };
 
 use enum_dispatch::enum_dispatch;
+use futures::future;
 use futures_core::Stream;
 use serde::{Deserialize, Serialize};
 use specta::Type;
@@ -39,11 +46,20 @@ pub struct DetectorInfo {
 pub enum CaptureManagerStatus {
     Available,
     Capturing(AdvancedCapture),
+    // Held between frames with the detector idle until resumed
+    Paused(AdvancedCapture),
     DarkMapsRequired,
     DefectMapsRequired,
+    // Captures are still allowed, but the listed maps should be regenerated
+    CalibrationStale(Vec<StaleMap>),
+    // Captures are allowed, but the detector has no serial number to confirm the maps were
+    // captured on it
+    CalibrationUnverified,
     DetectorDisconnected,
 }
 
+// Variant names are the tags the frontend sends, so they keep the Capture suffix
+#[allow(clippy::enum_variant_names)]
 #[enum_dispatch(AdvCapture)]
 #[derive(Clone, Serialize, Deserialize, Type, Debug, PartialEq)]
 #[serde(tag = "type")]
@@ -54,34 +70,227 @@ pub enum AdvancedCapture {
     LiveCapture,
     DarkMapCapture,
     DefectMapCapture,
+    FlatFieldCapture,
+    LagMeasurementCapture,
+    TimeLapseCapture,
+    AveragedCapture,
 }
 
+impl AdvancedCapture {
+    // Captures that generate the maps other captures are corrected with, so they can run
+    // before those maps exist
+    pub fn builds_calibration(&self) -> bool {
+        matches!(
+            self,
+            AdvancedCapture::DarkMapCapture(_)
+                | AdvancedCapture::DefectMapCapture(_)
+                | AdvancedCapture::FlatFieldCapture(_)
+        )
+    }
+
+    // Sweeps over several exposures, which can be held part way through
+    pub fn can_pause(&self) -> bool {
+        matches!(
+            self,
+            AdvancedCapture::MultiCapture(_)
+                | AdvancedCapture::AveragedCapture(_)
+                | AdvancedCapture::SignalAccumulationCapture(_)
+                | AdvancedCapture::SmartCapture(_)
+                | AdvancedCapture::DarkMapCapture(_)
+                | AdvancedCapture::DefectMapCapture(_)
+                | AdvancedCapture::FlatFieldCapture(_)
+                | AdvancedCapture::TimeLapseCapture(_)
+        )
+    }
+
+    // Live captures run until they're stopped, so never hand the detector on to anything else
+    pub fn terminates(&self) -> bool {
+        !matches!(self, AdvancedCapture::LiveCapture(_))
+    }
+}
+
+// Images are handed straight to the stream buffer, so they're not boxed
+#[allow(clippy::large_enum_variant)]
 pub enum CaptureStreamItem {
     Image(ImageHandler),
     Progress(CaptureProgress),
     CaptureResult(Vec<ImageHandler>),
+    // Always the last item of a capture that couldn't finish
+    Failed(CaptureError),
+    // Always the last item of a capture that was stopped before it finished
+    Cancelled,
+}
+
+// How a capture run from the frontend ended
+#[derive(Clone, Serialize, Type, Debug, PartialEq)]
+pub enum CaptureOutcome {
+    Completed,
+    // Result images the capture had finished before it was stopped are kept as a partial
+    // image stack
+    Cancelled { partial_frames: u32 },
 }
 
 #[derive(Clone, Serialize, Type, Debug)]
 pub struct CaptureProgress {
     message: String,
+    // Exposure step being captured, counting from 1
     current_step: u32,
     total_steps: u32,
+    // Frames captured so far over every step
+    frame: u32,
+    // None for captures that run until they're stopped
+    total_frames: Option<u32>,
+    elapsed_ms: u32,
+    eta_ms: Option<u32>,
+}
+
+impl CaptureProgress {
+    #[cfg(test)]
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+// A step of a capture, `frames` frames at `exp_time`ms, or processing when it has none
+#[derive(Clone, Copy, Debug)]
+pub struct ProgressStep {
+    pub exp_time: u32,
+    pub frames: u32,
+}
+
+// Follows a capture through its steps frame by frame, and estimates how long is left from the
+// exposure of every frame still to come plus the readout overhead seen on the frames so far
+pub struct ProgressTracker {
+    steps: Vec<ProgressStep>,
+    continuous: bool,
+    message: String,
+    // None until the first step starts
+    step: Option<usize>,
+    frames: u32,
+    step_frames: u32,
+    started: Instant,
+    step_started: Instant,
+    last_frame: Instant,
+    // Time each frame took beyond its exposure, in ms
+    overheads: Vec<f32>,
+    // Steps that start on a schedule take at least this long, apart from the last
+    interval_ms: Option<u32>,
+}
+
+// Lets a running capture be held, e.g. to reposition the sample part way through an exposure
+// sweep. Captures that support it check for a pause at frame boundaries and wait with the
+// detector out of live mode until resumed.
+#[derive(Clone)]
+pub struct PauseControl {
+    state: Arc<Mutex<PauseState>>,
+    on_change: Arc<dyn Fn(bool) + Send + Sync>,
+}
+
+#[derive(Default)]
+struct PauseState {
+    requested: bool,
+    waker: Option<Waker>,
+    paused_for: Duration,
+}
+
+impl PauseControl {
+    // `on_change` is told when the capture actually pauses and when it resumes
+    pub fn new(on_change: impl Fn(bool) + Send + Sync + 'static) -> Self {
+        PauseControl {
+            state: Arc::new(Mutex::new(PauseState::default())),
+            on_change: Arc::new(on_change),
+        }
+    }
+
+    pub fn pause(&self) {
+        self.state.lock().unwrap().requested = true;
+    }
+
+    pub fn resume(&self) {
+        let waker = {
+            let mut state = self.state.lock().unwrap();
+            state.requested = false;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.state.lock().unwrap().requested
+    }
+
+    // Total time the capture has spent paused so far
+    pub fn paused_for(&self) -> Duration {
+        self.state.lock().unwrap().paused_for
+    }
+
+    // Returns straight away unless a pause was requested
+    pub async fn wait_while_paused(&self) {
+        if !self.is_requested() {
+            return;
+        }
+
+        (self.on_change)(true);
+        let paused_at = Instant::now();
+        future::poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            if state.requested {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+        .await;
+        self.state.lock().unwrap().paused_for += paused_at.elapsed();
+        (self.on_change)(false);
+    }
+}
+
+impl Default for PauseControl {
+    fn default() -> Self {
+        PauseControl::new(|_| {})
+    }
+}
+
+// The result images a capture has finished so far. Captures build their result here rather than
+// holding it themselves, so what they'd finished can still be kept if they're stopped part way
+// through. Calibration captures save maps rather than images and leave it empty.
+#[derive(Clone, Default)]
+pub struct CaptureResults(Arc<Mutex<Vec<ImageHandler>>>);
+
+impl CaptureResults {
+    pub fn push(&self, image_handler: ImageHandler) {
+        self.0.lock().unwrap().push(image_handler);
+    }
+
+    // For captures whose result is the best image so far rather than every image
+    pub fn replace(&self, image_handlers: Vec<ImageHandler>) {
+        *self.0.lock().unwrap() = image_handlers;
+    }
+
+    pub fn take(&self) -> Vec<ImageHandler> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
 }
 
 #[enum_dispatch]
 pub trait AdvCapture {
-    fn start_stream(
+    fn start_stream<D: DetectorBackend + Clone + 'static>(
         &self,
-        detector_controller_mutex: DetectorController,
+        detector_controller_mutex: DetectorController<D>,
         correction_maps: &CorrectionMaps,
-        progress_tx: Sender<CaptureProgress>,
+        pause: PauseControl,
+        results: CaptureResults,
     ) -> Pin<Box<dyn Stream<Item = CaptureStreamItem> + Send>>;
 
-    fn check_stop_signal(
+    #[allow(dead_code)]
+    fn check_stop_signal<D: DetectorBackend + Clone + 'static>(
         &self,
         stop_signal: &Arc<AtomicBool>,
-        detector_controller: &mut DetectorController,
+        _detector_controller: &mut DetectorController<D>,
     ) -> bool {
         if stop_signal.load(Ordering::SeqCst) {
             true // Indicating that it should stop
@@ -91,19 +300,131 @@ pub trait AdvCapture {
     }
 }
 
-impl CaptureProgress {
-    pub fn new(total_steps: u32, message: String) -> Self {
+impl ProgressTracker {
+    pub fn new(steps: Vec<ProgressStep>) -> Self {
+        let now = Instant::now();
+        ProgressTracker {
+            steps,
+            continuous: false,
+            message: String::new(),
+            step: None,
+            frames: 0,
+            step_frames: 0,
+            started: now,
+            step_started: now,
+            last_frame: now,
+            overheads: Vec::new(),
+            interval_ms: None,
+        }
+    }
+
+    // For captures whose steps start every `interval_ms`, such as a time lapse
+    pub fn with_interval(mut self, interval_ms: u32) -> Self {
+        self.interval_ms = Some(interval_ms);
+        self
+    }
+
+    // A single step that carries on until the capture is stopped, so has no total or ETA
+    pub fn continuous(exp_time: u32) -> Self {
+        ProgressTracker {
+            continuous: true,
+            ..Self::new(vec![ProgressStep {
+                exp_time,
+                frames: 0,
+            }])
+        }
+    }
+
+    pub fn start_step(&mut self, message: String) -> CaptureProgress {
+        let step = self.step.map_or(0, |step| step + 1);
+        self.step = Some(step.min(self.steps.len().saturating_sub(1)));
+        self.step_frames = 0;
+        self.step_started = Instant::now();
+        self.last_frame = self.step_started;
+        self.message = message;
+        self.progress()
+    }
+
+    // Records a frame of the current step. The first frame of a step also counts the time taken
+    // to start the sequence as overhead.
+    pub fn frame(&mut self) -> CaptureProgress {
+        let now = Instant::now();
+        let exp_time = self.current_step().map_or(0, |step| step.exp_time);
+        let taken_ms = now.duration_since(self.last_frame).as_secs_f32() * 1000.0;
+        self.overheads.push((taken_ms - exp_time as f32).max(0.0));
+        self.last_frame = now;
+        self.frames += 1;
+        self.step_frames += 1;
+        self.progress()
+    }
+
+    // Reports something about the current step without moving on to the next one
+    pub fn message(&mut self, message: String) -> CaptureProgress {
+        self.message = message;
+        self.progress()
+    }
+
+    fn current_step(&self) -> Option<&ProgressStep> {
+        self.step.and_then(|step| self.steps.get(step))
+    }
+
+    fn progress(&self) -> CaptureProgress {
         CaptureProgress {
-            message,
-            current_step: 0,
-            total_steps,
+            message: self.message.clone(),
+            current_step: self.step.map_or(0, |step| step as u32 + 1),
+            total_steps: self.steps.len() as u32,
+            frame: self.frames,
+            total_frames: (!self.continuous)
+                .then(|| self.steps.iter().map(|step| step.frames).sum()),
+            elapsed_ms: self.started.elapsed().as_millis() as u32,
+            eta_ms: (!self.continuous).then(|| self.remaining_ms()),
         }
     }
 
-    pub fn update(&mut self, new_message: String) -> Self {
-        self.message = new_message;
-        self.current_step += 1;
-        self.clone()
+    // The median overhead is used so the time spent paused doesn't count against every frame
+    // still to come
+    fn remaining_ms(&self) -> u32 {
+        let overhead = median(&self.overheads);
+        let remaining: f32 = self
+            .steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| {
+                let frames_left = match self.step {
+                    Some(current) if i < current => 0,
+                    Some(current) if i == current => step.frames.saturating_sub(self.step_frames),
+                    _ => step.frames,
+                };
+                let capture_ms = frames_left as f32 * (step.exp_time as f32 + overhead);
+
+                let is_last = i + 1 == self.steps.len();
+                match (self.interval_ms, self.step) {
+                    (Some(interval_ms), Some(current)) if i == current && !is_last => {
+                        let step_ms = self.step_started.elapsed().as_secs_f32() * 1000.0;
+                        capture_ms.max(interval_ms as f32 - step_ms)
+                    }
+                    (Some(interval_ms), current) if current.map_or(true, |c| i > c) && !is_last => {
+                        capture_ms.max(interval_ms as f32)
+                    }
+                    _ => capture_ms,
+                }
+            })
+            .sum();
+        remaining.round() as u32
+    }
+}
+
+fn median(values: &[f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(cmp::Ordering::Equal));
+    let mid = sorted.len() / 2;
+    if sorted.len() % 2 == 0 {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
     }
 }
 
@@ -113,8 +434,94 @@ pub struct CaptureManagerEvent(pub CaptureManagerEventPayload);
 #[derive(Debug, Clone, Serialize, Type, Event)]
 pub struct CaptureManagerEventPayload {
     pub dark_maps: Vec<u32>,
+    pub dark_map_keys: Vec<CorrectionMapKey>,
+    pub gain_maps: Vec<CorrectionMapKey>,
     pub status: CaptureManagerStatus,
 }
 
 #[derive(Debug, Clone, Serialize, Type, Event)]
 pub struct CaptureProgressEvent(pub CaptureProgress);
+
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct CaptureFailure {
+    pub capture: AdvancedCapture,
+    pub error: CaptureError,
+    pub message: String,
+}
+
+impl CaptureFailure {
+    pub fn new(capture: AdvancedCapture, error: CaptureError) -> Self {
+        CaptureFailure {
+            capture,
+            message: error.to_string(),
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Type, Event)]
+pub struct CaptureFailedEvent(pub CaptureFailure);
+
+#[cfg(test)]
+mod tests {
+    use super::{ProgressStep, ProgressTracker};
+
+    #[test]
+    fn progress_counts_frames_over_steps_and_estimates_what_is_left() {
+        let mut progress = ProgressTracker::new(vec![
+            ProgressStep {
+                exp_time: 100,
+                frames: 2,
+            },
+            ProgressStep {
+                exp_time: 1000,
+                frames: 1,
+            },
+        ]);
+
+        let first = progress.start_step("First step".to_string());
+        assert_eq!((first.current_step, first.total_steps), (1, 2));
+        assert_eq!((first.frame, first.total_frames), (0, Some(3)));
+        assert_eq!(first.eta_ms, Some(1200));
+
+        // Frames arriving faster than their exposure have no overhead to add
+        progress.frame();
+        assert_eq!(progress.frame().frame, 2);
+        let second = progress.start_step("Second step".to_string());
+        assert_eq!(second.current_step, 2);
+        assert_eq!(second.eta_ms, Some(1000));
+
+        let last = progress.frame();
+        assert_eq!((last.frame, last.eta_ms), (3, Some(0)));
+    }
+
+    #[test]
+    fn scheduled_steps_take_at_least_their_interval() {
+        let step = ProgressStep {
+            exp_time: 100,
+            frames: 1,
+        };
+        let mut progress = ProgressTracker::new(vec![step; 3]).with_interval(60_000);
+
+        // The wait for the next step counts rather than how quickly this one's frames arrive
+        progress.start_step("First".to_string());
+        let eta = progress.frame().eta_ms.unwrap();
+        assert!(eta > 119_000 && eta <= 120_100, "{eta}");
+
+        progress.start_step("Second".to_string());
+        progress.frame();
+        progress.start_step("Last".to_string());
+        assert_eq!(progress.frame().eta_ms, Some(0));
+    }
+
+    #[test]
+    fn continuous_progress_has_no_total_or_eta() {
+        let mut progress = ProgressTracker::continuous(100);
+        progress.start_step("Live".to_string());
+
+        let progress = progress.frame();
+        assert_eq!((progress.current_step, progress.total_steps), (1, 1));
+        assert_eq!(progress.frame, 1);
+        assert_eq!((progress.total_frames, progress.eta_ms), (None, None));
+    }
+}
@@ -1,11 +1,11 @@
 use image::{ImageBuffer, Luma};
-use log::{error, info};
+use log::error;
 use tauri::{AppHandle, Window};
 use tauri_specta::Event;
 
-use crate::{image::{
+use crate::image::{
  types::DataExtractor, Annotation, ImageIterator, LineProfile, calculate_histogram_min_max,
-}, charts::types::HistogramBin};
+};
 
 use super::types::{ChartData, ChartDataEvent};
 
@@ -14,11 +14,13 @@ pub trait ChartSubscriber {
 }
 
 pub struct LineProfileSubscriber {
+    #[allow(dead_code)]
     pub app: AppHandle,
     pub window: Window,
 }
 
 pub struct HistogramSubscriber {
+    #[allow(dead_code)]
     pub app: AppHandle,
     pub window: Window,
 }
@@ -26,12 +28,11 @@ pub struct HistogramSubscriber {
 impl ChartSubscriber for LineProfileSubscriber {
     fn update(&self, image: &ImageBuffer<Luma<u16>, Vec<u16>>, roi: Option<Annotation>) {
         if let Some(roi) = roi {
-            let line_profile_data: LineProfile = roi.get_profile(&image);
+            let line_profile_data: LineProfile = roi.get_profile(image);
             if let Err(e) =
                 ChartDataEvent(ChartData::LineProfileData(line_profile_data)).emit(&self.window)
             {
                 error!("Error when emitting chart data event for line profile {e}");
-            } else {
             }
         }
     }
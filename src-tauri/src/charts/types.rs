@@ -28,5 +28,6 @@ pub struct ChartDataEvent(pub ChartData);
 #[derive(Clone, Serialize, Type, Event)]
 pub struct LineProfileEvent(pub LineProfile);
 
+#[allow(dead_code)]
 #[derive(Clone, Serialize, Type, Event)]
 pub struct HistogramEvent(pub Vec<u32>);
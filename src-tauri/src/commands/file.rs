@@ -1,11 +1,8 @@
-
 use crate::image::{ImageService, ImageStack};
 use image::io::Reader as ImageReader;
-use log::info;
 use std::sync::Mutex;
-use tauri::{AppHandle, Manager, State};
+use tauri::{AppHandle, State};
 use tauri_plugin_dialog::DialogExt;
-use tauri_specta::Event;
 
 #[tauri::command(async)]
 #[specta::specta]
@@ -69,7 +66,7 @@ pub fn save_image(
         image_service.save_image(
             stack_index as usize,
             image_index as usize,
-            &file_path.as_path(),
+            file_path.as_path(),
         );
     }
 
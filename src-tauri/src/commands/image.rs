@@ -1,13 +1,13 @@
+use crate::capture::corrections::{CorrectionError, DestripeSettings, LineOffsets};
 use crate::image::Annotation;
 use crate::image::ImageService;
-use image::imageops;
-use image::DynamicImage;
-use image::EncodableLayout;
 use log::info;
 use std::sync::Mutex;
 use tauri::ipc::Response;
 use tauri::{AppHandle, Manager, State};
 
+// saturated_pixel_rgb is part of the invoke signature but not applied to stack images yet
+#[allow(unused_variables)]
 #[tauri::command(async)]
 #[specta::specta]
 pub fn get_image_binary_rgba(
@@ -90,6 +90,28 @@ pub fn histogram_equilization(
     }
 }
 
+#[tauri::command(async)]
+#[specta::specta]
+pub fn destripe(
+    image_service_mutex: State<Mutex<ImageService>>,
+    image_idx: u32,
+    stack_idx: u32,
+    settings: DestripeSettings,
+    app: AppHandle,
+) -> Result<Option<LineOffsets>, CorrectionError> {
+    info!("Image command called: Destripe");
+    let mut image_service = image_service_mutex.lock().unwrap();
+
+    match image_service.get_mut_handler(stack_idx as usize, image_idx as usize) {
+        Some(image_handler) => {
+            let offsets = image_handler.destripe(&settings)?;
+            app.emit("image-modified", "").unwrap();
+            Ok(Some(offsets))
+        }
+        None => Ok(None),
+    }
+}
+
 #[tauri::command(async)]
 #[specta::specta]
 pub fn invert_colours(
@@ -129,6 +151,7 @@ pub fn rotate(
     }
 }
 
+#[allow(dead_code)]
 #[tauri::command(async)]
 #[specta::specta]
 pub fn flip(
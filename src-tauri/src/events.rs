@@ -1,11 +1,7 @@
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
 
-use crate::{
-    capture::types::CaptureManagerInfo,
-    image::image::{ImageService, LineProfile},
-};
+use crate::image::image::ImageService;
 use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
 use specta::Type;
 use tauri_specta::Event;
 
@@ -1,5 +1,7 @@
 use super::types::{Annotation, DataExtractor, Line, Rect};
 use super::{get_points_along_line, ImageMetadata};
+use crate::capture::correction_pipeline::CorrectionStageKind;
+use crate::capture::corrections::{destripe, CorrectionError, DestripeSettings, LineOffsets};
 use crate::capture::types::AdvancedCapture;
 use crate::charts::charts::ChartSubscriber;
 use crate::image::HistogramEquilisation;
@@ -8,7 +10,7 @@ use chrono::prelude::{DateTime, Utc};
 use image::ImageEncoder;
 use image::{ImageBuffer, Luma};
 use image_lib::{imageops, EncodableLayout};
-use log::info;
+use log::{error, info};
 use rayon::prelude::ParallelIterator;
 use rayon::slice::ParallelSlice;
 use serde::Serialize;
@@ -39,7 +41,7 @@ pub struct ImageService {
 }
 
 impl Debug for ImageService {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         Ok(())
     }
 }
@@ -72,7 +74,7 @@ impl ImageService {
             }
         }
 
-        return None;
+        None
     }
 
     pub fn get_mut_handler(
@@ -86,13 +88,15 @@ impl ImageService {
             }
         }
 
-        return None;
+        None
     }
 
     pub fn save_image(&self, stack_idx: usize, image_index: usize, path: &Path) {
         if let Some(stack) = self.image_stacks.get(stack_idx) {
             if let Some(image_handler) = stack.image_handlers.get(image_index) {
-                image_handler.image.save(path);
+                if let Err(e) = image_handler.image.save(path) {
+                    error!("Failed to save image to {}: {e}", path.display());
+                }
             }
         }
     }
@@ -122,15 +126,18 @@ impl ImageStack {
                     capture_settings: None,
                     date_created: None,
                     extra_info: None,
+                    dark_correction: None,
+                    correction_map_ids: Vec::new(),
+                    correction_stages: Vec::new(),
                 },
             ));
         }
 
-        return Self {
+        Self {
             timestamp,
             image_handlers,
             capture,
-        };
+        }
     }
 
     pub fn save(&self, path: PathBuf) {
@@ -227,11 +234,11 @@ impl ImageHandler {
     }
 
     pub fn rotate_left(&mut self) {
-        self.image = imageops::rotate270(&mut self.image);
+        self.image = imageops::rotate270(&self.image);
     }
 
     pub fn rotate_right(&mut self) {
-        self.image = imageops::rotate90(&mut self.image);
+        self.image = imageops::rotate90(&self.image);
     }
 
     pub fn flip(&mut self, vertically: bool) {
@@ -242,6 +249,22 @@ impl ImageHandler {
         }
     }
 
+    // Offline row/column noise correction, for frames captured without it
+    pub fn destripe(
+        &mut self,
+        settings: &DestripeSettings,
+    ) -> Result<LineOffsets, CorrectionError> {
+        let offsets = destripe(&mut self.image, settings)?;
+        self.image_metadata
+            .correction_stages
+            .push(CorrectionStageKind::RowColumnNoise);
+        if self.lut.is_some() {
+            self.apply_histogram_equilization();
+        }
+        self.notify_subscribers();
+        Ok(offsets)
+    }
+
     pub fn invert_colours(&mut self) {
         self.inverted_colours = !self.inverted_colours;
     }
@@ -264,15 +287,16 @@ impl ImageHandler {
         }
     }
 
+    #[allow(dead_code)]
     fn apply_lut(brightness: i32, contrast: f32) -> [u16; RANGE_SIZE] {
         let mut lut = [0u16; RANGE_SIZE];
 
         let mid_point = RANGE_SIZE as f32 / 2.0;
 
-        for i in 0..RANGE_SIZE {
+        for (i, entry) in lut.iter_mut().enumerate() {
             let mut value = (i as f32 - mid_point) * contrast + mid_point + brightness as f32;
             value = value.max(0.0).min(RANGE_SIZE as f32 - 1.0); // Clamping to 0-RANGE_SIZE-1
-            lut[i] = value as u16;
+            *entry = value as u16;
         }
 
         lut
@@ -312,24 +336,24 @@ impl ImageHandler {
         for (new_pixel, original) in thresholded_image.iter().zip(self.image.iter()) {
             let mut scaled_value = ((*new_pixel as f32 / 16383.0) * 255.0) as u8;
 
-            if (self.inverted_colours) {
+            if self.inverted_colours {
                 scaled_value = 255 - scaled_value;
             }
             if let Some(threshold) = saturated_pixel_threshold {
                 if *original > threshold as u16 {
                     data.extend_from_slice(saturated_colors);
-                    data.push(255 as u8);
+                    data.push(255_u8);
                 } else {
                     data.push(scaled_value);
                     data.push(scaled_value);
                     data.push(scaled_value);
-                    data.push(255 as u8);
+                    data.push(255_u8);
                 }
             } else {
                 data.push(scaled_value);
                 data.push(scaled_value);
                 data.push(scaled_value);
-                data.push(255 as u8);
+                data.push(255_u8);
             }
         }
 
@@ -343,8 +367,6 @@ impl ImageHandler {
 
             iter.for_each(|p| {
                 let lut_val = lut_array[*p as usize];
-                let max_value = RANGE_SIZE as u16 - 1;
-
                 *p = lut_val as u16;
             });
         };
@@ -361,7 +383,7 @@ impl ImageHandler {
         thresholded_image
     }
 
-    pub fn get_mean(&self, roi: Option<Annotation>) -> (f64, f64) {
+    pub fn get_mean(&self, _roi: Option<Annotation>) -> (f64, f64) {
         todo!();
     }
 
@@ -390,7 +412,7 @@ impl ImageHandler {
                 let lutval = lut_array[intensity];
 
                 // TODO: put this elsewhere
-                if (self.inverted_colours) {
+                if self.inverted_colours {
                     *pixel = Luma([u16::MAX - lutval as u16])
                 } else {
                     *pixel = Luma([lutval as u16]);
@@ -454,7 +476,7 @@ impl<'a> Iterator for ImageIterator<'a> {
         }
 
         if let Some(coord_iterator) = &mut self.coord_iterators {
-            while let Some((x, y)) = coord_iterator.next() {
+            for (x, y) in coord_iterator.by_ref() {
                 if x < self.image.width() && y < self.image.height() {
                     return Some(&self.image.get_pixel(x, y).0[0]);
                 }
@@ -494,9 +516,8 @@ impl DataExtractor for Rect {
             }
         }
         let variance = sum_squared_diff / count as f64;
-        let std = variance.sqrt();
 
-        return std;
+        variance.sqrt()
     }
 
     fn get_profile(&self, img: &ImageBuffer<Luma<u16>, Vec<u16>>) -> LineProfile {
@@ -539,7 +560,7 @@ impl DataExtractor for Line {
         // Calculate the mean pixel value along the line
         let mut sum = 0;
         for point in &points {
-            if let Some(pixel) = img.get_pixel(point.0 as u32, point.1 as u32).0.get(0) {
+            if let Some(pixel) = img.get_pixel(point.0 as u32, point.1 as u32).0.first() {
                 sum += *pixel as u64;
             }
         }
@@ -548,7 +569,7 @@ impl DataExtractor for Line {
         // Calculate the sum of squared differences
         let mut sum_squared_diff = 0.0;
         for point in &points {
-            if let Some(pixel) = img.get_pixel(point.0 as u32, point.1 as u32).0.get(0) {
+            if let Some(pixel) = img.get_pixel(point.0 as u32, point.1 as u32).0.first() {
                 let diff = (*pixel as f64 - mean).powi(2);
                 sum_squared_diff += diff;
             }
@@ -556,9 +577,8 @@ impl DataExtractor for Line {
 
         // Calculate the variance and standard deviation
         let variance = sum_squared_diff / points.len() as f64;
-        let std = variance.sqrt();
 
-        std
+        variance.sqrt()
     }
 
     fn get_profile(&self, img: &ImageBuffer<Luma<u16>, Vec<u16>>) -> LineProfile {
@@ -574,7 +594,7 @@ impl DataExtractor for Line {
         let mut line_profile = LineProfile::new();
 
         // Build the profile data by averaging each column
-        let mut prev_point = points.get(0).unwrap();
+        let mut prev_point = points.first().unwrap();
         let mut column_sum = img.get_pixel(prev_point.0 as u32, prev_point.1 as u32)[0] as u64;
         let mut column_count: u16 = 1;
         for point in points.iter().skip(1) {
@@ -602,6 +622,7 @@ impl DataExtractor for Line {
     }
 }
 
+#[allow(dead_code)]
 trait CoordIterator: Iterator<Item = (u32, u32)> {}
 
 struct RectIterator {
@@ -647,7 +668,7 @@ impl Iterator for LineIterator {
         let length = (dx.powi(2) + dy.powi(2)).sqrt();
 
         if self.current <= length as u32 {
-            let t = self.current as f64 / length as f64;
+            let t = self.current as f64 / length;
             let x = (self.line.start.x as f64 + dx * t).round() as u32;
             let y = (self.line.start.y as f64 + dy * t).round() as u32;
             self.current += 1;
@@ -2,7 +2,10 @@ use chrono::{DateTime, Utc};
 use serde::Serialize;
 use specta::Type;
 
-use crate::capture::capture::CaptureSetting;
+use crate::capture::{
+    capture::CaptureSetting, correction_pipeline::CorrectionStageKind,
+    corrections::DarkCorrectionMethod, lag::LagModel, stack_reduction::AveragingSummary,
+};
 
 use super::types::Rect;
 
@@ -11,13 +14,23 @@ pub struct ImageMetadata {
     pub capture_settings: Option<CaptureSetting>,
     pub date_created: Option<DateTime<Utc>>,
     pub extra_info: Option<CaptureResultData>,
+    // None when the image was not dark corrected
+    pub dark_correction: Option<DarkCorrectionMethod>,
+    // IDs from the provenance of every correction map applied to the image
+    pub correction_map_ids: Vec<String>,
+    // Correction stages that ran on the image, in order
+    pub correction_stages: Vec<CorrectionStageKind>,
 }
 
+// Variant names are the tags the frontend matches on, so they keep the Data suffix
+#[allow(clippy::enum_variant_names)]
 #[derive(Clone, Serialize, Type, Debug)]
 #[serde(tag = "type")]
 pub enum CaptureResultData {
     SmartCaptureData(SmartCaptureData),
     SignalAccumulationData(SignalAccumulationData),
+    LagMeasurementData(LagMeasurementData),
+    AveragedCaptureData(AveragedCaptureData),
 }
 
 #[derive(Clone, Serialize, Type, Debug)]
@@ -32,10 +45,23 @@ pub struct SignalAccumulationData {
     pub accumulated_exp_time: u32,
 }
 
+#[derive(Clone, Serialize, Type, Debug)]
+pub struct LagMeasurementData {
+    pub model: LagModel,
+}
+
+#[derive(Clone, Serialize, Type, Debug)]
+pub struct AveragedCaptureData {
+    pub averaging: AveragingSummary,
+}
+
 pub struct ImageMetadataBuilder {
     capture_settings: Option<CaptureSetting>,
     date_created: Option<DateTime<Utc>>,
     extra_info: Option<CaptureResultData>,
+    dark_correction: Option<DarkCorrectionMethod>,
+    correction_map_ids: Vec<String>,
+    correction_stages: Vec<CorrectionStageKind>,
 }
 
 impl ImageMetadataBuilder {
@@ -44,6 +70,9 @@ impl ImageMetadataBuilder {
             capture_settings: None,
             date_created: None,
             extra_info: None,
+            dark_correction: None,
+            correction_map_ids: Vec::new(),
+            correction_stages: Vec::new(),
         }
     }
 
@@ -62,11 +91,29 @@ impl ImageMetadataBuilder {
         self
     }
 
+    pub fn dark_correction(&mut self, method: Option<DarkCorrectionMethod>) -> &mut Self {
+        self.dark_correction = method;
+        self
+    }
+
+    pub fn correction_map_ids(&mut self, ids: Vec<String>) -> &mut Self {
+        self.correction_map_ids = ids;
+        self
+    }
+
+    pub fn correction_stages(&mut self, stages: Vec<CorrectionStageKind>) -> &mut Self {
+        self.correction_stages = stages;
+        self
+    }
+
     pub fn build(&self) -> ImageMetadata {
         ImageMetadata {
             capture_settings: self.capture_settings.clone(),
-            date_created: self.date_created.clone(),
+            date_created: self.date_created,
             extra_info: self.extra_info.clone(),
+            dark_correction: self.dark_correction.clone(),
+            correction_map_ids: self.correction_map_ids.clone(),
+            correction_stages: self.correction_stages.clone(),
         }
     }
 }
\ No newline at end of file
@@ -8,7 +8,7 @@ pub use image::{
     ImageHandler, ImageIterator, ImageService, ImageStack, LineProfile
 };
 
-pub use metadata::{ImageMetadata, CaptureResultData, ImageMetadataBuilder, SmartCaptureData, SignalAccumulationData};
+pub use metadata::{ImageMetadata, CaptureResultData, ImageMetadataBuilder, SmartCaptureData, SignalAccumulationData, LagMeasurementData, AveragedCaptureData};
 
 pub use types::*;
 pub use operations::*;
@@ -1,7 +1,6 @@
 use std::sync::{Arc, Mutex};
 
 use image::{ImageBuffer, Luma};
-use log::info;
 use rayon::prelude::*;
 
 use crate::charts::types::HistogramBin;
@@ -10,6 +9,7 @@ use super::DataExtractor;
 
 type Histogram = Vec<u32>;
 
+#[allow(dead_code)]
 pub fn median_filter_threaded(
     image: &ImageBuffer<Luma<u16>, Vec<u16>>,
     window_height: u32,
@@ -41,7 +41,7 @@ pub fn median_filter_threaded(
                 let new_y = y as i32 + j;
 
                 if new_x >= 0 && new_x < width as i32 && new_y >= 0 && new_y < height as i32 {
-                    let pixel_value = image.get_pixel(new_x as u32, new_y as u32)[0] as u16;
+                    let pixel_value = image.get_pixel(new_x as u32, new_y as u32)[0];
                     window_values.push(pixel_value);
                 }
             }
@@ -56,7 +56,6 @@ pub fn median_filter_threaded(
     });
 
     Arc::try_unwrap(output_image)
-        .ok()
         .expect("Failed to unwrap Arc")
         .into_inner()
         .expect("Failed to get Mutex inner value")
@@ -70,7 +69,8 @@ where
 
     vals.into_iter()
         .fold(vec![0; num_bins as usize], |mut histogram, &value| {
-            let bin_index = (value as u32 / bin_size) as usize;
+            // Corrected images can exceed max_value, so those land in the top bin
+            let bin_index = (value as u32 / bin_size).min(num_bins - 1) as usize;
             histogram[bin_index] += 1;
             histogram
         })
@@ -94,11 +94,7 @@ pub fn calculate_histogram_min_max(vals: Vec<&u16>, num_bins: u32) -> Vec<Histog
 
     for &value in vals.iter() {
         let value = *value as u32;
-        let bin_index = if bin_size > 0 {
-            ((value - min_value) / bin_size) as usize
-        } else {
-            0
-        };
+        let bin_index = (value - min_value).checked_div(bin_size).unwrap_or(0) as usize;
 
         let bin_index = bin_index.min(num_bins as usize - 1);
         bins[bin_index].count += 1;
@@ -142,24 +138,25 @@ pub trait HistogramEquilisation {
 }
 
 impl HistogramEquilisation for ImageBuffer<image::Luma<u16>, Vec<u16>> {
-    fn cumulative_histogram(&self, range: usize) -> Vec<u32> {
+    fn cumulative_histogram(&self, _range: usize) -> Vec<u32> {
         let histogram = calculate_histogram(self.iter(), 16383, 16384);
         create_lut(&histogram)
     }
 
-    fn cumulative_histogram_roi(&self, roi: &dyn DataExtractor, range: usize) -> Vec<u32> {
-        let histogram = calculate_histogram(roi.iter_values(&self), 16383, 16384);
+    fn cumulative_histogram_roi(&self, roi: &dyn DataExtractor, _range: usize) -> Vec<u32> {
+        let histogram = calculate_histogram(roi.iter_values(self), 16383, 16384);
         create_lut(&histogram)
     }
 }
 
+#[allow(dead_code)]
 pub fn adjust_brightness(
     image: &ImageBuffer<Luma<u16>, Vec<u16>>,
     delta: u16,
 ) -> ImageBuffer<Luma<u16>, Vec<u16>> {
     let mut adjusted_image = ImageBuffer::<Luma<u16>, Vec<u16>>::new(image.width(), image.height());
     for (x, y, pixel) in adjusted_image.enumerate_pixels_mut() {
-        let old_pixel_intensity = image.get_pixel(x, y)[0] as u16;
+        let old_pixel_intensity = image.get_pixel(x, y)[0];
         let new_intensity = old_pixel_intensity.saturating_add(delta); // Ensure the new value doesn't exceed u16 range
         *pixel = Luma([new_intensity]);
     }
@@ -167,6 +164,7 @@ pub fn adjust_brightness(
     adjusted_image
 }
 
+#[allow(dead_code)]
 pub fn adjust_contrast(
     image: &ImageBuffer<Luma<u16>, Vec<u16>>,
     contrast: f32,
@@ -176,13 +174,14 @@ pub fn adjust_contrast(
     for (x, y, pixel) in adjusted_image.enumerate_pixels_mut() {
         let original_intensity = image.get_pixel(x, y)[0] as f32;
         let new_intensity = 32768.0 + (original_intensity - 32768.0) * contrast;
-        let clamped_intensity = new_intensity.max(0.0).min(65535.0);
+        let clamped_intensity = new_intensity.clamp(0.0, 65535.0);
         *pixel = Luma([clamped_intensity as u16]);
     }
 
     adjusted_image
 }
 
+#[allow(dead_code)]
 pub fn invert_colors_grayscale(
     image: &ImageBuffer<Luma<u16>, Vec<u16>>,
 ) -> ImageBuffer<Luma<u16>, Vec<u16>> {
@@ -192,7 +191,7 @@ pub fn invert_colors_grayscale(
     for y in 0..height {
         for x in 0..width {
             let pixel = image.get_pixel(x, y);
-            let inverted_pixel_value = u16::max_value() - pixel[0];
+            let inverted_pixel_value = u16::MAX - pixel[0];
             inverted_image.put_pixel(x, y, Luma([inverted_pixel_value]));
         }
     }
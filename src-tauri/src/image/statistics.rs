@@ -1,13 +1,11 @@
 use image::{ImageBuffer, Luma};
 use image_lib::GenericImageView;
-use log::info;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Instant;
 
 use crate::image::types::{Point, Rect};
 
+#[allow(dead_code)]
 pub fn snr(
     image: &ImageBuffer<Luma<u16>, Vec<u16>>,
     window_size: u32,
@@ -40,15 +38,15 @@ pub fn snr(
         for y in 0..height - window_size {
             let window = image.view(x, y, window_size, window_size).to_image();
 
-            let (window_mean, window_std_dev) = calculate_mean_and_std(&window);
+            let (window_mean, _window_std_dev) = calculate_mean_and_std(&window);
 
-            if (window_mean < min_mean) {
+            if window_mean < min_mean {
                 min_mean = window_mean;
                 bg_rect.pos.x = x;
                 bg_rect.pos.y = y;
             }
 
-            if (window_mean > max_mean) {
+            if window_mean > max_mean {
                 max_mean = window_mean;
                 fg_rect.pos.x = x;
                 fg_rect.pos.y = y;
@@ -100,10 +98,6 @@ pub fn snr_threaded(
 
     let integral_image = compute_integral_image(image);
 
-    let positions = (0..width - (window_size - 1))
-    .flat_map(move |x| (0..height - (window_size - 1)).map(move |y| (x, y)))
-    .collect::<Vec<_>>();
-
     // Process each position concurrently
     (0..width - (window_size - 1)).into_par_iter().for_each(|x| {
         let mut local_min_mean = u32::MAX as f64;
@@ -142,6 +136,7 @@ pub fn snr_threaded(
     Ok((snr, state.bg_rect.clone(), state.fg_rect.clone()))
 }
 
+#[allow(dead_code)]
 pub fn calculate_mean_and_std_iter<'a, I>(vals: I) -> (f64, f64)
 where
     I: IntoIterator<Item = &'a u16> + Clone,
@@ -173,6 +168,7 @@ where
     (mean, std_dev)
 }
 
+#[allow(dead_code)]
 fn calculate_mean_and_std(buffer: &ImageBuffer<Luma<u16>, Vec<u16>>) -> (f64, f64) {
     let width = buffer.width();
     let height = buffer.height();
@@ -295,18 +291,13 @@ fn area_sum(integral_image: &[Vec<u64>], x: u32, y: u32, window_size: u32) -> u6
 
 #[cfg(test)]
 mod tests {
-    use std::ops::DerefMut;
-
     use super::*;
-    use image::{GenericImage, ImageBuffer, Luma};
-    use imageproc::integral_image;
-    use log::info;
+    use image::{ImageBuffer, Luma};
 
     fn create_test_image(width: u32, height: u32, value: u16) -> ImageBuffer<Luma<u16>, Vec<u16>> {
         ImageBuffer::from_pixel(width, height, Luma([value]))
     }
 
-    
     fn set_region_to_value(
         img: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
         x: u32,
@@ -352,10 +343,7 @@ mod tests {
 
         let window_size = 5;
 
-        // Replace the following line with the actual expected SNR value for the test image
-        let expected_snr = 2.0;
-
-        let x = snr_threaded(&mut image, window_size).unwrap();
+        let x = snr_threaded(&image, window_size).unwrap();
         println!("{:?}", x);
     }
 }
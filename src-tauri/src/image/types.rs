@@ -11,6 +11,7 @@ pub struct Point {
     pub y: u32,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Type, Clone, Debug)]
 pub struct Circle {
     pub pos: Point,
@@ -41,6 +42,7 @@ pub enum Annotation {
 #[enum_dispatch]
 pub trait DataExtractor {
     fn iter_values<'a>(&self, image: &'a ImageBuffer<Luma<u16>, Vec<u16>>) -> ImageIterator<'a>;
+    #[allow(dead_code)]
     fn get_std(&self, img: &ImageBuffer<Luma<u16>, Vec<u16>>) -> f64;
     fn get_profile(&self, img: &ImageBuffer<Luma<u16>, Vec<u16>>) -> LineProfile;
 }
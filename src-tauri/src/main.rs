@@ -1,13 +1,26 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
+// Modules are named after their main type, e.g. image::image::ImageHandler
+#![allow(clippy::module_inception)]
 
 mod capture {
     pub mod advanced_capture;
+    pub mod backend;
+    pub mod calibration_bundle;
     pub mod capture;
     pub mod capture_manager;
     pub mod commands;
+    pub mod correction_pipeline;
     pub mod corrections;
+    pub mod defect_map;
     pub mod detector;
+    pub mod job_queue;
+    pub mod lag;
+    pub mod provenance;
+    pub mod replay;
+    pub mod simulated;
+    pub mod stack_reduction;
+    #[cfg(test)]
     pub mod test_utils;
     pub mod types;
 }
@@ -23,20 +36,25 @@ mod commands {
 mod events;
 mod image;
 mod utils;
+#[cfg(feature = "spectrum-logic")]
+mod wrapper;
+#[cfg(not(feature = "spectrum-logic"))]
+#[path = "wrapper_fallback.rs"]
 mod wrapper;
 
 extern crate image as image_lib;
 
 use capture::{
     capture_manager::CaptureManager,
-    types::{CaptureManagerEvent, CaptureProgressEvent},
+    job_queue::{launch_scheduler, CaptureJobEvent, JobQueue, JOB_QUEUE_FILE_NAME},
+    types::{CaptureFailedEvent, CaptureManagerEvent, CaptureProgressEvent},
 };
 use charts::types::{ChartDataEvent, LineProfileEvent};
 use concurrent_queue::ConcurrentQueue;
 use events::{CancelCaptureEvent, HistogramEvent, ImageStateEvent, StreamCaptureEvent};
 use image::{ImageHandler, ImageService};
 use std::sync::Mutex;
-use tauri::{http, Manager};
+use tauri::Manager;
 
 use tauri_plugin_log::{fern::colors::ColoredLevelConfig, Target, TargetKind};
 
@@ -47,11 +65,10 @@ pub struct StreamBuffer {
 
 impl StreamBuffer {
     fn new(size: usize) -> StreamBuffer {
-        let buffer = StreamBuffer {
+        StreamBuffer {
             q: ConcurrentQueue::<ImageHandler>::bounded(size),
             size,
-        };
-        buffer
+        }
     }
 
     fn clear(&mut self) {
@@ -66,7 +83,20 @@ fn main() {
                 capture::commands::generate_dark_maps,
                 capture::commands::run_capture,
                 capture::commands::stop_capture,
+                capture::commands::pause_capture,
+                capture::commands::resume_capture,
                 capture::commands::generate_defect_map,
+                capture::commands::generate_gain_maps,
+                capture::commands::set_correction_settings,
+                capture::commands::export_calibration,
+                capture::commands::import_calibration,
+                capture::commands::start_session_recording,
+                capture::commands::stop_session_recording,
+                capture::commands::queue_capture,
+                capture::commands::get_capture_jobs,
+                capture::commands::move_capture_job,
+                capture::commands::cancel_capture_job,
+                capture::commands::set_recalibration_settings,
                 commands::file::open_images,
                 commands::file::save_image,
                 commands::file::save_stack,
@@ -74,12 +104,15 @@ fn main() {
                 commands::image::get_pixel_value,
                 commands::image::update_roi,
                 commands::image::invert_colours,
+                commands::image::destripe,
                 commands::image::rotate,
                 charts::commands::subscribe_chart,
             ])
             .events(tauri_specta::collect_events!(
                 StreamCaptureEvent,
                 CaptureProgressEvent,
+                CaptureFailedEvent,
+                CaptureJobEvent,
                 CancelCaptureEvent,
                 CaptureManagerEvent,
                 ChartDataEvent,
@@ -117,6 +150,12 @@ fn main() {
             app.manage(Mutex::new(ImageService::new(handle.clone())));
             app.manage(Mutex::new(StreamBuffer::new(10)));
 
+            let local_data = handle.path().app_local_data_dir().unwrap();
+            app.manage(Mutex::new(JobQueue::load(
+                &local_data.join(JOB_QUEUE_FILE_NAME),
+            )));
+            launch_scheduler(handle.clone());
+
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
@@ -34,6 +34,8 @@ unsafe impl Sync for SLImage {}
 #[derive(Clone)]
 pub struct SLDeviceRS {
     device: Arc<Mutex<UniquePtr<SLDevice>>>,
+    // Pixels combined along each side by the current binning mode
+    binning_factor: Arc<Mutex<u32>>,
 }
 
 impl SLDeviceRS {
@@ -43,6 +45,7 @@ impl SLDeviceRS {
                 SLDevice::new(DeviceInterface::USB, autocxx::c_int(1), "", "", "")
                     .within_unique_ptr(),
             )),
+            binning_factor: Arc::new(Mutex::new(1)),
         }
     }
 
@@ -112,6 +115,39 @@ impl SLDeviceRS {
         }
     }
 
+    pub fn set_binning_mode(
+        &mut self,
+        binning_mode: BinningModesRS,
+    ) -> Result<(), InternalSLError> {
+        let factor = match binning_mode.0 {
+            BinningModes::x11 => 1,
+            BinningModes::x22 => 2,
+            BinningModes::x44 => 4,
+            _ => return Err(SLError::SL_ERROR_INVALID_PARAM.into()),
+        };
+
+        let mut lock = self.device.lock().unwrap();
+        match lock.pin_mut().SetBinningMode(binning_mode.0) {
+            SLError::SL_ERROR_SUCCESS => {
+                *self.binning_factor.lock().unwrap() = factor;
+                Ok(())
+            }
+            err => Err(err.into()),
+        }
+    }
+
+    pub fn binning_factor(&self) -> u32 {
+        *self.binning_factor.lock().unwrap()
+    }
+
+    pub fn set_dds(&mut self, dds: bool) -> Result<(), InternalSLError> {
+        let mut lock = self.device.lock().unwrap();
+        match lock.pin_mut().SetDDS(dds) {
+            SLError::SL_ERROR_SUCCESS => Ok(()),
+            err => Err(err.into()),
+        }
+    }
+
     pub fn open_camera(&mut self, buffer_depth: u32) -> Result<(), InternalSLError> {
         let mut lock = self.device.lock().unwrap();
 
@@ -255,46 +291,6 @@ impl SLImageRs {
         }
     }
 
-    pub fn offset_correction(
-        &mut self,
-        dark_map: &mut SLImageRs,
-        offset: u32,
-    ) -> Result<(), InternalSLError> {
-        unsafe {
-            {
-                match SLImage::OffsetCorrection(
-                    self.image.pin_mut(),
-                    std::pin::Pin::<&mut SLImage>::into_inner_unchecked(
-                        dark_map.get_underlying().pin_mut(),
-                    ),
-                    autocxx::c_int(offset as i32),
-                ) {
-                    SLError::SL_ERROR_SUCCESS => Ok(()),
-                    e => Err(e.into()),
-                }
-            }
-        }
-    }
-
-    pub fn defect_correction(
-        &mut self,
-        out_image: &mut SLImageRs,
-        defect_map: &mut SLImageRs,
-    ) -> Result<(), InternalSLError> {
-        unsafe {
-            match SLImage::DefectCorrection(
-                self.image.pin_mut(),
-                out_image.get_underlying().pin_mut(),
-                std::pin::Pin::<&mut SLImage>::into_inner_unchecked(
-                    defect_map.get_underlying().pin_mut(),
-                ),
-            ) {
-                SLError::SL_ERROR_SUCCESS => Ok(()),
-                e => Err(e.into()),
-            }
-        }
-    }
-
     pub fn get_height(&mut self) -> u32 {
         i32::from(self.image.pin_mut().GetHeight()) as u32
     }
@@ -324,11 +320,32 @@ impl SLImageRs {
 
         ImageBuffer::<Luma<u16>, Vec<u16>>::from_vec(width as u32, height as u32, u16_vec).unwrap()
     }
+
+    pub fn from_image_buffer(buffer: &ImageBuffer<Luma<u16>, Vec<u16>>) -> Self {
+        let mut image = SLImageRs::new(buffer.height(), buffer.width());
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                buffer.as_raw().as_ptr(),
+                image.get_data_pointer(0) as *mut u16,
+                buffer.as_raw().len(),
+            );
+        }
+        image
+    }
 }
 
-#[derive(Serialize, Debug, Type)]
+#[derive(Serialize, Debug, Clone, Type)]
 pub struct InternalSLError(String);
 
+impl InternalSLError {
+    // Whether this was converted from the given SDK error
+    pub fn is(&self, error: &SLError) -> bool {
+        self.0 == format!("{:?}", error)
+    }
+}
+
+// Only describes the SDK enum to specta, it's never constructed
+#[allow(dead_code)]
 #[derive(Type)]
 pub enum RemoteBinningModes {
     BinningUnknown,
@@ -382,6 +399,8 @@ impl fmt::Debug for BinningModesRS {
     }
 }
 
+// Only describes the SDK enum to specta, it's never constructed
+#[allow(dead_code)]
 #[derive(Type)]
 pub enum RemoteFullWellModes {
     High,
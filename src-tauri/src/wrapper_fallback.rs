@@ -0,0 +1,331 @@
+// Pure-Rust stand-ins for the Spectrum Logic SDK types, used when the
+// `spectrum-logic` feature is disabled. They mirror the API of `wrapper.rs`
+// closely enough that the capture and image code is shared between both builds.
+#![allow(non_camel_case_types)]
+
+use image::ImageBuffer;
+use image::Luma;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use specta::Type;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::{colortype, TiffEncoder};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SLError {
+    SL_ERROR_SUCCESS,
+    SL_ERROR_INVALID_PARAM,
+    SL_ERROR_NO_DEVICE,
+    SL_ERROR_NOT_FOUND,
+    SL_ERROR_BUSY,
+    SL_ERROR_TIMEOUT,
+    SL_ERROR_CORRECTION,
+    SL_ERROR_NOT_SUPPORTED,
+    SL_ERROR_ALREADY_EXISTS,
+    SL_ERROR_INTERNAL,
+    SL_ERROR_OTHER,
+    SL_ERROR_DEVICE_CLOSED,
+    SL_ERROR_DEVICE_STREAMING,
+    SL_ERROR_CONFIG_FAILED,
+    SL_ERROR_CONFIG_FILE_NOT_FOUND,
+    SL_ERROR_NOT_ENOUGH_MEMORY,
+    SL_ERROR_OVERFLOW,
+    SL_ERROR_PIPE,
+    SL_ERROR_INTERRUPTED,
+    SL_ERROR_IO,
+    SL_ERROR_ACCESS,
+    SL_ERROR_REQUIRES_ADMIN,
+    SL_ERROR_CRITICAL,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ExposureModes {
+    seq_mode,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BinningModes {
+    BinningUnknown,
+    x11,
+    x22,
+    x44,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FullWellModes {
+    High,
+    Low,
+    Unknown,
+}
+
+pub struct SLImageRs {
+    width: u32,
+    height: u32,
+    depth: u32,
+    data: Vec<u16>,
+}
+
+impl SLImageRs {
+    pub fn new(height: u32, width: u32) -> Self {
+        Self::new_depth(height, width, 1)
+    }
+
+    pub fn new_depth(height: u32, width: u32, depth: u32) -> Self {
+        Self {
+            width,
+            height,
+            depth,
+            data: vec![0; (width * height * depth) as usize],
+        }
+    }
+
+    pub fn read_tiff_image(&mut self, path: &PathBuf) -> Result<(), ()> {
+        let file = File::open(path).map_err(|_| ())?;
+        let mut decoder = Decoder::new(BufReader::new(file)).map_err(|_| ())?;
+        let (width, height) = decoder.dimensions().map_err(|_| ())?;
+
+        match decoder.read_image().map_err(|_| ())? {
+            DecodingResult::U16(data) => {
+                self.width = width;
+                self.height = height;
+                self.depth = 1;
+                self.data = data;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    pub fn write_tiff_image(&mut self, path: &PathBuf) -> Result<(), ()> {
+        let file = File::create(path).map_err(|_| ())?;
+        let mut encoder = TiffEncoder::new(file).map_err(|_| ())?;
+        let frame_len = (self.width * self.height) as usize;
+        encoder
+            .write_image::<colortype::Gray16>(self.width, self.height, &self.data[..frame_len])
+            .map_err(|_| ())
+    }
+
+    pub fn get_average_image(&mut self) -> SLImageRs {
+        let frame_len = (self.width * self.height) as usize;
+        let mut sums = vec![0u64; frame_len];
+
+        for frame in self.data.chunks_exact(frame_len) {
+            for (sum, &value) in sums.iter_mut().zip(frame) {
+                *sum += value as u64;
+            }
+        }
+
+        let depth = self.depth.max(1) as u64;
+        SLImageRs {
+            width: self.width,
+            height: self.height,
+            depth: 1,
+            data: sums
+                .into_iter()
+                .map(|sum| ((sum + depth / 2) / depth) as u16)
+                .collect(),
+        }
+    }
+
+    pub fn get_height(&mut self) -> u32 {
+        self.height
+    }
+
+    pub fn get_width(&mut self) -> u32 {
+        self.width
+    }
+
+    pub fn get_data_pointer(&mut self, frame: u32) -> *mut u8 {
+        let offset = (frame * self.width * self.height) as usize;
+        self.data[offset..].as_mut_ptr() as *mut u8
+    }
+
+    // Takes &mut self to match the SDK wrapper
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_image_buffer(&mut self) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        let frame_len = (self.width * self.height) as usize;
+        ImageBuffer::<Luma<u16>, Vec<u16>>::from_vec(
+            self.width,
+            self.height,
+            self.data[..frame_len].to_vec(),
+        )
+        .unwrap()
+    }
+
+    pub fn from_image_buffer(buffer: &ImageBuffer<Luma<u16>, Vec<u16>>) -> Self {
+        SLImageRs {
+            width: buffer.width(),
+            height: buffer.height(),
+            depth: 1,
+            data: buffer.as_raw().clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Type)]
+pub struct InternalSLError(String);
+
+impl InternalSLError {
+    // Whether this was converted from the given SDK error
+    pub fn is(&self, error: &SLError) -> bool {
+        self.0 == format!("{:?}", error)
+    }
+}
+
+// Only describes the SDK enum to specta, it's never constructed
+#[allow(dead_code)]
+#[derive(Type)]
+pub enum RemoteBinningModes {
+    BinningUnknown,
+    x11,
+    x22,
+    x44,
+}
+
+#[derive(Serialize, Type, Deserialize, Clone)]
+pub struct BinningModesRS(#[specta(type = RemoteBinningModes)] pub BinningModes);
+
+impl Serialize for BinningModes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            BinningModes::BinningUnknown => serializer.serialize_str("Unknown"),
+            BinningModes::x11 => serializer.serialize_str("x11"),
+            BinningModes::x22 => serializer.serialize_str("x22"),
+            BinningModes::x44 => serializer.serialize_str("x44"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BinningModes {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        match s.as_ref() {
+            "Unknown" => Ok(BinningModes::BinningUnknown),
+            "x11" => Ok(BinningModes::x11),
+            "x22" => Ok(BinningModes::x22),
+            "x44" => Ok(BinningModes::x44),
+            _ => Err(serde::de::Error::custom("Invalid value for Binning Mode")),
+        }
+    }
+}
+
+impl fmt::Debug for BinningModesRS {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self.0 {
+                BinningModes::BinningUnknown => "Unknown",
+                BinningModes::x11 => "x11",
+                BinningModes::x22 => "x22",
+                BinningModes::x44 => "x44",
+            }
+        )
+    }
+}
+
+// Only describes the SDK enum to specta, it's never constructed
+#[allow(dead_code)]
+#[derive(Type)]
+pub enum RemoteFullWellModes {
+    High,
+    Low,
+    Enum,
+}
+
+#[derive(Serialize, Type, Deserialize, Clone)]
+pub struct FullWellModesRS {
+    #[specta(type = RemoteFullWellModes)]
+    pub remote_ty: FullWellModes,
+}
+
+impl fmt::Display for FullWellModesRS {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self.remote_ty {
+                FullWellModes::Low => "LFW",
+                FullWellModes::High => "HFW",
+                FullWellModes::Unknown => "Uknown",
+            }
+        )
+    }
+}
+
+impl fmt::Debug for FullWellModesRS {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl Serialize for FullWellModes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            FullWellModes::High => serializer.serialize_str("High"),
+            FullWellModes::Low => serializer.serialize_str("Low"),
+            FullWellModes::Unknown => serializer.serialize_str("Unknown"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FullWellModes {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        match s.as_ref() {
+            "High" => Ok(FullWellModes::High),
+            "Low" => Ok(FullWellModes::Low),
+            "Unknown" => Ok(FullWellModes::Unknown),
+            _ => Err(serde::de::Error::custom("Invalid value for FullWellModes")),
+        }
+    }
+}
+
+impl std::fmt::Debug for SLError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SL_ERROR_SUCCESS => write!(f, "SL_ERROR_SUCCESS"),
+            Self::SL_ERROR_INVALID_PARAM => write!(f, "SL_ERROR_INVALID_PARAM"),
+            Self::SL_ERROR_NO_DEVICE => write!(f, "SL_ERROR_NO_DEVICE"),
+            Self::SL_ERROR_NOT_FOUND => write!(f, "SL_ERROR_NOT_FOUND"),
+            Self::SL_ERROR_BUSY => write!(f, "SL_ERROR_BUSY"),
+            Self::SL_ERROR_TIMEOUT => write!(f, "SL_ERROR_TIMEOUT"),
+            Self::SL_ERROR_CORRECTION => write!(f, "SL_ERROR_CORRECTION"),
+            Self::SL_ERROR_NOT_SUPPORTED => write!(f, "SL_ERROR_NOT_SUPPORTED"),
+            Self::SL_ERROR_ALREADY_EXISTS => write!(f, "SL_ERROR_ALREADY_EXISTS"),
+            Self::SL_ERROR_INTERNAL => write!(f, "SL_ERROR_INTERNAL"),
+            Self::SL_ERROR_OTHER => write!(f, "SL_ERROR_OTHER"),
+            Self::SL_ERROR_DEVICE_CLOSED => write!(f, "SL_ERROR_DEVICE_CLOSED"),
+            Self::SL_ERROR_DEVICE_STREAMING => write!(f, "SL_ERROR_DEVICE_STREAMING"),
+            Self::SL_ERROR_CONFIG_FAILED => write!(f, "SL_ERROR_CONFIG_FAILED"),
+            Self::SL_ERROR_CONFIG_FILE_NOT_FOUND => write!(f, "SL_ERROR_CONFIG_FILE_NOT_FOUND"),
+            Self::SL_ERROR_NOT_ENOUGH_MEMORY => write!(f, "SL_ERROR_NOT_ENOUGH_MEMORY"),
+            Self::SL_ERROR_OVERFLOW => write!(f, "SL_ERROR_OVERFLOW"),
+            Self::SL_ERROR_PIPE => write!(f, "SL_ERROR_PIPE"),
+            Self::SL_ERROR_INTERRUPTED => write!(f, "SL_ERROR_INTERRUPTED"),
+            Self::SL_ERROR_IO => write!(f, "SL_ERROR_IO"),
+            Self::SL_ERROR_ACCESS => write!(f, "SL_ERROR_ACCESS"),
+            Self::SL_ERROR_REQUIRES_ADMIN => write!(f, "SL_ERROR_REQUIRES_ADMIN"),
+            Self::SL_ERROR_CRITICAL => write!(f, "SL_ERROR_CRITICAL"),
+        }
+    }
+}
+
+impl From<SLError> for InternalSLError {
+    fn from(error: SLError) -> Self {
+        let error_str = format!("{:?}", error); // Using Debug implementation to get the string representation
+        InternalSLError(error_str)
+    }
+}
@@ -40,6 +40,7 @@ import CaptureButton from "./components/CaptureButton";
 import StreamButton from "./components/StreamButton";
 import ImageListRadix from "./components/ImageList/ImageListRadix";
 import { useAppSettingsStore } from "./stores/appSettingsStore";
+import { isAvailableStatus } from "./utils";
 
 function App() {
   useDetectorListener();
@@ -69,9 +70,12 @@ function App() {
     updateStacks: state.updateStacks,
   }));
 
-  const { autoSaveCaptures } = useAppSettingsStore((state) => ({
-    autoSaveCaptures: state.autoSaveCaptures,
-  }));
+  const { autoSaveCaptures, calibrationExpTimes, calibrationFrames } =
+    useAppSettingsStore((state) => ({
+      autoSaveCaptures: state.autoSaveCaptures,
+      calibrationExpTimes: state.calibrationExpTimes,
+      calibrationFrames: state.calibrationFrames,
+    }));
 
   const [captureManagerInfo, setCaptureManagerInfo] =
     useState<CaptureManagerEventPayload>({
@@ -145,10 +149,10 @@ function App() {
 
   const handleAdvancedCapture = async () => {
     if (captureManagerInfo.status == "DarkMapsRequired") {
-      commands.generateDarkMaps();
+      commands.generateDarkMaps(calibrationExpTimes, calibrationFrames, null);
     } else if (captureManagerInfo.status == "DefectMapsRequired") {
-      commands.generateDefectMap();
-    } else if (captureManagerInfo.status == "Available") {
+      commands.generateDefectMap(calibrationExpTimes, calibrationFrames, null);
+    } else if (isAvailableStatus(captureManagerInfo.status)) {
       setCaptureSettingsModalOpened(true);
     }
   };
@@ -159,11 +163,11 @@ function App() {
   };
 
   const handleGenerateDarkMaps = async () => {
-    await commands.generateDarkMaps();
+    await commands.generateDarkMaps(calibrationExpTimes, calibrationFrames, null);
   };
 
   const handleGenerationDefectMap = async () => {
-    await commands.generateDefectMap();
+    await commands.generateDefectMap(calibrationExpTimes, calibrationFrames, null);
   };
 
   return (
@@ -1,15 +1,15 @@
          // This file was generated by [tauri-specta](https://github.com/oscartbeaumont/tauri-specta). Do not edit this file manually.
 
          export const commands = {
-async generateDarkMaps() : Promise<__Result__<null, "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { SLError: InternalSLError } | "Unknown">> {
+async generateDarkMaps(expTimes: number[], framesPerCapture: number, stackReduction: { reducer: StackReducer; frame_rejection_sigma: number | null } | null) : Promise<__Result__<null, "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { CalibrationFailed: [CorrectionMapKey, CorrectionError] } | { SLError: InternalSLError } | { FileError: string } | { UnsupportedSetting: string } | { SettingRejected: [string, InternalSLError] } | "DetectorTimeout" | { FrameTimeout: [number, number] } | { TransferFailed: InternalSLError } | "FrameSizeUnavailable" | "NotCapturing" | "PauseUnsupported" | { JobNotFound: number } | "CaptureNeverEnds" | { NoSourceSignal: number } | { SnrWindowTooLarge: number } | "Unknown">> {
 try {
-    return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|generate_dark_maps") };
+    return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|generate_dark_maps", { expTimes, framesPerCapture, stackReduction }) };
 } catch (e) {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
 }
 },
-async runCapture(capture: AdvancedCapture, saveCapture: boolean) : Promise<__Result__<null, "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { SLError: InternalSLError } | "Unknown">> {
+async runCapture(capture: AdvancedCapture, saveCapture: boolean) : Promise<__Result__<"Completed" | { Cancelled: { partial_frames: number } }, "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { CalibrationFailed: [CorrectionMapKey, CorrectionError] } | { SLError: InternalSLError } | { FileError: string } | { UnsupportedSetting: string } | { SettingRejected: [string, InternalSLError] } | "DetectorTimeout" | { FrameTimeout: [number, number] } | { TransferFailed: InternalSLError } | "FrameSizeUnavailable" | "NotCapturing" | "PauseUnsupported" | { JobNotFound: number } | "CaptureNeverEnds" | { NoSourceSignal: number } | { SnrWindowTooLarge: number } | "Unknown">> {
 try {
     return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|run_capture", { capture, saveCapture }) };
 } catch (e) {
@@ -20,14 +20,103 @@ try {
 async stopCapture() : Promise<null> {
 return await TAURI_INVOKE("plugin:tauri-specta|stop_capture");
 },
-async generateDefectMap() : Promise<__Result__<null, null>> {
+async pauseCapture() : Promise<__Result__<null, "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { CalibrationFailed: [CorrectionMapKey, CorrectionError] } | { SLError: InternalSLError } | { FileError: string } | { UnsupportedSetting: string } | { SettingRejected: [string, InternalSLError] } | "DetectorTimeout" | { FrameTimeout: [number, number] } | { TransferFailed: InternalSLError } | "FrameSizeUnavailable" | "NotCapturing" | "PauseUnsupported" | { JobNotFound: number } | "CaptureNeverEnds" | { NoSourceSignal: number } | { SnrWindowTooLarge: number } | "Unknown">> {
 try {
-    return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|generate_defect_map") };
+    return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|pause_capture") };
 } catch (e) {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
 }
 },
+async resumeCapture() : Promise<__Result__<null, "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { CalibrationFailed: [CorrectionMapKey, CorrectionError] } | { SLError: InternalSLError } | { FileError: string } | { UnsupportedSetting: string } | { SettingRejected: [string, InternalSLError] } | "DetectorTimeout" | { FrameTimeout: [number, number] } | { TransferFailed: InternalSLError } | "FrameSizeUnavailable" | "NotCapturing" | "PauseUnsupported" | { JobNotFound: number } | "CaptureNeverEnds" | { NoSourceSignal: number } | { SnrWindowTooLarge: number } | "Unknown">> {
+try {
+    return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|resume_capture") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async generateDefectMap(expTimes: number[], framesPerCapture: number, settings: { hot_sigma: number; dead_fraction: number; noisy_sigma: number; non_linear_sigma: number; line_fraction: number; line_offset_sigma: number; min_cluster_size: number } | null) : Promise<__Result__<null, "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { CalibrationFailed: [CorrectionMapKey, CorrectionError] } | { SLError: InternalSLError } | { FileError: string } | { UnsupportedSetting: string } | { SettingRejected: [string, InternalSLError] } | "DetectorTimeout" | { FrameTimeout: [number, number] } | { TransferFailed: InternalSLError } | "FrameSizeUnavailable" | "NotCapturing" | "PauseUnsupported" | { JobNotFound: number } | "CaptureNeverEnds" | { NoSourceSignal: number } | { SnrWindowTooLarge: number } | "Unknown">> {
+try {
+    return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|generate_defect_map", { expTimes, framesPerCapture, settings }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async generateGainMaps(expTimes: number[], framesPerCapture: number) : Promise<__Result__<null, "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { CalibrationFailed: [CorrectionMapKey, CorrectionError] } | { SLError: InternalSLError } | { FileError: string } | { UnsupportedSetting: string } | { SettingRejected: [string, InternalSLError] } | "DetectorTimeout" | { FrameTimeout: [number, number] } | { TransferFailed: InternalSLError } | "FrameSizeUnavailable" | "NotCapturing" | "PauseUnsupported" | { JobNotFound: number } | "CaptureNeverEnds" | { NoSourceSignal: number } | { SnrWindowTooLarge: number } | "Unknown">> {
+try {
+    return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|generate_gain_maps", { expTimes, framesPerCapture }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async setCorrectionSettings(settings: CorrectionSettings) : Promise<null> {
+return await TAURI_INVOKE("plugin:tauri-specta|set_correction_settings", { settings });
+},
+async exportCalibration(path: string) : Promise<__Result__<null, "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { CalibrationFailed: [CorrectionMapKey, CorrectionError] } | { SLError: InternalSLError } | { FileError: string } | { UnsupportedSetting: string } | { SettingRejected: [string, InternalSLError] } | "DetectorTimeout" | { FrameTimeout: [number, number] } | { TransferFailed: InternalSLError } | "FrameSizeUnavailable" | "NotCapturing" | "PauseUnsupported" | { JobNotFound: number } | "CaptureNeverEnds" | { NoSourceSignal: number } | { SnrWindowTooLarge: number } | "Unknown">> {
+try {
+    return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|export_calibration", { path }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async importCalibration(path: string) : Promise<__Result__<null, "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { CalibrationFailed: [CorrectionMapKey, CorrectionError] } | { SLError: InternalSLError } | { FileError: string } | { UnsupportedSetting: string } | { SettingRejected: [string, InternalSLError] } | "DetectorTimeout" | { FrameTimeout: [number, number] } | { TransferFailed: InternalSLError } | "FrameSizeUnavailable" | "NotCapturing" | "PauseUnsupported" | { JobNotFound: number } | "CaptureNeverEnds" | { NoSourceSignal: number } | { SnrWindowTooLarge: number } | "Unknown">> {
+try {
+    return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|import_calibration", { path }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async startSessionRecording() : Promise<__Result__<string, "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { CalibrationFailed: [CorrectionMapKey, CorrectionError] } | { SLError: InternalSLError } | { FileError: string } | { UnsupportedSetting: string } | { SettingRejected: [string, InternalSLError] } | "DetectorTimeout" | { FrameTimeout: [number, number] } | { TransferFailed: InternalSLError } | "FrameSizeUnavailable" | "NotCapturing" | "PauseUnsupported" | { JobNotFound: number } | "CaptureNeverEnds" | { NoSourceSignal: number } | { SnrWindowTooLarge: number } | "Unknown">> {
+try {
+    return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|start_session_recording") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async stopSessionRecording() : Promise<__Result__<null, "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { CalibrationFailed: [CorrectionMapKey, CorrectionError] } | { SLError: InternalSLError } | { FileError: string } | { UnsupportedSetting: string } | { SettingRejected: [string, InternalSLError] } | "DetectorTimeout" | { FrameTimeout: [number, number] } | { TransferFailed: InternalSLError } | "FrameSizeUnavailable" | "NotCapturing" | "PauseUnsupported" | { JobNotFound: number } | "CaptureNeverEnds" | { NoSourceSignal: number } | { SnrWindowTooLarge: number } | "Unknown">> {
+try {
+    return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|stop_session_recording") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async queueCapture(request: JobRequest) : Promise<__Result__<{ id: number; capture: AdvancedCapture; priority: JobPriority; start_at: string | null; repeat_every_secs: number | null; save_capture: boolean; recalibration: boolean; started_at: string | null; state: JobState }, "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { CalibrationFailed: [CorrectionMapKey, CorrectionError] } | { SLError: InternalSLError } | { FileError: string } | { UnsupportedSetting: string } | { SettingRejected: [string, InternalSLError] } | "DetectorTimeout" | { FrameTimeout: [number, number] } | { TransferFailed: InternalSLError } | "FrameSizeUnavailable" | "NotCapturing" | "PauseUnsupported" | { JobNotFound: number } | "CaptureNeverEnds" | { NoSourceSignal: number } | { SnrWindowTooLarge: number } | "Unknown">> {
+try {
+    return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|queue_capture", { request }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async getCaptureJobs() : Promise<CaptureJob[]> {
+return await TAURI_INVOKE("plugin:tauri-specta|get_capture_jobs");
+},
+async moveCaptureJob(id: number, position: number) : Promise<__Result__<null, "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { CalibrationFailed: [CorrectionMapKey, CorrectionError] } | { SLError: InternalSLError } | { FileError: string } | { UnsupportedSetting: string } | { SettingRejected: [string, InternalSLError] } | "DetectorTimeout" | { FrameTimeout: [number, number] } | { TransferFailed: InternalSLError } | "FrameSizeUnavailable" | "NotCapturing" | "PauseUnsupported" | { JobNotFound: number } | "CaptureNeverEnds" | { NoSourceSignal: number } | { SnrWindowTooLarge: number } | "Unknown">> {
+try {
+    return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|move_capture_job", { id, position }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async cancelCaptureJob(id: number) : Promise<__Result__<null, "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { CalibrationFailed: [CorrectionMapKey, CorrectionError] } | { SLError: InternalSLError } | { FileError: string } | { UnsupportedSetting: string } | { SettingRejected: [string, InternalSLError] } | "DetectorTimeout" | { FrameTimeout: [number, number] } | { TransferFailed: InternalSLError } | "FrameSizeUnavailable" | "NotCapturing" | "PauseUnsupported" | { JobNotFound: number } | "CaptureNeverEnds" | { NoSourceSignal: number } | { SnrWindowTooLarge: number } | "Unknown">> {
+try {
+    return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|cancel_capture_job", { id }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async setRecalibrationSettings(settings: RecalibrationSettings) : Promise<null> {
+return await TAURI_INVOKE("plugin:tauri-specta|set_recalibration_settings", { settings });
+},
 async openImages() : Promise<null> {
 return await TAURI_INVOKE("plugin:tauri-specta|open_images");
 },
@@ -54,6 +143,14 @@ return await TAURI_INVOKE("plugin:tauri-specta|update_roi", { annotation, imageI
 async invertColours(imageIdx: number, stackIdx: number) : Promise<null> {
 return await TAURI_INVOKE("plugin:tauri-specta|invert_colours", { imageIdx, stackIdx });
 },
+async destripe(imageIdx: number, stackIdx: number, settings: DestripeSettings) : Promise<__Result__<{ rows: number[]; columns: number[] } | null, { SLError: InternalSLError } | { FileNotFound: string } | { DarkMapNotFound: CorrectionMapKey } | { DarkMapMismatch: [CorrectionMapKey, CorrectionMapKey[]] } | "DefectMapNotFound" | { DimensionMismatch: [number, number, number, number] } | { WriteFailed: string } | "NoFrames" | { GainMapNotFound: CorrectionMapKey } | { InsufficientSignal: [number, number] } | { InsufficientDarkMaps: number } | { DarkModelOutOfRange: [number, number, number] } | { InvalidBundle: string } | { BundleDetectorMismatch: [DetectorIdentity, DetectorIdentity] } | { InvalidReferenceLines: [number, number, number] } | "NoLagTransition" | "InsufficientLag">> {
+try {
+    return { status: "ok", data: await TAURI_INVOKE("plugin:tauri-specta|destripe", { imageIdx, stackIdx, settings }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
 async rotate(imageIdx: number, stackIdx: number, rotateLeft: boolean) : Promise<null> {
 return await TAURI_INVOKE("plugin:tauri-specta|rotate", { imageIdx, stackIdx, rotateLeft });
 },
@@ -65,6 +162,8 @@ return await TAURI_INVOKE("plugin:tauri-specta|subscribe_chart", { label, imageI
 export const events = __makeEvents__<{
 streamCaptureEvent: StreamCaptureEvent,
 captureProgressEvent: CaptureProgressEvent,
+captureFailedEvent: CaptureFailedEvent,
+captureJobEvent: CaptureJobEvent,
 cancelCaptureEvent: CancelCaptureEvent,
 captureManagerEvent: CaptureManagerEvent,
 chartDataEvent: ChartDataEvent,
@@ -74,6 +173,8 @@ histogramEvent: HistogramEvent
 }>({
 streamCaptureEvent: "plugin:tauri-specta:stream-capture-event",
 captureProgressEvent: "plugin:tauri-specta:capture-progress-event",
+captureFailedEvent: "plugin:tauri-specta:capture-failed-event",
+captureJobEvent: "plugin:tauri-specta:capture-job-event",
 cancelCaptureEvent: "plugin:tauri-specta:cancel-capture-event",
 captureManagerEvent: "plugin:tauri-specta:capture-manager-event",
 chartDataEvent: "plugin:tauri-specta:chart-data-event",
@@ -84,47 +185,86 @@ histogramEvent: "plugin:tauri-specta:histogram-event"
 
 /** user-defined types **/
 
-export type AdvancedCapture = ({ type: "SmartCapture" } & SmartCapture) | ({ type: "SignalAccumulationCapture" } & SignalAccumulation) | ({ type: "MultiCapture" } & MultiCapture) | ({ type: "LiveCapture" } & LiveCapture) | ({ type: "DarkMapCapture" } & DarkMapCapture) | ({ type: "DefectMapCapture" } & DefectMapCapture)
+export type AdvancedCapture = ({ type: "SmartCapture" } & SmartCapture) | ({ type: "SignalAccumulationCapture" } & SignalAccumulation) | ({ type: "MultiCapture" } & MultiCapture) | ({ type: "LiveCapture" } & LiveCapture) | ({ type: "DarkMapCapture" } & DarkMapCapture) | ({ type: "DefectMapCapture" } & DefectMapCapture) | ({ type: "FlatFieldCapture" } & FlatFieldCapture) | ({ type: "LagMeasurementCapture" } & LagMeasurementCapture) | ({ type: "TimeLapseCapture" } & TimeLapseCapture) | ({ type: "AveragedCapture" } & AveragedCapture)
 export type Annotation = ({ type: "Rect" } & Rect) | ({ type: "Line" } & Line)
+export type AveragedCapture = { exp_times: number[]; frames_per_capture: number; reducer: IncrementalReducer; corrections?: CorrectionPipeline }
+export type AveragedCaptureData = { averaging: AveragingSummary }
+export type AveragingSummary = { reducer: IncrementalReducer; frame_count: number; rejected_pixels: number; single_frame_noise: number; averaged_noise: number; noise_reduction: number }
 export type BinningModesRS = RemoteBinningModes
 export type CancelCaptureEvent = []
+export type CaptureError = "DetectorDisconnected" | "DetectorInUse" | { File2Error: CorrectionError } | { CalibrationFailed: [CorrectionMapKey, CorrectionError] } | { SLError: InternalSLError } | { FileError: string } | { UnsupportedSetting: string } | { SettingRejected: [string, InternalSLError] } | "DetectorTimeout" | { FrameTimeout: [number, number] } | { TransferFailed: InternalSLError } | "FrameSizeUnavailable" | "NotCapturing" | "PauseUnsupported" | { JobNotFound: number } | "CaptureNeverEnds" | { NoSourceSignal: number } | { SnrWindowTooLarge: number } | "Unknown"
+export type CaptureFailedEvent = CaptureFailure
+export type CaptureFailure = { capture: AdvancedCapture; error: CaptureError; message: string }
+export type CaptureJob = { id: number; capture: AdvancedCapture; priority: JobPriority; start_at: string | null; repeat_every_secs: number | null; save_capture: boolean; recalibration: boolean; started_at: string | null; state: JobState }
+export type CaptureJobEvent = CaptureJob
 export type CaptureManagerEvent = CaptureManagerEventPayload
-export type CaptureManagerEventPayload = { dark_maps: number[]; status: CaptureManagerStatus }
-export type CaptureManagerStatus = "Available" | { Capturing: AdvancedCapture } | "DarkMapsRequired" | "DefectMapsRequired" | "DetectorDisconnected"
-export type CaptureProgress = { message: string; current_step: number; total_steps: number }
+export type CaptureManagerEventPayload = { dark_maps: number[]; dark_map_keys: CorrectionMapKey[]; gain_maps: CorrectionMapKey[]; status: CaptureManagerStatus }
+export type CaptureManagerStatus = "Available" | { Capturing: AdvancedCapture } | { Paused: AdvancedCapture } | "DarkMapsRequired" | "DefectMapsRequired" | { CalibrationStale: StaleMap[] } | "CalibrationUnverified" | "DetectorDisconnected"
+export type CaptureProgress = { message: string; current_step: number; total_steps: number; frame: number; total_frames: number | null; elapsed_ms: number; eta_ms: number | null }
 export type CaptureProgressEvent = CaptureProgress
-export type CaptureResultData = ({ type: "SmartCaptureData" } & SmartCaptureData) | ({ type: "SignalAccumulationData" } & SignalAccumulationData)
-export type CaptureSetting = { exp_time: number; dds: boolean; full_well: FullWellModesRS; binning_mode: BinningModesRS; roi: number[] | null; corrected: boolean }
+export type CaptureResultData = ({ type: "SmartCaptureData" } & SmartCaptureData) | ({ type: "SignalAccumulationData" } & SignalAccumulationData) | ({ type: "LagMeasurementData" } & LagMeasurementData) | ({ type: "AveragedCaptureData" } & AveragedCaptureData)
+export type CaptureSetting = { exp_time: number; dds: boolean; full_well: FullWellModesRS; binning_mode: BinningModesRS; roi: Roi | null; corrections?: CorrectionPipeline; read_policy?: ReadPolicy }
 export type Chart = "Histogram" | "LineProfile"
 export type ChartData = { LineProfileData: LineProfileData[] } | { HistogramData: HistogramBin[] }
 export type ChartDataEvent = ChartData
-export type CorrectionError = { SLError: InternalSLError } | { FileNotFound: string }
-export type DarkMapCapture = { exp_times: number[]; frames_per_capture: number }
-export type DefectMapCapture = { exp_times: number[]; frames_per_capture: number }
+export type CorrectionError = { SLError: InternalSLError } | { FileNotFound: string } | { DarkMapNotFound: CorrectionMapKey } | { DarkMapMismatch: [CorrectionMapKey, CorrectionMapKey[]] } | "DefectMapNotFound" | { DimensionMismatch: [number, number, number, number] } | { WriteFailed: string } | "NoFrames" | { GainMapNotFound: CorrectionMapKey } | { InsufficientSignal: [number, number] } | { InsufficientDarkMaps: number } | { DarkModelOutOfRange: [number, number, number] } | { InvalidBundle: string } | { BundleDetectorMismatch: [DetectorIdentity, DetectorIdentity] } | { InvalidReferenceLines: [number, number, number] } | "NoLagTransition" | "InsufficientLag"
+export type CorrectionMapKey = { exp_time: number; full_well: FullWellModesRS; binning_mode: BinningModesRS; dds: boolean }
+export type CorrectionPipeline = { stages: CorrectionStageSetting[] }
+export type CorrectionSettings = { pedestal: number; defect_correction: DefectCorrectionSettings; destripe?: DestripeSettings; max_calibration_age_days: number }
+export type CorrectionStageKind = { type: "Offset" } | { type: "Lag" } | { type: "Gain" } | { type: "Defect" } | { type: "RowColumnNoise" } | { type: "Custom"; name: string }
+export type CorrectionStageSetting = { stage: CorrectionStageKind; enabled: boolean }
+export type DarkCorrectionMethod = { type: "Exact"; key: CorrectionMapKey } | { type: "Interpolated"; key: CorrectionMapKey; exp_times: number[]; estimated_error: number | null } | { type: "Extrapolated"; key: CorrectionMapKey; exp_times: number[]; estimated_error: number | null }
+export type DarkMapCapture = { exp_times: number[]; frames_per_capture: number; stack_reduction?: StackReductionSettings }
+export type DefectCorrectionSettings = { kernel_size: number; replacement: DefectReplacement; line_defects: boolean; min_line_length: number }
+export type DefectMapCapture = { exp_times: number[]; frames_per_capture: number; settings?: DefectMapSettings }
+export type DefectMapSettings = { hot_sigma: number; dead_fraction: number; noisy_sigma: number; non_linear_sigma: number; line_fraction: number; line_offset_sigma: number; min_cluster_size: number }
+export type DefectReplacement = "Median" | "Mean"
+export type DestripeSettings = { rows: boolean; columns: boolean; row_reference: LineRange | null; column_reference: LineRange | null; neighbours: number }
+export type DetectorIdentity = { model: string; serial: string | null; width: number; height: number }
+export type FlatFieldCapture = { exp_times: number[]; frames_per_capture: number }
 export type FullWellModesRS = { remote_ty: RemoteFullWellModes }
 export type Histogram = number[]
 export type HistogramBin = { range: number; count: number }
 export type HistogramEvent = Histogram
 export type ImageHandler = { image_metadata: ImageMetadata; roi: Annotation | null; inverted_colours: boolean }
-export type ImageMetadata = { capture_settings: CaptureSetting | null; date_created: string | null; extra_info: CaptureResultData | null }
+export type ImageMetadata = { capture_settings: CaptureSetting | null; date_created: string | null; extra_info: CaptureResultData | null; dark_correction: DarkCorrectionMethod | null; correction_map_ids: string[]; correction_stages: CorrectionStageKind[] }
 export type ImageService = { image_stacks: ImageStack[] }
 export type ImageStack = { timestamp: string | null; image_handlers: ImageHandler[]; capture: AdvancedCapture | null }
 export type ImageStateEvent = ImageService
+export type IncrementalReducer = { type: "Mean" } | { type: "Median" } | { type: "SigmaClippedMean"; sigma: number }
 export type InternalSLError = string
+export type JobPriority = "Low" | "Normal" | "High"
+export type JobRequest = { capture: AdvancedCapture; priority: JobPriority; start_at: string | null; repeat_every_secs: number | null; save_capture: boolean }
+export type JobState = { type: "Queued" } | { type: "Running" } | { type: "Completed" } | { type: "Cancelled" } | { type: "Failed"; message: string }
+export type LagMeasurementCapture = { exp_time: number; num_frames: number; terms: number; settle_frames: number }
+export type LagMeasurementData = { model: LagModel }
+export type LagModel = { key: CorrectionMapKey; terms: LagTerm[]; measured: number[] }
+export type LagTerm = { amplitude: number; decay: number }
 export type Line = { start: Point; finish: Point }
 export type LineProfileData = { idx: number; value: number }
 export type LineProfileEvent = LineProfileData[]
-export type LiveCapture = { exp_time: number; type: "LiveCapture" }
-export type MultiCapture = { exp_times: number[]; frames_per_capture: number; type: "MultiCapture" }
+export type LineRange = { start: number; end: number }
+export type LiveCapture = { exp_time: number; corrections?: CorrectionPipeline }
+export type MultiCapture = { exp_times: number[]; frames_per_capture: number; corrections?: CorrectionPipeline }
 export type Point = { x: number; y: number }
+export type ReadPolicy = { frame_timeout_ms: number; max_retries: number }
+export type RecalibrationSettings = { enabled: boolean; exp_times: number[]; frames_per_capture: number }
 export type Rect = { width: number; height: number; pos: Point }
 export type RemoteBinningModes = "BinningUnknown" | "x11" | "x22" | "x44"
 export type RemoteFullWellModes = "High" | "Low" | "Enum"
-export type SignalAccumulation = { exp_times: number[]; frames_per_capture: number; type: "SignalAccumulation" }
+export type Roi = { x: number; y: number; width: number; height: number }
+export type SignalAccumulation = { exp_times: number[]; frames_per_capture: number; corrections?: CorrectionPipeline }
 export type SignalAccumulationData = { accumulated_exp_time: number }
-export type SmartCapture = { exp_times: number[]; frames_per_capture: number; window_size: number; median_filtered: boolean; type: "SmartCapture" }
+export type SmartCapture = { exp_times: number[]; frames_per_capture: number; window_size: number; median_filtered: boolean; corrections?: CorrectionPipeline }
 export type SmartCaptureData = { signal_noise_ratio: number; background_rect: Rect; foreground_rect: Rect }
+export type StackReducer = { type: "Mean" } | { type: "Median" } | { type: "SigmaClippedMean"; sigma: number; iterations: number } | { type: "TrimmedMean"; fraction: number }
+export type StackReductionSettings = { reducer: StackReducer; frame_rejection_sigma: number | null }
+export type StaleMap = { map: string; id: string; reason: StaleReason }
+export type StaleReason = { type: "Expired"; created: string } | { type: "DifferentDetector"; detector: DetectorIdentity }
 export type StreamCaptureEvent = []
+export type TimeLapseCapture = { exp_time: number; frames_per_acquisition: number; interval_secs: number; length: TimeLapseLength; frames: TimeLapseFrames; corrections?: CorrectionPipeline }
+export type TimeLapseFrames = { type: "All" } | { type: "BestSnr"; window_size: number }
+export type TimeLapseLength = { type: "Acquisitions"; count: number } | { type: "Duration"; secs: number }
 
 /** tauri-specta globals **/
 
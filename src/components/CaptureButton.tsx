@@ -1,5 +1,9 @@
 import { useDetectorStore } from "../stores/detectorStore";
-import { camelCaseToWords, isCapturingStatus } from "../utils";
+import {
+  camelCaseToWords,
+  isAvailableStatus,
+  isCapturingStatus,
+} from "../utils";
 
 interface CaptureButtonProps {
   onClick: () => void;
@@ -13,14 +17,14 @@ const CaptureButton = ({ onClick }: CaptureButtonProps) => {
 
   const isDisabled =
     status === "DetectorDisconnected" ||
-    (typeof status === "object" && status.Capturing.type == "LiveCapture");
+    (isCapturingStatus(status) && status.Capturing.type == "LiveCapture");
 
   const buttonClass = `relative text-lg px-4 py-2 font-semibold rounded ${
     isDisabled
       ? "bg-grey-400"
       : status === "DarkMapsRequired" || status === "DefectMapsRequired"
       ? "bg-red-500" // Red background for generating defect or dark maps
-      : status === "Available"
+      : isAvailableStatus(status)
       ? "bg-blue-500" // Blue background for other specified statuses
       : "bg-transparent" // Transparent background for other cases
   } text-white w-full h-full`;
@@ -47,6 +51,8 @@ const CaptureButton = ({ onClick }: CaptureButtonProps) => {
         {status == "DarkMapsRequired" && "Generate Dark Maps"}
         {status == "DefectMapsRequired" && "Generate Defect Map"}
         {status == "Available" && "Advanced Capture"}
+        {status == "CalibrationUnverified" &&
+          "Advanced Capture (Calibration Unverified)"}
         {isCapturingStatus(status) && (
           <>Running {camelCaseToWords(status.Capturing.type)}</>
         )}
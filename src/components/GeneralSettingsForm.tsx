@@ -3,6 +3,7 @@ import {
   Checkbox,
   ColorInput,
   NumberInput,
+  TagsInput,
   Title,
 } from "@mantine/core";
 import { useAppSettingsStore } from "../stores/appSettingsStore";
@@ -12,9 +13,13 @@ export const GeneralSettingsForm = (): JSX.Element => {
     saturatedPixelColor,
     saturatedPixelThreshold,
     autoSaveCaptures,
+    calibrationExpTimes,
+    calibrationFrames,
     setSaturatedPixelColour,
     setdSaturatedPixelThreshold,
     setAutoSaveCaptures,
+    setCalibrationExpTimes,
+    setCalibrationFrames,
   } = useAppSettingsStore((state) => ({
     saturatedPixelThreshold: state.saturatedPixelThreshold,
     saturatedPixelColor: state.saturatedPixelRGBColour,
@@ -22,6 +27,10 @@ export const GeneralSettingsForm = (): JSX.Element => {
     setSaturatedPixelColour: state.setSaturatedPixelRGBColour,
     setdSaturatedPixelThreshold: state.setSaturatedPixelThreshold,
     setAutoSaveCaptures: state.setAutoSaveCaptures,
+    calibrationExpTimes: state.calibrationExpTimes,
+    calibrationFrames: state.calibrationFrames,
+    setCalibrationExpTimes: state.setCalibrationExpTimes,
+    setCalibrationFrames: state.setCalibrationFrames,
   }));
 
   return (
@@ -52,6 +61,26 @@ export const GeneralSettingsForm = (): JSX.Element => {
         min={0}
         max={16384}
       />
+      <TagsInput
+        label="Exposure times (ms) used for calibration"
+        value={calibrationExpTimes.map((expTime) => expTime.toString())}
+        onChange={(values: string[]) => {
+          const expTimes = values
+            .map((value) => parseInt(value, 10))
+            .filter(
+              (expTime, i, all) => expTime > 0 && all.indexOf(expTime) == i
+            );
+          setCalibrationExpTimes(expTimes.sort((a, b) => a - b));
+        }}
+      />
+      <NumberInput
+        label="Frames per exposure used for calibration"
+        value={calibrationFrames}
+        onChange={(frames) => {
+          setCalibrationFrames(Number(frames));
+        }}
+        min={1}
+      />
     </>
   );
 };
@@ -1,7 +1,7 @@
-import { LiveCapture, commands } from "../bindings";
+import { AdvancedCapture, commands } from "../bindings";
 import { useDetectorStore } from "../stores/detectorStore";
 import { useImageStore } from "../stores/imageStore";
-import { isCapturingStatus } from "../utils";
+import { isAvailableStatus, isCapturingStatus } from "../utils";
 
 const StreamButton = () => {
   const { status } = useDetectorStore((state) => ({
@@ -15,10 +15,10 @@ const StreamButton = () => {
     status === "DetectorDisconnected" ||
     status === "DarkMapsRequired" ||
     status === "DefectMapsRequired" ||
-    (typeof status === "object" && status.Capturing.type !== "LiveCapture");
+    (isCapturingStatus(status) && status.Capturing.type !== "LiveCapture");
 
   const isLiveCapture =
-    typeof status === "object" && status.Capturing.type === "LiveCapture";
+    isCapturingStatus(status) && status.Capturing.type === "LiveCapture";
 
   const buttonClass = `relative text-lg px-4 py-2 font-semibold rounded text-white w-full h-full ${
     isDisabled
@@ -34,9 +34,9 @@ const StreamButton = () => {
         await commands.stopCapture();
         setStreaming(false);
       }
-    } else if (status === "Available") {
+    } else if (isAvailableStatus(status)) {
       setStreaming(true);
-      const capture: LiveCapture = {
+      const capture: AdvancedCapture = {
         exp_time: 100,
         type: "LiveCapture",
       };
@@ -46,7 +46,7 @@ const StreamButton = () => {
 
   return (
     <button className={buttonClass} disabled={isDisabled} onClick={handleClick}>
-      {status === "Available"
+      {isAvailableStatus(status)
         ? "Go Live"
         : isCapturingStatus(status) && status.Capturing.type === "LiveCapture"
         ? "Stop Live"
@@ -9,6 +9,11 @@ interface AppSettings {
   setAutoSaveCaptures: (newValue: boolean) => void;
   autoHistogramEqualization: boolean;
   setAutoHistogramEqualization: (newValue: boolean) => void;
+  // Exposures and frames per exposure used when calibrating from the capture button
+  calibrationExpTimes: number[];
+  setCalibrationExpTimes: (newExpTimes: number[]) => void;
+  calibrationFrames: number;
+  setCalibrationFrames: (newFrames: number) => void;
 }
 
 export const useAppSettingsStore = create<AppSettings>((set) => ({
@@ -26,4 +31,10 @@ export const useAppSettingsStore = create<AppSettings>((set) => ({
   autoHistogramEqualization: true,
   setAutoHistogramEqualization: (newValue: boolean) =>
     set({ autoHistogramEqualization: newValue }),
+  calibrationExpTimes: [100, 150, 200, 250, 300],
+  setCalibrationExpTimes: (newExpTimes: number[]) =>
+    set({ calibrationExpTimes: newExpTimes }),
+  calibrationFrames: 10,
+  setCalibrationFrames: (newFrames: number) =>
+    set({ calibrationFrames: newFrames }),
 }));
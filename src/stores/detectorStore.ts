@@ -3,7 +3,6 @@ import {
   AdvancedCapture,
   CaptureManagerStatus,
   CaptureProgress,
-  LiveCapture,
   commands,
 } from "../bindings";
 
@@ -30,7 +29,7 @@ export const useDetectorStore = create<DetectorState>((set) => ({
     set({ captureProgress }),
   goLive: () => {
     return async () => {
-      const capture: LiveCapture = {
+      const capture: AdvancedCapture = {
         exp_time: 100,
         type: "LiveCapture",
       };
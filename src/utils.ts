@@ -8,6 +8,10 @@ export const isCapturingStatus = (
   return typeof status === "object" && "Capturing" in status;
 };
 
+// The detector can't confirm unverified calibration is its own, but captures are still allowed
+export const isAvailableStatus = (status: CaptureManagerStatus) =>
+  status === "Available" || status === "CalibrationUnverified";
+
 export const streamWorker = new ComlinkWorker<
   typeof import("./workers/StreamWorker.ts")
 >(new URL("workers/StreamWorker.ts", import.meta.url));